@@ -0,0 +1,67 @@
+#ifndef ADDRINDEXRS_H
+#define ADDRINDEXRS_H
+
+/* Generated by cbindgen from src/ffi.rs (see cbindgen.toml), don't edit it manually */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/*
+ Index opened by `addrindexrs_open()`
+ */
+typedef struct AddrIndex AddrIndex;
+
+/*
+ Open the index of the `count` options, each being "name=value" (or "name"
+ for the switches), using the config file names (e.g. "db_dir=/var/lib/db")
+ */
+AddrIndex *addrindexrs_open(const char *const *options, size_t count);
+
+void addrindexrs_close(AddrIndex *index);
+
+/*
+ Index the new blocks and mempool transactions, returning 1 if there were
+ any (0 otherwise)
+ */
+int addrindexrs_update(const AddrIndex *index);
+
+/*
+ JSON array of the transactions of a script, like `blockchain.scripthash.get_history`
+ */
+char *addrindexrs_get_history(const AddrIndex *index, const char *script_hash);
+
+/*
+ JSON array of the unspent outputs of a script, like `blockchain.scripthash.listunspent`
+ */
+char *addrindexrs_get_utxos(const AddrIndex *index, const char *script_hash);
+
+/*
+ Confirmed and unconfirmed balances of a script (in satoshis), returning 0 on success
+ */
+int addrindexrs_get_balance(const AddrIndex *index,
+                            const char *script_hash,
+                            uint64_t *confirmed,
+                            int64_t *unconfirmed);
+
+/*
+ Hex-encoded raw transaction, like `blockchain.transaction.get`
+ */
+char *addrindexrs_get_transaction(const AddrIndex *index, const char *txid);
+
+/*
+ Height of the indexed tip, or -1 on failure
+ */
+int64_t addrindexrs_get_height(const AddrIndex *index);
+
+void addrindexrs_free_string(char *string);
+
+/*
+ Error of the last failed call made by the current thread (or NULL), valid
+ until its next failed call
+ */
+const char *addrindexrs_last_error(void);
+
+#endif /* ADDRINDEXRS_H */
@@ -1,34 +1,183 @@
 use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 
-use crate::{daemon, errors::*, index, signal::Waiter, store};
+use crate::cache::BlockTxIDsCache;
+use crate::config::Config;
+use crate::filter::{compute_block_filter, filter_key, filter_row};
+use crate::mempool::SNAPSHOT_CODE;
+use crate::opreturn::{self, CounterpartyTx, OpReturn, OpReturnIndex};
+use crate::progress::Progress;
+use crate::store::{check_schema, full_compaction, is_fully_compacted, ReadStore, Row, WriteStore};
+use crate::util::{Bytes, FullHash};
+use crate::utxo::{self, Spenders, Utxo, UtxoIndex};
+use crate::watch::Watchlist;
+use crate::{daemon, errors::*, index, metrics::{Gauge, GaugeVec, Metrics}, signal::Waiter, store};
 
 //
-// Application
+// Size and RocksDB statistics of the index DB
+//
+struct DBMetrics {
+    size: Arc<Gauge>,
+    level_size: Arc<GaugeVec>,
+    level_files: Arc<GaugeVec>,
+    pending_compaction: Arc<Gauge>,
+    block_cache_hit_ratio: Arc<Gauge>,
+}
+
+impl DBMetrics {
+    fn new(metrics: &Metrics) -> DBMetrics {
+        DBMetrics {
+            size: metrics.gauge("addrindexrs_db_size_bytes", "Size of the index DB files"),
+            level_size: metrics.gauge_vec(
+                "addrindexrs_db_level_size_bytes",
+                "Size of the SST files by level",
+                "level",
+            ),
+            level_files: metrics.gauge_vec(
+                "addrindexrs_db_level_files",
+                "Number of SST files by level",
+                "level",
+            ),
+            pending_compaction: metrics.gauge(
+                "addrindexrs_db_pending_compaction_bytes",
+                "Estimated bytes to rewrite by the pending compactions",
+            ),
+            block_cache_hit_ratio: metrics.gauge(
+                "addrindexrs_db_block_cache_hit_ratio",
+                "Fraction of the block cache lookups which hit, since startup",
+            ),
+        }
+    }
+
+    fn update(&self, store: &store::DBStore) {
+        self.size.set(store.get_size() as f64);
+        let stats = store.get_stats();
+        for (level, level_stats) in stats.levels.iter().enumerate() {
+            self.level_size.set(&level.to_string(), level_stats.size as f64);
+            self.level_files.set(&level.to_string(), level_stats.files as f64);
+        }
+        self.pending_compaction.set(stats.pending_compaction_bytes as f64);
+        self.block_cache_hit_ratio.set(stats.block_cache_hit_ratio().unwrap_or(0.0));
+    }
+}
+
+//
+// Application: the index DB, kept in sync with bitcoind by `update()`
+// and queried through `query::Query`
 //
 pub struct App {
     store: store::DBStore,
     index: index::Index,
     daemon: daemon::Daemon,
     tip: Mutex<Sha256dHash>,
+    db_metrics: DBMetrics,
+    block_filters: bool,
+    utxo_index: Option<UtxoIndex>,
+    opreturn_index: Option<OpReturnIndex>,
 }
 
 impl App {
     pub fn new(
         store: store::DBStore,
         index: index::Index,
-        daemon: daemon::Daemon
+        daemon: daemon::Daemon,
+        metrics: &Metrics,
+        block_filters: bool,
+        utxo_index: Option<UtxoIndex>,
+        opreturn_index: Option<OpReturnIndex>,
     ) -> Result<Arc<App>> {
+        let db_metrics = DBMetrics::new(metrics);
+        db_metrics.update(&store);
         Ok(Arc::new(App {
             store,
             index,
             daemon: daemon.reconnect()?,
             tip: Mutex::new(Sha256dHash::default()),
+            db_metrics,
+            block_filters,
+            utxo_index,
+            opreturn_index,
         }))
     }
 
-    fn write_store(&self) -> &impl store::WriteStore {
-        &self.store
+    /// Opens the index of `config` and catches up with bitcoind, over JSONRPC,
+    /// for embedding the indexer in another service. None of the servers are
+    /// started, and the one-shot operations (`reindex_from`, `verify`) are
+    /// left to the binary.
+    pub fn open(config: &Config, metrics: &Metrics, signal: &Waiter) -> Result<Arc<App>> {
+        let blocktxids_cache = BlockTxIDsCache::new(config.reloadable.blocktxids_cache_size);
+        let daemon = daemon::Daemon::new(
+            &config.daemon_dir,
+            config.daemon_rpc.clone(),
+            config.cookie_getter(),
+            config.magic,
+            signal.clone(),
+            Arc::new(blocktxids_cache),
+            config.p2p_peers.clone(),
+        )?;
+        let store = match config.read_only {
+            true => {
+                let secondary_dir = config.secondary_dir.as_ref();
+                let secondary_path =
+                    secondary_dir.map(|dir| dir.join(config.network_type.db_subdir()));
+                store::DBStore::open_read_only(
+                    &config.db_path,
+                    secondary_path.as_deref(),
+                    config.db_tuning.clone(),
+                )
+            }
+            false => store::DBStore::open(
+                &config.db_path,
+                /*low_memory=*/ true,
+                config.db_tuning.clone(),
+            ),
+        };
+        check_schema(&store)?;
+        index::set_index_pubkeys(config.index_pubkeys);
+        let index = index::Index::load(
+            &store,
+            &daemon,
+            metrics,
+            config.index_batch_size,
+            config.index_fetch_threads,
+            config.history_limits,
+            Progress::new(metrics),
+        )?
+        .keep_undo(config.undo_depth);
+        let index = match config.watch_file {
+            Some(ref path) => {
+                let network = config.network_type.network();
+                let watchlist = Watchlist::load(path, network, config.watch_range, &store)?;
+                index.watch(Arc::new(watchlist))
+            }
+            None => index,
+        };
+        let store = if is_fully_compacted(&store) || config.read_only {
+            store
+        } else {
+            index.update(&store, signal)?;
+            full_compaction(store)
+        }
+        .enable_compaction();
+        let utxo_index = match config.utxo_index {
+            true => Some(UtxoIndex::new(&daemon, config.index_batch_size)?),
+            false => None,
+        };
+        let opreturn_index = match config.opreturn_index {
+            true => Some(OpReturnIndex::new(&daemon, config.index_batch_size)?),
+            false => None,
+        };
+        App::new(
+            store,
+            index,
+            daemon,
+            metrics,
+            config.block_filters,
+            utxo_index,
+            opreturn_index,
+        )
     }
 
     // TODO: use index for queries.
@@ -36,6 +185,11 @@ impl App {
         &self.store
     }
 
+    // Rows starting with `prefix`, in key order (without loading them all)
+    pub fn iter_scan(&self, prefix: &[u8]) -> impl Iterator<Item = store::Row> + '_ {
+        self.store.iter_scan(prefix)
+    }
+
     pub fn index(&self) -> &index::Index {
         &self.index
     }
@@ -44,12 +198,147 @@ impl App {
         &self.daemon
     }
 
+    /// Indexes the new blocks (if any), returning whether the tip has changed
     pub fn update(&self, signal: &Waiter) -> Result<bool> {
         let mut tip = self.tip.lock().expect("failed to lock tip");
+        if self.store.is_read_only() {
+            // the index is updated by another process
+            self.store.catch_up();
+            let indexed = index::read_last_indexed_block(&self.store);
+            let new_block = *tip != indexed;
+            if new_block {
+                debug!("caught up with the index at {}", indexed);
+                self.index.reload(&self.store);
+                *tip = indexed;
+                self.db_metrics.update(&self.store);
+            }
+            return Ok(new_block);
+        }
         let new_block = *tip != self.daemon().getbestblockhash()?;
         if new_block {
-            *tip = self.index().update(self.write_store(), &signal)?;
+            let initial_sync = *tip == Sha256dHash::default();
+            *tip = self.index().update(&self.store, &signal)?;
+            self.index.prune(&self.store, signal)?;
+            // older blocks' filters are computed on demand
+            if self.block_filters && !initial_sync {
+                if let Err(e) = self.get_block_filter(&tip) {
+                    warn!("failed to compute block filter of {}: {}", *tip, e);
+                }
+            }
+            if let Some(ref utxo_index) = self.utxo_index {
+                utxo_index.update(&self.store, &self.index, signal)?;
+            }
+            if let Some(ref opreturn_index) = self.opreturn_index {
+                opreturn_index.update(&self.store, &self.index, signal)?;
+            }
+            self.db_metrics.update(&self.store);
         }
         Ok(new_block)
     }
+
+    pub fn db_size(&self) -> u64 {
+        self.store.get_size()
+    }
+
+    pub fn db_stats(&self) -> store::DBStats {
+        self.store.get_stats()
+    }
+
+    // The history of the blocks below it isn't indexed (see `HistoryLimits`)
+    pub fn history_start_height(&self) -> usize {
+        index::read_pruned_height(&self.store)
+    }
+
+    // Snapshot the index (between updates, so it's consistent with a single tip)
+    pub fn backup(&self, dir: &Path) -> Result<PathBuf> {
+        let _tip = self.tip.lock().expect("failed to lock tip");
+        self.store.backup(dir)
+    }
+
+    // Rows of the mempool snapshot saved on the last shutdown (see `Tracker::snapshot`)
+    pub fn read_mempool_snapshot(&self) -> Vec<Row> {
+        self.store.scan(SNAPSHOT_CODE)
+    }
+
+    // Replace the mempool snapshot, unless the index is updated by another process
+    pub fn write_mempool_snapshot(&self, rows: Vec<Row>) -> bool {
+        if self.store.is_read_only() {
+            return false;
+        }
+        let keys: HashSet<&Bytes> = rows.iter().map(|row| &row.key).collect();
+        let stale: Vec<Bytes> = self
+            .store
+            .iter_scan(SNAPSHOT_CODE)
+            .map(|row| row.key)
+            .filter(|key| !keys.contains(key))
+            .collect();
+        self.store.write_batch(rows, stale);
+        self.store.flush();
+        true
+    }
+
+    // Confirmed unspent outputs of a script (if the UTXO set is maintained)
+    pub fn get_unspent(&self, script_hash: &FullHash) -> Option<Vec<Utxo>> {
+        self.utxo_index
+            .as_ref()
+            .map(|_| utxo::unspent(&self.store, script_hash))
+    }
+
+    // Confirmed balance of a script (if the UTXO set is maintained)
+    pub fn get_balance(&self, script_hash: &FullHash) -> Option<u64> {
+        self.utxo_index
+            .as_ref()
+            .map(|_| utxo::balance(&self.store, script_hash))
+    }
+
+    // Spending txid prefixes of a script's confirmed outputs, by (funding txid
+    // prefix, vout), with the height they are indexed up to (if the UTXO set
+    // is maintained, and up to date with the indexed chain)
+    pub fn get_spenders(&self, script_hash: &[u8]) -> Option<(Spenders, usize)> {
+        self.utxo_index.as_ref()?;
+        let height = utxo::tip_height(&self.store, &self.index)?;
+        Some((utxo::spenders(&self.store, script_hash), height))
+    }
+
+    // OP_RETURN outputs of the indexed chain whose data starts with `prefix`
+    pub fn get_op_returns(&self, prefix: &[u8], from: usize, to: usize) -> Result<Vec<OpReturn>> {
+        if self.opreturn_index.is_none() {
+            bail!("OP_RETURN index is disabled (see --opreturn-index)");
+        }
+        let mut result = opreturn::find(&self.store, prefix, from, to);
+        result.retain(|op_return| {
+            let header = self.index.get_header(op_return.height);
+            header.is_some_and(|header| *header.hash() == op_return.blockhash)
+        });
+        Ok(result)
+    }
+
+    // Counterparty transactions of the indexed chain, in the blocks' order
+    pub fn get_counterparty_txs(&self, from: usize, to: usize) -> Result<Vec<CounterpartyTx>> {
+        if self.opreturn_index.is_none() {
+            bail!("OP_RETURN index is disabled (see --opreturn-index)");
+        }
+        let mut result = opreturn::find_counterparty(&self.store, from, to);
+        result.retain(|txn| {
+            let header = self.index.get_header(txn.height);
+            header.is_some_and(|header| *header.hash() == txn.blockhash)
+        });
+        Ok(result)
+    }
+
+    // BIP158 basic filter of a block, persisted once computed
+    pub fn get_block_filter(&self, blockhash: &Sha256dHash) -> Result<Bytes> {
+        if !self.block_filters {
+            bail!("block filters are disabled (see --block-filters)");
+        }
+        if let Some(filter) = self.store.get(&filter_key(blockhash)) {
+            return Ok(filter);
+        }
+        let block = self.daemon.getblock(blockhash)?;
+        let filter = compute_block_filter(&block, &self.daemon)?;
+        if !self.store.is_read_only() {
+            self.store.write(vec![filter_row(blockhash, filter.clone())]);
+        }
+        Ok(filter)
+    }
 }
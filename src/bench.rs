@@ -0,0 +1,145 @@
+use bitcoin::network::constants::Network;
+use error_chain::ChainedError;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::errors::*;
+use crate::index::parse_script_hash;
+use crate::query::Query;
+use crate::util::FullHash;
+
+// The script hashes of a queries file, one address or Electrum script hash
+// per line (skipping the empty lines and the '#' comments)
+fn parse_queries(content: &str, network: Network) -> Result<Vec<FullHash>> {
+    content
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(number, line)| {
+            parse_script_hash(line, network).chain_err(|| format!("bad query at line {}", number))
+        })
+        .collect()
+}
+
+//
+// Latencies and throughput of a benchmark run
+//
+#[derive(Debug)]
+pub struct Report {
+    pub queries: usize,
+    pub errors: usize,
+    pub elapsed: Duration,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl Report {
+    fn new(mut latencies: Vec<Duration>, errors: usize, elapsed: Duration) -> Report {
+        latencies.sort();
+        Report {
+            queries: latencies.len(),
+            errors,
+            elapsed,
+            p50: percentile(&latencies, 50),
+            p95: percentile(&latencies, 95),
+            p99: percentile(&latencies, 99),
+            max: latencies.last().cloned().unwrap_or_default(),
+        }
+    }
+
+    // Queries per second
+    pub fn throughput(&self) -> f64 {
+        match self.elapsed.as_secs_f64() {
+            secs if secs > 0.0 => self.queries as f64 / secs,
+            _ => 0.0,
+        }
+    }
+}
+
+// Nearest-rank percentile of sorted latencies
+fn percentile(sorted: &[Duration], p: usize) -> Duration {
+    if sorted.is_empty() {
+        return Duration::default();
+    }
+    let rank = (sorted.len() * p).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+// Replay the queries of `path` on `threads` threads, looking up the whole
+// history of each script hash (bypassing the history cache, so that the runs
+// measure the store and not the cache)
+pub fn run(query: &Query, path: &Path, network: Network, threads: usize) -> Result<Report> {
+    let content = fs::read_to_string(path).chain_err(|| format!("failed to read {:?}", path))?;
+    let script_hashes = parse_queries(&content, network)?;
+    if script_hashes.is_empty() {
+        bail!("no queries in {:?}", path);
+    }
+    let next = AtomicUsize::new(0);
+    let latencies = Mutex::new(Vec::with_capacity(script_hashes.len()));
+    let errors = AtomicUsize::new(0);
+    let start = Instant::now();
+    thread::scope(|scope| {
+        for _ in 0..threads.clamp(1, script_hashes.len()) {
+            scope.spawn(|| {
+                let mut durations = vec![];
+                while let Some(script_hash) =
+                    script_hashes.get(next.fetch_add(1, Ordering::Relaxed))
+                {
+                    let lookup_start = Instant::now();
+                    let lookup = query
+                        .status(script_hash, 9999999999, false)
+                        .and_then(|status| query.history(&status));
+                    match lookup {
+                        Ok(_) => durations.push(lookup_start.elapsed()),
+                        Err(e) => {
+                            warn!("benchmark query failed: {}", e.display_chain());
+                            errors.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                }
+                latencies.lock().unwrap().extend(durations);
+            });
+        }
+    });
+    let elapsed = start.elapsed();
+    Ok(Report::new(
+        latencies.into_inner().unwrap(),
+        errors.into_inner(),
+        elapsed,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let latencies: Vec<Duration> = (1..=200).rev().map(Duration::from_millis).collect();
+        let report = Report::new(latencies, 3, Duration::from_secs(4));
+        assert_eq!(report.queries, 200);
+        assert_eq!(report.errors, 3);
+        assert_eq!(report.p50, Duration::from_millis(100));
+        assert_eq!(report.p95, Duration::from_millis(190));
+        assert_eq!(report.p99, Duration::from_millis(198));
+        assert_eq!(report.max, Duration::from_millis(200));
+        assert_eq!(report.throughput(), 50.0);
+        assert_eq!(
+            percentile(&[Duration::from_millis(7)], 99),
+            Duration::from_millis(7)
+        );
+        assert_eq!(percentile(&[], 50), Duration::default());
+
+        let script_hash = "740485f380ff6379d11ef6fe7d7cdd68aea7f8bd0d953d9fdf3531fb7d531833";
+        let content = format!("# genesis\n\n  {}\n", script_hash);
+        assert_eq!(parse_queries(&content, Network::Bitcoin).unwrap().len(), 1);
+        assert!(parse_queries("# ok\nnot an address\n", Network::Bitcoin).is_err());
+    }
+}
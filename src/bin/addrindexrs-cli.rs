@@ -0,0 +1,279 @@
+extern crate addrindexrs;
+
+#[macro_use]
+extern crate error_chain;
+
+use error_chain::ChainedError;
+use serde_json::{json, Value};
+use std::env;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::process;
+use std::str::FromStr;
+use std::time::Duration;
+
+use addrindexrs::{config::BitcoinNetwork, errors::*};
+
+const USAGE: &str = "\
+Usage: addrindexrs-cli [OPTIONS] <COMMAND> [ARGS...]
+
+Queries a running indexer over its JSON-RPC server.
+
+Commands:
+    history <address|scripthash>    transactions of an address (or an Electrum script hash)
+    utxos <address|scripthash>      unspent outputs of an address
+    balance <address|scripthash>    confirmed and unconfirmed balance of an address
+    status                          indexing status of the server
+    call <method> [params...]       any other method (each param is parsed as JSON, or
+                                    taken as a string)
+
+Options:
+    --server <host:port>     RPC server (default: 127.0.0.1 and the network's default port)
+    --socket <path>          Unix socket of the RPC server (see indexer_rpc_socket)
+    --network <network>      network of the default port ('mainnet', 'testnet', 'regtest' or
+                             'signet')
+    --auth-token <secret>    sent by server.auth first (ADDRINDEXRS_AUTH_TOKEN also works,
+                             and keeps it out of the process list)
+    --timeout <seconds>      of each request (default: 60, 0 for none)
+";
+
+//
+// Connection to the indexer, over TCP or a Unix socket
+//
+struct Client {
+    reader: BufReader<Box<dyn std::io::Read>>,
+    writer: Box<dyn Write>,
+    next_id: u64,
+}
+
+impl Client {
+    fn connect(args: &Args) -> Result<Client> {
+        let timeout = args.timeout;
+        let (reader, writer): (Box<dyn std::io::Read>, Box<dyn Write>) = match args.socket {
+            Some(ref path) => {
+                let stream = UnixStream::connect(path)
+                    .chain_err(|| format!("failed to connect to {:?}", path))?;
+                stream
+                    .set_read_timeout(timeout)
+                    .chain_err(|| "failed to set timeout")?;
+                (
+                    Box::new(stream.try_clone().chain_err(|| "failed to clone")?),
+                    Box::new(stream),
+                )
+            }
+            None => {
+                let stream = TcpStream::connect(&args.server)
+                    .chain_err(|| format!("failed to connect to {}", args.server))?;
+                stream
+                    .set_read_timeout(timeout)
+                    .chain_err(|| "failed to set timeout")?;
+                (
+                    Box::new(stream.try_clone().chain_err(|| "failed to clone")?),
+                    Box::new(stream),
+                )
+            }
+        };
+        Ok(Client {
+            reader: BufReader::new(reader),
+            writer,
+            next_id: 0,
+        })
+    }
+
+    fn call(&mut self, method: &str, params: Vec<Value>) -> Result<Value> {
+        self.next_id += 1;
+        let request =
+            json!({"jsonrpc": "2.0", "id": self.next_id, "method": method, "params": params});
+        let line = request.to_string() + "\n";
+        self.writer
+            .write_all(line.as_bytes())
+            .chain_err(|| "failed to send request")?;
+        loop {
+            let mut line = String::new();
+            let size = self
+                .reader
+                .read_line(&mut line)
+                .chain_err(|| "failed to read response")?;
+            if size == 0 {
+                bail!("connection closed by the server");
+            }
+            let response: Value =
+                serde_json::from_str(&line).chain_err(|| format!("invalid response {:?}", line))?;
+            if response["id"] != json!(self.next_id) {
+                continue; // e.g. a notification
+            }
+            if let Some(error) = response.get("error").filter(|error| !error.is_null()) {
+                let message = error["message"].as_str().map(str::to_owned);
+                bail!("{}", message.unwrap_or_else(|| error.to_string()));
+            }
+            return Ok(response["result"].clone());
+        }
+    }
+}
+
+struct Args {
+    server: String,
+    socket: Option<PathBuf>,
+    auth_token: Option<String>,
+    timeout: Option<Duration>,
+    command: Vec<String>,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args> {
+    let mut server = None;
+    let mut socket = None;
+    let mut network = BitcoinNetwork::Bitcoin;
+    let mut auth_token = env::var("ADDRINDEXRS_AUTH_TOKEN").ok();
+    let mut timeout = Some(Duration::from_secs(60));
+    let mut command = vec![];
+    while let Some(arg) = args.next() {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name.to_owned(), Some(value.to_owned())),
+            None => (arg.clone(), None),
+        };
+        if !name.starts_with("--") || !command.is_empty() {
+            command.push(arg);
+            continue;
+        }
+        if name == "--help" {
+            print!("{}", USAGE);
+            process::exit(0);
+        }
+        let value = match value.or_else(|| args.next()) {
+            Some(value) => value,
+            None => bail!("missing value of {}", name),
+        };
+        match name.as_str() {
+            "--server" => server = Some(value),
+            "--socket" => socket = Some(PathBuf::from(value)),
+            "--network" => network = BitcoinNetwork::from_str(&value)?,
+            "--auth-token" => auth_token = Some(value),
+            "--timeout" => {
+                timeout = match value
+                    .parse()
+                    .chain_err(|| format!("bad timeout {:?}", value))?
+                {
+                    0 => None,
+                    secs => Some(Duration::from_secs(secs)),
+                }
+            }
+            _ => bail!("unknown option {}", name),
+        }
+    }
+    let server = server.unwrap_or_else(|| format!("127.0.0.1:{}", network.default_indexer_port()));
+    Ok(Args {
+        server,
+        socket,
+        auth_token,
+        timeout,
+        command,
+    })
+}
+
+// Electrum script hashes are 64 hex digits, unlike the addresses
+fn script_method(suffix: &str, target: &str) -> (String, Value) {
+    let is_script_hash = target.len() == 64 && target.chars().all(|c| c.is_ascii_hexdigit());
+    let kind = if is_script_hash {
+        "scripthash"
+    } else {
+        "address"
+    };
+    (format!("blockchain.{}.{}", kind, suffix), json!(target))
+}
+
+// The method and params of a command
+fn request(command: &[String]) -> Result<(String, Vec<Value>)> {
+    let (name, args) = match command.split_first() {
+        Some((name, args)) => (name.as_str(), args),
+        None => bail!("missing command (see --help)"),
+    };
+    let target = || match args {
+        [target] => Ok(target.as_str()),
+        _ => Err(Error::from(format!(
+            "{} takes an address or a script hash",
+            name
+        ))),
+    };
+    let (method, params) = match name {
+        "history" => script_method("get_history", target()?),
+        "utxos" => script_method("listunspent", target()?),
+        "balance" => script_method("get_balance", target()?),
+        "status" => return Ok(("server.status".to_owned(), vec![])),
+        "call" => {
+            let (method, params) = match args.split_first() {
+                Some(split) => split,
+                None => bail!("call takes a method name"),
+            };
+            let params = params
+                .iter()
+                .map(|param| serde_json::from_str(param).unwrap_or_else(|_| json!(param)))
+                .collect();
+            return Ok((method.clone(), params));
+        }
+        _ => bail!("unknown command {:?} (see --help)", name),
+    };
+    Ok((method, vec![params]))
+}
+
+fn run() -> Result<()> {
+    let args = parse_args(env::args().skip(1))?;
+    let (method, params) = request(&args.command)?;
+    let mut client = Client::connect(&args)?;
+    if let Some(ref token) = args.auth_token {
+        client
+            .call("server.auth", vec![json!(token)])
+            .chain_err(|| "authentication failed")?;
+    }
+    let result = client.call(&method, params)?;
+    let output = serde_json::to_string_pretty(&result).chain_err(|| "failed to format result")?;
+    println!("{}", output);
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("{}", e.display_chain().to_string().trim_end());
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_request() {
+        let script_hash = "740485f380ff6379d11ef6fe7d7cdd68aea7f8bd0d953d9fdf3531fb7d531833";
+        let (method, params) = request(&command(&["history", script_hash])).unwrap();
+        assert_eq!(method, "blockchain.scripthash.get_history");
+        assert_eq!(params, vec![json!(script_hash)]);
+        let (method, _) = request(&command(&[
+            "utxos",
+            "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq",
+        ]))
+        .unwrap();
+        assert_eq!(method, "blockchain.address.listunspent");
+        let (method, params) =
+            request(&command(&["call", "blockchain.block.header", "5", "x"])).unwrap();
+        assert_eq!(method, "blockchain.block.header");
+        assert_eq!(params, vec![json!(5), json!("x")]);
+        assert!(request(&command(&["balance"])).is_err());
+        assert!(request(&command(&["nope"])).is_err());
+
+        let args = parse_args(command(&["--network", "regtest", "status"]).into_iter()).unwrap();
+        assert_eq!(args.server, "127.0.0.1:18543");
+        assert_eq!(args.command, command(&["status"]));
+        let args = parse_args(
+            command(&["--server=[::1]:50001", "--timeout=0", "call", "--x"]).into_iter(),
+        );
+        let args = args.unwrap();
+        assert_eq!((args.server.as_str(), args.timeout), ("[::1]:50001", None));
+        assert_eq!(args.command, command(&["call", "--x"]));
+    }
+}
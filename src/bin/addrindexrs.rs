@@ -5,81 +5,385 @@ extern crate error_chain;
 extern crate log;
 
 use error_chain::ChainedError;
-use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
 use std::process;
 use std::sync::Arc;
 use std::time::Duration;
 
 use addrindexrs::{
     app::App,
+    bench,
     bulk,
-    cache::BlockTxIDsCache,
+    cache::{BlockTxIDsCache, HistoryCache, TransactionCache},
     config::Config,
     daemon::Daemon,
     errors::*,
-    index::Index,
+    export,
+    grpc::Grpc,
+    index::{self, Index},
+    metrics::Metrics,
+    opreturn::OpReturnIndex,
+    progress::Progress,
     query::Query,
-    rpc::RPC,
-    signal::Waiter,
-    store::{full_compaction, is_fully_compacted, DBStore},
+    rest,
+    rpc::{QueryPool, RateLimiter, ServerSettings, Transport, RPC},
+    signal::{self, Waiter},
+    store::{check_schema, full_compaction, is_fully_compacted, DBStore},
+    systemd,
+    tls::TlsAcceptor,
+    trace,
+    util::spawn_thread,
+    utxo::UtxoIndex,
+    verify,
+    watch::Watchlist,
+    webhook::Webhooks,
+    zmq::{Notifier, Publisher, Refresher},
 };
 
 
 
+//
+// Caches and limits shared by the networks served by the process
+// (txids don't collide across networks, unlike script hashes)
+//
+#[derive(Clone)]
+struct Shared {
+    blocktxids_cache: Arc<BlockTxIDsCache>,
+    tx_cache: Arc<TransactionCache>,
+    rate_limiter: Arc<RateLimiter>,
+    tls: Option<Arc<TlsAcceptor>>,
+}
+
 fn run_server(config: &Config) -> Result<()> {
     let signal = Waiter::start();
-    let blocktxids_cache = Arc::new(BlockTxIDsCache::new(config.blocktxids_cache_size));
+    let metrics = Metrics::new(config.monitoring_addr);
+    metrics.start()?;
+    if let Some(addr) = config.otlp_addr {
+        trace::start_exporter(addr);
+    }
+    // for all the networks, since it changes the rows of the index
+    index::set_index_pubkeys(config.index_pubkeys);
+    let tls = match (&config.tls_cert_file, &config.tls_key_file) {
+        (Some(cert), Some(key)) => Some(Arc::new(TlsAcceptor::new(
+            cert,
+            key,
+            config.tls_min_version,
+        )?)),
+        _ => None,
+    };
+    let shared = Shared {
+        blocktxids_cache: Arc::new(BlockTxIDsCache::new(
+            config.reloadable.blocktxids_cache_size,
+        )),
+        tx_cache: Arc::new(TransactionCache::new(config.reloadable.tx_cache_size)),
+        rate_limiter: RateLimiter::new(
+            config.reloadable.rpc_rate_limit,
+            config.reloadable.rpc_rate_burst,
+        ),
+        tls,
+    };
+    for extra in &config.extra_networks {
+        let (extra, shared, signal) = (extra.clone(), shared.clone(), signal.clone());
+        spawn_thread("network", move || {
+            // only the main network's metrics are exported
+            let metrics = Metrics::new(None);
+            if let Err(e) = run_network(&extra, &shared, &metrics, &signal) {
+                error!("{:?} server failed: {}", extra.network_type, e.display_chain());
+                process::exit(1);
+            }
+        });
+    }
+    run_network(config, &shared, &metrics, &signal)
+}
 
-    let daemon_rpc = config.daemon_rpc_host.as_str().to_owned() + ":" + &config.daemon_rpc_port.to_string();
+fn run_network(config: &Config, shared: &Shared, metrics: &Metrics, signal: &Waiter) -> Result<()> {
+    let reload_requests = signal::reload_requests(); // applied once the servers are started
+    let progress = Progress::new(metrics);
+    let durations = metrics.histogram_vec(
+        "addrindexrs_loop_duration_seconds",
+        "Duration of the main loop steps (index and mempool updates, full compactions)",
+        "step",
+    );
+    let blocktxids_cache = &shared.blocktxids_cache;
+    let tx_cache = &shared.tx_cache;
+    let rate_limiter = &shared.rate_limiter;
 
     let daemon = Daemon::new(
         &config.daemon_dir,
-        daemon_rpc.as_str().to_socket_addrs().unwrap().next().unwrap(),
-        //SocketAddr::new(config.daemon_rpc_host, config.daemon_rpc_port),
+        config.daemon_rpc.clone(),
         config.cookie_getter(),
-        config.network_type,
+        config.magic,
         signal.clone(),
-        blocktxids_cache,
-    )?;
+        blocktxids_cache.clone(),
+        config.p2p_peers.clone(),
+    )?
+    .with_metrics(metrics);
+    let daemon = match config.daemon_rest {
+        true => daemon.enable_rest(),
+        false => daemon,
+    };
+    if config.wait_for_sync {
+        daemon.wait_for_sync()?;
+    }
 
+    systemd::status("indexing");
     // Perform initial indexing from local blk*.dat block files.
-    let store = DBStore::open(&config.db_path, /*low_memory=*/ config.jsonrpc_import);
-    let index = Index::load(&store, &daemon, config.index_batch_size)?;
+    let store = match config.read_only {
+        true => {
+            let secondary_dir = config.secondary_dir.as_ref();
+            let secondary_path = secondary_dir.map(|dir| dir.join(config.network_type.db_subdir()));
+            DBStore::open_read_only(
+                &config.db_path,
+                secondary_path.as_deref(),
+                config.db_tuning.clone(),
+            )
+        }
+        false => DBStore::open(
+            &config.db_path,
+            /*low_memory=*/ config.jsonrpc_import,
+            config.db_tuning.clone(),
+        ),
+    };
+    check_schema(&store)?;
+    let index = Index::load(
+        &store,
+        &daemon,
+        metrics,
+        config.index_batch_size,
+        config.index_fetch_threads,
+        config.history_limits,
+        progress.clone(),
+    )?
+    .keep_undo(config.undo_depth);
+    let index = match config.watch_file {
+        Some(ref path) => {
+            let network = config.network_type.network();
+            let watchlist = Watchlist::load(path, network, config.watch_range, &store)?;
+            index.watch(Arc::new(watchlist))
+        }
+        None => index,
+    };
+
+    if let Some(height) = config.reindex_from {
+        index.rewind(&store, height, signal)?;
+    }
 
-    let store = if is_fully_compacted(&store) {
-        // initial import and full compaction are over
+    if let Some(samples) = config.verify {
+        let mismatches = verify::verify(&store, &index, &daemon, samples, signal)?;
+        for mismatch in &mismatches {
+            error!("{}", mismatch);
+        }
+        info!("index verification found {} mismatches", mismatches.len());
+        process::exit(if mismatches.is_empty() { 0 } else { 2 });
+    }
+
+    let store = if is_fully_compacted(&store) || config.read_only {
+        // initial import and full compaction are over (or left to the writer process)
         store
-    } else if config.jsonrpc_import {
-        // slower: uses JSONRPC for fetching blocks
-        index.update(&store, &signal)?;
-        full_compaction(store)
+    } else if config.jsonrpc_import
+        || daemon.is_pruned()
+        || config.history_limits.start_height.is_some()
+        || config.watch_file.is_some()
+    {
+        // slower: uses JSONRPC (or P2P, for pruned blocks) for fetching blocks
+        // (and skips the blocks below the start height, or indexes them in order
+        // for a watchlist)
+        index.update(&store, signal)?;
+        durations.time("compaction", || full_compaction(store))
     } else {
         // faster, but uses more memory
-        let store = bulk::index_blk_files(&daemon, config.bulk_index_threads, &signal, store)?;
-        let store = full_compaction(store);
+        let store = bulk::index_blk_files(
+            &daemon,
+            config.bulk_index_threads,
+            signal,
+            store,
+            progress,
+            config.utxo_index,
+        )?;
+        let store = durations.time("compaction", || full_compaction(store));
         // make sure the block header index is up-to-date
         index.reload(&store);
         store
     }
     .enable_compaction(); // enable auto compactions before starting incremental index updates.
 
-    let app = App::new(store, index, daemon)?;
-    let query = Query::new(app.clone(), 100);
+    let utxo_index = match config.utxo_index {
+        true => Some(UtxoIndex::new(&daemon, config.index_batch_size)?),
+        false => None,
+    };
+    let opreturn_index = match config.opreturn_index {
+        true => Some(OpReturnIndex::new(&daemon, config.index_batch_size)?),
+        false => None,
+    };
+    let app = App::new(
+        store,
+        index,
+        daemon,
+        metrics,
+        config.block_filters,
+        utxo_index,
+        opreturn_index,
+    )?;
+    let history_cache = Arc::new(HistoryCache::new(config.reloadable.history_cache_size));
+    let query = Query::new(
+        app.clone(),
+        metrics,
+        config.reloadable.txid_limit,
+        tx_cache.clone(),
+        history_cache.clone(),
+    );
+    query.load_mempool();
+
+    if let Some((ref address, ref path)) = config.export {
+        app.update(signal)?;
+        query.update_mempool()?;
+        let network = config.network_type.network();
+        let count = export::export_history(&query, address, network, path)?;
+        info!("exported {} transfers of {} to {:?}", count, address, path);
+        process::exit(0);
+    }
 
-    let mut server = None; // Indexer RPC server
-    loop {
-        app.update(&signal)?;
+    if let Some(ref path) = config.bench {
+        app.update(signal)?;
         query.update_mempool()?;
-        server.get_or_insert_with(|| {
-            RPC::start(
-                SocketAddr::new(IpAddr::V4(config.indexer_rpc_host), config.indexer_rpc_port),
-                query.clone(),
-            )
-        });
-        if let Err(err) = signal.wait(Duration::from_secs(5)) {
+        let network = config.network_type.network();
+        let report = bench::run(&query, path, network, config.query_threads)?;
+        info!(
+            "benchmarked {} queries ({} failed) on {} threads in {:.3}s: {:.1} queries/s, \
+             p50={:?} p95={:?} p99={:?} max={:?}",
+            report.queries,
+            report.errors,
+            config.query_threads,
+            report.elapsed.as_secs_f64(),
+            report.throughput(),
+            report.p50,
+            report.p95,
+            report.p99,
+            report.max
+        );
+        process::exit(0);
+    }
+
+    let mut notifier = Notifier::new();
+    if let Some(addr) = config.zmq_pub_raw_block {
+        notifier.subscribe(addr, "rawblock");
+    }
+    if let Some(addr) = config.zmq_pub_hash_tx {
+        notifier.subscribe(addr, "hashtx");
+    }
+    // With ZMQ, polling is only a fallback in case notifications are lost
+    let poll_interval = Duration::from_secs(if notifier.is_enabled() { 60 } else { 5 });
+    let poll_interval = config.mempool_poll_interval.unwrap_or(poll_interval);
+    // systemd expects a ping at least every half watchdog timeout
+    // A secondary instance catches up with the writer process more often
+    let poll_interval = match config.secondary_dir {
+        Some(_) => poll_interval.min(config.catch_up_interval),
+        None => poll_interval,
+    };
+    let poll_interval = match systemd::watchdog_interval() {
+        Some(interval) => poll_interval.min(interval),
+        None => poll_interval,
+    };
+
+    let backup_requests = config.backup_dir.as_ref().map(|_| signal::backup_requests());
+
+    let webhooks = match config.webhook_file {
+        Some(ref path) => {
+            let network = config.network_type.network();
+            Some(Arc::new(Webhooks::load(path, network, metrics)?))
+        }
+        None => None,
+    };
+    let mut server: Option<RPC> = None; // Indexer RPC server
+    let mut grpc: Option<Grpc> = None;
+    let refresher = Refresher::new(&notifier);
+    loop {
+        refresher.start();
+        let new_block = durations.time("index", || app.update(signal))?;
+        let mempool_changed = durations.time("mempool", || query.update_mempool())?;
+        refresher.finish();
+        match server {
+            Some(ref server) if new_block || mempool_changed => {
+                server.notify();
+                if let Some(ref grpc) = grpc {
+                    grpc.notify();
+                }
+                if let Some(ref webhooks) = webhooks {
+                    if let Err(e) = webhooks.update(&query) {
+                        warn!("failed to update webhooks: {}", e.display_chain());
+                    }
+                }
+            }
+            Some(_) => (),
+            None => {
+                let tcp = config.indexer_rpc_addrs.iter().map(|a| (*a, Transport::Tcp));
+                let ws = config.indexer_ws_addrs.iter().map(|a| (*a, Transport::WebSocket));
+                let settings = Arc::new(ServerSettings {
+                    max_connections: config.max_connections,
+                    auth_token: config.auth_token.clone(),
+                    banner: config.server_banner.clone(),
+                    network: config.network_type.network(),
+                    query_timeout: config.query_timeout,
+                    query_pool: Arc::new(QueryPool::new(
+                        config.query_threads,
+                        config.query_queue_size,
+                    )),
+                    webhooks: webhooks.clone(),
+                    refresher: Arc::clone(&refresher),
+                });
+                let socket = config.indexer_rpc_socket.clone();
+                server = Some(RPC::start(
+                    tcp.chain(ws).collect(),
+                    socket.map(|path| (path, config.indexer_rpc_socket_mode)),
+                    query.clone(),
+                    metrics,
+                    shared.tls.clone(),
+                    rate_limiter.clone(),
+                    Arc::clone(&settings),
+                ));
+                if let Some(addr) = config.rest_addr {
+                    rest::start(addr, query.clone(), rate_limiter.clone(), Arc::clone(&settings))?;
+                }
+                if let Some(addr) = config.grpc_addr {
+                    // shares the query pool and rate limits of the RPC server
+                    grpc = Some(Grpc::start(addr, query.clone(), rate_limiter.clone(), settings)?);
+                }
+                if let Some(ref webhooks) = webhooks {
+                    webhooks.update(&query)?; // the transactions seen until now aren't notified
+                }
+                if let Some(addr) = config.zmq_pub_index {
+                    query.publish_to(Arc::new(Publisher::start(addr)?));
+                }
+                systemd::ready();
+            }
+        }
+        systemd::watchdog();
+        if let Err(err) = signal.wait_or_notify(poll_interval, notifier.receiver()) {
             info!("stopping servertest: {}", err);
+            query.save_mempool();
             process::exit(1);
         }
+        if reload_requests.try_recv().is_ok() {
+            match Config::reload() {
+                Ok(reloadable) => {
+                    log::set_max_level(reloadable.log_level);
+                    query.set_txid_limit(reloadable.txid_limit);
+                    rate_limiter.set_limits(reloadable.rpc_rate_limit, reloadable.rpc_rate_burst);
+                    blocktxids_cache.set_capacity(reloadable.blocktxids_cache_size);
+                    tx_cache.set_capacity(reloadable.tx_cache_size);
+                    history_cache.set_capacity(reloadable.history_cache_size);
+                    info!("reloaded config: {:?}", reloadable);
+                }
+                Err(e) => error!("failed to reload config: {}", e),
+            }
+        }
+        if let (Some(dir), Some(requests)) = (&config.backup_dir, &backup_requests) {
+            if requests.try_recv().is_ok() {
+                match app.backup(dir) {
+                    Ok(path) => info!("index snapshot saved to {:?}", path),
+                    Err(e) => error!("index snapshot failed: {}", e.display_chain()),
+                }
+            }
+        }
     }
 }
 
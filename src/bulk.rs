@@ -1,11 +1,16 @@
 use bitcoin::blockdata::block::Block;
-use bitcoin::consensus::encode::{deserialize, Decodable};
+use bitcoin::blockdata::opcodes::all::*;
+use bitcoin::blockdata::script::{Builder, Script};
+use bitcoin::blockdata::transaction::TxOut;
+use bitcoin::consensus::encode::{deserialize, Decodable, VarInt};
 use bitcoin::util::hash::BitcoinHash;
+use bitcoin::util::key::PublicKey;
 use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use bitcoin_hashes::Hash;
 use libc;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::io::Cursor;
+use std::io::{Cursor, Read};
 use std::path::{Path, PathBuf};
 use std::sync::{
     mpsc::{Receiver, SyncSender},
@@ -16,9 +21,14 @@ use std::thread;
 use crate::daemon::Daemon;
 use crate::errors::*;
 use crate::index::{index_block, last_indexed_block, read_indexed_blockhashes};
+use crate::progress::Progress;
 use crate::signal::Waiter;
-use crate::store::{DBStore, Row, WriteStore};
-use crate::util::{spawn_thread, HeaderList, SyncChannel};
+use crate::store::{DBStore, ReadStore, Row, WriteStore};
+use crate::util::{spawn_thread, Bytes, HeaderList, SyncChannel};
+use crate::utxo::{self, SpentOutput};
+
+// Scripts longer than this are unspendable (and their undo data is truncated)
+const MAX_SCRIPT_SIZE: u64 = 10_000;
 
 //
 // Blockchain parser (bulk mode)
@@ -27,18 +37,22 @@ struct Parser {
     magic: u32,
     current_headers: HeaderList,
     indexed_blockhashes: Mutex<HashSet<Sha256dHash>>,
+    utxo_height: Option<usize>, // the UTXO set is built up to this height
 }
 
 impl Parser {
     fn new(
-        daemon: &Daemon,
+        magic: u32,
+        current_headers: HeaderList,
         indexed_blockhashes: HashSet<Sha256dHash>,
-    ) -> Result<Arc<Parser>> {
-        Ok(Arc::new(Parser {
-            magic: daemon.magic(),
-            current_headers: load_headers(daemon)?,
+        utxo_height: Option<usize>,
+    ) -> Arc<Parser> {
+        Arc::new(Parser {
+            magic,
+            current_headers,
             indexed_blockhashes: Mutex::new(indexed_blockhashes),
-        }))
+            utxo_height,
+        })
     }
 
     fn last_indexed_row(&self) -> Row {
@@ -59,25 +73,46 @@ impl Parser {
         Ok(blob)
     }
 
-    fn index_blkfile(&self, blob: Vec<u8>) -> Result<Vec<Row>> {
+    // The undo data of a blk*.dat file's blocks, if the UTXO set is built
+    fn read_revfile(&self, path: &Path) -> Result<Option<Vec<u8>>> {
+        if self.utxo_height.is_none() {
+            return Ok(None);
+        }
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let path = path.with_file_name(name.replacen("blk", "rev", 1));
+        let blob = fs::read(&path).chain_err(|| format!("failed to read {:?}", path))?;
+        Ok(Some(blob))
+    }
+
+    // Returns the rows of the new blocks, and their count
+    fn index_blkfile(&self, blob: Vec<u8>, undo: Option<Vec<u8>>) -> Result<(Vec<Row>, usize)> {
         let blocks = parse_blocks(blob, self.magic)?;
+        let mut undo = UndoFile::parse(undo.as_deref().unwrap_or_default(), self.magic)?;
 
         let mut rows = Vec::<Row>::new();
+        let mut count = 0;
         for block in blocks {
             let blockhash = block.bitcoin_hash();
-            if let Some(_header) = self.current_headers.header_by_blockhash(&blockhash) {
+            if let Some(header) = self.current_headers.header_by_blockhash(&blockhash) {
                 if self.indexed_blockhashes
                     .lock()
                     .expect("indexed_blockhashes")
                     .insert(blockhash)
                 {
-                    rows.extend(index_block(&block));
+                    rows.extend(index_block(&block, header.height()));
+                    if self.utxo_height.is_some_and(|height| header.height() <= height) {
+                        let spent = undo
+                            .take(&block)
+                            .chain_err(|| format!("missing undo data of block {}", blockhash))?;
+                        rows.extend(utxo::bulk_rows(&block, header.height(), &spent));
+                    }
+                    count += 1;
                 }
             }
         }
 
         rows.sort_unstable_by(|a, b| a.key.cmp(&b.key));
-        Ok(rows)
+        Ok((rows, count))
     }
 }
 
@@ -130,6 +165,180 @@ fn parse_blocks(blob: Vec<u8>, magic: u32) -> Result<Vec<Block>> {
     Ok(blocks)
 }
 
+//
+// Undo data of the blocks of a blk*.dat file, from the matching rev*.dat file
+// (with the outputs spent by each block, in the order the blocks were
+// connected). Each record holds the size of the data, the data and a checksum
+// of the parent block hash followed by the data.
+//
+#[derive(Default)]
+struct UndoFile<'a> {
+    // the records, by count of inputs of each non-coinbase transaction
+    records: HashMap<Vec<usize>, Vec<UndoRecord<'a>>>,
+}
+
+struct UndoRecord<'a> {
+    data: &'a [u8],
+    checksum: &'a [u8],
+    spent: Vec<Vec<SpentOutput>>,
+}
+
+impl<'a> UndoFile<'a> {
+    fn parse(blob: &'a [u8], magic: u32) -> Result<UndoFile<'a>> {
+        let mut records: HashMap<_, Vec<_>> = HashMap::new();
+        let mut pos = 0;
+        while pos + 8 <= blob.len() {
+            let value = u32::from_le_bytes([blob[pos], blob[pos + 1], blob[pos + 2], blob[pos + 3]]);
+            if value != magic {
+                pos += 1; // e.g. the zeroes preallocated by bitcoind
+                continue;
+            }
+            let size = u32::from_le_bytes([blob[pos + 4], blob[pos + 5], blob[pos + 6], blob[pos + 7]]);
+            let start = pos + 8;
+            let end = start + size as usize;
+            if end + 32 > blob.len() {
+                break; // still written by bitcoind
+            }
+            let (data, checksum) = (&blob[start..end], &blob[end..end + 32]);
+            let spent = decode_block_undo(data)
+                .chain_err(|| format!("failed to parse undo data at {}..{}", start, end))?;
+            let shape = spent.iter().map(|spent| spent.len()).collect();
+            records.entry(shape).or_default().push(UndoRecord {
+                data,
+                checksum,
+                spent,
+            });
+            pos = end + 32;
+        }
+        Ok(UndoFile { records })
+    }
+
+    // The outputs spent by each non-coinbase transaction of a block
+    fn take(&mut self, block: &Block) -> Option<Vec<Vec<SpentOutput>>> {
+        if block.txdata.len() == 1 {
+            return Some(vec![]); // nothing is spent
+        }
+        let shape: Vec<usize> = block.txdata[1..].iter().map(|tx| tx.input.len()).collect();
+        let records = self.records.get_mut(&shape)?;
+        let prev_blockhash = &block.header.prev_blockhash[..];
+        let pos = records.iter().position(|record| {
+            Sha256dHash::hash(&[prev_blockhash, record.data].concat())[..] == record.checksum[..]
+        })?;
+        Some(records.swap_remove(pos).spent)
+    }
+}
+
+// bitcoind's VARINT encoding (MSB base-128, with an offset per byte)
+fn read_varint(cursor: &mut Cursor<&[u8]>) -> Result<u64> {
+    let mut value: u64 = 0;
+    loop {
+        let byte = u8::consensus_decode(&mut *cursor).chain_err(|| "truncated VARINT")?;
+        if value > u64::MAX >> 7 {
+            bail!("VARINT overflow");
+        }
+        value = (value << 7) | u64::from(byte & 0x7f);
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        value += 1;
+    }
+}
+
+fn read_bytes(cursor: &mut Cursor<&[u8]>, len: u64) -> Result<Vec<u8>> {
+    let remaining = cursor.get_ref().len() as u64 - cursor.position();
+    if len > remaining {
+        bail!("truncated undo data");
+    }
+    let mut bytes = vec![0; len as usize];
+    cursor.read_exact(&mut bytes).chain_err(|| "truncated undo data")?;
+    Ok(bytes)
+}
+
+fn decompress_amount(mut x: u64) -> u64 {
+    if x == 0 {
+        return 0;
+    }
+    x -= 1;
+    let mut e = x % 10;
+    x /= 10;
+    let mut n = if e < 9 {
+        let d = x % 9 + 1;
+        x /= 9;
+        x * 10 + d
+    } else {
+        x + 1
+    };
+    while e > 0 {
+        n *= 10;
+        e -= 1;
+    }
+    n
+}
+
+fn decompress_script(cursor: &mut Cursor<&[u8]>) -> Result<Script> {
+    let size = read_varint(cursor)?;
+    Ok(match size {
+        0 => Builder::new()
+            .push_opcode(OP_DUP)
+            .push_opcode(OP_HASH160)
+            .push_slice(&read_bytes(cursor, 20)?)
+            .push_opcode(OP_EQUALVERIFY)
+            .push_opcode(OP_CHECKSIG)
+            .into_script(),
+        1 => Builder::new()
+            .push_opcode(OP_HASH160)
+            .push_slice(&read_bytes(cursor, 20)?)
+            .push_opcode(OP_EQUAL)
+            .into_script(),
+        2 | 3 => Builder::new()
+            .push_slice(&[&[size as u8][..], &read_bytes(cursor, 32)?].concat())
+            .push_opcode(OP_CHECKSIG)
+            .into_script(),
+        4 | 5 => {
+            let key = [&[size as u8 - 2][..], &read_bytes(cursor, 32)?].concat();
+            let mut key = PublicKey::from_slice(&key).chain_err(|| "invalid public key")?;
+            key.compressed = false;
+            Builder::new()
+                .push_slice(&key.to_bytes())
+                .push_opcode(OP_CHECKSIG)
+                .into_script()
+        }
+        _ if size - 6 > MAX_SCRIPT_SIZE => {
+            read_bytes(cursor, size - 6)?;
+            Builder::new().push_opcode(OP_RETURN).into_script()
+        }
+        _ => Script::from(read_bytes(cursor, size - 6)?),
+    })
+}
+
+// The outputs spent by each non-coinbase transaction of a block (`CBlockUndo`)
+fn decode_block_undo(data: &[u8]) -> Result<Vec<Vec<SpentOutput>>> {
+    let mut cursor = Cursor::new(data);
+    let mut txs = vec![];
+    let count = VarInt::consensus_decode(&mut cursor).chain_err(|| "truncated undo data")?;
+    for _ in 0..count.0 {
+        let mut spent = vec![];
+        let count = VarInt::consensus_decode(&mut cursor).chain_err(|| "truncated undo data")?;
+        for _ in 0..count.0 {
+            let code = read_varint(&mut cursor)?;
+            if code / 2 > 0 {
+                read_varint(&mut cursor)?; // unused, kept by bitcoind for compatibility
+            }
+            let value = decompress_amount(read_varint(&mut cursor)?);
+            let script_pubkey = decompress_script(&mut cursor)?;
+            spent.push(SpentOutput {
+                output: TxOut { value, script_pubkey },
+                height: (code / 2) as u32,
+            });
+        }
+        txs.push(spent);
+    }
+    if cursor.position() != data.len() as u64 {
+        bail!("unexpected trailing undo data");
+    }
+    Ok(txs)
+}
+
 //
 // Retrieve the block headers
 //
@@ -141,6 +350,40 @@ fn load_headers(daemon: &Daemon) -> Result<HeaderList> {
     Ok(headers)
 }
 
+//
+// Row marking a blk*.dat file as indexed, with its size (since bitcoind
+// keeps appending blocks to the last files)
+//
+fn blk_file_key(path: &Path) -> Bytes {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    [b"D", name.as_bytes()].concat()
+}
+
+fn blk_file_row(path: &Path, size: usize) -> Row {
+    Row {
+        key: blk_file_key(path),
+        value: bincode::serialize(&(size as u64)).unwrap(),
+    }
+}
+
+//
+// Skip the blk*.dat files indexed by a previous (interrupted) bulk import
+//
+fn skip_indexed_blk_files(store: &dyn ReadStore, blk_files: Vec<PathBuf>) -> Vec<PathBuf> {
+    let indexed: HashMap<Bytes, u64> = store
+        .scan(b"D")
+        .into_iter()
+        .map(|row| (row.key, bincode::deserialize(&row.value).unwrap()))
+        .collect();
+    blk_files
+        .into_iter()
+        .filter(|path| {
+            let size = fs::metadata(path).map(|m| m.len()).ok();
+            size.is_none() || indexed.get(&blk_file_key(path)) != size.as_ref()
+        })
+        .collect()
+}
+
 //
 // Manage open file limits
 //
@@ -163,7 +406,7 @@ fn set_open_files_limit(limit: libc::rlim_t) {
 
 
 type JoinHandle = thread::JoinHandle<Result<()>>;
-type BlobReceiver = Arc<Mutex<Receiver<(Vec<u8>, PathBuf)>>>;
+type BlobReceiver = Arc<Mutex<Receiver<(Vec<u8>, Option<Vec<u8>>, PathBuf)>>>;
 
 //
 // 
@@ -173,8 +416,9 @@ fn start_reader(blk_files: Vec<PathBuf>, parser: Arc<Parser>) -> (BlobReceiver,
     let blobs = chan.sender();
     let handle = spawn_thread("bulk_read", move || -> Result<()> {
         for path in blk_files {
+            let undo = parser.read_revfile(&path)?;
             blobs
-                .send((parser.read_blkfile(&path)?, path))
+                .send((parser.read_blkfile(&path)?, undo, path))
                 .expect("failed to send blk*.dat contents");
         }
         Ok(())
@@ -188,17 +432,19 @@ fn start_reader(blk_files: Vec<PathBuf>, parser: Arc<Parser>) -> (BlobReceiver,
 fn start_indexer(
     blobs: BlobReceiver,
     parser: Arc<Parser>,
-    writer: SyncSender<(Vec<Row>, PathBuf)>,
+    writer: SyncSender<(Vec<Row>, usize, PathBuf)>,
 ) -> JoinHandle {
     spawn_thread("bulk_index", move || -> Result<()> {
         loop {
             let msg = blobs.lock().unwrap().recv();
-            if let Ok((blob, path)) = msg {
-                let rows = parser
-                    .index_blkfile(blob)
+            if let Ok((blob, undo, path)) = msg {
+                let marker = blk_file_row(&path, blob.len());
+                let (mut rows, blocks) = parser
+                    .index_blkfile(blob, undo)
                     .chain_err(|| format!("failed to index {:?}", path))?;
+                rows.push(marker); // written together with the file's rows
                 writer
-                    .send((rows, path))
+                    .send((rows, blocks, path))
                     .expect("failed to send indexed rows")
             } else {
                 debug!("no more blocks to index");
@@ -210,24 +456,43 @@ fn start_indexer(
 }
 
 //
-// Index block files of bitcoind
+// Index block files of bitcoind (and build the UTXO set from the undo
+// files, if `utxo_index` is set and the import was started with it)
 //
 pub fn index_blk_files(
     daemon: &Daemon,
     index_threads: usize,
     signal: &Waiter,
     store: DBStore,
+    progress: Arc<Progress>,
+    utxo_index: bool,
 ) -> Result<DBStore> {
 
     set_open_files_limit(2048); // twice the default `ulimit -n` value
 
     let blk_files = daemon.list_blk_files()?;
+    let total = blk_files.len();
+    let blk_files = skip_indexed_blk_files(&store, blk_files);
+    if blk_files.len() < total {
+        info!("skipping {} already indexed blk*.dat files", total - blk_files.len());
+    }
     info!("indexing {} blk*.dat files", blk_files.len());
 
     let indexed_blockhashes = read_indexed_blockhashes(&store);
     debug!("found {} indexed blocks", indexed_blockhashes.len());
 
-    let parser = Parser::new(daemon, indexed_blockhashes)?;
+    let indexed = indexed_blockhashes.len();
+    let current_headers = load_headers(daemon)?;
+    let best_height = current_headers.len().saturating_sub(1);
+    let utxo_height = match utxo_index {
+        true => utxo::start_bulk_import(&store, indexed == 0, best_height),
+        false => None,
+    };
+    if let Some(height) = utxo_height {
+        info!("building the UTXO set up to height {} from the undo files", height);
+    }
+    let parser = Parser::new(daemon.magic(), current_headers, indexed_blockhashes, utxo_height);
+    progress.start(indexed, parser.current_headers.len());
     let (blobs, reader) = start_reader(blk_files, parser.clone());
     let rows_chan = SyncChannel::new(0);
 
@@ -238,9 +503,11 @@ pub fn index_blk_files(
     let signal = signal.clone();
 
     spawn_thread("bulk_writer", move || -> Result<DBStore> {
-        for (rows, path) in rows_chan.into_receiver() {
+        for (rows, blocks, path) in rows_chan.into_receiver() {
             trace!("indexed {:?}: {} rows", path, rows.len());
+            let rows_count = rows.len();
             store.write(rows);
+            progress.add(blocks, rows_count);
             signal
                 .poll()
                 .chain_err(|| "stopping bulk indexing due to signal")?;
@@ -258,6 +525,16 @@ pub fn index_blk_files(
         });
 
         store.write(vec![parser.last_indexed_row()]);
+        if let Some(height) = parser.utxo_height {
+            let indexed_blockhashes = parser.indexed_blockhashes.lock().unwrap();
+            let complete = parser
+                .current_headers
+                .iter()
+                .take(height + 1)
+                .all(|header| indexed_blockhashes.contains(header.hash()));
+            let tip = parser.current_headers.header_by_height(height).filter(|_| complete);
+            utxo::finish_bulk_import(&store, tip.map(|header| header.hash()));
+        }
         Ok(store)
     })
     .join()
@@ -271,6 +548,8 @@ mod tests {
     use bitcoin_hashes::Hash;
     use hex::decode as hex_decode;
 
+    use crate::store::TempStore;
+
     #[test]
     fn test_incomplete_block_parsing() {
         let magic = 0x0709110b;
@@ -283,6 +562,67 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_skip_indexed_blk_files() {
+        let store = TempStore::new("bulk");
+        let blk_files: Vec<PathBuf> =
+            (0..3).map(|i| store.dir().join(format!("blk0000{}.dat", i))).collect();
+        for path in &blk_files {
+            fs::write(path, b"blocks").unwrap();
+        }
+        assert_eq!(skip_indexed_blk_files(&store, blk_files.clone()), blk_files);
+
+        store.write(vec![blk_file_row(&blk_files[0], 6), blk_file_row(&blk_files[2], 6)]);
+        fs::write(&blk_files[2], b"more blocks").unwrap(); // still written by bitcoind
+        assert_eq!(skip_indexed_blk_files(&store, blk_files.clone()), &blk_files[1..]);
+    }
+
+    #[test]
+    fn test_undo_file() {
+        let magic = 0x0709110b;
+        let data = [
+            &[0x01, 0x02][..],       // 1 transaction, spending 2 outputs:
+            &[0x0b, 0x00, 0x32, 0x00], // of a coinbase at height 5, 50 BTC, P2PKH
+            &[0xab; 20],
+            &[0x00, 0x00, 0x07, 0x51], // at height 0, 0 BTC, to OP_TRUE
+        ]
+        .concat();
+        let spent = decode_block_undo(&data).unwrap();
+        assert_eq!(spent.len(), 1);
+        assert_eq!(spent[0][0].height, 5);
+        assert_eq!(spent[0][0].output.value, 5_000_000_000);
+        assert!(spent[0][0].output.script_pubkey.is_p2pkh());
+        assert_eq!(spent[0][0].output.script_pubkey[3..23], [0xab; 20]);
+        assert_eq!(spent[0][1].height, 0);
+        assert_eq!(spent[0][1].output.script_pubkey[..], [0x51]);
+        assert!(decode_block_undo(&data[..data.len() - 1]).is_err());
+        assert_eq!(decompress_amount(0), 0);
+        assert_eq!(decompress_amount(0x32), 5_000_000_000);
+        let mut cursor = Cursor::new(&[0x80, 0x00][..]);
+        assert_eq!(read_varint(&mut cursor).unwrap(), 128);
+
+        // a record is matched to its block by its checksum
+        let raw_blocks = hex_decode(fixture("incomplete_block.hex")).unwrap();
+        let mut block = parse_blocks(raw_blocks, magic).unwrap().remove(0);
+        block.txdata.truncate(1);
+        assert_eq!(undo_file(&[], magic).take(&block).unwrap().len(), 0);
+        let mut tx = block.txdata[0].clone();
+        tx.input.push(tx.input[0].clone());
+        block.txdata.push(tx);
+        let checksum = Sha256dHash::hash(&[&block.header.prev_blockhash[..], &data].concat());
+        let record = |checksum: &[u8]| {
+            [&magic.to_le_bytes()[..], &(data.len() as u32).to_le_bytes(), &data, checksum].concat()
+        };
+        let blob = [record(&[0; 32]), vec![0; 10], record(&checksum[..])].concat();
+        let mut undo = undo_file(&blob, magic);
+        assert_eq!(undo.take(&block).unwrap().len(), 1);
+        assert!(undo.take(&block).is_none());
+    }
+
+    fn undo_file(blob: &[u8], magic: u32) -> UndoFile<'_> {
+        UndoFile::parse(blob, magic).unwrap()
+    }
+
     pub fn fixture(filename: &str) -> String {
         let path = Path::new("src")
             .join("tests")
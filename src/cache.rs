@@ -1,8 +1,15 @@
 use crate::errors::*;
+use crate::index::compute_script_hash;
+use crate::query::{HistoryEntry, OutPoint};
+use crate::util::FullHash;
+use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::consensus::encode::{deserialize, serialize};
 use bitcoin_hashes::sha256d::Hash as Sha256dHash;
 use lru::LruCache;
+use std::collections::{HashMap, HashSet};
 use std::hash::Hash;
 use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 
 //
@@ -30,21 +37,47 @@ impl<K: Hash + Eq, V> SizedLruCache<K, V> {
         }
     }
 
-    fn put(&mut self, key: K, value: V, byte_size: usize) {
+    // Returns the evicted entries
+    fn put(&mut self, key: K, value: V, byte_size: usize) -> Vec<(K, V)> {
         if byte_size > self.bytes_capacity {
-            return;
+            return vec![];
         }
         if let Some((_, popped_size)) = self.map.put(key, (value, byte_size)) {
             self.bytes_usage -= popped_size
         }
         self.bytes_usage += byte_size;
+        self.evict()
+    }
 
+    fn remove(&mut self, key: &K) -> Option<V> {
+        let (value, byte_size) = self.map.pop(key)?;
+        self.bytes_usage -= byte_size;
+        Some(value)
+    }
+
+    fn clear(&mut self) {
+        self.map.clear();
+        self.bytes_usage = 0;
+    }
+
+    // Returns the evicted entries
+    fn set_capacity(&mut self, bytes_capacity: usize) -> Vec<(K, V)> {
+        self.bytes_capacity = bytes_capacity;
+        self.evict()
+    }
+
+    fn evict(&mut self) -> Vec<(K, V)> {
+        let mut evicted = vec![];
         while self.bytes_usage > self.bytes_capacity {
             match self.map.pop_lru() {
-                Some((_, (_, popped_size))) => self.bytes_usage -= popped_size,
-                None => return,
+                Some((key, (value, popped_size))) => {
+                    self.bytes_usage -= popped_size;
+                    evicted.push((key, value));
+                }
+                None => break,
             }
         }
+        evicted
     }
 }
 
@@ -82,6 +115,217 @@ impl BlockTxIDsCache {
             .put(*blockhash, txids.clone(), byte_size);
         Ok(txids)
     }
+
+    pub fn set_capacity(&self, bytes_capacity: usize) {
+        self.map.lock().unwrap().set_capacity(bytes_capacity);
+    }
+}
+
+//
+// Cache storing the transactions fetched from bitcoind (serialized, to account for their size)
+//
+pub struct TransactionCache {
+    map: Mutex<SizedLruCache<Sha256dHash /* txid */, Vec<u8> /* serialized tx */>>,
+}
+
+impl TransactionCache {
+    pub fn new(bytes_capacity: usize) -> TransactionCache {
+        TransactionCache {
+            map: Mutex::new(SizedLruCache::new(bytes_capacity)),
+        }
+    }
+
+    pub fn get(&self, txid: &Sha256dHash) -> Option<Transaction> {
+        let map = &mut self.map.lock().unwrap();
+        let bytes = map.get(txid)?;
+        Some(deserialize(bytes).expect("invalid cached transaction"))
+    }
+
+    pub fn put(&self, txid: Sha256dHash, tx: &Transaction) {
+        let bytes = serialize(tx);
+        let byte_size = 32 /* key */ + bytes.len();
+        self.map.lock().unwrap().put(txid, bytes, byte_size);
+    }
+
+    pub fn get_or_else<F>(&self, txid: &Sha256dHash, load_tx_func: F) -> Result<Transaction>
+    where
+        F: FnOnce() -> Result<Transaction>,
+    {
+        if let Some(tx) = self.get(txid) {
+            return Ok(tx);
+        }
+        let tx = load_tx_func()?;
+        self.put(*txid, &tx);
+        Ok(tx)
+    }
+
+    pub fn set_capacity(&self, bytes_capacity: usize) {
+        self.map.lock().unwrap().set_capacity(bytes_capacity);
+    }
+}
+
+struct CachedHistory {
+    entries: Vec<HistoryEntry>,
+    outpoints: Vec<OutPoint>, // funding the script
+}
+
+struct HistoryCacheState {
+    map: SizedLruCache<FullHash /* script hash */, CachedHistory>,
+    scripts_by_outpoint: HashMap<OutPoint, FullHash>,
+    mempool_scripts: HashSet<FullHash>, // with mempool transactions in their history
+    generation: u64,                    // incremented by each invalidation
+}
+
+impl HistoryCacheState {
+    fn remove(&mut self, script_hash: &FullHash) {
+        if let Some(history) = self.map.remove(script_hash) {
+            self.forget(script_hash, history);
+        }
+    }
+
+    fn forget(&mut self, script_hash: &FullHash, history: CachedHistory) {
+        for outpoint in &history.outpoints {
+            self.scripts_by_outpoint.remove(outpoint);
+        }
+        self.mempool_scripts.remove(script_hash);
+    }
+
+    fn forget_evicted(&mut self, evicted: Vec<(FullHash, CachedHistory)>) {
+        for (script_hash, history) in evicted {
+            self.forget(&script_hash, history);
+        }
+    }
+}
+
+//
+// Cache storing the history of script hashes, until a transaction
+// funds them or spends their outputs (or a mempool one is replaced)
+//
+pub struct HistoryCache {
+    state: Mutex<HistoryCacheState>,
+}
+
+impl HistoryCache {
+    pub fn new(bytes_capacity: usize) -> HistoryCache {
+        HistoryCache {
+            state: Mutex::new(HistoryCacheState {
+                map: SizedLruCache::new(bytes_capacity),
+                scripts_by_outpoint: HashMap::new(),
+                mempool_scripts: HashSet::new(),
+                generation: 0,
+            }),
+        }
+    }
+
+    // To be read before computing a history, and passed to put()
+    pub fn generation(&self) -> u64 {
+        self.state.lock().unwrap().generation
+    }
+
+    pub fn get(&self, script_hash: &FullHash) -> Option<Vec<HistoryEntry>> {
+        let state = &mut self.state.lock().unwrap();
+        state.map.get(script_hash).map(|history| history.entries.clone())
+    }
+
+    // Skipped if the cache was invalidated since `generation`, as the
+    // history may be missing the invalidating transactions
+    pub fn put(
+        &self,
+        script_hash: FullHash,
+        entries: Vec<HistoryEntry>,
+        outpoints: Vec<OutPoint>,
+        generation: u64,
+    ) {
+        let state = &mut self.state.lock().unwrap();
+        let byte_size = 32 /* key */ + 64 * entries.len() + 72 * outpoints.len();
+        if state.generation != generation || byte_size > state.map.bytes_capacity {
+            return;
+        }
+        state.remove(&script_hash);
+        if entries.iter().any(|entry| entry.height <= 0) {
+            state.mempool_scripts.insert(script_hash);
+        }
+        for outpoint in &outpoints {
+            state.scripts_by_outpoint.insert(*outpoint, script_hash);
+        }
+        let history = CachedHistory { entries, outpoints };
+        let evicted = state.map.put(script_hash, history, byte_size);
+        state.forget_evicted(evicted);
+    }
+
+    // Drop the histories of the scripts funded by the transaction, or whose outputs it spends
+    pub fn invalidate_tx(&self, tx: &Transaction) {
+        let state = &mut self.state.lock().unwrap();
+        state.generation += 1;
+        for output in &tx.output {
+            state.remove(&compute_script_hash(&output.script_pubkey[..]));
+        }
+        for input in &tx.input {
+            let outpoint = (input.previous_output.txid, input.previous_output.vout as usize);
+            if let Some(script_hash) = state.scripts_by_outpoint.get(&outpoint).copied() {
+                state.remove(&script_hash);
+            }
+        }
+    }
+
+    // Drop the histories with mempool transactions (e.g. when they are confirmed or replaced)
+    pub fn invalidate_mempool(&self) {
+        let state = &mut self.state.lock().unwrap();
+        state.generation += 1;
+        let script_hashes: Vec<FullHash> = state.mempool_scripts.iter().copied().collect();
+        for script_hash in &script_hashes {
+            state.remove(script_hash);
+        }
+    }
+
+    pub fn clear(&self) {
+        let state = &mut self.state.lock().unwrap();
+        state.generation += 1;
+        state.map.clear();
+        state.scripts_by_outpoint.clear();
+        state.mempool_scripts.clear();
+    }
+
+    pub fn set_capacity(&self, bytes_capacity: usize) {
+        let state = &mut self.state.lock().unwrap();
+        let evicted = state.map.set_capacity(bytes_capacity);
+        state.forget_evicted(evicted);
+    }
+}
+
+//
+// Cache storing fee estimates (by confirmation target) for a short period
+//
+pub struct FeeEstimatesCache {
+    map: Mutex<HashMap<u16 /* blocks */, (Instant, Option<f64> /* BTC/kvB */)>>,
+    ttl: Duration,
+}
+
+impl FeeEstimatesCache {
+    pub fn new(ttl: Duration) -> FeeEstimatesCache {
+        FeeEstimatesCache {
+            map: Mutex::new(HashMap::new()),
+            ttl,
+        }
+    }
+
+    pub fn get_or_else<F>(&self, blocks: u16, estimate_func: F) -> Result<Option<f64>>
+    where
+        F: FnOnce() -> Result<Option<f64>>,
+    {
+        if let Some((time, fee_rate)) = self.map.lock().unwrap().get(&blocks) {
+            if time.elapsed() < self.ttl {
+                return Ok(*fee_rate);
+            }
+        }
+
+        let fee_rate = estimate_func()?;
+        self.map
+            .lock()
+            .unwrap()
+            .insert(blocks, (Instant::now(), fee_rate));
+        Ok(fee_rate)
+    }
 }
 
 #[cfg(test)]
@@ -114,6 +358,10 @@ mod tests {
         assert_eq!(cache.get(&2), Some(&20));
         assert_eq!(cache.get(&3), Some(&33));
         assert_eq!(cache.get(&9), None);
+
+        cache.set_capacity(50); // drop oldest key (2)
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(cache.get(&3), Some(&33));
     }
 
     fn gen_hash(seed: u8) -> Sha256dHash {
@@ -160,4 +408,133 @@ mod tests {
         cache.get_or_else(&block1, &miss_func).unwrap();
         assert_eq!(4, *misses.lock().unwrap());
     }
+
+    #[test]
+    fn test_transaction_cache_hit_and_miss() {
+        use bitcoin::blockdata::constants::genesis_block;
+        use bitcoin::network::constants::Network;
+
+        let tx = genesis_block(Network::Regtest).txdata.remove(0);
+        let txid = tx.txid();
+        let misses: Mutex<usize> = Mutex::new(0);
+        let miss_func = || {
+            *misses.lock().unwrap() += 1;
+            Ok(tx.clone())
+        };
+
+        let cache = TransactionCache::new(1000);
+        assert_eq!(cache.get(&txid), None);
+        assert_eq!(cache.get_or_else(&txid, miss_func).unwrap(), tx);
+        assert_eq!(cache.get_or_else(&txid, miss_func).unwrap(), tx);
+        assert_eq!(1, *misses.lock().unwrap());
+
+        // too small for the transaction
+        cache.set_capacity(100);
+        assert_eq!(cache.get(&txid), None);
+        cache.get_or_else(&txid, miss_func).unwrap();
+        assert_eq!(2, *misses.lock().unwrap());
+    }
+
+    #[test]
+    fn test_history_cache_invalidation() {
+        use bitcoin::blockdata::script::Script;
+        use bitcoin::blockdata::transaction::{TxIn, TxOut};
+
+        let script_a = Script::from(vec![0x51]);
+        let script_b = Script::from(vec![0x52]);
+        let (hash_a, hash_b) = (compute_script_hash(&script_a[..]), compute_script_hash(&script_b[..]));
+        let entry = |n: u8, height: i64| HistoryEntry {
+            txid: gen_hash(n),
+            height,
+            position: None,
+            fee: None,
+            ancestors: None,
+        };
+        let tx = |inputs: Vec<OutPoint>, output: &Script| Transaction {
+            version: 1,
+            lock_time: 0,
+            input: inputs
+                .into_iter()
+                .map(|(txid, vout)| TxIn {
+                    previous_output: bitcoin::OutPoint { txid, vout: vout as u32 },
+                    script_sig: Script::new(),
+                    sequence: 0xFFFFFFFF,
+                    witness: vec![],
+                })
+                .collect(),
+            output: vec![TxOut {
+                value: 1,
+                script_pubkey: output.clone(),
+            }],
+        };
+        let fill = |cache: &HistoryCache| {
+            let generation = cache.generation();
+            cache.put(hash_a, vec![entry(1, 10)], vec![(gen_hash(1), 0)], generation);
+            cache.put(hash_b, vec![entry(2, 0)], vec![(gen_hash(2), 1)], generation);
+        };
+
+        let cache = HistoryCache::new(1000);
+        fill(&cache);
+        assert_eq!(cache.get(&hash_a).unwrap()[0].txid, gen_hash(1));
+        assert!(cache.get(&hash_b).is_some());
+
+        // unrelated transactions keep the histories
+        cache.invalidate_tx(&tx(vec![(gen_hash(1), 1)], &Script::new()));
+        assert!(cache.get(&hash_a).is_some());
+
+        // funding and spending transactions drop them
+        cache.invalidate_tx(&tx(vec![], &script_a));
+        assert!(cache.get(&hash_a).is_none());
+        assert!(cache.get(&hash_b).is_some());
+        cache.invalidate_tx(&tx(vec![(gen_hash(2), 1)], &Script::new()));
+        assert!(cache.get(&hash_b).is_none());
+
+        // only the histories with mempool transactions are dropped on mempool changes
+        fill(&cache);
+        cache.invalidate_mempool();
+        assert!(cache.get(&hash_a).is_some());
+        assert!(cache.get(&hash_b).is_none());
+
+        // histories computed before an invalidation aren't cached
+        let generation = cache.generation();
+        cache.clear();
+        cache.put(hash_b, vec![entry(2, 0)], vec![], generation);
+        assert!(cache.get(&hash_a).is_none());
+        assert!(cache.get(&hash_b).is_none());
+
+        // evicted histories aren't invalidated anymore
+        fill(&cache);
+        cache.set_capacity(200);
+        assert!(cache.get(&hash_a).is_none());
+        assert_eq!(state_len(&cache), (1, 1, 1));
+    }
+
+    fn state_len(cache: &HistoryCache) -> (usize, usize, usize) {
+        let state = cache.state.lock().unwrap();
+        (state.map.map.len(), state.scripts_by_outpoint.len(), state.mempool_scripts.len())
+    }
+
+    #[test]
+    fn test_fee_estimates_cache_expiry() {
+        let misses: Mutex<usize> = Mutex::new(0);
+        let miss_func = || {
+            *misses.lock().unwrap() += 1;
+            Ok(Some(0.0001))
+        };
+
+        let cache = FeeEstimatesCache::new(Duration::from_millis(100));
+        assert_eq!(Some(0.0001), cache.get_or_else(2, miss_func).unwrap());
+        assert_eq!(1, *misses.lock().unwrap());
+
+        // cache hit, other targets are cached separately
+        cache.get_or_else(2, miss_func).unwrap();
+        assert_eq!(1, *misses.lock().unwrap());
+        cache.get_or_else(6, miss_func).unwrap();
+        assert_eq!(2, *misses.lock().unwrap());
+
+        // expired estimates are refreshed
+        std::thread::sleep(Duration::from_millis(150));
+        cache.get_or_else(2, miss_func).unwrap();
+        assert_eq!(3, *misses.lock().unwrap());
+    }
 }
@@ -1,18 +1,26 @@
+use bitcoin::consensus::encode::serialize;
 use bitcoin::network::constants::Network;
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use bitcoin_hashes::Hash;
 use dirs::home_dir;
 use num_cpus;
 use std::convert::TryInto;
-use std::ffi::{OsStr, OsString};
+use std::ffi::OsString;
 use std::fmt;
 use std::fs;
-use std::net::Ipv4Addr;
-use std::path::PathBuf;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use serde::de::{self, Deserialize, Deserializer};
 use std::sync::Arc;
+use std::time::Duration;
 use stderrlog;
 
-use crate::daemon::CookieGetter;
+use crate::daemon::{CookieGetter, RpcSettings};
 use crate::errors::*;
+use crate::logger;
+use crate::index::HistoryLimits;
+use crate::store::DBTuning;
 
 //
 // Default IP address of the RPC server
@@ -55,61 +63,396 @@ impl fmt::Display for AddressError {
     }
 }
 
+impl From<AddressError> for Error {
+    fn from(err: AddressError) -> Error {
+        err.to_string().into()
+    }
+}
+
+//
+// Resolve a hostname, an IPv4 or an IPv6 literal (optionally within brackets)
+//
+fn resolve_address(host: &str, port: u16) -> std::result::Result<SocketAddr, AddressError> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    (host, port)
+        .to_socket_addrs()
+        .map_err(|err| AddressError::ResolvError {
+            addr: format!("{}:{}", host, port),
+            err,
+        })?
+        .next()
+        .ok_or_else(|| AddressError::NoAddrError(format!("{}:{}", host, port)))
+}
+
+//
+// Resolve a comma-separated list of 'addr:port' into all the matching socket addresses
 //
-// This newtype implements `ParseArg` for `Network`.
+fn resolve_address_list(list: &str) -> std::result::Result<Vec<SocketAddr>, AddressError> {
+    let mut result = vec![];
+    for addr in list.split(',').map(str::trim).filter(|addr| !addr.is_empty()) {
+        let resolved = addr
+            .to_socket_addrs()
+            .map_err(|err| AddressError::ResolvError {
+                addr: addr.to_owned(),
+                err,
+            })?;
+        let len = result.len();
+        for socket_addr in resolved {
+            if !result.contains(&socket_addr) {
+                result.push(socket_addr);
+            }
+        }
+        if len == result.len() {
+            return Err(AddressError::NoAddrError(addr.to_owned()));
+        }
+    }
+    if result.is_empty() {
+        return Err(AddressError::NoAddrError(list.to_owned()));
+    }
+    Ok(result)
+}
+
 //
-#[derive(Deserialize)]
-pub struct BitcoinNetwork(Network);
+// Bitcoin networks supported by the indexer.
+// Signet isn't known by rust-bitcoin, so we can't just wrap `Network`.
+//
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BitcoinNetwork {
+    #[default]
+    Bitcoin,
+    Testnet,
+    Regtest,
+    Signet,
+}
+
+impl BitcoinNetwork {
+    /// Returns the rust-bitcoin network sharing the same address encoding
+    pub fn network(self) -> Network {
+        match self {
+            BitcoinNetwork::Bitcoin => Network::Bitcoin,
+            BitcoinNetwork::Testnet | BitcoinNetwork::Signet => Network::Testnet,
+            BitcoinNetwork::Regtest => Network::Regtest,
+        }
+    }
+
+    /// Returns the magic bytes used by the blk*.dat files of this network
+    pub fn magic(self, signet_challenge: Option<&[u8]>) -> u32 {
+        match self {
+            BitcoinNetwork::Signet => {
+                signet_magic(signet_challenge.unwrap_or(&DEFAULT_SIGNET_CHALLENGE[..]))
+            }
+            _ => self.network().magic(),
+        }
+    }
+
+    /// Returns the name of the index DB subdirectory
+    pub fn db_subdir(self) -> &'static str {
+        match self {
+            // We must keep the name "mainnet" due to backwards compatibility
+            BitcoinNetwork::Bitcoin => "mainnet",
+            BitcoinNetwork::Testnet => "testnet",
+            BitcoinNetwork::Regtest => "regtest",
+            BitcoinNetwork::Signet => "signet",
+        }
+    }
+
+    /// Returns the subdirectory of the bitcoind data directory (if any)
+    fn daemon_subdir(self) -> Option<&'static str> {
+        match self {
+            BitcoinNetwork::Bitcoin => None,
+            BitcoinNetwork::Testnet => Some("testnet3"),
+            BitcoinNetwork::Regtest => Some("regtest"),
+            BitcoinNetwork::Signet => Some("signet"),
+        }
+    }
 
-impl Default for BitcoinNetwork {
-    fn default() -> Self {
-        BitcoinNetwork(Network::Bitcoin)
+    fn default_daemon_port(self) -> u16 {
+        match self {
+            BitcoinNetwork::Bitcoin => 8332,
+            BitcoinNetwork::Testnet => 18332,
+            BitcoinNetwork::Regtest => 18443,
+            BitcoinNetwork::Signet => 38332,
+        }
+    }
+
+    /// Returns the default port of the indexer RPC server
+    pub fn default_indexer_port(self) -> u16 {
+        match self {
+            BitcoinNetwork::Bitcoin => 8432,
+            BitcoinNetwork::Testnet => 18432,
+            BitcoinNetwork::Regtest => 18543,
+            BitcoinNetwork::Signet => 38432,
+        }
     }
 }
 
 impl FromStr for BitcoinNetwork {
-    type Err = <Network as FromStr>::Err;
+    type Err = String;
 
     fn from_str(string: &str) -> std::result::Result<Self, Self::Err> {
         match string {
-            "main" => Network::from_str("bitcoin").map(BitcoinNetwork),
-            "test" => Network::from_str("testnet").map(BitcoinNetwork),
-            _ => Network::from_str(string).map(BitcoinNetwork),
+            "bitcoin" | "mainnet" | "main" => Ok(BitcoinNetwork::Bitcoin),
+            "testnet" | "test" => Ok(BitcoinNetwork::Testnet),
+            "regtest" => Ok(BitcoinNetwork::Regtest),
+            "signet" => Ok(BitcoinNetwork::Signet),
+            _ => Err(format!("unknown network {:?}", string)),
         }
     }
 }
 
+impl<'de> Deserialize<'de> for BitcoinNetwork {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let string = String::deserialize(deserializer)?;
+        BitcoinNetwork::from_str(&string).map_err(de::Error::custom)
+    }
+}
+
 impl ::configure_me::parse_arg::ParseArgFromStr for BitcoinNetwork {
     fn describe_type<W: fmt::Write>(mut writer: W) -> std::fmt::Result {
-        write!(writer, "either 'bitcoin', 'testnet' or 'regtest'")
+        write!(writer, "either 'bitcoin', 'testnet', 'regtest' or 'signet'")
     }
 }
 
-impl Into<Network> for BitcoinNetwork {
-    fn into(self) -> Network {
-        self.0
+//
+// Format of the log lines
+//
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(string: &str) -> std::result::Result<Self, Self::Err> {
+        match string {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format {:?}", string)),
+        }
     }
 }
 
+impl ::configure_me::parse_arg::ParseArgFromStr for LogFormat {
+    fn describe_type<W: fmt::Write>(mut writer: W) -> std::fmt::Result {
+        write!(writer, "either 'text' or 'json'")
+    }
+}
+
+//
+// Minimum TLS version accepted by the indexer RPC server
+//
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+pub enum TlsVersion {
+    #[serde(rename = "1.0")]
+    Tls10,
+    #[serde(rename = "1.1")]
+    Tls11,
+    #[default]
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+impl FromStr for TlsVersion {
+    type Err = String;
+
+    fn from_str(string: &str) -> std::result::Result<Self, Self::Err> {
+        match string {
+            "1.0" => Ok(TlsVersion::Tls10),
+            "1.1" => Ok(TlsVersion::Tls11),
+            "1.2" => Ok(TlsVersion::Tls12),
+            "1.3" => Ok(TlsVersion::Tls13),
+            _ => Err(format!("unknown TLS version {:?}", string)),
+        }
+    }
+}
+
+impl ::configure_me::parse_arg::ParseArgFromStr for TlsVersion {
+    fn describe_type<W: fmt::Write>(mut writer: W) -> std::fmt::Result {
+        write!(writer, "either '1.0', '1.1', '1.2' or '1.3'")
+    }
+}
+
+//
+// Compression algorithm of the RocksDB tables
+//
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DbCompression {
+    None,
+    #[default]
+    Snappy,
+    Lz4,
+    Zstd,
+}
+
+impl FromStr for DbCompression {
+    type Err = String;
+
+    fn from_str(string: &str) -> std::result::Result<Self, Self::Err> {
+        match string {
+            "none" => Ok(DbCompression::None),
+            "snappy" => Ok(DbCompression::Snappy),
+            "lz4" => Ok(DbCompression::Lz4),
+            "zstd" => Ok(DbCompression::Zstd),
+            _ => Err(format!("unknown compression {:?}", string)),
+        }
+    }
+}
+
+impl ::configure_me::parse_arg::ParseArgFromStr for DbCompression {
+    fn describe_type<W: fmt::Write>(mut writer: W) -> std::fmt::Result {
+        write!(writer, "either 'none', 'snappy', 'lz4' or 'zstd'")
+    }
+}
+
+//
+// Compaction style of the RocksDB tables
+//
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DbCompactionStyle {
+    #[default]
+    Level,
+    Universal,
+}
+
+impl FromStr for DbCompactionStyle {
+    type Err = String;
+
+    fn from_str(string: &str) -> std::result::Result<Self, Self::Err> {
+        match string {
+            "level" => Ok(DbCompactionStyle::Level),
+            "universal" => Ok(DbCompactionStyle::Universal),
+            _ => Err(format!("unknown compaction style {:?}", string)),
+        }
+    }
+}
+
+impl ::configure_me::parse_arg::ParseArgFromStr for DbCompactionStyle {
+    fn describe_type<W: fmt::Write>(mut writer: W) -> std::fmt::Result {
+        write!(writer, "either 'level' or 'universal'")
+    }
+}
+
+//
+// Challenge script of the default (public) signet
+//
+const DEFAULT_SIGNET_CHALLENGE: [u8; 71] = [
+    0x51, 0x21, 0x03, 0xad, 0x5e, 0x0e, 0xda, 0xd1, 0x8c, 0xb1, 0xf0, 0xfc, 0x0d, 0x28, 0xa3,
+    0xd4, 0xf1, 0xf3, 0xe4, 0x45, 0x64, 0x03, 0x37, 0x48, 0x9a, 0xbb, 0x10, 0x40, 0x4f, 0x2d,
+    0x1e, 0x08, 0x6b, 0xe4, 0x30, 0x21, 0x03, 0x59, 0xef, 0x50, 0x21, 0x96, 0x4f, 0xe2, 0x2d,
+    0x6f, 0x8e, 0x05, 0xb2, 0x46, 0x3c, 0x95, 0x40, 0xce, 0x96, 0x88, 0x3f, 0xe3, 0xb2, 0x78,
+    0x76, 0x0f, 0x04, 0x8f, 0x51, 0x89, 0xf2, 0xe6, 0xc4, 0x52, 0xae,
+];
+
+//
+// Signet magic is the first 4 bytes of SHA256d(challenge), as done by bitcoind
+//
+fn signet_magic(challenge: &[u8]) -> u32 {
+    let script = serialize(&challenge.to_vec()); // prefixed by its compact size
+    let hash = Sha256dHash::hash(&script);
+    u32::from_le_bytes(hash[..4].try_into().expect("failed to convert magic"))
+}
+
 //
 // Parsed and post-processed configuration
 //
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Config {
     // See below for the documentation of each field:
-    pub log: stderrlog::StdErrLog,
-    pub network_type: Network,
+    pub log_format: LogFormat,
+    pub log_file: Option<PathBuf>,
+    pub network_type: BitcoinNetwork,
+    pub magic: u32,
     pub db_path: PathBuf,
+    pub backup_dir: Option<PathBuf>,
     pub daemon_dir: PathBuf,
-    pub daemon_rpc_host: String,
-    pub daemon_rpc_port: u16,
+    pub daemon_rpc: RpcSettings,
     pub cookie: Option<String>,
-    pub indexer_rpc_host: Ipv4Addr,
-    pub indexer_rpc_port: u16,
+    pub cookie_file: PathBuf,
+    pub indexer_rpc_addrs: Vec<SocketAddr>,
+    pub indexer_ws_addrs: Vec<SocketAddr>,
+    pub indexer_rpc_socket: Option<PathBuf>,
+    pub indexer_rpc_socket_mode: u32, // of the socket file
+    pub max_connections: usize,
+    pub p2p_peers: Vec<SocketAddr>,
+    pub rest_addr: Option<SocketAddr>,
+    pub monitoring_addr: Option<SocketAddr>,
+    pub grpc_addr: Option<SocketAddr>,
+    pub otlp_addr: Option<SocketAddr>,
+    pub tls_cert_file: Option<PathBuf>,
+    pub tls_key_file: Option<PathBuf>,
+    pub tls_min_version: TlsVersion,
+    pub auth_token: Option<String>,
+    pub server_banner: Option<String>,
+    pub query_timeout: Option<Duration>,
+    pub query_threads: usize,
+    pub query_queue_size: usize,
+    pub zmq_pub_raw_block: Option<SocketAddr>,
+    pub zmq_pub_hash_tx: Option<SocketAddr>,
+    pub zmq_pub_index: Option<SocketAddr>,
     pub jsonrpc_import: bool,
+    pub read_only: bool,
+    pub secondary_dir: Option<PathBuf>, // without the network subdirectory
+    pub catch_up_interval: Duration,
+    pub mempool_poll_interval: Option<Duration>, // None for the default one
+    pub daemon_rest: bool,
+    pub wait_for_sync: bool,
+    pub block_filters: bool,
+    pub utxo_index: bool,
+    pub opreturn_index: bool,
+    pub index_pubkeys: bool,
+    pub watch_file: Option<PathBuf>,
+    pub webhook_file: Option<PathBuf>,
+    pub watch_range: u32,
+    pub reindex_from: Option<usize>,
+    pub verify: Option<usize>,
+    pub export: Option<(String, PathBuf)>, // address and file
+    pub bench: Option<PathBuf>,
     pub index_batch_size: usize,
+    pub index_fetch_threads: usize,
+    pub undo_depth: usize,
     pub bulk_index_threads: usize,
-    pub blocktxids_cache_size: usize,
+    pub reloadable: Reloadable,
+    pub db_tuning: DBTuning,
+    pub history_limits: HistoryLimits,
+    pub extra_networks: Vec<Config>, // indexed and served by the same process
+}
+
+// Logs to stderr (or to the rotated `log_file`), at the reloadable level
+fn init_logging(config: &internal::Config, level: log::LevelFilter) -> Result<()> {
+    let max_size = config.log_rotate_size * (1 << 20);
+    let log_file = match config.log_file {
+        Some(ref path) => Some(
+            logger::RotatingFile::open(path, max_size)
+                .map_err(|err| format!("failed to open log file {:?}: {}", path, err))?,
+        ),
+        None => None,
+    };
+    let logger = match (config.log_format, log_file) {
+        (LogFormat::Text, None) => {
+            let mut log = stderrlog::new();
+            // the actual level is set by log::set_max_level(), so it can be reloaded
+            log.verbosity(4);
+
+            log.timestamp(if config.timestamp {
+                stderrlog::Timestamp::Millisecond
+            } else {
+                stderrlog::Timestamp::Off
+            });
+            log.init()
+        }
+        (format, file) => logger::Logger::new(format, config.timestamp, file).init(),
+    };
+    logger.map_err(|err| format!("logging initialization failed: {}", err))?;
+    log::set_max_level(level);
+    Ok(())
 }
 
 /// Returns default daemon directory
@@ -122,81 +465,245 @@ fn default_daemon_dir() -> PathBuf {
     home
 }
 
+/// Returns the config files, by decreasing priority
+fn config_files() -> Vec<PathBuf> {
+    let system_config = PathBuf::from("/etc/addrindexrs/config.toml");
+
+    let home_config = home_dir().map(|mut dir| {
+        dir.extend(&[".addrindexrs", "config.toml"]);
+        dir
+    });
+
+    let cwd_config = PathBuf::from("addrindexrs.toml");
+    std::iter::once(cwd_config)
+        .chain(home_config)
+        .chain(std::iter::once(system_config))
+        .collect()
+}
+
+fn log_level(verbose: usize) -> log::LevelFilter {
+    match verbose {
+        0 => log::LevelFilter::Error,
+        1 => log::LevelFilter::Warn,
+        2 => log::LevelFilter::Info,
+        3 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    }
+}
+
+//
+// Settings that can be changed without restarting (by sending SIGHUP)
+//
+#[derive(Clone, Debug)]
+pub struct Reloadable {
+    pub log_level: log::LevelFilter,
+    pub txid_limit: usize,
+    pub rpc_rate_limit: f64,
+    pub rpc_rate_burst: usize,
+    pub blocktxids_cache_size: usize,
+    pub tx_cache_size: usize,
+    pub history_cache_size: usize,
+}
+
+impl Reloadable {
+    fn new(config: &internal::Config) -> Reloadable {
+        const MB: f32 = (1 << 20) as f32;
+        Reloadable {
+            log_level: log_level(config.verbose as usize),
+            txid_limit: config.txid_limit,
+            rpc_rate_limit: config.rpc_rate_limit,
+            rpc_rate_burst: config.rpc_rate_burst,
+            blocktxids_cache_size: (config.blocktxids_cache_size_mb * MB) as usize,
+            tx_cache_size: (config.tx_cache_size_mb * MB) as usize,
+            history_cache_size: (config.history_cache_size_mb * MB) as usize,
+        }
+    }
+}
+
 impl Config {
+    /// Parses the args, env vars and config files again, for the reloadable settings
+    pub fn reload() -> Result<Reloadable> {
+        let (config, _) = internal::Config::including_optional_config_files(config_files())
+            .map_err(|e| format!("invalid config: {}", e))?;
+        Ok(Reloadable::new(&config))
+    }
+
     /// Parses args, env vars, config files and post-processes them
     pub fn from_args() -> Config {
         use internal::ResultExt;
 
-        let system_config: &OsStr = "/etc/addrindexrs/config.toml".as_ref();
-
-        let home_config = home_dir().map(|mut dir| {
-            dir.extend(&[".addrindexrs", "config.toml"]);
-            dir
+        let (config, _) =
+            internal::Config::including_optional_config_files(config_files()).unwrap_or_exit();
+        let config = Config::from_internal(config, /*init_logger=*/ true).unwrap_or_else(|e| {
+            eprintln!("Error: {}", e);
+            std::process::exit(1)
         });
+        eprintln!("{:#?}", config);
+        config
+    }
 
-        let cwd_config: &OsStr = "addrindexrs.toml".as_ref();
-        let configs = std::iter::once(cwd_config)
-            .chain(home_config.as_ref().map(AsRef::as_ref))
-            .chain(std::iter::once(system_config));
-
-        let (mut config, _) =
-            internal::Config::including_optional_config_files(configs).unwrap_or_exit();
+    /// Starts building a config for embedding the indexer, from the defaults
+    /// of the command line (see `ConfigBuilder`)
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
 
-        let db_subdir = match config.network {
-            // We must keep the name "mainnet" due to backwards compatibility
-            Network::Bitcoin => "mainnet",
-            Network::Testnet => "testnet",
-            Network::Regtest => "regtest",
-        };
+    // Post-processes the parsed config (the logger is left to the embedding
+    // application, unless `init_logger` is set)
+    fn from_internal(mut config: internal::Config, init_logger: bool) -> Result<Config> {
+        let reloadable = Reloadable::new(&config);
+        if init_logger {
+            init_logging(&config, reloadable.log_level)?;
+        }
 
-        config.db_dir.push(db_subdir);
+        if (config.read_only || config.secondary_dir.is_some()) && config.reindex_from.is_some() {
+            bail!("reindex_from can't be used with read_only");
+        }
 
-        let default_daemon_port = match config.network {
-            Network::Bitcoin => 8332,
-            Network::Testnet => 18332,
-            Network::Regtest => 18443,
+        let signet_challenge = match config.signet_challenge {
+            Some(ref challenge) => {
+                if config.network != BitcoinNetwork::Signet {
+                    bail!("signet_challenge requires network = 'signet'");
+                }
+                let challenge = hex::decode(challenge)
+                    .map_err(|err| format!("invalid signet_challenge {:?}: {}", challenge, err))?;
+                Some(challenge)
+            }
+            None => None,
         };
+        let magic = config.network.magic(signet_challenge.as_deref());
 
-        let default_indexer_port = match config.network {
-            Network::Bitcoin => 8432,
-            Network::Testnet => 18432,
-            Network::Regtest => 18543,
+        let extra_networks: Vec<BitcoinNetwork> = match config.extra_networks {
+            Some(ref list) => list
+                .split(',')
+                .map(|name| match BitcoinNetwork::from_str(name.trim()) {
+                    Ok(network) if network != config.network => Ok(network),
+                    Ok(network) => Err(format!("extra network {:?} is the main network", network)),
+                    Err(err) => Err(err),
+                })
+                .collect::<std::result::Result<_, _>>()?,
+            None => vec![],
         };
+        let db_base_dir = config.db_dir.clone();
+        let daemon_base_dir = config.daemon_dir.clone();
+
+        config.db_dir.push(config.network.db_subdir());
+
+        let default_indexer_port = config.network.default_indexer_port();
 
         let daemon_rpc_host = config
             .daemon_rpc_host
             .unwrap_or(DEFAULT_SERVER_ADDRESS_STRING.into());
-        let daemon_rpc_port = config.daemon_rpc_port.unwrap_or(default_daemon_port);
+        let daemon_rpc_port = config
+            .daemon_rpc_port
+            .unwrap_or(config.network.default_daemon_port());
+        let daemon_rpc_addr = resolve_address(&daemon_rpc_host, daemon_rpc_port)?;
+        let mut daemon_rpc_addrs = vec![daemon_rpc_addr];
+        if let Some(ref list) = config.daemon_rpc_fallback_addr {
+            let fallback_addrs = resolve_address_list(list)?;
+            daemon_rpc_addrs.extend(fallback_addrs.into_iter().filter(|a| *a != daemon_rpc_addr));
+        }
 
         let indexer_rpc_host = config
             .indexer_rpc_host
-            .unwrap_or(DEFAULT_SERVER_ADDRESS.into());
-        let indexer_rpc_port = config.indexer_rpc_port.unwrap_or(default_indexer_port);
+            .unwrap_or_else(|| DEFAULT_SERVER_ADDRESS.into());
+        let indexer_rpc_addrs = match config.indexer_rpc_addr {
+            Some(ref list) => resolve_address_list(list)?,
+            None => {
+                let indexer_rpc_port = config.indexer_rpc_port.unwrap_or(default_indexer_port);
+                vec![SocketAddr::new(indexer_rpc_host, indexer_rpc_port)]
+            }
+        };
+
+        let indexer_ws_addrs = match config.indexer_ws_addr {
+            Some(ref list) => resolve_address_list(list)?,
+            None => vec![],
+        };
+
+        let indexer_rpc_socket_mode = &config.indexer_rpc_socket_mode;
+        let indexer_rpc_socket_mode = u32::from_str_radix(indexer_rpc_socket_mode, 8)
+            .ok()
+            .filter(|mode| *mode <= 0o777)
+            .ok_or_else(|| {
+                format!(
+                    "invalid indexer_rpc_socket_mode {:?} (expected octal, e.g. 660)",
+                    indexer_rpc_socket_mode
+                )
+            })?;
+
+        let p2p_peers = match config.p2p_peers {
+            Some(ref list) => resolve_address_list(list)?,
+            None => vec![],
+        };
+
+        let rest_addr = match config.rest_addr {
+            Some(ref addr) => Some(resolve_address_list(addr)?.remove(0)),
+            None => None,
+        };
 
-        match config.network {
-            Network::Bitcoin => (),
-            Network::Testnet => config.daemon_dir.push("testnet3"),
-            Network::Regtest => config.daemon_dir.push("regtest"),
+        let monitoring_addr = match config.monitoring_addr {
+            Some(ref addr) => Some(resolve_address_list(addr)?.remove(0)),
+            None => None,
+        };
+
+        let grpc_addr = match config.grpc_addr {
+            Some(ref addr) => Some(resolve_address_list(addr)?.remove(0)),
+            None => None,
+        };
+
+        // spans are POSTed to the standard /v1/traces path, over plain HTTP
+        let otlp_addr = match config.otlp_endpoint {
+            Some(ref endpoint) => {
+                let addr = endpoint.trim_start_matches("http://");
+                let addr = addr.split('/').next().unwrap_or(addr);
+                let addrs = resolve_address_list(addr)
+                    .map_err(|err| format!("invalid OTLP endpoint {:?}: {}", endpoint, err))?;
+                Some(addrs[0])
+            }
+            None => None,
+        };
+
+        let cookie = match (config.daemon_rpc_user, config.daemon_rpc_pass) {
+            (None, None) => config.cookie,
+            (Some(user), Some(pass)) => {
+                if config.cookie.is_some() {
+                    bail!("cookie can't be used with daemon_rpc_user/daemon_rpc_pass");
+                }
+                Some(format!("{}:{}", user, pass))
+            }
+            _ => bail!("daemon_rpc_user and daemon_rpc_pass must be set together"),
+        };
+
+        let export = match (config.export_address, config.export_file) {
+            (Some(address), Some(file)) => Some((address, file)),
+            (None, None) => None,
+            _ => bail!("export_address and export_file must be set together"),
+        };
+
+        if config.tls_cert_file.is_some() != config.tls_key_file.is_some() {
+            bail!("tls_cert_file and tls_key_file must be set together");
         }
 
-        let mut log = stderrlog::new();
-        log.verbosity(
-            config
-                .verbose
-                .try_into()
-                .expect("Overflow: Running addrindexrs on less than 32 bit devices is unsupported"),
-        );
+        if let Some(subdir) = config.network.daemon_subdir() {
+            config.daemon_dir.push(subdir);
+        }
 
-        log.timestamp(if config.timestamp {
-            stderrlog::Timestamp::Millisecond
-        } else {
-            stderrlog::Timestamp::Off
-        });
+        let resolve_zmq = |endpoint: &String| {
+            let addr = endpoint.trim_start_matches("tcp://");
+            match resolve_address_list(addr) {
+                Ok(addrs) => Ok(addrs[0]),
+                Err(err) => Err(format!("invalid ZMQ endpoint {:?}: {}", endpoint, err)),
+            }
+        };
+        let zmq_pub_raw_block = config.zmq_pub_raw_block.as_ref().map(resolve_zmq).transpose()?;
+        let zmq_pub_hash_tx = config.zmq_pub_hash_tx.as_ref().map(resolve_zmq).transpose()?;
+        let zmq_pub_index = config.zmq_pub_index.as_ref().map(resolve_zmq).transpose()?;
+
+        let daemon_dir = &config.daemon_dir;
+        let cookie_file = config
+            .cookie_file
+            .unwrap_or_else(|| daemon_dir.join(".cookie"));
 
-        log.init().unwrap_or_else(|err| {
-            eprintln!("Error: logging initialization failed: {}", err);
-            std::process::exit(1)
-        });
 
         // Could have been default, but it's useful to allow the user to specify 0 when overriding
         // configs.
@@ -206,24 +713,154 @@ impl Config {
 
         const MB: f32 = (1 << 20) as f32;
 
-        let config = Config {
-            log,
+        let mut config = Config {
+            log_format: config.log_format,
+            log_file: config.log_file,
             network_type: config.network,
+            magic,
             db_path: config.db_dir,
+            backup_dir: config.backup_dir,
             daemon_dir: config.daemon_dir,
-            daemon_rpc_host,
-            daemon_rpc_port,
-            indexer_rpc_host,
-            indexer_rpc_port,
-            cookie: config.cookie,
+            daemon_rpc: RpcSettings {
+                addrs: daemon_rpc_addrs,
+                connect_timeout: Duration::from_secs(config.daemon_connect_timeout.max(1)),
+                read_timeout: match config.daemon_read_timeout {
+                    0 => None,
+                    secs => Some(Duration::from_secs(secs)),
+                },
+                retries: match config.daemon_rpc_retries {
+                    0 => None,
+                    retries => Some(retries),
+                },
+            },
+            indexer_rpc_addrs,
+            indexer_ws_addrs,
+            indexer_rpc_socket: config.indexer_rpc_socket,
+            indexer_rpc_socket_mode,
+            max_connections: config.max_connections,
+            p2p_peers,
+            rest_addr,
+            monitoring_addr,
+            grpc_addr,
+            otlp_addr,
+            tls_cert_file: config.tls_cert_file,
+            tls_key_file: config.tls_key_file,
+            tls_min_version: config.tls_min_version,
+            auth_token: config.auth_token,
+            server_banner: config.server_banner,
+            query_threads: match config.query_threads {
+                0 => num_cpus::get(),
+                threads => threads,
+            },
+            query_queue_size: config.query_queue_size,
+            query_timeout: match config.query_timeout {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            cookie,
+            cookie_file,
+            zmq_pub_raw_block,
+            zmq_pub_hash_tx,
+            zmq_pub_index,
             jsonrpc_import: config.jsonrpc_import,
+            read_only: config.read_only || config.secondary_dir.is_some(),
+            secondary_dir: config.secondary_dir,
+            catch_up_interval: Duration::from_millis(config.catch_up_interval_ms.max(1)),
+            mempool_poll_interval: config
+                .mempool_poll_interval_ms
+                .map(|ms| Duration::from_millis(ms.max(1))),
+            daemon_rest: config.daemon_rest,
+            wait_for_sync: config.wait_for_sync,
+            block_filters: config.block_filters,
+            utxo_index: config.utxo_index,
+            opreturn_index: config.opreturn_index,
+            index_pubkeys: config.index_pubkeys,
+            watch_file: config.watch_file,
+            webhook_file: config.webhook_file,
+            watch_range: config.watch_range,
+            reindex_from: config.reindex_from,
+            verify: config.verify,
+            export,
+            bench: config.bench,
             index_batch_size: config.index_batch_size,
+            index_fetch_threads: config.index_fetch_threads,
+            undo_depth: config.undo_depth,
             bulk_index_threads: config.bulk_index_threads,
-            blocktxids_cache_size: (config.blocktxids_cache_size_mb * MB) as usize,
+            reloadable,
+            history_limits: HistoryLimits {
+                start_height: config.start_height,
+                prune_below: config.prune_below,
+            },
+            db_tuning: DBTuning {
+                block_cache_size: (config.db_block_cache_mb * MB) as usize,
+                write_buffer_size: (config.db_write_buffer_mb * MB) as usize,
+                max_open_files: config.db_max_open_files,
+                compression: config.db_compression,
+                compaction_style: config.db_compaction_style,
+            },
+            extra_networks: vec![],
         };
+        config.extra_networks = extra_networks
+            .into_iter()
+            .map(|network| {
+                let mut daemon_dir = daemon_base_dir.clone();
+                daemon_dir.extend(network.daemon_subdir());
+                let daemon_rpc_addr =
+                    resolve_address(&daemon_rpc_host, network.default_daemon_port())?;
+                Ok(config.network_config(
+                    network,
+                    &db_base_dir,
+                    daemon_dir,
+                    daemon_rpc_addr,
+                    indexer_rpc_host,
+                ))
+            })
+            .collect::<Result<_>>()?;
+        Ok(config)
+    }
 
-        eprintln!("{:#?}", config);
-        config
+    // Settings of an extra network, served with its default ports and directories.
+    // The optional servers (e.g. REST, WebSocket or monitoring) and one-shot
+    // operations are only enabled for the main network.
+    fn network_config(
+        &self,
+        network: BitcoinNetwork,
+        db_base_dir: &Path,
+        daemon_dir: PathBuf,
+        daemon_rpc_addr: SocketAddr,
+        indexer_rpc_host: IpAddr,
+    ) -> Config {
+        Config {
+            network_type: network,
+            magic: network.magic(None),
+            db_path: db_base_dir.join(network.db_subdir()),
+            backup_dir: None,
+            cookie_file: daemon_dir.join(".cookie"),
+            daemon_dir,
+            daemon_rpc: RpcSettings {
+                addrs: vec![daemon_rpc_addr],
+                ..self.daemon_rpc.clone()
+            },
+            indexer_rpc_addrs: vec![SocketAddr::new(indexer_rpc_host, network.default_indexer_port())],
+            indexer_ws_addrs: vec![],
+            indexer_rpc_socket: None,
+            p2p_peers: vec![],
+            rest_addr: None,
+            monitoring_addr: None,
+            grpc_addr: None,
+            zmq_pub_raw_block: None,
+            zmq_pub_hash_tx: None,
+            zmq_pub_index: None,
+            reindex_from: None,
+            history_limits: HistoryLimits::default(),
+            watch_file: None,
+            webhook_file: None,
+            verify: None,
+            export: None,
+            bench: None,
+            extra_networks: vec![],
+            ..self.clone()
+        }
     }
 
     pub fn cookie_getter(&self) -> Arc<dyn CookieGetter> {
@@ -233,12 +870,102 @@ impl Config {
             })
         } else {
             Arc::new(CookieFile {
-                daemon_dir: self.daemon_dir.clone(),
+                path: self.cookie_file.clone(),
             })
         }
     }
 }
 
+//
+// Builds a `Config` for embedding the indexer in another service, without
+// reading the config files. The options are those of the command line (see
+// `config_spec.toml` or `--help`), and `ADDRINDEXRS_*` environment variables
+// are still applied. The global logger is left to the embedding application,
+// unless `init_logger()` is called.
+//
+#[derive(Clone, Debug, Default)]
+pub struct ConfigBuilder {
+    args: Vec<OsString>,
+    init_logger: bool,
+}
+
+impl ConfigBuilder {
+    /// Sets an option by its config file name (e.g. `"db_dir"`)
+    pub fn arg<V: Into<OsString>>(mut self, name: &str, value: V) -> ConfigBuilder {
+        self.args.push(format!("--{}", name.replace('_', "-")).into());
+        self.args.push(value.into());
+        self
+    }
+
+    /// Enables a switch by its config file name (e.g. `"jsonrpc_import"`)
+    pub fn switch(mut self, name: &str) -> ConfigBuilder {
+        self.args.push(format!("--{}", name.replace('_', "-")).into());
+        self
+    }
+
+    pub fn network(self, network: BitcoinNetwork) -> ConfigBuilder {
+        self.arg("network", network.db_subdir())
+    }
+
+    /// The index DB base directory (the network subdirectory is appended)
+    pub fn db_dir<P: AsRef<Path>>(self, dir: P) -> ConfigBuilder {
+        self.arg("db_dir", dir.as_ref().as_os_str())
+    }
+
+    /// The bitcoind data directory, holding the blk*.dat files and the cookie
+    pub fn daemon_dir<P: AsRef<Path>>(self, dir: P) -> ConfigBuilder {
+        self.arg("daemon_dir", dir.as_ref().as_os_str())
+    }
+
+    pub fn daemon_rpc(self, host: &str, port: u16) -> ConfigBuilder {
+        self.arg("daemon_rpc_host", host)
+            .arg("daemon_rpc_port", port.to_string())
+    }
+
+    /// The JSONRPC credentials ("USER:PASSWORD"), instead of the cookie file
+    pub fn cookie(self, cookie: &str) -> ConfigBuilder {
+        self.arg("cookie", cookie)
+    }
+
+    /// Fetches the blocks over JSONRPC, instead of reading the blk*.dat files
+    pub fn jsonrpc_import(self) -> ConfigBuilder {
+        self.switch("jsonrpc_import")
+    }
+
+    /// Comma-separated 'addr:port' list of the indexer RPC server
+    pub fn indexer_rpc_addr(self, addrs: &str) -> ConfigBuilder {
+        self.arg("indexer_rpc_addr", addrs)
+    }
+
+    /// Serves the index of another (writer) process, without indexing
+    pub fn read_only(self) -> ConfigBuilder {
+        self.switch("read_only")
+    }
+
+    /// Follows the writer's index as a RocksDB secondary instance (implies
+    /// `read_only()`), keeping its info logs in `dir`
+    pub fn secondary_dir<P: AsRef<Path>>(self, dir: P) -> ConfigBuilder {
+        self.arg("secondary_dir", dir.as_ref().as_os_str())
+    }
+
+    /// Also initializes the global logger from the `verbose`, `timestamp` and
+    /// `log_*` options (it can only be done once per process)
+    pub fn init_logger(mut self) -> ConfigBuilder {
+        self.init_logger = true;
+        self
+    }
+
+    /// Validates and post-processes the options, like the command line does
+    /// (without printing them, or exiting on errors)
+    pub fn build(self) -> Result<Config> {
+        let args = std::iter::once(OsString::from("addrindexrs")).chain(self.args);
+        let (config, _) =
+            internal::Config::custom_args_and_optional_files(args, Vec::<PathBuf>::new())
+                .map_err(|e| format!("invalid config: {}", e))?;
+        Config::from_internal(config, self.init_logger)
+    }
+}
+
 //
 // Auth cookie for bitcoind
 //
@@ -253,15 +980,89 @@ impl CookieGetter for StaticCookie {
 }
 
 struct CookieFile {
-    daemon_dir: PathBuf,
+    path: PathBuf,
 }
 
 impl CookieGetter for CookieFile {
     fn get(&self) -> Result<Vec<u8>> {
-        let path = self.daemon_dir.join(".cookie");
-        let contents = fs::read(&path).chain_err(|| {
-            ErrorKind::Connection(format!("failed to read cookie from {:?}", path))
+        let contents = fs::read(&self.path).chain_err(|| {
+            ErrorKind::Connection(format!("failed to read cookie from {:?}", self.path))
         })?;
         Ok(contents)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_address() {
+        let addr = resolve_address("127.0.0.1", 8332).ok().unwrap();
+        assert_eq!(addr, "127.0.0.1:8332".parse().unwrap());
+        let addr = resolve_address("::1", 8332).ok().unwrap();
+        assert_eq!(addr, "[::1]:8332".parse().unwrap());
+        let addr = resolve_address("[::1]", 8332).ok().unwrap();
+        assert_eq!(addr, "[::1]:8332".parse().unwrap());
+        let addr = resolve_address("localhost", 8332).ok().unwrap();
+        assert_eq!(addr.port(), 8332);
+        assert!(resolve_address("", 8332).is_err());
+    }
+
+    #[test]
+    fn test_resolve_address_list() {
+        let addrs = resolve_address_list("127.0.0.1:8432, [::1]:8432,").ok().unwrap();
+        assert_eq!(
+            addrs,
+            vec![
+                "127.0.0.1:8432".parse().unwrap(),
+                "[::1]:8432".parse().unwrap()
+            ]
+        );
+        let addrs = resolve_address_list("[::]:8432").ok().unwrap();
+        assert_eq!(addrs, vec!["[::]:8432".parse().unwrap()]);
+        assert!(resolve_address_list("127.0.0.1").is_err()); // missing port
+        assert!(resolve_address_list(" , ").is_err());
+    }
+
+    #[test]
+    fn test_signet_magic() {
+        assert_eq!(BitcoinNetwork::Signet.magic(None), 0x40CF030A);
+        assert_eq!(BitcoinNetwork::Bitcoin.magic(None), 0xD9B4BEF9);
+        // a custom challenge yields a different magic
+        assert_ne!(BitcoinNetwork::Signet.magic(Some(&[0x51])), 0x40CF030A);
+    }
+
+    #[test]
+    fn test_builder() {
+        let config = Config::builder()
+            .network(BitcoinNetwork::Regtest)
+            .db_dir("/tmp/db")
+            .daemon_dir("/tmp/bitcoin")
+            .daemon_rpc("127.0.0.1", 18000)
+            .cookie("user:pass")
+            .jsonrpc_import()
+            .arg("undo_depth", "10")
+            .build()
+            .unwrap();
+        assert_eq!(config.network_type, BitcoinNetwork::Regtest);
+        assert_eq!(config.db_path, PathBuf::from("/tmp/db/regtest"));
+        assert_eq!(config.cookie_file, PathBuf::from("/tmp/bitcoin/regtest/.cookie"));
+        assert_eq!(config.daemon_rpc.addrs, vec!["127.0.0.1:18000".parse().unwrap()]);
+        assert_eq!(config.cookie_getter().get().unwrap(), b"user:pass".to_vec());
+        assert!(config.jsonrpc_import);
+        assert_eq!(config.undo_depth, 10);
+
+        // invalid options are errors, instead of exiting the process
+        assert!(Config::builder().arg("undo_depth", "x").build().is_err());
+        let config = Config::builder().cookie("user:pass").arg("daemon_rpc_user", "user");
+        assert!(config.build().is_err());
+        assert!(Config::builder().switch("no_such_switch").build().is_err());
+
+        let config = Config::builder().secondary_dir("/tmp/replica").build().unwrap();
+        assert!(config.read_only);
+        assert_eq!(config.secondary_dir, Some(PathBuf::from("/tmp/replica")));
+        let config = Config::builder().read_only().arg("reindex_from", "100");
+        assert!(config.build().is_err());
+    }
+}
@@ -1,7 +1,8 @@
 use base64;
 use bitcoin::blockdata::block::{Block, BlockHeader};
+use bitcoin::blockdata::constants::genesis_block;
 use bitcoin::blockdata::transaction::Transaction;
-use bitcoin::consensus::encode::deserialize;
+use bitcoin::consensus::encode::{deserialize, serialize};
 use bitcoin::network::constants::Network;
 use bitcoin::util::hash::BitcoinHash;
 use bitcoin_hashes::hex::{FromHex, ToHex};
@@ -10,16 +11,19 @@ use glob;
 use hex;
 use serde_json::{from_str, from_value, Map, Value};
 use std::collections::{HashMap, HashSet};
-use std::io::{BufRead, BufReader, Lines, Write};
+use std::io::{BufRead, BufReader, Lines, Read, Write};
 use std::net::{SocketAddr, TcpStream};
 use std::path::PathBuf;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-use crate::cache::BlockTxIDsCache;
+use crate::cache::{BlockTxIDsCache, FeeEstimatesCache};
 use crate::errors::*;
+use crate::metrics::{HistogramVec, Metrics};
+use crate::p2p::BlockFetcher;
 use crate::signal::Waiter;
+use crate::trace;
 use crate::util::HeaderList;
 
 
@@ -55,6 +59,25 @@ fn tx_from_value(value: Value) -> Result<Transaction> {
     Ok(deserialize(&tx_bytes).chain_err(|| format!("failed to parse tx {}", tx_hex))?)
 }
 
+// Amounts are returned by bitcoind in BTC
+fn btc_to_satoshis(value: &Value) -> Option<u64> {
+    value.as_f64().map(|btc| (btc * 100_000_000f64).round() as u64)
+}
+
+fn mempool_entry_from_value(value: Value) -> Result<MempoolEntry> {
+    // "fee" was removed in favor of "fees.base" (bitcoind 0.23)
+    let fee = value
+        .pointer("/fees/base")
+        .or_else(|| value.get("fee"))
+        .and_then(btc_to_satoshis)
+        .chain_err(|| format!("missing fee: {}", value))?;
+    let vsize = value
+        .get("vsize")
+        .and_then(Value::as_u64)
+        .chain_err(|| format!("missing vsize: {}", value))?;
+    Ok(MempoolEntry::new(fee, vsize))
+}
+
 /// Parse JSONRPC error code, if exists.
 fn parse_error_code(err: &Value) -> Option<i64> {
     if err.is_null() {
@@ -69,7 +92,10 @@ fn check_error_code(reply_obj: &Map<String, Value>, method: &str) -> Result<()>
             match code {
                 // RPC_IN_WARMUP -> retry by later reconnection
                 -28 => bail!(ErrorKind::Connection(err.to_string())),
-                _ => bail!("{} RPC error: {}", method, err),
+                _ => {
+                    let msg = err.get("message").and_then(Value::as_str).unwrap_or_default();
+                    bail!(ErrorKind::Daemon(method.to_owned(), code, msg.to_owned()))
+                }
             }
         }
     }
@@ -107,6 +133,8 @@ struct BlockchainInfo {
     bestblockhash: String,
     pruned: bool,
     initialblockdownload: bool,
+    #[serde(default)]
+    verificationprogress: f64,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -115,53 +143,174 @@ struct NetworkInfo {
     subversion: String,
 }
 
+//
+// Fee and size of a mempool transaction
+//
+#[derive(Clone, Debug)]
+pub struct MempoolEntry {
+    fee: u64,   // in satoshis
+    vsize: u64, // in virtual bytes
+}
+
+impl MempoolEntry {
+    pub fn new(fee: u64, vsize: u64) -> MempoolEntry {
+        MempoolEntry { fee, vsize }
+    }
+
+    pub fn fee(&self) -> u64 {
+        self.fee
+    }
+
+    pub fn vsize(&self) -> u64 {
+        self.vsize
+    }
+
+    pub fn fee_per_vbyte(&self) -> f64 {
+        self.fee as f64 / self.vsize.max(1) as f64
+    }
+}
+
 pub trait CookieGetter: Send + Sync {
     fn get(&self) -> Result<Vec<u8>>;
 }
 
+//
+// How to reach bitcoind's JSONRPC interface
+//
+#[derive(Clone, Debug)]
+pub struct RpcSettings {
+    pub addrs: Vec<SocketAddr>, // by decreasing priority
+    pub connect_timeout: Duration,
+    pub read_timeout: Option<Duration>, // None to wait forever
+    pub retries: Option<usize>,         // None to retry forever
+}
+
 struct Connection {
     tx: TcpStream,
     rx: Lines<BufReader<TcpStream>>,
     cookie_getter: Arc<dyn CookieGetter>,
-    addr: SocketAddr,
+    settings: Arc<RpcSettings>,
+    addr: SocketAddr,     // currently connected to
+    genesis: Sha256dHash, // expected from the fallback endpoints
     signal: Waiter,
 }
 
-fn tcp_connect(addr: SocketAddr, signal: &Waiter) -> Result<TcpStream> {
-    loop {
-        match TcpStream::connect(addr) {
-            Ok(conn) => return Ok(conn),
-            Err(err) => {
-                warn!("failed to connect daemon at {}: {}", addr, err);
-                signal.wait(Duration::from_secs(3))?;
-                continue;
-            }
+//
+// Exponentially increasing delays between retries (e.g. while bitcoind is starting)
+//
+struct Backoff {
+    delay: Duration,
+}
+
+impl Backoff {
+    const MIN_DELAY: Duration = Duration::from_secs(1);
+    const MAX_DELAY: Duration = Duration::from_secs(60);
+
+    fn new() -> Backoff {
+        Backoff {
+            delay: Backoff::MIN_DELAY,
         }
     }
+
+    fn next_delay(&mut self) -> Duration {
+        let delay = self.delay;
+        self.delay = (delay * 2).min(Backoff::MAX_DELAY);
+        delay
+    }
 }
 
 impl Connection {
+    // Wait until an endpoint is reachable, on the same chain as the previous ones
     fn new(
-        addr: SocketAddr,
+        settings: Arc<RpcSettings>,
         cookie_getter: Arc<dyn CookieGetter>,
         signal: Waiter,
+        genesis: Option<Sha256dHash>,
     ) -> Result<Connection> {
-        let conn = tcp_connect(addr, &signal)?;
+        let mut backoff = Backoff::new();
+        loop {
+            match Connection::connect_any(&settings, &cookie_getter, &signal, genesis) {
+                Ok(conn) => return Ok(conn),
+                Err(e) => {
+                    let delay = backoff.next_delay();
+                    warn!("{} (retrying in {:?})", e, delay);
+                    signal.wait(delay)?;
+                }
+            }
+        }
+    }
+
+    // Connect to the first reachable endpoint
+    fn connect_any(
+        settings: &Arc<RpcSettings>,
+        cookie_getter: &Arc<dyn CookieGetter>,
+        signal: &Waiter,
+        genesis: Option<Sha256dHash>,
+    ) -> Result<Connection> {
+        for addr in &settings.addrs {
+            match Connection::connect(settings, *addr, cookie_getter, signal, genesis) {
+                Ok(conn) => return Ok(conn),
+                Err(e) => warn!("failed to connect daemon at {}: {}", addr, e),
+            }
+        }
+        bail!(ErrorKind::Connection("no reachable daemon".to_owned()))
+    }
+
+    fn connect(
+        settings: &Arc<RpcSettings>,
+        addr: SocketAddr,
+        cookie_getter: &Arc<dyn CookieGetter>,
+        signal: &Waiter,
+        genesis: Option<Sha256dHash>,
+    ) -> Result<Connection> {
+        let conn = TcpStream::connect_timeout(&addr, settings.connect_timeout)
+            .chain_err(|| "failed to connect")?;
+        conn.set_read_timeout(settings.read_timeout)
+            .chain_err(|| "failed to set read timeout")?;
         let reader = BufReader::new(
             conn.try_clone()
                 .chain_err(|| format!("failed to clone {:?}", conn))?,
         );
-        Ok(Connection {
+        let mut conn = Connection {
             tx: conn,
             rx: reader.lines(),
-            cookie_getter,
+            cookie_getter: Arc::clone(cookie_getter),
+            settings: Arc::clone(settings),
             addr,
-            signal,
-        })
+            genesis: Sha256dHash::default(),
+            signal: signal.clone(),
+        };
+        conn.genesis = conn.getgenesis()?;
+        if let Some(genesis) = genesis {
+            if genesis != conn.genesis {
+                bail!("wrong chain (genesis {}, expected {})", conn.genesis, genesis)
+            }
+        }
+        if addr != settings.addrs[0] {
+            warn!("failed over to daemon at {}", addr);
+        }
+        Ok(conn)
     }
 
     fn reconnect(&self) -> Result<Connection> {
-        Connection::new(self.addr, self.cookie_getter.clone(), self.signal.clone())
+        Connection::new(
+            Arc::clone(&self.settings),
+            self.cookie_getter.clone(),
+            self.signal.clone(),
+            Some(self.genesis),
+        )
+    }
+
+    // Single attempt, for the requests' retries
+    fn try_reconnect(&self) -> Result<Connection> {
+        Connection::connect_any(&self.settings, &self.cookie_getter, &self.signal, Some(self.genesis))
+    }
+
+    // Also checks that bitcoind replies to requests (e.g. it's not in warm-up)
+    fn getgenesis(&mut self) -> Result<Sha256dHash> {
+        self.send(&json!({"method": "getblockhash", "params": [0], "id": 0}).to_string())?;
+        let reply = from_str(&self.recv()?).chain_err(|| "invalid JSON")?;
+        parse_hash(&parse_jsonrpc_reply(reply, "getblockhash", 0)?).chain_err(|| "invalid blockhash")
     }
 
     fn send(&mut self, request: &str) -> Result<()> {
@@ -188,7 +337,7 @@ impl Connection {
             .chain_err(|| {
                 ErrorKind::Connection("disconnected from daemon while receiving".to_owned())
             })?
-            .chain_err(|| "failed to read status")?;
+            .chain_err(|| ErrorKind::Connection("failed to read status".to_owned()))?;
 
         let mut headers = HashMap::new();
 
@@ -234,6 +383,9 @@ impl Connection {
         } else if status == "HTTP/1.1 500 Internal Server Error" {
             warn!("HTTP status: {}", status);
             contents // the contents should have a JSONRPC error field
+        } else if status.starts_with("HTTP/1.1 503") {
+            // e.g. bitcoind is shutting down, or its work queue is full
+            bail!(ErrorKind::Connection(format!("{}: {}", status, contents)))
         } else {
             bail!(
                 "request failed {:?}: {:?} = {:?}",
@@ -245,6 +397,75 @@ impl Connection {
     }
 }
 
+//
+// Download blocks from bitcoind's REST interface (enabled by `-rest`),
+// pipelining the requests over a single connection
+//
+fn rest_getblocks(addr: SocketAddr, blockhashes: &[Sha256dHash]) -> Result<Vec<Block>> {
+    let mut conn = TcpStream::connect(addr).chain_err(|| format!("failed to connect to {}", addr))?;
+    let mut requests = String::new();
+    for (i, hash) in blockhashes.iter().enumerate() {
+        let last = i + 1 == blockhashes.len();
+        requests += &format!(
+            "GET /rest/block/{}.bin HTTP/1.1\r\nHost: {}\r\nConnection: {}\r\n\r\n",
+            hash.to_hex(),
+            addr,
+            if last { "close" } else { "keep-alive" }
+        );
+    }
+    conn.write_all(requests.as_bytes())
+        .chain_err(|| "failed to send REST requests")?;
+
+    let mut reader = BufReader::new(conn);
+    let mut blocks = vec![];
+    for hash in blockhashes {
+        let mut status = String::new();
+        reader
+            .read_line(&mut status)
+            .chain_err(|| "failed to read REST status")?;
+        let mut content_length = None;
+        loop {
+            let mut line = String::new();
+            reader
+                .read_line(&mut line)
+                .chain_err(|| "failed to read REST header")?;
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("Content-Length") {
+                    content_length = value.trim().parse::<usize>().ok();
+                }
+            }
+        }
+        let mut body = vec![0u8; content_length.chain_err(|| "REST reply without Content-Length")?];
+        reader
+            .read_exact(&mut body)
+            .chain_err(|| "failed to read REST reply")?;
+        if !status.starts_with("HTTP/1.1 200") {
+            bail!(
+                "REST request for block {} failed: {} ({})",
+                hash,
+                status.trim_end(),
+                String::from_utf8_lossy(&body).trim_end()
+            );
+        }
+        let block: Block = deserialize(&body).chain_err(|| format!("invalid block {}", hash))?;
+        if block.bitcoin_hash() != *hash {
+            bail!("unexpected block {} (instead of {})", block.bitcoin_hash(), hash);
+        }
+        blocks.push(block);
+    }
+    Ok(blocks)
+}
+
+// Fee estimates only change with new blocks and mempool transactions
+const FEE_ESTIMATES_TTL: Duration = Duration::from_secs(30);
+
+// bitcoind's verification progress is only an estimate, which never quite reaches 1
+const SYNCED_PROGRESS: f64 = 0.9999;
+
 struct Counter {
     value: AtomicU64,
 }
@@ -262,34 +483,47 @@ impl Counter {
 
 pub struct Daemon {
     daemon_dir: PathBuf,
-    network: Network,
+    magic: u32,
     conn: Mutex<Connection>,
     message_id: Counter, // for monotonic JSONRPC 'id'
     signal: Waiter,
     blocktxids_cache: Arc<BlockTxIDsCache>,
+    fee_estimates_cache: Arc<FeeEstimatesCache>,
+    p2p: Option<Arc<BlockFetcher>>, // for blocks pruned by bitcoind
+    rest_addr: Option<SocketAddr>,  // for downloading blocks without JSONRPC overhead
+    connected: Arc<AtomicBool>,     // shared by the reconnected instances
+    durations: Option<Arc<HistogramVec>>,
 }
 
 impl Daemon {
     pub fn new(
         daemon_dir: &PathBuf,
-        daemon_rpc_addr: SocketAddr,
+        rpc_settings: RpcSettings,
         cookie_getter: Arc<dyn CookieGetter>,
-        network: Network,
+        magic: u32,
         signal: Waiter,
         blocktxids_cache: Arc<BlockTxIDsCache>,
+        p2p_peers: Vec<SocketAddr>,
     ) -> Result<Daemon> {
 
-        let daemon = Daemon {
+        let mut daemon = Daemon {
             daemon_dir: daemon_dir.clone(),
-            network,
+            magic,
             conn: Mutex::new(Connection::new(
-                daemon_rpc_addr,
+                Arc::new(rpc_settings),
                 cookie_getter,
                 signal.clone(),
+                // unknown for signet, whose magic depends on its challenge
+                Network::from_magic(magic).map(|network| genesis_block(network).bitcoin_hash()),
             )?),
             message_id: Counter::new(),
             blocktxids_cache: blocktxids_cache,
+            fee_estimates_cache: Arc::new(FeeEstimatesCache::new(FEE_ESTIMATES_TTL)),
             signal: signal.clone(),
+            p2p: None,
+            rest_addr: None,
+            connected: Arc::new(AtomicBool::new(true)),
+            durations: None,
         };
 
         let network_info = daemon.getnetworkinfo()?;
@@ -303,8 +537,11 @@ impl Daemon {
 
         let blockchain_info = daemon.getblockchaininfo()?;
         info!("{:?}", blockchain_info);
+        if blockchain_info.pruned && p2p_peers.is_empty() {
+            bail!("pruned node requires P2P peers (use '--p2p-peers' or '-prune=0' bitcoind flag)".to_owned())
+        }
         if blockchain_info.pruned {
-            bail!("pruned node is not supported (use '-prune=0' bitcoind flag)".to_owned())
+            daemon.p2p = Some(Arc::new(BlockFetcher::new(p2p_peers, magic)));
         }
 
         loop {
@@ -317,17 +554,76 @@ impl Daemon {
         Ok(daemon)
     }
 
+    // Wait until bitcoind has verified its whole chain, and not only
+    // until it's out of the initial block download
+    pub fn wait_for_sync(&self) -> Result<()> {
+        loop {
+            let info = self.getblockchaininfo()?;
+            if info.blocks == info.headers && info.verificationprogress >= SYNCED_PROGRESS {
+                return Ok(());
+            }
+            info!(
+                "waiting for bitcoind to sync: {}/{} blocks, verification progress {:.2}%",
+                info.blocks,
+                info.headers,
+                100.0 * info.verificationprogress
+            );
+            self.signal.wait(Duration::from_secs(10))?;
+        }
+    }
+
     pub fn reconnect(&self) -> Result<Daemon> {
         Ok(Daemon {
             daemon_dir: self.daemon_dir.clone(),
-            network: self.network,
+            magic: self.magic,
             conn: Mutex::new(self.conn.lock().unwrap().reconnect()?),
             message_id: Counter::new(),
             signal: self.signal.clone(),
             blocktxids_cache: Arc::clone(&self.blocktxids_cache),
+            fee_estimates_cache: Arc::clone(&self.fee_estimates_cache),
+            p2p: self.p2p.clone(),
+            rest_addr: self.rest_addr,
+            connected: Arc::clone(&self.connected),
+            durations: self.durations.clone(),
         })
     }
 
+    // Download the blocks from bitcoind's REST interface (on the JSONRPC port)
+    pub fn enable_rest(mut self) -> Self {
+        self.rest_addr = Some(self.conn.lock().unwrap().addr);
+        self
+    }
+
+    // Export the duration of the JSONRPC requests (by method)
+    pub fn with_metrics(mut self, metrics: &Metrics) -> Self {
+        self.durations = Some(metrics.histogram_vec(
+            "addrindexrs_daemon_rpc_duration_seconds",
+            "Duration of the bitcoind JSONRPC requests by method (including retries)",
+            "method",
+        ));
+        self
+    }
+
+    // False while bitcoind is unreachable (e.g. restarting), until the next successful request
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    // Blocks can't be read from the local blk*.dat files
+    pub fn is_pruned(&self) -> bool {
+        self.p2p.is_some()
+    }
+
+    // Fetch the blocks pruned by bitcoind from the P2P peers
+    fn p2p_fallback(&self, err: Error, blockhashes: &[Sha256dHash]) -> Result<Vec<Block>> {
+        match (&self.p2p, err.kind()) {
+            (Some(p2p), ErrorKind::Daemon(_, _, msg)) if msg.contains("pruned") => {
+                p2p.getblocks(blockhashes)
+            }
+            _ => Err(err),
+        }
+    }
+
     pub fn list_blk_files(&self) -> Result<Vec<PathBuf>> {
         let mut path = self.daemon_dir.clone();
         path.push("blocks");
@@ -342,7 +638,7 @@ impl Daemon {
     }
 
     pub fn magic(&self) -> u32 {
-        self.network.magic()
+        self.magic
     }
 
     fn call_jsonrpc(&self, request: &Value) -> Result<Value> {
@@ -371,29 +667,58 @@ impl Daemon {
         bail!("non-array replies: {:?}", replies);
     }
 
+    fn timed_request_batch(&self, method: &str, params_list: &[Value]) -> Result<Vec<Value>> {
+        trace::add_daemon_request();
+        match self.durations {
+            Some(ref durations) => {
+                durations.time(method, || self.retry_request_batch(method, params_list))
+            }
+            None => self.retry_request_batch(method, params_list),
+        }
+    }
+
     fn retry_request_batch(&self, method: &str, params_list: &[Value]) -> Result<Vec<Value>> {
+        let max_retries = self.conn.lock().unwrap().settings.retries;
+        let mut backoff = Backoff::new();
+        let mut retries = 0;
+        let mut result = self.handle_request_batch(method, params_list);
         loop {
-            match self.handle_request_batch(method, params_list) {
-                Err(Error(ErrorKind::Connection(msg), _)) => {
-                    warn!("reconnecting to bitcoind: {}", msg);
-                    self.signal.wait(Duration::from_secs(3))?;
-                    let mut conn = self.conn.lock().unwrap();
-                    *conn = conn.reconnect()?;
-                    continue;
+            let msg = match result {
+                Err(Error(ErrorKind::Connection(msg), _)) => msg,
+                result => {
+                    if !self.connected.swap(true, Ordering::Relaxed) {
+                        info!("reconnected to bitcoind");
+                    }
+                    return result;
                 }
-                result => return result,
+            };
+            // e.g. bitcoind is restarting, or still loading its block index
+            if self.connected.swap(false, Ordering::Relaxed) {
+                error!("disconnected from bitcoind: {}", msg);
+            }
+            if max_retries.is_some_and(|max_retries| retries >= max_retries) {
+                bail!(ErrorKind::Connection(format!("{} (after {} retries)", msg, retries)));
             }
+            retries += 1;
+            let delay = backoff.next_delay();
+            warn!("reconnecting to bitcoind in {:?}: {}", delay, msg);
+            self.signal.wait(delay)?;
+            let reconnected = {
+                let mut conn = self.conn.lock().unwrap();
+                conn.try_reconnect().map(|new_conn| *conn = new_conn)
+            };
+            result = reconnected.and_then(|()| self.handle_request_batch(method, params_list));
         }
     }
 
     fn request(&self, method: &str, params: Value) -> Result<Value> {
-        let mut values = self.retry_request_batch(method, &[params])?;
+        let mut values = self.timed_request_batch(method, &[params])?;
         assert_eq!(values.len(), 1);
         Ok(values.remove(0))
     }
 
     fn requests(&self, method: &str, params_list: &[Value]) -> Result<Vec<Value>> {
-        self.retry_request_batch(method, params_list)
+        self.timed_request_batch(method, params_list)
     }
 
     // bitcoind JSONRPC API:
@@ -438,15 +763,23 @@ impl Daemon {
     }
 
     pub fn getblock(&self, blockhash: &Sha256dHash) -> Result<Block> {
-        let block = block_from_value(
-            self.request("getblock", json!([blockhash.to_hex(), /*verbose=*/ false]))?,
-        )?;
+        let block = match self.request("getblock", json!([blockhash.to_hex(), /*verbose=*/ false])) {
+            Ok(value) => block_from_value(value)?,
+            Err(e) => self.p2p_fallback(e, &[*blockhash])?.remove(0),
+        };
         assert_eq!(block.bitcoin_hash(), *blockhash);
         Ok(block)
     }
 
     fn load_blocktxids(&self, blockhash: &Sha256dHash) -> Result<Vec<Sha256dHash>> {
-        self.request("getblock", json!([blockhash.to_hex(), /*verbose=*/ 1]))?
+        let block = match self.request("getblock", json!([blockhash.to_hex(), /*verbose=*/ 1])) {
+            Ok(block) => block,
+            Err(e) => {
+                let block = self.p2p_fallback(e, &[*blockhash])?.remove(0);
+                return Ok(block.txdata.iter().map(|tx| tx.txid()).collect());
+            }
+        };
+        block
             .get("tx")
             .chain_err(|| "block missing txids")?
             .as_array()
@@ -461,12 +794,23 @@ impl Daemon {
             .get_or_else(&blockhash, || self.load_blocktxids(blockhash))
     }
 
+    // Blocks are requested as raw hex (verbosity 0) and decoded locally, so
+    // indexing them doesn't need any per-transaction request.
     pub fn getblocks(&self, blockhashes: &[Sha256dHash]) -> Result<Vec<Block>> {
+        if let Some(addr) = self.rest_addr {
+            match rest_getblocks(addr, blockhashes) {
+                Ok(blocks) => return Ok(blocks),
+                Err(e) => warn!("falling back to JSONRPC: {}", e),
+            }
+        }
         let params_list: Vec<Value> = blockhashes
             .iter()
             .map(|hash| json!([hash.to_hex(), /*verbose=*/ false]))
             .collect();
-        let values = self.requests("getblock", &params_list)?;
+        let values = match self.requests("getblock", &params_list) {
+            Ok(values) => values,
+            Err(e) => return self.p2p_fallback(e, blockhashes),
+        };
         let mut blocks = vec![];
         for value in values {
             blocks.push(block_from_value(value)?);
@@ -523,6 +867,34 @@ impl Daemon {
         Ok(result)
     }
 
+    pub fn broadcast(&self, tx: &Transaction) -> Result<Sha256dHash> {
+        let tx_hex = hex::encode(serialize(tx));
+        let txid = self.request("sendrawtransaction", json!([tx_hex]))?;
+        parse_hash(&txid).chain_err(|| format!("invalid txid: {}", txid))
+    }
+
+    // Fee rate (in BTC/kvB) for confirmation within `blocks`, if bitcoind has enough data
+    pub fn estimatesmartfee(&self, blocks: u16) -> Result<Option<f64>> {
+        self.fee_estimates_cache.get_or_else(blocks, || {
+            let estimate = self.request("estimatesmartfee", json!([blocks]))?;
+            Ok(estimate.get("feerate").and_then(Value::as_f64))
+        })
+    }
+
+    pub fn getmempoolentries(&self, txhashes: &[&Sha256dHash]) -> Result<Vec<MempoolEntry>> {
+        let params_list: Vec<Value> = txhashes
+            .iter()
+            .map(|txhash| json!([txhash.to_hex()]))
+            .collect();
+        let entries = self
+            .requests("getmempoolentry", &params_list)?
+            .into_iter()
+            .map(mempool_entry_from_value)
+            .collect::<Result<Vec<MempoolEntry>>>()?;
+        assert_eq!(txhashes.len(), entries.len());
+        Ok(entries)
+    }
+
     fn get_all_headers(&self, tip: &Sha256dHash) -> Result<Vec<BlockHeader>> {
         let info: Value = self.request("getblockheader", json!([tip.to_hex()]))?;
         let tip_height = info
@@ -583,3 +955,64 @@ impl Daemon {
         Ok(new_headers)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::new();
+        let delays: Vec<u64> = (0..8).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 16, 32, 60, 60]);
+    }
+
+    // Reply to pipelined requests with the genesis block, and then a 404
+    fn fake_rest_server(listener: TcpListener) {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut stream = stream;
+        let genesis = serialize(&genesis_block(Network::Regtest));
+        let mut replies = 0;
+        let mut line = String::new();
+        while reader.read_line(&mut line).unwrap() > 0 {
+            if line == "\r\n" {
+                let reply = if replies == 0 {
+                    let mut reply = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\n\r\n",
+                        genesis.len()
+                    )
+                    .into_bytes();
+                    reply.extend_from_slice(&genesis);
+                    reply
+                } else {
+                    b"HTTP/1.1 404 Not Found\r\nContent-Length: 10\r\n\r\nnot found\n".to_vec()
+                };
+                stream.write_all(&reply).unwrap();
+                replies += 1;
+            }
+            line.clear();
+        }
+    }
+
+    #[test]
+    fn test_rest_getblocks() {
+        let genesis = genesis_block(Network::Regtest);
+        for (blockhashes, ok) in [
+            (vec![genesis.bitcoin_hash()], true),
+            (vec![genesis.bitcoin_hash(), Sha256dHash::default()], false),
+        ] {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = thread::spawn(move || fake_rest_server(listener));
+            let result = rest_getblocks(addr, &blockhashes);
+            assert_eq!(result.is_ok(), ok);
+            if ok {
+                assert_eq!(result.unwrap(), vec![genesis.clone()]);
+            }
+            server.join().unwrap();
+        }
+    }
+}
@@ -0,0 +1,222 @@
+use bitcoin::blockdata::script::Script;
+use bitcoin::secp256k1::{Secp256k1, VerifyOnly};
+use bitcoin::util::address::Address;
+use bitcoin::util::base58;
+use bitcoin::util::bip32::{ChildNumber, ExtendedPubKey};
+use std::str::FromStr;
+
+use crate::errors::*;
+
+//
+// Output script derived from each key of a wallet
+//
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScriptType {
+    P2pkh,
+    P2shP2wpkh,
+    P2wpkh,
+}
+
+// SLIP-132 extended public key versions, and the matching script types
+const VERSIONS: [([u8; 4], [u8; 4], ScriptType); 6] = [
+    ([0x04, 0x88, 0xB2, 0x1E], XPUB, ScriptType::P2pkh),      // xpub
+    ([0x04, 0x9D, 0x7C, 0xB2], XPUB, ScriptType::P2shP2wpkh), // ypub
+    ([0x04, 0xB2, 0x47, 0x46], XPUB, ScriptType::P2wpkh),     // zpub
+    ([0x04, 0x35, 0x87, 0xCF], TPUB, ScriptType::P2pkh),      // tpub
+    ([0x04, 0x4A, 0x52, 0x62], TPUB, ScriptType::P2shP2wpkh), // upub
+    ([0x04, 0x5F, 0x1C, 0xF6], TPUB, ScriptType::P2wpkh),     // vpub
+];
+const XPUB: [u8; 4] = [0x04, 0x88, 0xB2, 0x1E];
+const TPUB: [u8; 4] = [0x04, 0x35, 0x87, 0xCF];
+
+// Parse a xpub/ypub/zpub (or testnet equivalent) key
+fn parse_xpub(key: &str) -> Result<(ExtendedPubKey, ScriptType)> {
+    let mut data = base58::from_check(key).chain_err(|| format!("invalid extended key {}", key))?;
+    if data.len() != 78 {
+        bail!("invalid extended key length {}", data.len());
+    }
+    let (version, script_type) = VERSIONS
+        .iter()
+        .find(|(version, _, _)| data[..4] == version[..])
+        .map(|(_, version, script_type)| (version, *script_type))
+        .chain_err(|| format!("unsupported extended key version {}", hex::encode(&data[..4])))?;
+    // rust-bitcoin only supports the BIP32 versions
+    data[..4].copy_from_slice(version);
+    let xpub = ExtendedPubKey::from_str(&base58::check_encode_slice(&data))
+        .chain_err(|| format!("invalid extended key {}", key))?;
+    Ok((xpub, script_type))
+}
+
+fn parse_index(step: &str) -> Result<ChildNumber> {
+    if step.ends_with('\'') || step.ends_with('h') {
+        bail!("hardened derivation {} requires a private key", step);
+    }
+    let index = step
+        .parse::<u32>()
+        .chain_err(|| format!("invalid derivation step {}", step))?;
+    ChildNumber::from_normal_idx(index).chain_err(|| format!("invalid derivation index {}", index))
+}
+
+//
+// Wallet described by an extended public key or an output descriptor
+// (`pkh`, `wpkh` and `sh(wpkh)` single-key descriptors, with an
+// optional `<a;b>` multipath step for receive/change chains).
+//
+#[derive(Debug)]
+pub struct Descriptor {
+    xpub: ExtendedPubKey,
+    script_type: ScriptType,
+    chains: Vec<Vec<ChildNumber>>, // derivation paths, up to the (last) wildcard step
+}
+
+impl Descriptor {
+    pub fn parse(input: &str) -> Result<Descriptor> {
+        let input = input.trim();
+        // checksums are optional, and not verified
+        let input = input.split('#').next().unwrap_or_default();
+
+        let (script_type, key) = if let Some(key) = strip_wrapper(input, "sh(wpkh(", "))") {
+            (Some(ScriptType::P2shP2wpkh), key)
+        } else if let Some(key) = strip_wrapper(input, "wpkh(", ")") {
+            (Some(ScriptType::P2wpkh), key)
+        } else if let Some(key) = strip_wrapper(input, "pkh(", ")") {
+            (Some(ScriptType::P2pkh), key)
+        } else if input.contains('(') {
+            bail!("unsupported descriptor {}", input);
+        } else {
+            (None, input)
+        };
+
+        // skip the key origin (e.g. "[d34db33f/84'/0'/0']")
+        let key = match key.strip_prefix('[') {
+            Some(rest) => rest.split_once(']').chain_err(|| "invalid key origin")?.1,
+            None => key,
+        };
+
+        let mut steps = key.split('/');
+        let (xpub, key_script_type) = parse_xpub(steps.next().unwrap_or_default())?;
+        let steps: Vec<&str> = steps.collect();
+
+        let chains = match (script_type, steps.split_last()) {
+            // a bare extended key: receive and change chains
+            (None, None) => vec![vec![ChildNumber::from(0)], vec![ChildNumber::from(1)]],
+            (Some(_), Some((&"*", steps))) => {
+                let mut chains = vec![vec![]];
+                for step in steps {
+                    match strip_wrapper(step, "<", ">") {
+                        Some(indexes) if chains.len() == 1 => {
+                            let prefix = chains.remove(0);
+                            for index in indexes.split(';') {
+                                let mut chain = prefix.clone();
+                                chain.push(parse_index(index)?);
+                                chains.push(chain);
+                            }
+                        }
+                        Some(_) => bail!("only one multipath step is supported"),
+                        None => {
+                            let index = parse_index(step)?;
+                            chains.iter_mut().for_each(|chain| chain.push(index));
+                        }
+                    }
+                }
+                chains
+            }
+            _ => bail!("descriptor keys must end with a '/*' wildcard step"),
+        };
+
+        Ok(Descriptor {
+            xpub,
+            script_type: script_type.unwrap_or(key_script_type),
+            chains,
+        })
+    }
+
+    pub fn chains(&self) -> usize {
+        self.chains.len()
+    }
+
+    pub fn path(&self, chain: usize, index: u32) -> String {
+        self.chains[chain]
+            .iter()
+            .map(|step| step.to_string())
+            .chain(std::iter::once(index.to_string()))
+            .collect::<Vec<String>>()
+            .join("/")
+    }
+
+    pub fn derive(&self, secp: &Secp256k1<VerifyOnly>, chain: usize, index: u32) -> Result<Script> {
+        let mut path = self.chains[chain].clone();
+        path.push(ChildNumber::from_normal_idx(index).chain_err(|| "invalid index")?);
+        let key = self
+            .xpub
+            .derive_pub(secp, &path)
+            .chain_err(|| format!("failed to derive {}", self.path(chain, index)))?
+            .public_key;
+        let network = self.xpub.network;
+        let address = match self.script_type {
+            ScriptType::P2pkh => Address::p2pkh(&key, network),
+            ScriptType::P2shP2wpkh => Address::p2shwpkh(&key, network),
+            ScriptType::P2wpkh => Address::p2wpkh(&key, network),
+        };
+        Ok(address.script_pubkey())
+    }
+}
+
+fn strip_wrapper<'a>(input: &'a str, prefix: &str, suffix: &str) -> Option<&'a str> {
+    input.strip_prefix(prefix)?.strip_suffix(suffix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // BIP84 test vector (m/84'/0'/0')
+    const ZPUB: &str = "zpub6rFR7y4Q2AijBEqTUquhVz398htDFrtymD9xYYfG1m4wAcvPhXNfE3EfH1r1ADqtfSdVCToUG868RvUUkgDKf31mGDtKsAYz2oz2AGutZYs";
+    // BIP49 test vector (m/49'/1'/0')
+    const UPUB: &str = "upub5EFU65HtV5TeiSHmZZm7FUffBGy8UKeqp7vw43jYbvZPpoVsgU93oac7Wk3u6moKegAEWtGNF8DehrnHtv21XXEMYRUocHqguyjknFHYfgY";
+
+    fn address(descriptor: &Descriptor, chain: usize, index: u32) -> String {
+        let secp = Secp256k1::verification_only();
+        let script = descriptor.derive(&secp, chain, index).unwrap();
+        let network = descriptor.xpub.network;
+        Address::from_script(&script, network).unwrap().to_string()
+    }
+
+    #[test]
+    fn test_parse_xpub() {
+        let descriptor = Descriptor::parse(ZPUB).unwrap();
+        assert_eq!(descriptor.script_type, ScriptType::P2wpkh);
+        assert_eq!(descriptor.chains(), 2);
+        assert_eq!(descriptor.path(1, 0), "1/0");
+        assert_eq!(address(&descriptor, 0, 0), "bc1qcr8te4kr609gcawutmrza0j4xv80jy8z306fyu");
+        assert_eq!(address(&descriptor, 0, 1), "bc1qnjg0jd8228aq7egyzacy8cys3knf9xvrerkf9g");
+        assert_eq!(address(&descriptor, 1, 0), "bc1q8c6fshw2dlwun7ekn9qwf37cu2rn755upcp6el");
+
+        let descriptor = Descriptor::parse(UPUB).unwrap();
+        assert_eq!(descriptor.script_type, ScriptType::P2shP2wpkh);
+        assert_eq!(address(&descriptor, 0, 0), "2Mww8dCYPUpKHofjgcXcBCEGmniw9CoaiD2");
+    }
+
+    #[test]
+    fn test_parse_descriptor() {
+        let xpub = Descriptor::parse(ZPUB).unwrap().xpub.to_string();
+
+        let descriptor = Descriptor::parse(&format!("wpkh([d34db33f/84'/0'/0']{}/<0;1>/*)#checksum", xpub)).unwrap();
+        assert_eq!(descriptor.script_type, ScriptType::P2wpkh);
+        assert_eq!(descriptor.chains(), 2);
+        assert_eq!(address(&descriptor, 1, 0), "bc1q8c6fshw2dlwun7ekn9qwf37cu2rn755upcp6el");
+
+        let descriptor = Descriptor::parse(&format!("pkh({}/1/*)", xpub)).unwrap();
+        assert_eq!(descriptor.script_type, ScriptType::P2pkh);
+        assert_eq!(descriptor.chains(), 1);
+        assert_eq!(descriptor.path(0, 7), "1/7");
+
+        let descriptor = Descriptor::parse(&format!("sh(wpkh({}/0/*))", xpub)).unwrap();
+        assert_eq!(descriptor.script_type, ScriptType::P2shP2wpkh);
+
+        assert!(Descriptor::parse(&format!("wpkh({})", xpub)).is_err()); // no wildcard
+        assert!(Descriptor::parse(&format!("wpkh({}/0'/*)", xpub)).is_err()); // hardened
+        assert!(Descriptor::parse(&format!("tr({}/0/*)", xpub)).is_err());
+        assert!(Descriptor::parse("wpkh(xpub123/0/*)").is_err());
+    }
+}
@@ -9,9 +9,33 @@ error_chain! {
             display("Connection error: {}", msg)
         }
 
+        Daemon(method: String, code: i64, msg: String) {
+            description("bitcoind RPC error")
+            display("{} RPC error {}: {}", method, code, msg)
+        }
+
         Interrupt(sig: i32) {
             description("Interruption by external signal")
             display("Interrupted by signal {}", sig)
         }
+
+        Timeout {
+            description("Query timeout")
+            display("query timed out")
+        }
+
+        TooManyTxs(limit: usize) {
+            description("History larger than txid_limit")
+            display("more than {} transactions, fetch the history by pages", limit)
+        }
+    }
+}
+
+// The error of a history larger than `txid_limit`, if it caused this one
+pub fn too_many_txs(e: &Error) -> Option<&Error> {
+    if let ErrorKind::TooManyTxs(_) = e.kind() {
+        return Some(e);
     }
+    let source = std::error::Error::source(e).and_then(|cause| cause.downcast_ref::<Error>());
+    source.and_then(too_many_txs)
 }
@@ -0,0 +1,180 @@
+use bitcoin::network::constants::Network;
+use bitcoin_hashes::hex::ToHex;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::errors::*;
+use crate::index::parse_script_hash;
+use crate::query::{Query, Transfer};
+
+//
+// Format of an exported history (JSON for a `.json` file, CSV otherwise)
+//
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Csv,
+    Json,
+}
+
+impl Format {
+    pub fn from_path(path: &Path) -> Format {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("json") => Format::Json,
+            _ => Format::Csv,
+        }
+    }
+}
+
+//
+// Writes the transfers of a history as they are streamed
+//
+struct Exporter<W: Write> {
+    writer: W,
+    format: Format,
+    count: usize,
+}
+
+impl<W: Write> Exporter<W> {
+    fn new(mut writer: W, format: Format) -> Result<Exporter<W>> {
+        let header = match format {
+            Format::Csv => "txid,height,direction,value\n",
+            Format::Json => "[",
+        };
+        writer
+            .write_all(header.as_bytes())
+            .chain_err(|| "failed to write header")?;
+        Ok(Exporter {
+            writer,
+            format,
+            count: 0,
+        })
+    }
+
+    fn write(&mut self, transfer: &Transfer) -> Result<()> {
+        let direction = if transfer.received {
+            "received"
+        } else {
+            "sent"
+        };
+        let line = match self.format {
+            Format::Csv => {
+                let value = transfer.value.map(|value| value.to_string());
+                format!(
+                    "{},{},{},{}\n",
+                    transfer.txid.to_hex(),
+                    transfer.height,
+                    direction,
+                    value.unwrap_or_default()
+                )
+            }
+            Format::Json => {
+                let entry = json!({
+                    "txid": transfer.txid.to_hex(),
+                    "height": transfer.height,
+                    "direction": direction,
+                    "value": transfer.value,
+                });
+                let separator = if self.count == 0 { "\n" } else { ",\n" };
+                format!("{}{}", separator, entry)
+            }
+        };
+        self.count += 1;
+        self.writer
+            .write_all(line.as_bytes())
+            .chain_err(|| "failed to write transfer")
+    }
+
+    fn finish(mut self) -> Result<W> {
+        if self.format == Format::Json {
+            self.writer
+                .write_all(b"\n]\n")
+                .chain_err(|| "failed to write footer")?;
+        }
+        self.writer.flush().chain_err(|| "failed to flush")?;
+        Ok(self.writer)
+    }
+}
+
+// Export the history of an address (or script hash) to `path`, returning the
+// number of transfers. It is written to `<path>.tmp` first, so an interrupted
+// export doesn't leave a truncated file behind.
+pub fn export_history(query: &Query, target: &str, network: Network, path: &Path) -> Result<usize> {
+    let script_hash = parse_script_hash(target, network)?;
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let file = File::create(&tmp_path).chain_err(|| format!("failed to create {:?}", tmp_path))?;
+    let mut exporter = Exporter::new(BufWriter::new(file), Format::from_path(path))?;
+    let count = query.export_transfers(&script_hash, |transfer| exporter.write(&transfer))?;
+    exporter.finish()?;
+    fs::rename(&tmp_path, path).chain_err(|| format!("failed to rename {:?}", tmp_path))?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+    use bitcoin_hashes::Hash;
+
+    use super::*;
+
+    fn transfers() -> Vec<Transfer> {
+        let txid = Sha256dHash::hash(b"tx");
+        vec![
+            Transfer {
+                txid,
+                height: 100,
+                received: true,
+                value: Some(5000),
+            },
+            Transfer {
+                txid,
+                height: 0,
+                received: false,
+                value: None,
+            },
+        ]
+    }
+
+    fn export(format: Format) -> String {
+        let mut exporter = Exporter::new(vec![], format).unwrap();
+        for transfer in transfers() {
+            exporter.write(&transfer).unwrap();
+        }
+        String::from_utf8(exporter.finish().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_export() {
+        let txid = Sha256dHash::hash(b"tx").to_hex();
+        let csv = export(Format::Csv);
+        let expected = format!(
+            "txid,height,direction,value\n{},100,received,5000\n{},0,sent,\n",
+            txid, txid
+        );
+        assert_eq!(csv, expected);
+
+        let json: serde_json::Value = serde_json::from_str(&export(Format::Json)).unwrap();
+        assert_eq!(
+            json,
+            json!([
+                {"txid": txid, "height": 100, "direction": "received", "value": 5000},
+                {"txid": txid, "height": 0, "direction": "sent", "value": null},
+            ])
+        );
+        let empty: serde_json::Value = serde_json::from_str(
+            &String::from_utf8(
+                Exporter::new(vec![], Format::Json)
+                    .unwrap()
+                    .finish()
+                    .unwrap(),
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(empty, json!([]));
+
+        assert_eq!(Format::from_path(Path::new("history.JSON")), Format::Json);
+        assert_eq!(Format::from_path(Path::new("history.csv")), Format::Csv);
+    }
+}
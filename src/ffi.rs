@@ -0,0 +1,256 @@
+// C ABI bindings of the core queries (see include/addrindexrs.h), for the
+// daemons written in other languages to link the index directly.
+//
+// The script hashes and txids are hex strings, in the Electrum format. The
+// returned strings are owned by the caller, and freed by
+// `addrindexrs_free_string()`. On failure, the functions return NULL (or -1),
+// and `addrindexrs_last_error()` describes the error.
+#![allow(clippy::missing_safety_doc)] // the pointers must be valid, as documented in the header
+
+use bitcoin::consensus::encode::serialize;
+use bitcoin_hashes::hex::{FromHex, ToHex};
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use serde_json::Value;
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::sync::Arc;
+
+use crate::app::App;
+use crate::cache::{HistoryCache, TransactionCache};
+use crate::config::Config;
+use crate::errors::*;
+use crate::metrics::Metrics;
+use crate::query::{set_maturity, Query};
+use crate::signal::Waiter;
+
+thread_local! {
+    // Error of the last failed call made by the current thread
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|cell| *cell.borrow_mut() = Some(message));
+}
+
+// Run `f`, returning `failed` on errors (and panics, which can't unwind into C)
+fn call<T>(failed: T, f: impl FnOnce() -> Result<T>) -> T {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(value)) => value,
+        Ok(Err(e)) => {
+            // the causes are joined, e.g. "failed to open DB: permission denied"
+            let causes: Vec<String> = e.iter().map(|cause| cause.to_string()).collect();
+            set_last_error(causes.join(": "));
+            failed
+        }
+        Err(_) => {
+            set_last_error("panicked (see the logs)".to_owned());
+            failed
+        }
+    }
+}
+
+unsafe fn to_str<'a>(ptr: *const c_char) -> Result<&'a str> {
+    if ptr.is_null() {
+        bail!("unexpected NULL string");
+    }
+    CStr::from_ptr(ptr)
+        .to_str()
+        .chain_err(|| "invalid UTF-8 string")
+}
+
+unsafe fn to_hash(ptr: *const c_char) -> Result<Sha256dHash> {
+    let hex = to_str(ptr)?;
+    Sha256dHash::from_hex(hex).chain_err(|| format!("invalid hash {:?}", hex))
+}
+
+fn to_c_string(value: String) -> Result<*mut c_char> {
+    Ok(CString::new(value).chain_err(|| "unexpected NUL")?.into_raw())
+}
+
+/// Index opened by `addrindexrs_open()`
+pub struct AddrIndex {
+    app: Arc<App>,
+    query: Arc<Query>,
+    signal: Waiter,
+}
+
+unsafe fn to_index<'a>(index: *const AddrIndex) -> Result<&'a AddrIndex> {
+    index.as_ref().chain_err(|| "unexpected NULL index")
+}
+
+/// Open the index of the `count` options, each being "name=value" (or "name"
+/// for the switches), using the config file names (e.g. "db_dir=/var/lib/db")
+#[no_mangle]
+pub unsafe extern "C" fn addrindexrs_open(
+    options: *const *const c_char,
+    count: usize,
+) -> *mut AddrIndex {
+    call(ptr::null_mut(), || {
+        let mut builder = Config::builder();
+        for i in 0..count {
+            let option = to_str(*options.add(i))?;
+            builder = match option.split_once('=') {
+                Some((name, value)) => builder.arg(name.trim(), value),
+                None => builder.switch(option.trim()),
+            };
+        }
+        let config = builder.build()?;
+        let metrics = Metrics::new(None);
+        let signal = Waiter::detached();
+        let app = App::open(&config, &metrics, &signal)?;
+        let query = Query::new(
+            app.clone(),
+            &metrics,
+            config.reloadable.txid_limit,
+            Arc::new(TransactionCache::new(config.reloadable.tx_cache_size)),
+            Arc::new(HistoryCache::new(config.reloadable.history_cache_size)),
+        );
+        let index = AddrIndex { app, query, signal };
+        Ok(Box::into_raw(Box::new(index)))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn addrindexrs_close(index: *mut AddrIndex) {
+    if !index.is_null() {
+        drop(Box::from_raw(index));
+    }
+}
+
+/// Index the new blocks and mempool transactions, returning 1 if there were
+/// any (0 otherwise)
+#[no_mangle]
+pub unsafe extern "C" fn addrindexrs_update(index: *const AddrIndex) -> c_int {
+    call(-1, || {
+        let index = to_index(index)?;
+        let new_block = index.app.update(&index.signal)?;
+        let mempool_changed = index.query.update_mempool()?;
+        Ok((new_block || mempool_changed) as c_int)
+    })
+}
+
+/// JSON array of the transactions of a script, like `blockchain.scripthash.get_history`
+#[no_mangle]
+pub unsafe extern "C" fn addrindexrs_get_history(
+    index: *const AddrIndex,
+    script_hash: *const c_char,
+) -> *mut c_char {
+    call(ptr::null_mut(), || {
+        let index = to_index(index)?;
+        let script_hash = to_hash(script_hash)?;
+        let tip_height = index.query.get_best_header()?.height();
+        let history = index.query.get_history(&script_hash[..])?;
+        let history: Vec<Value> = history.iter().map(|entry| entry.to_json(tip_height)).collect();
+        to_c_string(json!(history).to_string())
+    })
+}
+
+/// JSON array of the unspent outputs of a script, like `blockchain.scripthash.listunspent`
+#[no_mangle]
+pub unsafe extern "C" fn addrindexrs_get_utxos(
+    index: *const AddrIndex,
+    script_hash: *const c_char,
+) -> *mut c_char {
+    call(ptr::null_mut(), || {
+        let index = to_index(index)?;
+        let script_hash = to_hash(script_hash)?;
+        let tip_height = index.query.get_best_header()?.height();
+        let mut utxos = vec![];
+        for (txo, value) in index.query.unspent(&script_hash[..])? {
+            let mut utxo = json!({
+                "tx_hash": txo.txid.to_hex(),
+                "tx_pos": txo.vout,
+                "value": value,
+                "height": txo.blockindex,
+            });
+            set_maturity(&mut utxo, txo.maturity(tip_height));
+            utxos.push(utxo);
+        }
+        to_c_string(json!(utxos).to_string())
+    })
+}
+
+/// Confirmed and unconfirmed balances of a script (in satoshis), returning 0 on success
+#[no_mangle]
+pub unsafe extern "C" fn addrindexrs_get_balance(
+    index: *const AddrIndex,
+    script_hash: *const c_char,
+    confirmed: *mut u64,
+    unconfirmed: *mut i64,
+) -> c_int {
+    call(-1, || {
+        let index = to_index(index)?;
+        let script_hash = to_hash(script_hash)?;
+        let balance = index.query.get_balance(&script_hash[..])?;
+        if let Some(confirmed) = confirmed.as_mut() {
+            *confirmed = balance.0;
+        }
+        if let Some(unconfirmed) = unconfirmed.as_mut() {
+            *unconfirmed = balance.1;
+        }
+        Ok(0)
+    })
+}
+
+/// Hex-encoded raw transaction, like `blockchain.transaction.get`
+#[no_mangle]
+pub unsafe extern "C" fn addrindexrs_get_transaction(
+    index: *const AddrIndex,
+    txid: *const c_char,
+) -> *mut c_char {
+    call(ptr::null_mut(), || {
+        let index = to_index(index)?;
+        let tx = index.query.get_transaction(&to_hash(txid)?)?;
+        to_c_string(hex::encode(serialize(&tx)))
+    })
+}
+
+/// Height of the indexed tip, or -1 on failure
+#[no_mangle]
+pub unsafe extern "C" fn addrindexrs_get_height(index: *const AddrIndex) -> i64 {
+    call(-1, || Ok(to_index(index)?.query.get_best_header()?.height() as i64))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn addrindexrs_free_string(string: *mut c_char) {
+    if !string.is_null() {
+        drop(CString::from_raw(string));
+    }
+}
+
+/// Error of the last failed call made by the current thread (or NULL), valid
+/// until its next failed call
+#[no_mangle]
+pub extern "C" fn addrindexrs_last_error() -> *const c_char {
+    LAST_ERROR.with(|cell| match *cell.borrow() {
+        Some(ref message) => message.as_ptr(),
+        None => ptr::null(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_errors() {
+        assert!(addrindexrs_last_error().is_null());
+        let options = [b"network=nonet\0".as_ptr() as *const c_char];
+        let index = unsafe { addrindexrs_open(options.as_ptr(), options.len()) };
+        assert!(index.is_null());
+        let error = unsafe { CStr::from_ptr(addrindexrs_last_error()) };
+        assert!(error.to_str().unwrap().contains("nonet"), "{:?}", error);
+
+        let script_hash = b"00\0".as_ptr() as *const c_char;
+        let history = unsafe { addrindexrs_get_history(ptr::null(), script_hash) };
+        assert!(history.is_null());
+        let error = unsafe { CStr::from_ptr(addrindexrs_last_error()) };
+        assert_eq!(error.to_str().unwrap(), "unexpected NULL index");
+        assert_eq!(call(1, || panic!("boom")), 1);
+        unsafe { addrindexrs_close(ptr::null_mut()) };
+    }
+}
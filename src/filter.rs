@@ -0,0 +1,96 @@
+use bitcoin::blockdata::block::Block;
+use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::transaction::OutPoint;
+use bitcoin::util::bip158::{self, BlockFilter};
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use std::collections::HashMap;
+
+use crate::daemon::Daemon;
+use crate::errors::*;
+use crate::store::{DBStore, Row};
+use crate::util::{full_hash, Bytes, FullHash};
+
+// Number of previous transactions fetched per bitcoind batch request
+const PREVOUT_BATCH_SIZE: usize = 1000;
+
+// Filters moved at once by `migrate_rows`
+const MIGRATION_BATCH_SIZE: usize = 1000;
+
+//
+// Key of a row storing the BIP158 basic filter of a block
+//
+#[derive(Serialize, Deserialize)]
+struct BlockFilterKey {
+    code: u8,
+    hash: FullHash,
+}
+
+pub fn filter_key(blockhash: &Sha256dHash) -> Bytes {
+    bincode::serialize(&BlockFilterKey {
+        code: b'K',
+        hash: full_hash(&blockhash[..]),
+    })
+    .unwrap()
+}
+
+pub fn filter_row(blockhash: &Sha256dHash, filter: Bytes) -> Row {
+    Row {
+        key: filter_key(blockhash),
+        value: filter,
+    }
+}
+
+// Move the filters stored by the older releases under the code of the full
+// compaction marker (`F`, which is kept) to their own code
+pub fn migrate_rows(store: &DBStore) {
+    let mut rows = vec![];
+    let mut deleted = vec![];
+    for row in store.iter_scan(b"F").filter(|row| row.key.len() > 1) {
+        rows.push(Row {
+            key: [&b"K"[..], &row.key[1..]].concat(),
+            value: row.value,
+        });
+        deleted.push(row.key);
+        if rows.len() >= MIGRATION_BATCH_SIZE {
+            store.write_batch(std::mem::take(&mut rows), std::mem::take(&mut deleted));
+        }
+    }
+    store.write_batch(rows, deleted);
+}
+
+//
+// Compute the basic filter of a block (its output scripts, and the
+// scripts spent by its inputs, fetched from bitcoind).
+//
+pub fn compute_block_filter(block: &Block, daemon: &Daemon) -> Result<Bytes> {
+    let mut txids: Vec<&Sha256dHash> = block
+        .txdata
+        .iter()
+        .skip(1) // coinbase
+        .flat_map(|tx| tx.input.iter().map(|txin| &txin.previous_output.txid))
+        .collect();
+    txids.sort_unstable();
+    txids.dedup();
+
+    let mut scripts: HashMap<OutPoint, Script> = HashMap::new();
+    for chunk in txids.chunks(PREVOUT_BATCH_SIZE) {
+        for (txid, tx) in chunk.iter().zip(daemon.gettransactions(chunk)?) {
+            for (vout, output) in tx.output.into_iter().enumerate() {
+                let outpoint = OutPoint {
+                    txid: **txid,
+                    vout: vout as u32,
+                };
+                scripts.insert(outpoint, output.script_pubkey);
+            }
+        }
+    }
+
+    let filter = BlockFilter::new_script_filter(block, |outpoint| {
+        scripts
+            .get(outpoint)
+            .cloned()
+            .ok_or(bip158::Error::UtxoMissing(*outpoint))
+    })
+    .chain_err(|| "failed to compute block filter")?;
+    Ok(filter.content)
+}
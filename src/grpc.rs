@@ -0,0 +1,921 @@
+//
+// gRPC server over cleartext HTTP/2 (see proto/addrindexrs.proto), for the
+// backends preferring typed requests over the line-based JSON protocol
+//
+#[cfg(feature = "grpc")]
+mod imp {
+    use bitcoin::network::constants::Network;
+    use bitcoin_hashes::hex::ToHex;
+    use std::collections::HashMap;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc::{self, Receiver, Sender};
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+
+    use crate::errors::*;
+    use crate::http2::{self, Frame, HeaderDecoder};
+    use crate::index::address_script_hash;
+    use crate::query::{self, is_mature, Query};
+    use crate::rpc::{is_timeout, same_secret, RateLimiter, ServerSettings};
+    use crate::trace;
+    use crate::util::{full_hash, spawn_thread, FullHash};
+
+    const SERVICE: &str = "/addrindexrs.Addrindex/";
+    const MAX_CONCURRENT_STREAMS: u32 = 100;
+    const MAX_MESSAGE_SIZE: usize = 1 << 20;
+    const MAX_SUBSCRIBED_SCRIPTS: usize = 10_000; // by stream
+
+    // gRPC status codes
+    const OK: u32 = 0;
+    const INVALID_ARGUMENT: u32 = 3;
+    const DEADLINE_EXCEEDED: u32 = 4;
+    const RESOURCE_EXHAUSTED: u32 = 8;
+    const UNIMPLEMENTED: u32 = 12;
+    const INTERNAL: u32 = 13;
+    const UNAVAILABLE: u32 = 14;
+    const UNAUTHENTICATED: u32 = 16;
+
+    struct Status {
+        code: u32,
+        message: String,
+    }
+
+    impl Status {
+        fn new(code: u32, message: &str) -> Status {
+            Status {
+                code,
+                message: message.to_owned(),
+            }
+        }
+
+        fn from_error(e: &Error) -> Status {
+            match e.kind() {
+                _ if is_timeout(e) => Status::new(DEADLINE_EXCEEDED, "query timed out"),
+                ErrorKind::Connection(_) => Status::new(UNAVAILABLE, &e.to_string()),
+                _ => Status::new(INTERNAL, &e.to_string()),
+            }
+        }
+    }
+
+    //
+    // Protocol Buffers encoding of a message (the fields set to their
+    // default value are skipped, like proto3 encoders do)
+    //
+    #[derive(Default)]
+    struct Message(Vec<u8>);
+
+    // Wire types
+    const VARINT: u8 = 0;
+    const FIXED64: u8 = 1;
+    const LENGTH_DELIMITED: u8 = 2;
+    const FIXED32: u8 = 5;
+
+    fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+        while value >= 0x80 {
+            out.push((value & 0x7f) as u8 | 0x80);
+            value >>= 7;
+        }
+        out.push(value as u8);
+    }
+
+    impl Message {
+        fn key(&mut self, field: u32, wire_type: u8) {
+            write_varint(&mut self.0, u64::from(field << 3 | u32::from(wire_type)));
+        }
+
+        fn uint(&mut self, field: u32, value: u64) {
+            if value != 0 {
+                self.key(field, VARINT);
+                write_varint(&mut self.0, value);
+            }
+        }
+
+        // int64 fields (negative values take 10 bytes)
+        fn int(&mut self, field: u32, value: i64) {
+            self.uint(field, value as u64)
+        }
+
+        fn bytes(&mut self, field: u32, value: &[u8]) {
+            if !value.is_empty() {
+                self.key(field, LENGTH_DELIMITED);
+                write_varint(&mut self.0, value.len() as u64);
+                self.0.extend_from_slice(value);
+            }
+        }
+
+        // Repeated messages are kept even if empty
+        fn message(&mut self, field: u32, value: &Message) {
+            self.key(field, LENGTH_DELIMITED);
+            write_varint(&mut self.0, value.0.len() as u64);
+            self.0.extend_from_slice(&value.0);
+        }
+    }
+
+    enum Field<'a> {
+        #[allow(dead_code)] // the requests have no integer fields (yet)
+        Varint(u64),
+        Bytes(&'a [u8]),
+        Fixed,
+    }
+
+    fn read_varint(data: &[u8], pos: &mut usize) -> Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = *data.get(*pos).chain_err(|| "truncated varint")?;
+            *pos += 1;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        bail!("varint too large")
+    }
+
+    fn parse_message(data: &[u8]) -> Result<Vec<(u32, Field<'_>)>> {
+        let mut fields = vec![];
+        let mut pos = 0;
+        while pos < data.len() {
+            let key = read_varint(data, &mut pos)?;
+            let field = (key >> 3) as u32;
+            let value = match (key & 7) as u8 {
+                VARINT => Field::Varint(read_varint(data, &mut pos)?),
+                LENGTH_DELIMITED => {
+                    let size = read_varint(data, &mut pos)? as usize;
+                    let end = pos.checked_add(size).filter(|end| *end <= data.len());
+                    let end = end.chain_err(|| "truncated field")?;
+                    let value = &data[pos..end];
+                    pos = end;
+                    Field::Bytes(value)
+                }
+                FIXED64 => {
+                    pos += 8;
+                    Field::Fixed
+                }
+                FIXED32 => {
+                    pos += 4;
+                    Field::Fixed
+                }
+                wire_type => bail!("unsupported wire type {}", wire_type),
+            };
+            fields.push((field, value));
+        }
+        if pos > data.len() {
+            bail!("truncated field");
+        }
+        Ok(fields)
+    }
+
+    // The hashes are sent in the byte order of their usual hex encoding
+    // (i.e. reversed, like the Electrum protocol's script hashes)
+    fn reversed(hash: &[u8]) -> Vec<u8> {
+        hash.iter().rev().cloned().collect()
+    }
+
+    // Script hashes (field 1) and addresses (field 2) of a request
+    fn parse_scripts(data: &[u8], network: Network) -> Result<Vec<FullHash>> {
+        let mut script_hashes = vec![];
+        for (field, value) in parse_message(data)? {
+            match (field, value) {
+                (1, Field::Bytes(hash)) if hash.len() == 32 => {
+                    script_hashes.push(full_hash(&reversed(hash)))
+                }
+                (1, _) => bail!("invalid script hash"),
+                (2, Field::Bytes(address)) => {
+                    let address = std::str::from_utf8(address).chain_err(|| "invalid address")?;
+                    script_hashes.push(address_script_hash(address, network)?);
+                }
+                _ => (),
+            }
+        }
+        Ok(script_hashes)
+    }
+
+    fn single_script(data: &[u8], network: Network) -> Result<FullHash> {
+        match parse_scripts(data, network)?[..] {
+            [script_hash] => Ok(script_hash),
+            _ => bail!("expected a script_hash or an address"),
+        }
+    }
+
+    fn get_history(query: &Query, script_hash: &FullHash) -> Result<Message> {
+        let tip_height = query.get_best_header()?.height();
+        let mut reply = Message::default();
+        for entry in query.get_history(script_hash)? {
+            let mut item = Message::default();
+            item.bytes(1, &reversed(&entry.txid[..]));
+            item.int(2, entry.height);
+            item.uint(3, entry.fee.unwrap_or(0));
+            if entry.is_coinbase() {
+                item.uint(4, 1);
+                item.uint(5, is_mature(entry.height as usize, tip_height) as u64);
+            }
+            if let Some(ancestors) = entry.ancestors {
+                item.uint(6, ancestors.count as u64);
+                item.uint(7, ancestors.fee);
+                item.uint(8, ancestors.vsize);
+            }
+            reply.message(1, &item);
+        }
+        Ok(reply)
+    }
+
+    fn list_unspent(query: &Query, script_hash: &FullHash) -> Result<Message> {
+        let tip_height = query.get_best_header()?.height();
+        let mut reply = Message::default();
+        for (txo, value) in query.unspent(script_hash)? {
+            let mut item = Message::default();
+            item.bytes(1, &reversed(&txo.txid[..]));
+            item.uint(2, txo.vout as u64);
+            item.uint(3, txo.blockindex as u64);
+            item.uint(4, value);
+            if let Some(mature) = txo.maturity(tip_height) {
+                item.uint(5, 1);
+                item.uint(6, mature as u64);
+            }
+            reply.message(1, &item);
+        }
+        Ok(reply)
+    }
+
+    fn get_balance(query: &Query, script_hash: &FullHash) -> Result<Message> {
+        let (confirmed, unconfirmed) = query.get_balance(script_hash)?;
+        let mut reply = Message::default();
+        reply.uint(1, confirmed);
+        reply.int(2, unconfirmed);
+        Ok(reply)
+    }
+
+    fn activity_event(script_hash: &FullHash, status: &Option<FullHash>, height: usize) -> Message {
+        let mut event = Message::default();
+        event.bytes(1, &reversed(script_hash));
+        event.bytes(2, status.as_ref().map(|hash| &hash[..]).unwrap_or_default());
+        event.uint(3, height as u64);
+        event
+    }
+
+    // Header values are percent-encoded (e.g. grpc-message)
+    fn percent_encode(message: &str) -> String {
+        let mut encoded = String::new();
+        for byte in message.bytes().take(1000) {
+            match byte {
+                b' '..=b'~' if byte != b'%' => encoded.push(byte as char),
+                _ => encoded.push_str(&format!("%{:02X}", byte)),
+            }
+        }
+        encoded
+    }
+
+    enum Event {
+        Frame(Frame),
+        Closed(String),
+        Reply(u32, std::result::Result<Message, Status>), // of a unary call
+        Notify,                                           // new block or mempool transactions
+    }
+
+    //
+    // HTTP/2 stream of a call
+    //
+    struct Stream {
+        send_window: i64,
+        header_block: Vec<u8>, // until END_HEADERS
+        headers: HashMap<String, String>,
+        body: Vec<u8>,
+        started: bool,            // response headers sent
+        pending: Vec<u8>,         // response DATA waiting for the flow-control windows
+        trailers: Option<Status>, // sent after the pending DATA
+        subscription: Option<HashMap<FullHash, Option<FullHash>>>, // script hashes' statuses
+    }
+
+    //
+    // gRPC connection, handling its frames and replies from a single thread
+    //
+    struct Connection {
+        query: Arc<Query>,
+        settings: Arc<ServerSettings>,
+        rate_limiter: Arc<RateLimiter>,
+        addr: SocketAddr,
+        id: usize,
+        writer: TcpStream,
+        events: Sender<Event>, // for the replies of the unary calls
+        decoder: HeaderDecoder,
+        streams: HashMap<u32, Stream>,
+        continuation: Option<(u32, bool)>, // stream expecting CONTINUATION, and its END_STREAM
+        last_stream_id: u32,
+        send_window: i64,    // of the connection
+        initial_window: i64, // of the new streams
+        max_frame_size: usize,
+    }
+
+    impl Connection {
+        fn write(&mut self, frame: &[u8]) -> Result<()> {
+            self.writer
+                .write_all(frame)
+                .chain_err(|| "failed to write HTTP/2 frame")
+        }
+
+        fn run(mut self, receiver: Receiver<Event>) -> Result<()> {
+            let settings = [(
+                http2::SETTINGS_MAX_CONCURRENT_STREAMS,
+                MAX_CONCURRENT_STREAMS,
+            )];
+            self.write(&http2::settings_frame(&settings))?;
+            for event in receiver.iter() {
+                match event {
+                    Event::Frame(frame) => {
+                        if let Err(e) = self.handle_frame(frame) {
+                            let _ = self.write(&http2::goaway_frame(
+                                self.last_stream_id,
+                                http2::PROTOCOL_ERROR,
+                            ));
+                            return Err(e);
+                        }
+                    }
+                    Event::Closed(reason) => {
+                        debug!("[{}] gRPC peer #{} closed: {}", self.addr, self.id, reason);
+                        return Ok(());
+                    }
+                    Event::Reply(stream_id, reply) => {
+                        if self.streams.contains_key(&stream_id) {
+                            match reply {
+                                Ok(message) => {
+                                    self.send_message(stream_id, &message)?;
+                                    self.finish(stream_id, Status::new(OK, ""))?;
+                                }
+                                Err(status) => self.finish(stream_id, status)?,
+                            }
+                        }
+                    }
+                    Event::Notify => self.update_subscriptions()?,
+                }
+            }
+            Ok(())
+        }
+
+        fn handle_frame(&mut self, frame: Frame) -> Result<()> {
+            if let Some((stream_id, _)) = self.continuation {
+                if frame.kind != http2::CONTINUATION || frame.stream_id != stream_id {
+                    bail!("expected CONTINUATION of stream {}", stream_id);
+                }
+            }
+            match frame.kind {
+                http2::SETTINGS if !frame.has(http2::ACK) => {
+                    for (id, value) in frame.settings()? {
+                        match id {
+                            http2::SETTINGS_INITIAL_WINDOW_SIZE => {
+                                let delta = i64::from(value) - self.initial_window;
+                                for stream in self.streams.values_mut() {
+                                    stream.send_window += delta;
+                                }
+                                self.initial_window = i64::from(value);
+                            }
+                            http2::SETTINGS_MAX_FRAME_SIZE => {
+                                self.max_frame_size = value as usize;
+                            }
+                            _ => (),
+                        }
+                    }
+                    self.write(&Frame::encode(http2::SETTINGS, http2::ACK, 0, &[]))?;
+                    self.flush_all()?;
+                }
+                http2::PING if !frame.has(http2::ACK) => {
+                    self.write(&Frame::encode(http2::PING, http2::ACK, 0, &frame.payload))?;
+                }
+                http2::WINDOW_UPDATE => {
+                    let increment = frame.window_increment()?;
+                    match frame.stream_id {
+                        0 => http2::grow_window(&mut self.send_window, increment)?,
+                        stream_id => {
+                            if let Some(stream) = self.streams.get_mut(&stream_id) {
+                                http2::grow_window(&mut stream.send_window, increment)?;
+                            }
+                        }
+                    }
+                    self.flush_all()?;
+                }
+                http2::HEADERS => {
+                    let stream_id = frame.stream_id;
+                    if stream_id & 1 == 0 || stream_id <= self.last_stream_id {
+                        bail!("invalid stream {}", stream_id); // e.g. trailers from the client
+                    }
+                    self.last_stream_id = stream_id;
+                    let stream = Stream {
+                        send_window: self.initial_window,
+                        header_block: frame.content()?.to_vec(),
+                        headers: HashMap::new(),
+                        body: vec![],
+                        started: false,
+                        pending: vec![],
+                        trailers: None,
+                        subscription: None,
+                    };
+                    self.streams.insert(stream_id, stream);
+                    self.continuation = Some((stream_id, frame.has(http2::END_STREAM)));
+                    if frame.has(http2::END_HEADERS) {
+                        self.end_headers()?;
+                    }
+                }
+                http2::CONTINUATION => {
+                    let stream = self.streams.get_mut(&frame.stream_id);
+                    let stream = stream.chain_err(|| "unexpected CONTINUATION")?;
+                    stream.header_block.extend_from_slice(&frame.payload);
+                    if frame.has(http2::END_HEADERS) {
+                        self.end_headers()?;
+                    }
+                }
+                http2::DATA => {
+                    let size = frame.payload.len();
+                    if size > 0 {
+                        // the received data is consumed right away
+                        self.write(&http2::window_update_frame(0, size))?;
+                        self.write(&http2::window_update_frame(frame.stream_id, size))?;
+                    }
+                    let stream = match self.streams.get_mut(&frame.stream_id) {
+                        Some(stream) => stream,
+                        None => return Ok(()), // reset by us
+                    };
+                    stream.body.extend_from_slice(frame.content()?);
+                    if stream.body.len() > MAX_MESSAGE_SIZE + 5 {
+                        let status = Status::new(RESOURCE_EXHAUSTED, "request too large");
+                        return self.finish(frame.stream_id, status);
+                    }
+                    if frame.has(http2::END_STREAM) {
+                        self.handle_request(frame.stream_id)?;
+                    }
+                }
+                http2::RST_STREAM => {
+                    self.streams.remove(&frame.stream_id);
+                }
+                http2::GOAWAY => bail!("received GOAWAY"),
+                http2::PUSH_PROMISE => bail!("unexpected PUSH_PROMISE"),
+                _ => (), // e.g. PRIORITY, or the ACKs
+            }
+            Ok(())
+        }
+
+        fn end_headers(&mut self) -> Result<()> {
+            let (stream_id, end_stream) = self.continuation.take().unwrap();
+            let stream = self.streams.get_mut(&stream_id).unwrap();
+            // decoded even for a refused stream, to keep the HPACK table in sync
+            let headers = self.decoder.decode(&stream.header_block)?;
+            stream.headers = headers.into_iter().collect();
+            if self.streams.len() > MAX_CONCURRENT_STREAMS as usize {
+                self.streams.remove(&stream_id);
+                return self.write(&http2::rst_stream_frame(stream_id, http2::REFUSED_STREAM));
+            }
+            if end_stream {
+                self.handle_request(stream_id)?;
+            }
+            Ok(())
+        }
+
+        // The request of a stream is complete
+        fn handle_request(&mut self, stream_id: u32) -> Result<()> {
+            let stream = &self.streams[&stream_id];
+            let header = |name: &str| stream.headers.get(name).map(String::as_str);
+            let path = header(":path").unwrap_or_default().to_owned();
+            let content_type = header("content-type").unwrap_or_default();
+            if header(":method") != Some("POST") || !content_type.starts_with("application/grpc") {
+                let status = Status::new(INVALID_ARGUMENT, "expected a gRPC request");
+                return self.finish(stream_id, status);
+            }
+            if let Some(ref expected) = self.settings.auth_token {
+                let token = header("authorization").and_then(|value| value.strip_prefix("Bearer "));
+                if !token.is_some_and(|token| same_secret(token.as_bytes(), expected.as_bytes())) {
+                    let status = Status::new(UNAUTHENTICATED, "invalid or missing bearer token");
+                    return self.finish(stream_id, status);
+                }
+            }
+            if !self.rate_limiter.allow(self.addr.ip(), Instant::now()) {
+                let status = Status::new(RESOURCE_EXHAUSTED, "rate limit exceeded");
+                return self.finish(stream_id, status);
+            }
+            let message = match parse_body(&stream.body) {
+                Ok(message) => message.to_vec(),
+                Err(status) => return self.finish(stream_id, status),
+            };
+            let method = path.strip_prefix(SERVICE).unwrap_or_default();
+            let call: fn(&Query, &FullHash) -> Result<Message> = match method {
+                "GetHistory" => get_history,
+                "ListUnspent" => list_unspent,
+                "GetBalance" => get_balance,
+                "SubscribeActivity" => return self.subscribe(stream_id, &message),
+                _ => {
+                    let status = Status::new(UNIMPLEMENTED, &format!("unknown method {}", path));
+                    return self.finish(stream_id, status);
+                }
+            };
+            let script_hash = match single_script(&message, self.settings.network) {
+                Ok(script_hash) => script_hash,
+                Err(e) => {
+                    return self.finish(stream_id, Status::new(INVALID_ARGUMENT, &e.to_string()))
+                }
+            };
+            let mut span = trace::Span::start(&path);
+            span.set("rpc.connection", json!(self.id));
+            span.set("net.peer", json!(self.addr.to_string()));
+            span.set("scripthash", json!(reversed(&script_hash).to_hex()));
+            let (query, settings, events) = (
+                Arc::clone(&self.query),
+                Arc::clone(&self.settings),
+                self.events.clone(),
+            );
+            spawn_thread("grpc_call", move || {
+                let result = settings.query_pool.run(|| {
+                    let deadline = settings
+                        .query_timeout
+                        .map(|timeout| Instant::now() + timeout);
+                    span.enter(|| query::with_deadline(deadline, || call(&query, &script_hash)))
+                });
+                let reply = match result {
+                    Some(Ok(message)) => Ok(message),
+                    Some(Err(e)) => Err(Status::from_error(&e)),
+                    None => Err(Status::new(UNAVAILABLE, "server busy")),
+                };
+                span.finish(reply.as_ref().err().map(|status| status.message.clone()));
+                let _ = events.send(Event::Reply(stream_id, reply));
+            });
+            Ok(())
+        }
+
+        // Send the current statuses, then their changes, until the stream is reset
+        fn subscribe(&mut self, stream_id: u32, message: &[u8]) -> Result<()> {
+            let script_hashes = match parse_scripts(message, self.settings.network) {
+                Ok(script_hashes) if script_hashes.len() <= MAX_SUBSCRIBED_SCRIPTS => script_hashes,
+                Ok(_) => {
+                    let status = Status::new(RESOURCE_EXHAUSTED, "too many scripts");
+                    return self.finish(stream_id, status);
+                }
+                Err(e) => {
+                    return self.finish(stream_id, Status::new(INVALID_ARGUMENT, &e.to_string()))
+                }
+            };
+            let subscription = script_hashes.into_iter().map(|hash| (hash, None)).collect();
+            self.streams.get_mut(&stream_id).unwrap().subscription = Some(subscription);
+            self.update_subscription(stream_id, /*initial=*/ true)
+        }
+
+        fn update_subscriptions(&mut self) -> Result<()> {
+            let mut stream_ids: Vec<u32> = self
+                .streams
+                .iter()
+                .filter(|(_, stream)| stream.subscription.is_some())
+                .map(|(stream_id, _)| *stream_id)
+                .collect();
+            stream_ids.sort_unstable();
+            for stream_id in stream_ids {
+                self.update_subscription(stream_id, /*initial=*/ false)?;
+            }
+            Ok(())
+        }
+
+        fn update_subscription(&mut self, stream_id: u32, initial: bool) -> Result<()> {
+            let script_hashes: Vec<FullHash> = match self.streams[&stream_id].subscription {
+                Some(ref subscription) => subscription.keys().cloned().collect(),
+                None => return Ok(()),
+            };
+            let statuses = self
+                .query
+                .get_history_batch(&script_hashes)
+                .and_then(|statuses| Ok((statuses, self.query.get_best_header()?.height())));
+            let (statuses, height) = match statuses {
+                Ok(statuses) => statuses,
+                Err(e) => return self.finish(stream_id, Status::from_error(&e)),
+            };
+            let mut events = vec![];
+            let subscription = self
+                .streams
+                .get_mut(&stream_id)
+                .unwrap()
+                .subscription
+                .as_mut();
+            let subscription = subscription.unwrap();
+            for (script_hash, status) in script_hashes.iter().zip(statuses) {
+                let status = match status {
+                    Some((status, _)) => status,
+                    // it can't be tracked anymore
+                    None => {
+                        warn!("unsubscribed {}: too many transactions", hex::encode(script_hash));
+                        subscription.remove(script_hash);
+                        continue;
+                    }
+                };
+                if initial || subscription[script_hash] != status {
+                    events.push(activity_event(script_hash, &status, height));
+                    subscription.insert(*script_hash, status);
+                }
+            }
+            for event in events {
+                self.send_message(stream_id, &event)?;
+            }
+            Ok(())
+        }
+
+        fn send_message(&mut self, stream_id: u32, message: &Message) -> Result<()> {
+            let stream = self.streams.get_mut(&stream_id).unwrap();
+            let headers = if stream.started {
+                None
+            } else {
+                stream.started = true;
+                Some(http2::encode_headers(&[
+                    (":status", "200"),
+                    ("content-type", "application/grpc"),
+                ]))
+            };
+            stream.pending.push(0); // uncompressed
+            stream
+                .pending
+                .extend_from_slice(&(message.0.len() as u32).to_be_bytes());
+            stream.pending.extend_from_slice(&message.0);
+            if let Some(block) = headers {
+                self.write(&Frame::encode(
+                    http2::HEADERS,
+                    http2::END_HEADERS,
+                    stream_id,
+                    &block,
+                ))?;
+            }
+            self.flush(stream_id)
+        }
+
+        // End the call once its pending DATA is sent
+        fn finish(&mut self, stream_id: u32, status: Status) -> Result<()> {
+            let stream = self.streams.get_mut(&stream_id).unwrap();
+            stream.trailers = Some(status);
+            self.flush(stream_id)
+        }
+
+        fn flush_all(&mut self) -> Result<()> {
+            let mut stream_ids: Vec<u32> = self.streams.keys().cloned().collect();
+            stream_ids.sort_unstable();
+            for stream_id in stream_ids {
+                self.flush(stream_id)?;
+            }
+            Ok(())
+        }
+
+        // Send the pending DATA allowed by the flow-control windows, then the trailers
+        fn flush(&mut self, stream_id: u32) -> Result<()> {
+            let mut frames = vec![];
+            let stream = self.streams.get_mut(&stream_id).unwrap();
+            let mut sent = 0;
+            while sent < stream.pending.len() {
+                let window = self.send_window.min(stream.send_window);
+                let size = (stream.pending.len() - sent).min(self.max_frame_size);
+                let size = size.min(window.max(0) as usize);
+                if size == 0 {
+                    break;
+                }
+                let data = &stream.pending[sent..sent + size];
+                frames.push(Frame::encode(http2::DATA, 0, stream_id, data));
+                self.send_window -= size as i64;
+                stream.send_window -= size as i64;
+                sent += size;
+            }
+            stream.pending.drain(..sent);
+            let done = stream.pending.is_empty() && stream.trailers.is_some();
+            if done {
+                let status = stream.trailers.take().unwrap();
+                let code = status.code.to_string();
+                let message = percent_encode(&status.message);
+                let mut headers = vec![];
+                if !stream.started {
+                    // "Trailers-Only" response
+                    headers.push((":status", "200"));
+                    headers.push(("content-type", "application/grpc"));
+                }
+                headers.push(("grpc-status", &code));
+                if !message.is_empty() {
+                    headers.push(("grpc-message", &message));
+                }
+                let block = http2::encode_headers(&headers);
+                let flags = http2::END_HEADERS | http2::END_STREAM;
+                frames.push(Frame::encode(http2::HEADERS, flags, stream_id, &block));
+                self.streams.remove(&stream_id);
+            }
+            for frame in frames {
+                self.write(&frame)?;
+            }
+            Ok(())
+        }
+    }
+
+    // The (uncompressed) message of a request body
+    fn parse_body(body: &[u8]) -> std::result::Result<&[u8], Status> {
+        if body.len() < 5 {
+            return Err(Status::new(INVALID_ARGUMENT, "missing request message"));
+        }
+        if body[0] != 0 {
+            return Err(Status::new(
+                UNIMPLEMENTED,
+                "compressed messages are not supported",
+            ));
+        }
+        let size = u32::from_be_bytes([body[1], body[2], body[3], body[4]]) as usize;
+        if body.len() != 5 + size {
+            return Err(Status::new(
+                INVALID_ARGUMENT,
+                "expected a single request message",
+            ));
+        }
+        Ok(&body[5..])
+    }
+
+    fn read_frames(mut reader: TcpStream, events: Sender<Event>) {
+        let mut preface = [0u8; 24];
+        let reason = match reader.read_exact(&mut preface) {
+            Ok(()) if preface[..] == *http2::PREFACE => loop {
+                match Frame::read(&mut reader) {
+                    Ok(frame) => {
+                        if events.send(Event::Frame(frame)).is_err() {
+                            return;
+                        }
+                    }
+                    Err(e) => break e.to_string(),
+                }
+            },
+            Ok(()) => "invalid HTTP/2 preface (the client must use h2c prior knowledge)".to_owned(),
+            Err(e) => e.to_string(),
+        };
+        let _ = events.send(Event::Closed(reason));
+    }
+
+    //
+    // gRPC server, started with the RPC server (so once the initial sync is over)
+    //
+    pub struct Grpc {
+        connections: Arc<Mutex<Vec<Sender<Event>>>>,
+    }
+
+    impl Grpc {
+        pub fn start(
+            addr: SocketAddr,
+            query: Arc<Query>,
+            rate_limiter: Arc<RateLimiter>,
+            settings: Arc<ServerSettings>,
+        ) -> Result<Grpc> {
+            let listener = TcpListener::bind(addr)
+                .chain_err(|| format!("failed to start gRPC server at {}", addr))?;
+            info!("gRPC server running on {}", addr);
+            let connections = Arc::new(Mutex::new(vec![]));
+            let grpc = Grpc {
+                connections: Arc::clone(&connections),
+            };
+            let active = Arc::new(AtomicUsize::new(0));
+            spawn_thread("grpc", move || {
+                for (id, stream) in listener.incoming().enumerate() {
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            warn!("failed to accept gRPC connection: {}", e);
+                            continue;
+                        }
+                    };
+                    let max_connections = settings.max_connections;
+                    if max_connections > 0 && active.load(Ordering::SeqCst) >= max_connections {
+                        drop(stream); // the clients retry their calls on another connection
+                        continue;
+                    }
+                    let (sender, receiver) = mpsc::channel();
+                    connections.lock().unwrap().push(sender.clone());
+                    let (query, rate_limiter, settings, active) = (
+                        Arc::clone(&query),
+                        Arc::clone(&rate_limiter),
+                        Arc::clone(&settings),
+                        Arc::clone(&active),
+                    );
+                    spawn_thread("grpc_peer", move || {
+                        let addr = match stream.peer_addr() {
+                            Ok(addr) => addr,
+                            Err(_) => return,
+                        };
+                        let writer = match stream.try_clone() {
+                            Ok(writer) => writer,
+                            Err(e) => return warn!("[{}] failed to clone stream: {}", addr, e),
+                        };
+                        info!("[{}] connected gRPC peer #{}", addr, id);
+                        active.fetch_add(1, Ordering::SeqCst);
+                        let events = sender.clone();
+                        spawn_thread("grpc_reader", move || read_frames(stream, sender));
+                        let conn = Connection {
+                            query,
+                            settings,
+                            rate_limiter,
+                            addr,
+                            id,
+                            writer,
+                            events,
+                            decoder: HeaderDecoder::default(),
+                            streams: HashMap::new(),
+                            continuation: None,
+                            last_stream_id: 0,
+                            send_window: http2::DEFAULT_WINDOW_SIZE,
+                            initial_window: http2::DEFAULT_WINDOW_SIZE,
+                            max_frame_size: http2::DEFAULT_MAX_FRAME_SIZE,
+                        };
+                        let writer = conn.writer.try_clone();
+                        if let Err(e) = conn.run(receiver) {
+                            warn!("[{}] gRPC peer #{} failed: {}", addr, id, e);
+                        }
+                        if let Ok(writer) = writer {
+                            let _ = writer.shutdown(std::net::Shutdown::Both); // stops the reader
+                        }
+                        active.fetch_sub(1, Ordering::SeqCst);
+                        info!("[{}] disconnected gRPC peer #{}", addr, id);
+                    });
+                }
+            });
+            Ok(grpc)
+        }
+
+        // Notify the subscriptions of the new block or mempool transactions
+        pub fn notify(&self) {
+            let mut connections = self.connections.lock().unwrap();
+            connections.retain(|sender| sender.send(Event::Notify).is_ok());
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_protobuf() {
+            let mut item = Message::default();
+            item.bytes(1, &[0xab; 2]);
+            item.int(2, -1);
+            item.uint(3, 0); // skipped
+            let mut reply = Message::default();
+            reply.message(1, &item);
+            reply.message(1, &Message::default());
+            let mut expected = vec![0x0a, 15, 0x0a, 2, 0xab, 0xab, 0x10];
+            expected.extend_from_slice(&[0xff; 9]);
+            expected.push(0x01);
+            assert_eq!(item.0, expected[2..].to_vec());
+            expected.extend_from_slice(&[0x0a, 0]);
+            assert_eq!(reply.0, expected);
+
+            let fields = parse_message(&reply.0).unwrap();
+            assert_eq!(fields.len(), 2);
+            match fields[0] {
+                (1, Field::Bytes(item)) => match parse_message(item).unwrap()[1] {
+                    (2, Field::Varint(value)) => assert_eq!(value as i64, -1),
+                    _ => panic!("expected a varint"),
+                },
+                _ => panic!("expected a message"),
+            }
+            assert!(parse_message(&[0x0a, 5, 1]).is_err());
+
+            let network = Network::Bitcoin;
+            let mut request = Message::default();
+            request.bytes(1, &[1; 32]);
+            assert_eq!(single_script(&request.0, network).unwrap(), [1; 32]);
+            request.bytes(2, b"1BitcoinEaterAddressDontSendf59kuE");
+            assert_eq!(parse_scripts(&request.0, network).unwrap().len(), 2);
+            assert!(single_script(&request.0, network).is_err());
+            assert!(single_script(&[0x0a, 1, 0], network).is_err());
+
+            assert!(parse_body(&[0, 0, 0, 0, 1, 0x08]).is_ok());
+            assert_eq!(
+                parse_body(&[1, 0, 0, 0, 0]).err().unwrap().code,
+                UNIMPLEMENTED
+            );
+            assert_eq!(
+                parse_body(&[0, 0, 0, 0, 2, 0]).err().unwrap().code,
+                INVALID_ARGUMENT
+            );
+            assert_eq!(percent_encode("bad 100% \n"), "bad 100%25 %0A");
+        }
+    }
+}
+
+//
+// Placeholder when built without gRPC support
+//
+#[cfg(not(feature = "grpc"))]
+mod imp {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    use crate::errors::*;
+    use crate::query::Query;
+    use crate::rpc::{RateLimiter, ServerSettings};
+
+    pub struct Grpc {}
+
+    impl Grpc {
+        pub fn start(
+            _addr: SocketAddr,
+            _query: Arc<Query>,
+            _rate_limiter: Arc<RateLimiter>,
+            _settings: Arc<ServerSettings>,
+        ) -> Result<Grpc> {
+            bail!("gRPC support requires building addrindexrs with `--features grpc`")
+        }
+
+        pub fn notify(&self) {}
+    }
+}
+
+pub use self::imp::Grpc;
@@ -0,0 +1,780 @@
+use std::collections::VecDeque;
+use std::io::Read;
+use std::sync::OnceLock;
+
+use crate::errors::*;
+
+//
+// Minimal HTTP/2 framing and HPACK header compression (RFC 7540 and
+// 7541), enough to serve gRPC over cleartext connections (h2c with prior
+// knowledge). There is no server push, and the priorities are ignored.
+//
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+pub const DEFAULT_WINDOW_SIZE: i64 = 65_535;
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16_384;
+const MAX_WINDOW_SIZE: i64 = (1 << 31) - 1;
+
+// Frame types
+pub const DATA: u8 = 0x0;
+pub const HEADERS: u8 = 0x1;
+pub const RST_STREAM: u8 = 0x3;
+pub const SETTINGS: u8 = 0x4;
+pub const PUSH_PROMISE: u8 = 0x5;
+pub const PING: u8 = 0x6;
+pub const GOAWAY: u8 = 0x7;
+pub const WINDOW_UPDATE: u8 = 0x8;
+pub const CONTINUATION: u8 = 0x9;
+
+// Frame flags
+pub const END_STREAM: u8 = 0x1;
+pub const ACK: u8 = 0x1;
+pub const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY: u8 = 0x20;
+
+// Settings
+pub const SETTINGS_HEADER_TABLE_SIZE: u16 = 0x1;
+pub const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+pub const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+pub const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+
+// Error codes (of RST_STREAM and GOAWAY)
+pub const NO_ERROR: u32 = 0x0;
+pub const PROTOCOL_ERROR: u32 = 0x1;
+pub const FLOW_CONTROL_ERROR: u32 = 0x3;
+pub const FRAME_SIZE_ERROR: u32 = 0x6;
+pub const REFUSED_STREAM: u32 = 0x7;
+pub const COMPRESSION_ERROR: u32 = 0x9;
+
+pub struct Frame {
+    pub kind: u8,
+    pub flags: u8,
+    pub stream_id: u32,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    // Fails on EOF, or on frames larger than our SETTINGS_MAX_FRAME_SIZE
+    pub fn read(reader: &mut dyn Read) -> Result<Frame> {
+        let mut header = [0u8; 9];
+        reader
+            .read_exact(&mut header)
+            .chain_err(|| "failed to read HTTP/2 frame")?;
+        let size = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+        if size > DEFAULT_MAX_FRAME_SIZE {
+            bail!("HTTP/2 frame too large: {} bytes", size);
+        }
+        let mut payload = vec![0u8; size];
+        reader
+            .read_exact(&mut payload)
+            .chain_err(|| "failed to read HTTP/2 frame payload")?;
+        Ok(Frame {
+            kind: header[3],
+            flags: header[4],
+            stream_id: u32::from_be_bytes([header[5], header[6], header[7], header[8]])
+                & 0x7fff_ffff,
+            payload,
+        })
+    }
+
+    pub fn encode(kind: u8, flags: u8, stream_id: u32, payload: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(9 + payload.len());
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+        frame.push(kind);
+        frame.push(flags);
+        frame.extend_from_slice(&stream_id.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    pub fn has(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    // Payload of a DATA or HEADERS frame, without its padding (and priority)
+    pub fn content(&self) -> Result<&[u8]> {
+        let mut content = &self.payload[..];
+        let mut padding = 0;
+        if self.has(PADDED) {
+            padding = *content
+                .first()
+                .chain_err(|| "missing HTTP/2 padding length")? as usize;
+            content = &content[1..];
+        }
+        if self.kind == HEADERS && self.has(PRIORITY) {
+            content = content.get(5..).chain_err(|| "truncated HTTP/2 priority")?;
+        }
+        if padding > content.len() {
+            bail!("HTTP/2 padding exceeds the frame");
+        }
+        Ok(&content[..content.len() - padding])
+    }
+
+    // (identifier, value) pairs of a SETTINGS frame
+    pub fn settings(&self) -> Result<Vec<(u16, u32)>> {
+        let settings = self.payload.chunks_exact(6);
+        if !settings.remainder().is_empty() {
+            bail!("invalid HTTP/2 SETTINGS size: {}", self.payload.len());
+        }
+        Ok(settings
+            .map(|s| {
+                let id = u16::from_be_bytes([s[0], s[1]]);
+                (id, u32::from_be_bytes([s[2], s[3], s[4], s[5]]))
+            })
+            .collect())
+    }
+
+    pub fn window_increment(&self) -> Result<i64> {
+        let payload = self
+            .payload
+            .get(..4)
+            .chain_err(|| "truncated WINDOW_UPDATE")?;
+        let increment = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
+        Ok(i64::from(increment & 0x7fff_ffff))
+    }
+}
+
+pub fn settings_frame(settings: &[(u16, u32)]) -> Vec<u8> {
+    let mut payload = vec![];
+    for (id, value) in settings {
+        payload.extend_from_slice(&id.to_be_bytes());
+        payload.extend_from_slice(&value.to_be_bytes());
+    }
+    Frame::encode(SETTINGS, 0, 0, &payload)
+}
+
+pub fn window_update_frame(stream_id: u32, increment: usize) -> Vec<u8> {
+    Frame::encode(
+        WINDOW_UPDATE,
+        0,
+        stream_id,
+        &(increment as u32).to_be_bytes(),
+    )
+}
+
+pub fn rst_stream_frame(stream_id: u32, error_code: u32) -> Vec<u8> {
+    Frame::encode(RST_STREAM, 0, stream_id, &error_code.to_be_bytes())
+}
+
+pub fn goaway_frame(last_stream_id: u32, error_code: u32) -> Vec<u8> {
+    let mut payload = last_stream_id.to_be_bytes().to_vec();
+    payload.extend_from_slice(&error_code.to_be_bytes());
+    Frame::encode(GOAWAY, 0, 0, &payload)
+}
+
+// Add a WINDOW_UPDATE increment to a flow-control window
+pub fn grow_window(window: &mut i64, increment: i64) -> Result<()> {
+    if increment == 0 || *window + increment > MAX_WINDOW_SIZE {
+        bail!("invalid HTTP/2 window increment {}", increment);
+    }
+    *window += increment;
+    Ok(())
+}
+
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+// Size of the dynamic table we allow (the SETTINGS_HEADER_TABLE_SIZE default)
+const HEADER_TABLE_SIZE: usize = 4096;
+
+pub type Header = (String, String);
+
+//
+// HPACK decoder of a connection's header blocks (with its dynamic table)
+//
+pub struct HeaderDecoder {
+    dynamic: VecDeque<Header>, // newest first
+    size: usize,
+    max_size: usize,
+}
+
+impl Default for HeaderDecoder {
+    fn default() -> Self {
+        HeaderDecoder {
+            dynamic: VecDeque::new(),
+            size: 0,
+            max_size: HEADER_TABLE_SIZE,
+        }
+    }
+}
+
+impl HeaderDecoder {
+    pub fn decode(&mut self, block: &[u8]) -> Result<Vec<Header>> {
+        let mut headers = vec![];
+        let mut pos = 0;
+        while pos < block.len() {
+            let byte = block[pos];
+            if byte & 0x80 != 0 {
+                // indexed header field
+                let index = decode_integer(block, &mut pos, 7)?;
+                headers.push(self.get(index)?);
+            } else if byte & 0xc0 == 0x40 {
+                // literal with incremental indexing
+                let header = self.decode_literal(block, &mut pos, 6)?;
+                self.insert(header.clone());
+                headers.push(header);
+            } else if byte & 0xe0 == 0x20 {
+                // dynamic table size update
+                let max_size = decode_integer(block, &mut pos, 5)?;
+                if max_size > HEADER_TABLE_SIZE {
+                    bail!(
+                        "HPACK table size {} exceeds {}",
+                        max_size,
+                        HEADER_TABLE_SIZE
+                    );
+                }
+                self.max_size = max_size;
+                self.evict(0);
+            } else {
+                // literal without indexing, or never indexed
+                headers.push(self.decode_literal(block, &mut pos, 4)?);
+            }
+        }
+        Ok(headers)
+    }
+
+    fn get(&self, index: usize) -> Result<Header> {
+        match index {
+            0 => bail!("invalid HPACK index 0"),
+            1..=61 => {
+                let (name, value) = STATIC_TABLE[index - 1];
+                Ok((name.to_owned(), value.to_owned()))
+            }
+            _ => self
+                .dynamic
+                .get(index - 62)
+                .cloned()
+                .chain_err(|| format!("invalid HPACK index {}", index)),
+        }
+    }
+
+    fn decode_literal(&self, block: &[u8], pos: &mut usize, prefix: u8) -> Result<Header> {
+        let name = match decode_integer(block, pos, prefix)? {
+            0 => decode_string(block, pos)?,
+            index => self.get(index)?.0,
+        };
+        Ok((name, decode_string(block, pos)?))
+    }
+
+    fn insert(&mut self, header: Header) {
+        let size = header_size(&header);
+        self.evict(size);
+        if size <= self.max_size {
+            self.size += size;
+            self.dynamic.push_front(header);
+        }
+    }
+
+    // Make room for `size` bytes (the table is emptied by a larger entry)
+    fn evict(&mut self, size: usize) {
+        while self.size + size > self.max_size {
+            match self.dynamic.pop_back() {
+                Some(header) => self.size -= header_size(&header),
+                None => break,
+            }
+        }
+    }
+}
+
+fn header_size(header: &Header) -> usize {
+    header.0.len() + header.1.len() + 32
+}
+
+fn decode_integer(block: &[u8], pos: &mut usize, prefix: u8) -> Result<usize> {
+    let max_prefix = (1usize << prefix) - 1;
+    let first = *block.get(*pos).chain_err(|| "truncated HPACK integer")? as usize;
+    *pos += 1;
+    let mut value = first & max_prefix;
+    if value < max_prefix {
+        return Ok(value);
+    }
+    for shift in (0..28).step_by(7) {
+        let byte = *block.get(*pos).chain_err(|| "truncated HPACK integer")? as usize;
+        *pos += 1;
+        value += (byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    bail!("HPACK integer too large")
+}
+
+fn decode_string(block: &[u8], pos: &mut usize) -> Result<String> {
+    let huffman = block.get(*pos).chain_err(|| "truncated HPACK string")? & 0x80 != 0;
+    let size = decode_integer(block, pos, 7)?;
+    let data = block
+        .get(*pos..*pos + size)
+        .chain_err(|| "truncated HPACK string")?;
+    *pos += size;
+    let data = match huffman {
+        true => huffman_decode(data)?,
+        false => data.to_vec(),
+    };
+    String::from_utf8(data).chain_err(|| "non-UTF8 HTTP/2 header")
+}
+
+fn encode_integer(out: &mut Vec<u8>, flags: u8, prefix: u8, mut value: usize) {
+    let max_prefix = (1usize << prefix) - 1;
+    if value < max_prefix {
+        out.push(flags | value as u8);
+        return;
+    }
+    out.push(flags | max_prefix as u8);
+    value -= max_prefix;
+    while value >= 0x80 {
+        out.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+// Header block of literals without indexing (so the peer's table is unused),
+// except for the ":status: 200" of the static table
+pub fn encode_headers(headers: &[(&str, &str)]) -> Vec<u8> {
+    let mut block = vec![];
+    for (name, value) in headers {
+        if (*name, *value) == (":status", "200") {
+            block.push(0x88);
+            continue;
+        }
+        block.push(0);
+        for string in &[name, value] {
+            encode_integer(&mut block, 0, 7, string.len());
+            block.extend_from_slice(string.as_bytes());
+        }
+    }
+    block
+}
+
+// (code, bits) of each symbol, and of EOS (256)
+const HUFFMAN_CODES: [(u32, u8); 257] = [
+    (0x1ff8, 13),
+    (0x7fffd8, 23),
+    (0xfffffe2, 28),
+    (0xfffffe3, 28),
+    (0xfffffe4, 28),
+    (0xfffffe5, 28),
+    (0xfffffe6, 28),
+    (0xfffffe7, 28),
+    (0xfffffe8, 28),
+    (0xffffea, 24),
+    (0x3ffffffc, 30),
+    (0xfffffe9, 28),
+    (0xfffffea, 28),
+    (0x3ffffffd, 30),
+    (0xfffffeb, 28),
+    (0xfffffec, 28),
+    (0xfffffed, 28),
+    (0xfffffee, 28),
+    (0xfffffef, 28),
+    (0xffffff0, 28),
+    (0xffffff1, 28),
+    (0xffffff2, 28),
+    (0x3ffffffe, 30),
+    (0xffffff3, 28),
+    (0xffffff4, 28),
+    (0xffffff5, 28),
+    (0xffffff6, 28),
+    (0xffffff7, 28),
+    (0xffffff8, 28),
+    (0xffffff9, 28),
+    (0xffffffa, 28),
+    (0xffffffb, 28),
+    (0x14, 6),
+    (0x3f8, 10),
+    (0x3f9, 10),
+    (0xffa, 12),
+    (0x1ff9, 13),
+    (0x15, 6),
+    (0xf8, 8),
+    (0x7fa, 11),
+    (0x3fa, 10),
+    (0x3fb, 10),
+    (0xf9, 8),
+    (0x7fb, 11),
+    (0xfa, 8),
+    (0x16, 6),
+    (0x17, 6),
+    (0x18, 6),
+    (0x0, 5),
+    (0x1, 5),
+    (0x2, 5),
+    (0x19, 6),
+    (0x1a, 6),
+    (0x1b, 6),
+    (0x1c, 6),
+    (0x1d, 6),
+    (0x1e, 6),
+    (0x1f, 6),
+    (0x5c, 7),
+    (0xfb, 8),
+    (0x7ffc, 15),
+    (0x20, 6),
+    (0xffb, 12),
+    (0x3fc, 10),
+    (0x1ffa, 13),
+    (0x21, 6),
+    (0x5d, 7),
+    (0x5e, 7),
+    (0x5f, 7),
+    (0x60, 7),
+    (0x61, 7),
+    (0x62, 7),
+    (0x63, 7),
+    (0x64, 7),
+    (0x65, 7),
+    (0x66, 7),
+    (0x67, 7),
+    (0x68, 7),
+    (0x69, 7),
+    (0x6a, 7),
+    (0x6b, 7),
+    (0x6c, 7),
+    (0x6d, 7),
+    (0x6e, 7),
+    (0x6f, 7),
+    (0x70, 7),
+    (0x71, 7),
+    (0x72, 7),
+    (0xfc, 8),
+    (0x73, 7),
+    (0xfd, 8),
+    (0x1ffb, 13),
+    (0x7fff0, 19),
+    (0x1ffc, 13),
+    (0x3ffc, 14),
+    (0x22, 6),
+    (0x7ffd, 15),
+    (0x3, 5),
+    (0x23, 6),
+    (0x4, 5),
+    (0x24, 6),
+    (0x5, 5),
+    (0x25, 6),
+    (0x26, 6),
+    (0x27, 6),
+    (0x6, 5),
+    (0x74, 7),
+    (0x75, 7),
+    (0x28, 6),
+    (0x29, 6),
+    (0x2a, 6),
+    (0x7, 5),
+    (0x2b, 6),
+    (0x76, 7),
+    (0x2c, 6),
+    (0x8, 5),
+    (0x9, 5),
+    (0x2d, 6),
+    (0x77, 7),
+    (0x78, 7),
+    (0x79, 7),
+    (0x7a, 7),
+    (0x7b, 7),
+    (0x7ffe, 15),
+    (0x7fc, 11),
+    (0x3ffd, 14),
+    (0x1ffd, 13),
+    (0xffffffc, 28),
+    (0xfffe6, 20),
+    (0x3fffd2, 22),
+    (0xfffe7, 20),
+    (0xfffe8, 20),
+    (0x3fffd3, 22),
+    (0x3fffd4, 22),
+    (0x3fffd5, 22),
+    (0x7fffd9, 23),
+    (0x3fffd6, 22),
+    (0x7fffda, 23),
+    (0x7fffdb, 23),
+    (0x7fffdc, 23),
+    (0x7fffdd, 23),
+    (0x7fffde, 23),
+    (0xffffeb, 24),
+    (0x7fffdf, 23),
+    (0xffffec, 24),
+    (0xffffed, 24),
+    (0x3fffd7, 22),
+    (0x7fffe0, 23),
+    (0xffffee, 24),
+    (0x7fffe1, 23),
+    (0x7fffe2, 23),
+    (0x7fffe3, 23),
+    (0x7fffe4, 23),
+    (0x1fffdc, 21),
+    (0x3fffd8, 22),
+    (0x7fffe5, 23),
+    (0x3fffd9, 22),
+    (0x7fffe6, 23),
+    (0x7fffe7, 23),
+    (0xffffef, 24),
+    (0x3fffda, 22),
+    (0x1fffdd, 21),
+    (0xfffe9, 20),
+    (0x3fffdb, 22),
+    (0x3fffdc, 22),
+    (0x7fffe8, 23),
+    (0x7fffe9, 23),
+    (0x1fffde, 21),
+    (0x7fffea, 23),
+    (0x3fffdd, 22),
+    (0x3fffde, 22),
+    (0xfffff0, 24),
+    (0x1fffdf, 21),
+    (0x3fffdf, 22),
+    (0x7fffeb, 23),
+    (0x7fffec, 23),
+    (0x1fffe0, 21),
+    (0x1fffe1, 21),
+    (0x3fffe0, 22),
+    (0x1fffe2, 21),
+    (0x7fffed, 23),
+    (0x3fffe1, 22),
+    (0x7fffee, 23),
+    (0x7fffef, 23),
+    (0xfffea, 20),
+    (0x3fffe2, 22),
+    (0x3fffe3, 22),
+    (0x3fffe4, 22),
+    (0x7ffff0, 23),
+    (0x3fffe5, 22),
+    (0x3fffe6, 22),
+    (0x7ffff1, 23),
+    (0x3ffffe0, 26),
+    (0x3ffffe1, 26),
+    (0xfffeb, 20),
+    (0x7fff1, 19),
+    (0x3fffe7, 22),
+    (0x7ffff2, 23),
+    (0x3fffe8, 22),
+    (0x1ffffec, 25),
+    (0x3ffffe2, 26),
+    (0x3ffffe3, 26),
+    (0x3ffffe4, 26),
+    (0x7ffffde, 27),
+    (0x7ffffdf, 27),
+    (0x3ffffe5, 26),
+    (0xfffff1, 24),
+    (0x1ffffed, 25),
+    (0x7fff2, 19),
+    (0x1fffe3, 21),
+    (0x3ffffe6, 26),
+    (0x7ffffe0, 27),
+    (0x7ffffe1, 27),
+    (0x3ffffe7, 26),
+    (0x7ffffe2, 27),
+    (0xfffff2, 24),
+    (0x1fffe4, 21),
+    (0x1fffe5, 21),
+    (0x3ffffe8, 26),
+    (0x3ffffe9, 26),
+    (0xffffffd, 28),
+    (0x7ffffe3, 27),
+    (0x7ffffe4, 27),
+    (0x7ffffe5, 27),
+    (0xfffec, 20),
+    (0xfffff3, 24),
+    (0xfffed, 20),
+    (0x1fffe6, 21),
+    (0x3fffe9, 22),
+    (0x1fffe7, 21),
+    (0x1fffe8, 21),
+    (0x7ffff3, 23),
+    (0x3fffea, 22),
+    (0x3fffeb, 22),
+    (0x1ffffee, 25),
+    (0x1ffffef, 25),
+    (0xfffff4, 24),
+    (0xfffff5, 24),
+    (0x3ffffea, 26),
+    (0x7ffff4, 23),
+    (0x3ffffeb, 26),
+    (0x7ffffe6, 27),
+    (0x3ffffec, 26),
+    (0x3ffffed, 26),
+    (0x7ffffe7, 27),
+    (0x7ffffe8, 27),
+    (0x7ffffe9, 27),
+    (0x7ffffea, 27),
+    (0x7ffffeb, 27),
+    (0xffffffe, 28),
+    (0x7ffffec, 27),
+    (0x7ffffed, 27),
+    (0x7ffffee, 27),
+    (0x7ffffef, 27),
+    (0x7fffff0, 27),
+    (0x3ffffee, 26),
+    (0x3fffffff, 30),
+];
+
+const EOS: u16 = 256;
+
+// Binary tree of the Huffman codes: the children of each branch (leaves are
+// encoded as 0x8000 | symbol)
+fn huffman_tree() -> &'static Vec<[u16; 2]> {
+    static TREE: OnceLock<Vec<[u16; 2]>> = OnceLock::new();
+    TREE.get_or_init(|| {
+        let mut tree = vec![[0u16; 2]];
+        for (symbol, (code, bits)) in HUFFMAN_CODES.iter().enumerate() {
+            let mut node = 0;
+            for i in (0..*bits).rev() {
+                let bit = ((code >> i) & 1) as usize;
+                if i == 0 {
+                    tree[node][bit] = 0x8000 | symbol as u16;
+                } else {
+                    if tree[node][bit] == 0 {
+                        tree.push([0, 0]);
+                        tree[node][bit] = (tree.len() - 1) as u16;
+                    }
+                    node = tree[node][bit] as usize;
+                }
+            }
+        }
+        tree
+    })
+}
+
+pub fn huffman_decode(data: &[u8]) -> Result<Vec<u8>> {
+    let tree = huffman_tree();
+    let mut decoded = vec![];
+    let mut node = 0;
+    let mut depth = 0; // bits since the last symbol
+    let mut all_ones = true; // the padding is a prefix of EOS
+    for byte in data {
+        for i in (0..8).rev() {
+            let bit = ((byte >> i) & 1) as usize;
+            all_ones &= bit == 1;
+            depth += 1;
+            let next = tree[node][bit];
+            if next & 0x8000 == 0 {
+                node = next as usize;
+                continue;
+            }
+            let symbol = next & 0x7fff;
+            if symbol == EOS {
+                bail!("invalid Huffman string: EOS symbol");
+            }
+            decoded.push(symbol as u8);
+            node = 0;
+            depth = 0;
+            all_ones = true;
+        }
+    }
+    if depth >= 8 || !all_ones {
+        bail!("invalid Huffman string padding");
+    }
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hpack() {
+        // RFC 7541, C.4: requests with Huffman encoding
+        let blocks = [
+            "828684418cf1e3c2e5f23a6ba0ab90f4ff",
+            "828684be5886a8eb10649cbf",
+            "828785bf408825a849e95ba97d7f8925a849e95bb8e8b4bf",
+        ];
+        let mut decoder = HeaderDecoder::default();
+        let headers: Vec<Vec<Header>> = blocks
+            .iter()
+            .map(|block| decoder.decode(&hex::decode(block).unwrap()).unwrap())
+            .collect();
+        let header = |name: &str, value: &str| (name.to_owned(), value.to_owned());
+        assert_eq!(
+            headers[0],
+            vec![
+                header(":method", "GET"),
+                header(":scheme", "http"),
+                header(":path", "/"),
+                header(":authority", "www.example.com"),
+            ]
+        );
+        assert_eq!(headers[1][4], header("cache-control", "no-cache"));
+        assert_eq!(headers[2][1], header(":scheme", "https"));
+        assert_eq!(headers[2][3], header(":authority", "www.example.com"));
+        assert_eq!(headers[2][4], header("custom-key", "custom-value"));
+        assert_eq!(decoder.size, 164);
+
+        assert!(huffman_decode(&[0xff, 0xff, 0xff, 0xff]).is_err()); // EOS
+        assert!(huffman_decode(&[
+            0xf1, 0xe3, 0xc2, 0xe5, 0xf2, 0x3a, 0x6b, 0xa0, 0xab, 0x90, 0xf4
+        ])
+        .is_err()); // truncated padding
+
+        let block = encode_headers(&[(":status", "200"), ("grpc-status", "0")]);
+        let decoded = HeaderDecoder::default().decode(&block).unwrap();
+        assert_eq!(
+            decoded,
+            vec![header(":status", "200"), header("grpc-status", "0")]
+        );
+        let mut block = vec![];
+        encode_integer(&mut block, 0, 5, 1337);
+        assert_eq!(block, vec![0x1f, 0x9a, 0x0a]); // RFC 7541, C.1.2
+        assert_eq!(decode_integer(&block, &mut 0, 5).unwrap(), 1337);
+    }
+}
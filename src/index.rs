@@ -1,23 +1,60 @@
 use bincode;
 use bitcoin::blockdata::block::{Block, BlockHeader};
+use bitcoin::blockdata::opcodes;
+use bitcoin::blockdata::script::{Instruction, Script};
 use bitcoin::blockdata::transaction::{Transaction, TxIn, TxOut};
 use bitcoin::consensus::encode::{deserialize, serialize};
+use bitcoin::network::constants::Network;
+use bitcoin::util::address::Address;
 use bitcoin::util::hash::BitcoinHash;
+use bitcoin::util::key::PublicKey;
+use bitcoin_hashes::hex::FromHex;
 use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use bitcoin_hashes::Hash;
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
-use std::collections::HashSet;
-use std::sync::RwLock;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 use crate::daemon::Daemon;
 use crate::errors::*;
+use crate::metrics::{Counter, Gauge, Metrics};
+use crate::progress::Progress;
 use crate::signal::Waiter;
-use crate::store::{ReadStore, Row, WriteStore};
+use crate::store::{DBStore, ReadStore, Row, WriteStore};
 use crate::util::{
     full_hash, hash_prefix, spawn_thread, Bytes,
     FullHash, HashPrefix, HeaderEntry, HeaderList,
     HeaderMap, SyncChannel, HASH_PREFIX_LEN,
 };
+use crate::watch::Watchlist;
+
+//
+// Number of a transaction: its block height (the high 24 bits) and its
+// position in the block (the low 24 bits), in big-endian so that the rows
+// referencing it are sorted by height. Mempool transactions are numbered
+// above `MEMPOOL_HEIGHT`.
+//
+pub type TxNum = [u8; 6];
+
+pub const MEMPOOL_HEIGHT: usize = 0xFF_FFFF;
+
+pub fn tx_num(height: usize, position: usize) -> TxNum {
+    let value = ((height as u64) << 24) | position as u64;
+    let bytes = value.to_be_bytes();
+    [bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7]]
+}
+
+pub fn tx_num_height(num: &TxNum) -> usize {
+    u32::from_be_bytes([0, num[0], num[1], num[2]]) as usize
+}
+
+pub fn tx_num_position(num: &TxNum) -> usize {
+    u32::from_be_bytes([0, num[3], num[4], num[5]]) as usize
+}
 
 //
 // Key of a row storing an input of a transaction
@@ -35,18 +72,18 @@ pub struct TxInKey {
 #[derive(Serialize, Deserialize)]
 pub struct TxInRow {
     key: TxInKey,
-    pub txid_prefix: HashPrefix,
+    pub tx_num: TxNum,
 }
 
 impl TxInRow {
-    pub fn new(txid: &Sha256dHash, input: &TxIn) -> TxInRow {
+    pub fn new(tx_num: TxNum, input: &TxIn) -> TxInRow {
         TxInRow {
             key: TxInKey {
                 code: b'I',
                 prev_txid_prefix: hash_prefix(&input.previous_output.txid[..]),
                 prev_vout: input.previous_output.vout as u16,
             },
-            txid_prefix: hash_prefix(&txid[..]),
+            tx_num,
         }
     }
 
@@ -86,18 +123,24 @@ pub struct TxOutKey {
 #[derive(Serialize, Deserialize)]
 pub struct TxOutRow {
     key: TxOutKey,
-    pub txid_prefix: HashPrefix,
+    pub tx_num: TxNum,
     pub vout: u16,
 }
 
 impl TxOutRow {
-    pub fn new(txid: &Sha256dHash, vout: u32, output: &TxOut) -> TxOutRow {
+    pub fn new(tx_num: TxNum, vout: u32, output: &TxOut) -> TxOutRow {
+        let script_hash = compute_script_hash(&output.script_pubkey[..]);
+        TxOutRow::with_script_hash(tx_num, vout, &script_hash)
+    }
+
+    // Row of an output, indexed under another script hash than its own
+    pub fn with_script_hash(tx_num: TxNum, vout: u32, script_hash: &FullHash) -> TxOutRow {
         TxOutRow {
             key: TxOutKey {
                 code: b'O',
-                script_hash_prefix: hash_prefix(&compute_script_hash(&output.script_pubkey[..])),
+                script_hash_prefix: hash_prefix(&script_hash[..]),
             },
-            txid_prefix: hash_prefix(&txid[..]),
+            tx_num,
             vout: vout as u16,
         }
     }
@@ -128,52 +171,58 @@ impl TxOutRow {
 #[derive(Serialize, Deserialize)]
 pub struct TxKey {
     code: u8,
-    pub txid: FullHash,
+    pub tx_num: TxNum,
 }
 
 //
-// Row storing a transaction
+// Row storing the txid of a transaction, by its number
 //
 #[derive(Serialize, Deserialize)]
 pub struct TxRow {
     pub key: TxKey,
-    pub block_hash: FullHash
+    pub txid: FullHash,
 }
 
 impl TxRow {
-    pub fn new(txid: &Sha256dHash, blockhash: &Sha256dHash) -> TxRow {
+    pub fn new(tx_num: TxNum, txid: &Sha256dHash) -> TxRow {
         TxRow {
             key: TxKey {
                 code: b'T',
-                txid: full_hash(&txid[..]),             
+                tx_num,
             },
-            block_hash: full_hash(&blockhash)
+            txid: full_hash(&txid[..]),
         }
     }
 
-    pub fn filter_prefix(txid_prefix: HashPrefix) -> Bytes {
-        [b"T", &txid_prefix[..]].concat()
-    }
-
-    pub fn filter_full(txid: &Sha256dHash) -> Bytes {
-        [b"T", &txid[..]].concat()
+    pub fn key(tx_num: TxNum) -> Bytes {
+        bincode::serialize(&TxKey {
+            code: b'T',
+            tx_num,
+        })
+        .unwrap()
     }
 
     pub fn to_row(&self) -> Row {
         Row {
             key: bincode::serialize(&self.key).unwrap(),
-            value: bincode::serialize(&self.block_hash).unwrap(),
+            value: self.txid.to_vec(),
         }
     }
 
     pub fn from_row(row: &Row) -> TxRow {
         TxRow {
-            key:bincode::deserialize(&row.key).expect("failed to parse TxRow"),
-            block_hash:bincode::deserialize(&row.value).expect("failed to parse TxRow")
+            key: bincode::deserialize(&row.key).expect("failed to parse TxRow"),
+            txid: full_hash(&row.value),
         }
     }
 }
 
+// The txid of a transaction number, if it is indexed
+pub fn lookup_txid(store: &dyn ReadStore, tx_num: TxNum) -> Option<Sha256dHash> {
+    let value = store.get(&TxRow::key(tx_num))?;
+    Some(deserialize(&value).expect("failed to parse txid"))
+}
+
 //
 // Key of a row storing a block
 //
@@ -194,10 +243,66 @@ pub fn compute_script_hash(data: &[u8]) -> FullHash {
     hash
 }
 
+//
+// Compute the script hash of an address, making sure it belongs to `network`
+//
+pub fn address_script_hash(address: &str, network: Network) -> Result<FullHash> {
+    let address = Address::from_str(address).chain_err(|| format!("invalid address {}", address))?;
+    // base58 regtest addresses can't be told apart from testnet ones
+    let valid = match network {
+        Network::Bitcoin => address.network == Network::Bitcoin,
+        _ => address.network != Network::Bitcoin,
+    };
+    if !valid {
+        bail!("address {} doesn't belong to {} network", address, network);
+    }
+    Ok(compute_script_hash(&address.script_pubkey()[..]))
+}
+
+// The script hash of an Electrum script hash (in its hex encoding), or else of an address
+pub fn parse_script_hash(entry: &str, network: Network) -> Result<FullHash> {
+    if entry.len() == 64 {
+        if let Ok(script_hash) = Sha256dHash::from_hex(entry) {
+            return Ok(full_hash(&script_hash.into_inner()[..]));
+        }
+    }
+    address_script_hash(entry, network)
+}
+
+// Whether the outputs paying to public keys are also indexed under their P2PKH script hashes
+static INDEX_PUBKEYS: AtomicBool = AtomicBool::new(false);
+
+pub fn set_index_pubkeys(enabled: bool) {
+    INDEX_PUBKEYS.store(enabled, Ordering::Relaxed);
+}
+
+//
+// Script hashes of the P2PKH scripts of the keys of a P2PK or bare multisig
+// output (which wallets query by address)
+//
+pub fn pubkey_script_hashes(script: &Script) -> Vec<FullHash> {
+    let instructions: Vec<Instruction> = script.iter(false).collect();
+    let keys = match instructions.split_last() {
+        Some((Instruction::Op(opcodes::all::OP_CHECKSIG), keys)) if keys.len() == 1 => keys,
+        Some((Instruction::Op(opcodes::all::OP_CHECKMULTISIG), keys)) => keys,
+        _ => return vec![],
+    };
+    keys.iter()
+        .filter_map(|instruction| match instruction {
+            Instruction::PushBytes(key) => PublicKey::from_slice(key).ok(),
+            _ => None,
+        })
+        .map(|key| {
+            let script = Address::p2pkh(&key, Network::Bitcoin).script_pubkey();
+            compute_script_hash(&script[..])
+        })
+        .collect()
+}
+
 //
 // Index a transaction
 //
-pub fn index_transaction<'a>(txn: &'a Transaction, blockhash: &Sha256dHash) -> impl 'a + Iterator<Item = Row> {
+pub fn index_transaction(txn: &Transaction, tx_num: TxNum) -> impl '_ + Iterator<Item = Row> {
     let null_hash = Sha256dHash::default();
     let txid: Sha256dHash = txn.txid();
 
@@ -205,40 +310,61 @@ pub fn index_transaction<'a>(txn: &'a Transaction, blockhash: &Sha256dHash) -> i
         if input.previous_output.txid == null_hash {
             None
         } else {
-            Some(TxInRow::new(&txid, &input).to_row())
+            Some(TxInRow::new(tx_num, &input).to_row())
         }
     });
 
+    let index_pubkeys = INDEX_PUBKEYS.load(Ordering::Relaxed);
     let outputs = txn
         .output
         .iter()
         .enumerate()
-        .map(move |(vout, output)| TxOutRow::new(&txid, vout as u32, &output).to_row());
+        .flat_map(move |(vout, output)| {
+            let mut rows = vec![TxOutRow::new(tx_num, vout as u32, &output).to_row()];
+            if index_pubkeys {
+                for script_hash in pubkey_script_hashes(&output.script_pubkey) {
+                    rows.push(TxOutRow::with_script_hash(tx_num, vout as u32, &script_hash).to_row());
+                }
+            }
+            rows
+        });
 
     inputs
         .chain(outputs)
-        .chain(std::iter::once(TxRow::new(&txid, &blockhash).to_row()))
+        .chain(std::iter::once(TxRow::new(tx_num, &txid).to_row()))
+}
+
+//
+// Index the transactions of a block
+//
+pub fn index_block_txs(block: &Block, height: usize) -> impl '_ + Iterator<Item = Row> {
+    block
+        .txdata
+        .iter()
+        .enumerate()
+        .flat_map(move |(position, txn)| index_transaction(txn, tx_num(height, position)))
 }
 
 //
 // Index a block
 //
-pub fn index_block<'a>(block: &'a Block) -> impl 'a + Iterator<Item = Row> {
-    let blockhash = block.bitcoin_hash();
-    // Persist block hash and header
-    let row = Row {
+pub fn index_block(block: &Block, height: usize) -> impl '_ + Iterator<Item = Row> {
+    let row = header_row(&block.header);
+    index_block_txs(block, height).chain(std::iter::once(row))
+}
+
+//
+// Persist block hash and header
+//
+pub fn header_row(header: &BlockHeader) -> Row {
+    Row {
         key: bincode::serialize(&BlockKey {
             code: b'B',
-            hash: full_hash(&blockhash[..]),
+            hash: full_hash(&header.bitcoin_hash()[..]),
         })
         .unwrap(),
-        value: serialize(&block.header),
-    };
-    block
-        .txdata
-        .iter()
-        .flat_map(move |txn| index_transaction(&txn, &blockhash))
-        .chain(std::iter::once(row))
+        value: serialize(header),
+    }
 }
 
 //
@@ -252,6 +378,121 @@ pub fn last_indexed_block(blockhash: &Sha256dHash) -> Row {
     }
 }
 
+//
+// Height below which the blocks' rows were dropped (see `Index::prune`)
+//
+const PRUNED_KEY: &[u8] = b"R";
+
+// Headers written at once, for the blocks below the start height
+const SKIPPED_BATCH_SIZE: usize = 10_000;
+
+fn pruned_row(height: usize) -> Row {
+    Row {
+        key: PRUNED_KEY.to_vec(),
+        value: (height as u32).to_le_bytes().to_vec(),
+    }
+}
+
+pub fn read_pruned_height(store: &dyn ReadStore) -> usize {
+    match store.get(PRUNED_KEY) {
+        Some(value) => u32::from_le_bytes([value[0], value[1], value[2], value[3]]) as usize,
+        None => 0,
+    }
+}
+
+//
+// Blocks disconnected by a reorg, with the scripts funded or spent and the
+// transactions confirmed by them, journaled in `J` rows numbered from 0
+//
+#[derive(Serialize, Deserialize)]
+pub struct Reorg {
+    pub time: u64,                  // seconds since the epoch
+    pub height: u32,                // of the first disconnected block
+    pub blockhashes: Vec<FullHash>, // disconnected, by height
+    pub script_hashes: Vec<FullHash>,
+    pub txids: Vec<FullHash>,
+}
+
+fn reorg_key(id: u32) -> Bytes {
+    [&b"J"[..], &id.to_be_bytes()].concat()
+}
+
+// Journaled reorgs, from the `from` one (as (id, reorg) pairs)
+pub fn read_reorgs(store: &dyn ReadStore, from: u32) -> Vec<(u32, Reorg)> {
+    store
+        .scan(b"J")
+        .into_iter()
+        .filter_map(|row| {
+            let id = row.key.get(1..5)?;
+            let id = u32::from_be_bytes([id[0], id[1], id[2], id[3]]);
+            let reorg = bincode::deserialize(&row.value).ok()?;
+            Some((id, reorg))
+        })
+        .filter(|(id, _)| *id >= from)
+        .collect()
+}
+
+//
+// Undo data of a recently indexed block (see `undo_depth`): the keys of its
+// rows, so a reorg removes them without fetching the block again, and the
+// script hashes of its outputs and the outputs spent by it, for the journal
+//
+#[derive(Serialize, Deserialize)]
+struct Undo {
+    blockhash: FullHash,
+    keys: Vec<Bytes>,
+    txs: Vec<(FullHash, Vec<FullHash>)>, // txid and the script hashes of its outputs
+    prevouts: Vec<(FullHash, u32)>,
+}
+
+impl Undo {
+    fn new(block: &Block, keys: Vec<Bytes>) -> Undo {
+        let mut txs = vec![];
+        let mut prevouts = vec![];
+        for txn in &block.txdata {
+            let script_hashes = txn
+                .output
+                .iter()
+                .map(|output| compute_script_hash(&output.script_pubkey[..]))
+                .collect();
+            txs.push((full_hash(&txn.txid()[..]), script_hashes));
+            if !txn.is_coin_base() {
+                prevouts.extend(txn.input.iter().map(|input| {
+                    let prevout = &input.previous_output;
+                    (full_hash(&prevout.txid[..]), prevout.vout)
+                }));
+            }
+        }
+        Undo {
+            blockhash: full_hash(&block.bitcoin_hash()[..]),
+            keys,
+            txs,
+            prevouts,
+        }
+    }
+
+    fn to_row(&self, height: usize) -> Row {
+        Row {
+            key: undo_key(height),
+            value: bincode::serialize(self).unwrap(),
+        }
+    }
+}
+
+fn undo_key(height: usize) -> Bytes {
+    [&b"H"[..], &(height as u32).to_be_bytes()].concat()
+}
+
+// The undo data of an indexed block (unless it's too old)
+fn read_undo(store: &dyn ReadStore, header: &HeaderEntry) -> Option<Undo> {
+    let value = store.get(&undo_key(header.height()))?;
+    let undo: Undo = bincode::deserialize(&value).ok()?;
+    match undo.blockhash == full_hash(&header.hash()[..]) {
+        true => Some(undo),
+        false => None,
+    }
+}
+
 //
 // Retrieve the hashes of all the indexed blocks
 //
@@ -267,12 +508,16 @@ pub fn read_indexed_blockhashes(store: &dyn ReadStore) -> HashSet<Sha256dHash> {
 //
 // Retrieve the headers of all the indexed blocks
 //
-fn read_indexed_headers(store: &dyn ReadStore) -> HeaderList {
-    let latest_blockhash: Sha256dHash = match store.get(b"L") {
-        // latest blockheader persisted in the DB.
+// Latest blockheader persisted in the DB (or the null hash)
+pub fn read_last_indexed_block(store: &dyn ReadStore) -> Sha256dHash {
+    match store.get(b"L") {
         Some(row) => deserialize(&row).unwrap(),
         None => Sha256dHash::default(),
-    };
+    }
+}
+
+fn read_indexed_headers(store: &dyn ReadStore) -> HeaderList {
+    let latest_blockhash = read_last_indexed_block(store);
     trace!("lastest indexed blockhash: {}", latest_blockhash);
 
     let mut map = HeaderMap::new();
@@ -325,26 +570,89 @@ pub struct Index {
     // TODO: store also latest snapshot.
     headers: RwLock<HeaderList>,
     daemon: Daemon,
+    stats: Stats,
     batch_size: usize,
+    fetch_threads: usize,
+    limits: HistoryLimits,
+    undo_depth: usize, // blocks with undo data, to roll back reorgs
+    watchlist: Option<Arc<Watchlist>>,
+    progress: Arc<Progress>,
+}
+
+//
+// Heights whose history is left out of the index
+//
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HistoryLimits {
+    pub start_height: Option<usize>, // only the headers of the blocks below it are indexed
+    pub prune_below: Option<usize>,  // the rows of the blocks below it are dropped
+}
+
+struct Stats {
+    height: Arc<Gauge>,
+    blocks: Arc<Counter>,
+    blocks_per_second: Arc<Gauge>,
+}
+
+impl Stats {
+    fn new(metrics: &Metrics) -> Stats {
+        Stats {
+            height: metrics.gauge("addrindexrs_index_height", "Height of the indexed chain tip"),
+            blocks: metrics.counter("addrindexrs_index_blocks_total", "Number of indexed blocks"),
+            blocks_per_second: metrics.gauge(
+                "addrindexrs_index_blocks_per_second",
+                "Indexing rate of the latest batch of blocks",
+            ),
+        }
+    }
 }
 
 impl Index {
     pub fn load(
         store: &dyn ReadStore,
         daemon: &Daemon,
+        metrics: &Metrics,
         batch_size: usize,
+        fetch_threads: usize,
+        limits: HistoryLimits,
+        progress: Arc<Progress>,
     ) -> Result<Index> {
         let headers = read_indexed_headers(store);
+        let stats = Stats::new(metrics);
+        stats.height.set(headers.len().saturating_sub(1) as f64);
         Ok(Index {
             headers: RwLock::new(headers),
             daemon: daemon.reconnect()?,
+            stats,
             batch_size,
+            fetch_threads: fetch_threads.max(1),
+            limits,
+            undo_depth: 0,
+            watchlist: None,
+            progress,
         })
     }
 
+    // Keep the undo data of the last `depth` blocks, to roll back reorgs
+    pub fn keep_undo(mut self, depth: usize) -> Index {
+        self.undo_depth = depth;
+        self
+    }
+
+    // Only index the transactions of the watched scripts
+    pub fn watch(mut self, watchlist: Arc<Watchlist>) -> Index {
+        self.watchlist = Some(watchlist);
+        self
+    }
+
+    pub fn watchlist(&self) -> Option<&Arc<Watchlist>> {
+        self.watchlist.as_ref()
+    }
+
     pub fn reload(&self, store: &dyn ReadStore) {
         let mut headers = self.headers.write().unwrap();
         *headers = read_indexed_headers(store);
+        self.stats.height.set(headers.len().saturating_sub(1) as f64);
     }
 
     pub fn best_header(&self) -> Option<HeaderEntry> {
@@ -368,7 +676,7 @@ impl Index {
             .cloned()
     }
 
-    pub fn update(&self, store: &impl WriteStore, waiter: &Waiter) -> Result<Sha256dHash> {
+    pub fn update(&self, store: &DBStore, waiter: &Waiter) -> Result<Sha256dHash> {
         let daemon = self.daemon.reconnect()?;
         let tip = daemon.getbestblockhash()?;
 
@@ -376,52 +684,413 @@ impl Index {
             let indexed_headers = self.headers.read().unwrap();
             indexed_headers.order(daemon.get_new_headers(&indexed_headers, &tip)?)
         };
+        if let (Some(first), Some(best)) = (new_headers.first(), self.best_header()) {
+            if first.height() <= best.height() {
+                self.remove_stale_blocks(store, first.height(), waiter)?;
+            }
+        }
 
         if let Some(latest_header) = new_headers.last() {
             info!("{:?} ({} left to index)", latest_header, new_headers.len());
+            let indexed = latest_header.height() + 1 - new_headers.len();
+            self.progress.start(indexed, latest_header.height() + 1);
         };
 
-        let chan = SyncChannel::new(1);
-        let sender = chan.sender();
-        let blockhashes: Vec<Sha256dHash> = new_headers.iter().map(|h| *h.hash()).collect();
-        let batch_size = self.batch_size;
+        // the blocks below the start height aren't fetched
+        let start_height = self.limits.start_height.unwrap_or(0);
+        let skipped = new_headers
+            .iter()
+            .take_while(|h| h.height() < start_height)
+            .count();
+        if skipped > 0 {
+            info!("indexing only the headers of {} blocks below height {}", skipped, start_height);
+        }
+        for chunk in new_headers[..skipped].chunks(SKIPPED_BATCH_SIZE) {
+            waiter.poll()?;
+            let last = &chunk[chunk.len() - 1];
+            let mut rows: Vec<Row> = chunk.iter().map(|h| header_row(h.header())).collect();
+            rows.push(last_indexed_block(last.hash()));
+            rows.push(pruned_row(last.height() + 1));
+            let rows_count = rows.len();
+            store.write(rows);
+            self.progress.add(chunk.len(), rows_count);
+        }
 
-        let fetcher = spawn_thread("fetcher", move || {
-            for chunk in blockhashes.chunks(batch_size) {
-                sender
-                    .send(daemon.getblocks(&chunk))
-                    .expect("failed sending blocks to be indexed");
-            }
-            sender
-                .send(Ok(vec![]))
-                .expect("failed sending explicit end of stream");
-        });
+        let blockhashes: Vec<Sha256dHash> =
+            new_headers[skipped..].iter().map(|h| *h.hash()).collect();
+        let chunks: Vec<&[Sha256dHash]> = blockhashes.chunks(self.batch_size).collect();
+        let first_height = new_headers.get(skipped).map_or(0, |h| h.height());
+        // the undo data is only kept for the last blocks
+        let undo_height = new_headers
+            .last()
+            .map_or(0, |h| (h.height() + 1).saturating_sub(self.undo_depth));
+
+        // Chunk #i is fetched by fetcher #(i % fetch_threads), so their
+        // requests are pipelined, while the blocks are indexed in order.
+        let mut receivers = vec![];
+        let mut fetchers = vec![];
+        for i in 0..self.fetch_threads {
+            let chan = SyncChannel::new(1);
+            let sender = chan.sender();
+            receivers.push(chan.into_receiver());
+            let chunks: Vec<Vec<Sha256dHash>> = chunks
+                .iter()
+                .skip(i)
+                .step_by(self.fetch_threads)
+                .map(|chunk| chunk.to_vec())
+                .collect();
+            let daemon = daemon.reconnect()?;
+            fetchers.push(spawn_thread("fetcher", move || {
+                for chunk in chunks {
+                    sender
+                        .send(daemon.getblocks(&chunk))
+                        .expect("failed sending blocks to be indexed");
+                }
+            }));
+        }
 
-        loop {
+        for i in 0..chunks.len() {
             waiter.poll()?;
+            let start = Instant::now();
 
-            let batch = chan
-                .receiver()
+            let batch = receivers[i % self.fetch_threads]
                 .recv()
                 .expect("block fetch exited prematurely")?;
 
-            if batch.is_empty() {
-                break;
-            }
-
-            let rows_iter = batch.iter().flat_map(|block| {
+            let heights = first_height + i * self.batch_size..;
+            let mut deleted = vec![];
+            let rows_iter = batch.iter().zip(heights).flat_map(|(block, height)| {
                 let blockhash = block.bitcoin_hash();
                 info!("indexing block {}", blockhash);
-                index_block(block).chain(std::iter::once(last_indexed_block(&blockhash)))
+                let mut rows = match self.watchlist {
+                    Some(ref watchlist) => watchlist.index_block(block, height),
+                    None => index_block(block, height).collect(),
+                };
+                if height >= undo_height {
+                    let keys = rows.iter().filter(|row| row.key[0] != b'B');
+                    let undo = Undo::new(block, keys.map(|row| row.key.clone()).collect());
+                    rows.push(undo.to_row(height));
+                    if let Some(old_height) = height.checked_sub(self.undo_depth) {
+                        deleted.push(undo_key(old_height));
+                    }
+                }
+                rows.into_iter().chain(std::iter::once(last_indexed_block(&blockhash)))
             });
+            let rows: Vec<Row> = rows_iter.collect();
+            let rows_count = rows.len();
 
-            store.write(rows_iter);
+            store.write_batch(rows, deleted);
+            self.progress.add(batch.len(), rows_count);
+
+            self.stats.blocks.inc_by(batch.len() as u64);
+            let elapsed = start.elapsed().as_secs_f64();
+            if elapsed > 0.0 {
+                self.stats.blocks_per_second.set(batch.len() as f64 / elapsed);
+            }
         }
 
         store.flush(); // make sure no row is left behind
-        fetcher.join().expect("block fetcher failed");
+        for fetcher in fetchers {
+            fetcher.join().expect("block fetcher failed");
+        }
         self.headers.write().unwrap().apply(new_headers, tip);
-        assert_eq!(tip, self.headers.read().unwrap().tip());
+        let headers = self.headers.read().unwrap();
+        assert_eq!(tip, headers.tip());
+        self.stats.height.set(headers.len().saturating_sub(1) as f64);
         Ok(tip)
     }
+
+    // Drop the transactions' rows of the indexed blocks at or above `height`,
+    // replaced by a reorg (the new blocks' transactions get the same numbers),
+    // and journal them once they are all dropped. The rows are found from the
+    // undo data of the last `undo_depth` blocks, or else by fetching the blocks.
+    fn remove_stale_blocks(&self, store: &DBStore, height: usize, waiter: &Waiter) -> Result<()> {
+        let stale: Vec<HeaderEntry> = {
+            let headers = self.headers.read().unwrap();
+            headers.iter().skip(height).cloned().collect()
+        };
+        let undo: Option<Vec<Undo>> = stale.iter().map(|h| read_undo(store, h)).collect();
+        let undo = match undo {
+            Some(undo) => {
+                info!("rolling back {} stale blocks from their undo data", stale.len());
+                let mut deleted: Vec<Bytes> = undo.iter().flat_map(|u| u.keys.clone()).collect();
+                deleted.extend(stale.iter().map(|h| undo_key(h.height())));
+                store.write_batch(vec![], deleted);
+                undo
+            }
+            None => {
+                warn!(
+                    "reorg of {} blocks is deeper than the undo data (undo_depth={}): \
+                     fetching the stale blocks to reindex from height {}",
+                    stale.len(),
+                    self.undo_depth,
+                    height
+                );
+                let mut undo = vec![];
+                for chunk in stale.chunks(self.batch_size) {
+                    waiter.poll()?;
+                    let blockhashes: Vec<Sha256dHash> = chunk.iter().map(|h| *h.hash()).collect();
+                    let blocks = self.daemon.getblocks(&blockhashes)?;
+                    let mut deleted = vec![];
+                    for (block, header) in blocks.iter().zip(chunk) {
+                        let keys: Vec<Bytes> = index_block_txs(block, header.height())
+                            .map(|row| row.key)
+                            .collect();
+                        deleted.extend(keys.iter().cloned());
+                        deleted.push(undo_key(header.height()));
+                        undo.push(Undo::new(block, keys));
+                    }
+                    store.write_batch(vec![], deleted);
+                }
+                undo
+            }
+        };
+        self.journal_reorg(store, height, &stale, undo);
+        Ok(())
+    }
+
+    fn journal_reorg(&self, store: &DBStore, height: usize, stale: &[HeaderEntry], undo: Vec<Undo>) {
+        let mut outputs = HashMap::<FullHash, Vec<FullHash>>::new(); // of the stale txs
+        let mut txids = vec![];
+        let mut prevouts = vec![];
+        for block in undo {
+            for (txid, script_hashes) in block.txs {
+                txids.push(txid);
+                outputs.insert(txid, script_hashes);
+            }
+            prevouts.extend(block.prevouts);
+        }
+        let mut script_hashes: HashSet<FullHash> =
+            outputs.values().flat_map(|hashes| hashes.iter().copied()).collect();
+        // the outputs spent by the stale blocks are looked up afterwards
+        let mut missing: Vec<Sha256dHash> = prevouts
+            .iter()
+            .filter(|(txid, _)| !outputs.contains_key(txid))
+            .map(|(txid, _)| Sha256dHash::from_slice(txid).unwrap())
+            .collect();
+        missing.sort_unstable();
+        missing.dedup();
+        let missing_refs: Vec<&Sha256dHash> = missing.iter().collect();
+        let prev_txs = match self.daemon.gettransactions(&missing_refs) {
+            Ok(txs) => txs,
+            Err(e) => {
+                warn!("failed to journal the scripts spent by the stale blocks: {}", e);
+                vec![]
+            }
+        };
+        let mut prev_outputs = HashMap::new();
+        for (txid, txn) in missing.iter().zip(&prev_txs) {
+            prev_outputs.insert(full_hash(&txid[..]), &txn.output);
+        }
+        for (txid, vout) in prevouts {
+            let script_hash = match outputs.get(&txid) {
+                Some(hashes) => hashes.get(vout as usize).copied(),
+                None => prev_outputs
+                    .get(&txid)
+                    .and_then(|outputs| outputs.get(vout as usize))
+                    .map(|output| compute_script_hash(&output.script_pubkey[..])),
+            };
+            script_hashes.extend(script_hash);
+        }
+        let mut script_hashes: Vec<FullHash> = script_hashes.into_iter().collect();
+        script_hashes.sort_unstable();
+        let reorg = Reorg {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            height: height as u32,
+            blockhashes: stale.iter().map(|h| full_hash(&h.hash()[..])).collect(),
+            script_hashes,
+            txids,
+        };
+        let id = read_reorgs(store, 0).last().map_or(0, |(id, _)| id + 1);
+        let row = Row {
+            key: reorg_key(id),
+            value: bincode::serialize(&reorg).unwrap(),
+        };
+        store.write_batch(vec![row], vec![]);
+    }
+
+    // Drop the rows of the blocks at or above `height`, so they get indexed again
+    // (the highest blocks go first, so an interrupted rewind can be resumed)
+    pub fn rewind(&self, store: &DBStore, height: usize, waiter: &Waiter) -> Result<()> {
+        let best_height = match self.best_header() {
+            Some(best) if best.height() >= height => best.height(),
+            _ => bail!("no indexed block at height {}", height),
+        };
+        info!("removing blocks {}..={} from the index", height, best_height);
+        let header = |height| self.get_header(height).expect("missing indexed header");
+        let heights: Vec<usize> = (height..=best_height).rev().collect();
+        for chunk in heights.chunks(self.batch_size) {
+            waiter.poll()?;
+            let blockhashes: Vec<Sha256dHash> = chunk.iter().map(|h| *header(*h).hash()).collect();
+            let blocks = self.daemon.getblocks(&blockhashes)?;
+            let mut deleted: Vec<Bytes> = blocks
+                .iter()
+                .zip(chunk)
+                .flat_map(|(block, height)| index_block(block, *height))
+                .map(|row| row.key)
+                .collect();
+            let rows = match chunk[chunk.len() - 1].checked_sub(1) {
+                Some(prev_height) => vec![last_indexed_block(header(prev_height).hash())],
+                None => {
+                    deleted.push(b"L".to_vec());
+                    vec![]
+                }
+            };
+            store.write_batch(rows, deleted);
+            debug!("removed blocks down to height {}", chunk[chunk.len() - 1]);
+        }
+        // the rewound blocks may be pruned again
+        if read_pruned_height(store) > height {
+            store.write_batch(vec![pruned_row(height)], vec![]);
+        }
+        store.flush();
+        self.reload(store);
+        Ok(())
+    }
+
+    // Drop the history rows of the blocks below `prune_below` (keeping their
+    // headers), lowest first, recording the pruned height after each batch
+    pub fn prune(&self, store: &DBStore, waiter: &Waiter) -> Result<()> {
+        let cutoff = match (self.limits.prune_below, self.best_header()) {
+            (Some(height), Some(best)) => height.min(best.height() + 1),
+            _ => return Ok(()),
+        };
+        let pruned = read_pruned_height(store);
+        if pruned >= cutoff {
+            return Ok(());
+        }
+        info!("pruning blocks {}..{} from the index", pruned, cutoff);
+        let heights: Vec<usize> = (pruned..cutoff).collect();
+        for chunk in heights.chunks(self.batch_size) {
+            waiter.poll()?;
+            let blockhashes: Vec<Sha256dHash> = chunk
+                .iter()
+                .map(|h| *self.get_header(*h).expect("missing indexed header").hash())
+                .collect();
+            let blocks = self.daemon.getblocks(&blockhashes)?;
+            let deleted: Vec<Bytes> = blocks
+                .iter()
+                .zip(chunk)
+                .flat_map(|(block, height)| index_block_txs(block, *height))
+                .map(|row| row.key)
+                .collect();
+            let next = chunk[chunk.len() - 1] + 1;
+            store.write_batch(vec![pruned_row(next)], deleted);
+            debug!("pruned blocks up to height {}", next);
+        }
+        store.flush();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::blockdata::script::Builder;
+
+    use crate::store::TempStore;
+
+    #[test]
+    fn test_tx_num() {
+        assert_eq!(tx_num(0, 0), [0; 6]);
+        assert_eq!(tx_num(0x123456, 0xabcdef), [0x12, 0x34, 0x56, 0xab, 0xcd, 0xef]);
+        assert_eq!(tx_num_height(&tx_num(800_000, 1234)), 800_000);
+        assert_eq!(tx_num_height(&tx_num(MEMPOOL_HEIGHT, 5)), MEMPOOL_HEIGHT);
+        assert_eq!(tx_num_position(&tx_num(800_000, 1234)), 1234);
+        // sorted by height, then by position
+        assert!(tx_num(1, 0xffffff) < tx_num(2, 0));
+        assert!(tx_num(2, 1) < tx_num(2, 2));
+
+        let block = genesis_block(Network::Regtest);
+        let rows: Vec<Row> = index_block(&block, 0).collect();
+        let codes: Vec<u8> = rows.iter().map(|row| row.key[0]).collect();
+        assert_eq!(codes, b"OTB".to_vec());
+        assert_eq!(TxOutRow::from_row(&rows[0]).tx_num, tx_num(0, 0));
+        assert_eq!(TxRow::from_row(&rows[1]).txid, full_hash(&block.txdata[0].txid()[..]));
+    }
+
+    #[test]
+    fn test_read_reorgs() {
+        let store = TempStore::new("reorgs");
+        let rows = [0, 256, 1].iter().map(|id| Row {
+            key: reorg_key(*id),
+            value: bincode::serialize(&Reorg {
+                time: 1_700_000_000,
+                height: *id,
+                blockhashes: vec![[1u8; 32]],
+                script_hashes: vec![],
+                txids: vec![[2u8; 32]],
+            })
+            .unwrap(),
+        });
+        store.write(rows);
+        let ids: Vec<u32> = read_reorgs(&store, 0).iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, vec![0, 1, 256]); // in order
+        let reorgs = read_reorgs(&store, 1);
+        assert_eq!(reorgs.len(), 2);
+        assert_eq!(reorgs[1].1.height, 256);
+        assert_eq!(reorgs[1].1.txids, vec![[2u8; 32]]);
+    }
+
+    #[test]
+    fn test_undo() {
+        let store = TempStore::new("undo");
+        let block = genesis_block(Network::Regtest);
+        let header = HeaderList::empty().order(vec![block.header]).remove(0);
+        assert!(read_undo(&store, &header).is_none());
+
+        let keys: Vec<Bytes> = index_block_txs(&block, 0).map(|row| row.key).collect();
+        store.write(vec![Undo::new(&block, keys.clone()).to_row(0)]);
+        let undo = read_undo(&store, &header).unwrap();
+        assert_eq!(undo.keys, keys);
+        let script_hash = compute_script_hash(&block.txdata[0].output[0].script_pubkey[..]);
+        assert_eq!(undo.txs[0].1, vec![script_hash]);
+        assert!(undo.prevouts.is_empty()); // coinbase
+
+        // replaced by another block at the same height
+        let other = genesis_block(Network::Testnet);
+        store.write(vec![Undo::new(&other, vec![]).to_row(0)]);
+        assert!(read_undo(&store, &header).is_none());
+    }
+
+    #[test]
+    fn test_parse_script_hash() {
+        let hex = "740485f380ff6379d11ef6fe7d7cdd68aea7f8bd0d953d9fdf3531fb7d531833";
+        let script_hash = parse_script_hash(hex, Network::Bitcoin).unwrap();
+        assert_eq!(script_hash[..], Sha256dHash::from_hex(hex).unwrap().into_inner()[..]);
+        let address = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
+        assert_eq!(
+            parse_script_hash(address, Network::Bitcoin).unwrap(),
+            address_script_hash(address, Network::Bitcoin).unwrap()
+        );
+        assert!(parse_script_hash(address, Network::Testnet).is_err());
+        assert!(parse_script_hash("not an address", Network::Bitcoin).is_err());
+        assert!(parse_script_hash(&hex[1..], Network::Bitcoin).is_err());
+    }
+
+    #[test]
+    fn test_pubkey_script_hashes() {
+        let block = genesis_block(Network::Bitcoin);
+        let p2pk = &block.txdata[0].output[0].script_pubkey;
+        let satoshi = address_script_hash("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa", Network::Bitcoin);
+        assert_eq!(pubkey_script_hashes(p2pk), vec![satoshi.unwrap()]);
+
+        let p2pkh = Address::from_str("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa").unwrap().script_pubkey();
+        assert!(pubkey_script_hashes(&p2pkh).is_empty());
+
+        // 1-of-2 multisig, with an invalid key
+        let key = &p2pk[1..66];
+        let multisig = Builder::new()
+            .push_int(1)
+            .push_slice(key)
+            .push_slice(&[7u8; 33])
+            .push_int(2)
+            .push_opcode(opcodes::all::OP_CHECKMULTISIG)
+            .into_script();
+        assert_eq!(pubkey_script_hashes(&multisig), pubkey_script_hashes(p2pk));
+    }
 }
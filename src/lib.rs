@@ -1,5 +1,7 @@
 #![recursion_limit = "1024"]
 
+#[macro_use]
+extern crate crossbeam_channel;
 #[macro_use]
 extern crate error_chain;
 #[macro_use]
@@ -12,15 +14,42 @@ extern crate serde_json;
 extern crate configure_me;
 
 pub mod app;
+pub mod bench;
 pub mod bulk;
 pub mod cache;
 pub mod config;
 pub mod daemon;
+pub mod descriptor;
 pub mod errors;
+pub mod export;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod filter;
+pub mod grpc;
+#[cfg(feature = "grpc")]
+pub mod http2;
 pub mod index;
 pub mod mempool;
+pub mod logdb;
+pub mod logger;
+pub mod metrics;
+pub mod opreturn;
+pub mod p2p;
+pub mod poll;
+pub mod progress;
 pub mod query;
+pub mod rest;
 pub mod rpc;
 pub mod signal;
+pub mod stats;
 pub mod store;
+pub mod systemd;
+pub mod tls;
+pub mod trace;
 pub mod util;
+pub mod utxo;
+pub mod verify;
+pub mod watch;
+pub mod webhook;
+pub mod websocket;
+pub mod zmq;
@@ -0,0 +1,310 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+
+use crate::errors::*;
+use crate::store::{Backend, DBStats, Row};
+use crate::util::Bytes;
+
+const LOG_FILE: &str = "index.log";
+const OP_PUT: u8 = 0;
+const OP_DELETE: u8 = 1;
+
+//
+// Pure-Rust backend, keeping all the rows in memory and appending
+// each batch to a log file (replayed on startup, and rewritten by
+// compactions). Meant for small chains (e.g. regtest or signet),
+// or platforms where RocksDB is hard to build.
+//
+pub struct LogDB {
+    path: PathBuf,
+    rows: RwLock<BTreeMap<Bytes, Bytes>>,
+    log: Mutex<BufWriter<File>>,
+    replayed: Option<Mutex<u64>>, // size of the log, if opened read-only
+}
+
+fn put_bytes(record: &mut Vec<u8>, bytes: &[u8]) {
+    record.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    record.extend_from_slice(bytes);
+}
+
+fn take_bytes<'a>(record: &mut &'a [u8]) -> Option<&'a [u8]> {
+    if record.len() < 4 {
+        return None;
+    }
+    let len = u32::from_le_bytes([record[0], record[1], record[2], record[3]]) as usize;
+    if record.len() < 4 + len {
+        return None;
+    }
+    let bytes = &record[4..4 + len];
+    *record = &record[4 + len..];
+    Some(bytes)
+}
+
+// A batch is logged as a single length-prefixed record of operations
+fn encode_batch(rows: &[Row], deleted: &[Bytes]) -> Vec<u8> {
+    let mut ops = vec![];
+    for row in rows {
+        ops.push(OP_PUT);
+        put_bytes(&mut ops, &row.key);
+        put_bytes(&mut ops, &row.value);
+    }
+    for key in deleted {
+        ops.push(OP_DELETE);
+        put_bytes(&mut ops, key);
+    }
+    let mut record = vec![];
+    put_bytes(&mut record, &ops);
+    record
+}
+
+fn apply_batch(rows: &mut BTreeMap<Bytes, Bytes>, mut ops: &[u8]) {
+    while let Some((&op, rest)) = ops.split_first() {
+        ops = rest;
+        let key = take_bytes(&mut ops).expect("corrupted log record").to_vec();
+        match op {
+            OP_PUT => {
+                let value = take_bytes(&mut ops).expect("corrupted log record");
+                rows.insert(key, value.to_vec());
+            }
+            OP_DELETE => {
+                rows.remove(&key);
+            }
+            _ => panic!("unknown log operation {}", op),
+        }
+    }
+}
+
+// Returns the rows, and the size of the complete records
+fn replay(path: &Path) -> (BTreeMap<Bytes, Bytes>, u64) {
+    let mut rows = BTreeMap::new();
+    let mut data = vec![];
+    if let Ok(file) = File::open(path) {
+        BufReader::new(file)
+            .read_to_end(&mut data)
+            .unwrap_or_else(|e| panic!("failed to read {:?}: {}", path, e));
+    }
+    let mut records = &data[..];
+    while let Some(ops) = take_bytes(&mut records) {
+        apply_batch(&mut rows, ops);
+    }
+    if !records.is_empty() {
+        warn!("ignoring incomplete last record of {:?}", path);
+    }
+    (rows, (data.len() - records.len()) as u64)
+}
+
+impl LogDB {
+    pub fn open(path: &Path) -> LogDB {
+        fs::create_dir_all(path).unwrap_or_else(|e| panic!("failed to create {:?}: {}", path, e));
+        let log_path = path.join(LOG_FILE);
+        let (rows, size) = replay(&log_path);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(&log_path)
+            .unwrap_or_else(|e| panic!("failed to open {:?}: {}", log_path, e));
+        // drop an incomplete last record (e.g. after a crash)
+        file.set_len(size).expect("failed to truncate log");
+        file.seek(SeekFrom::End(0)).expect("failed to seek log");
+        debug!("loaded {} rows from {:?}", rows.len(), log_path);
+        LogDB {
+            path: path.to_path_buf(),
+            rows: RwLock::new(rows),
+            log: Mutex::new(BufWriter::new(file)),
+            replayed: None,
+        }
+    }
+
+    // The log is replayed again once it has changed (see `catch_up`)
+    pub fn open_read_only(path: &Path) -> LogDB {
+        let log_path = path.join(LOG_FILE);
+        let file = File::open(&log_path)
+            .unwrap_or_else(|e| panic!("failed to open {:?}: {}", log_path, e));
+        let (rows, size) = replay(&log_path);
+        debug!("loaded {} rows from {:?} (read-only)", rows.len(), log_path);
+        LogDB {
+            path: path.to_path_buf(),
+            rows: RwLock::new(rows),
+            log: Mutex::new(BufWriter::new(file)),
+            replayed: Some(Mutex::new(size)),
+        }
+    }
+}
+
+impl Backend for LogDB {
+    fn get(&self, key: &[u8]) -> Option<Bytes> {
+        self.rows.read().unwrap().get(key).cloned()
+    }
+
+    fn iter_scan<'a>(&'a self, prefix: &[u8]) -> Box<dyn Iterator<Item = Row> + 'a> {
+        let rows: Vec<Row> = self
+            .rows
+            .read()
+            .unwrap()
+            .range(prefix.to_vec()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| Row {
+                key: key.clone(),
+                value: value.clone(),
+            })
+            .collect();
+        Box::new(rows.into_iter())
+    }
+
+    fn write(&self, rows: Vec<Row>, deleted: Vec<Bytes>, durable: bool) {
+        let record = encode_batch(&rows, &deleted);
+        let mut log = self.log.lock().unwrap();
+        log.write_all(&record).expect("failed to write log");
+        if durable {
+            log.flush().expect("failed to flush log");
+            log.get_ref().sync_data().expect("failed to sync log");
+        }
+        let mut map = self.rows.write().unwrap();
+        for row in rows {
+            map.insert(row.key, row.value);
+        }
+        for key in deleted {
+            map.remove(&key);
+        }
+    }
+
+    fn flush(&self) {
+        let mut log = self.log.lock().unwrap();
+        log.flush().expect("failed to flush log");
+        log.get_ref().sync_data().expect("failed to sync log");
+    }
+
+    // Rewrite the log with the current rows only
+    fn compact(&self) {
+        let mut log = self.log.lock().unwrap();
+        let rows = self.rows.read().unwrap();
+        let tmp_path = self.path.join(format!("{}.tmp", LOG_FILE));
+        let mut tmp = BufWriter::new(File::create(&tmp_path).expect("failed to create log"));
+        let mut batch = vec![];
+        for (key, value) in rows.iter() {
+            batch.push(Row {
+                key: key.clone(),
+                value: value.clone(),
+            });
+            if batch.len() == 10_000 {
+                tmp.write_all(&encode_batch(&batch, &[])).expect("failed to write log");
+                batch.clear();
+            }
+        }
+        tmp.write_all(&encode_batch(&batch, &[])).expect("failed to write log");
+        let tmp = tmp.into_inner().expect("failed to flush log");
+        tmp.sync_all().expect("failed to sync log");
+        fs::rename(&tmp_path, self.path.join(LOG_FILE)).expect("failed to replace log");
+        *log = BufWriter::new(tmp);
+    }
+
+    fn enable_auto_compactions(&self) {}
+
+    fn size(&self) -> u64 {
+        fs::metadata(self.path.join(LOG_FILE))
+            .map(|m| m.len())
+            .unwrap_or(0)
+    }
+
+    // The rows are only in memory and in the log
+    fn stats(&self) -> DBStats {
+        DBStats::default()
+    }
+
+    // Copy the log, while blocking the writes
+    fn checkpoint(&self, path: &Path) -> Result<()> {
+        let mut log = self.log.lock().unwrap();
+        log.flush().chain_err(|| "failed to flush log")?;
+        if path.exists() {
+            bail!("{:?} already exists", path);
+        }
+        fs::create_dir_all(path).chain_err(|| format!("failed to create {:?}", path))?;
+        fs::copy(self.path.join(LOG_FILE), path.join(LOG_FILE))
+            .chain_err(|| format!("failed to copy {}", LOG_FILE))?;
+        Ok(())
+    }
+
+    // Compactions rewrite the log, so it's replayed from the start
+    fn catch_up(&self) {
+        if let Some(ref replayed) = self.replayed {
+            let mut replayed = replayed.lock().unwrap();
+            if self.size() != *replayed {
+                let (rows, size) = replay(&self.path.join(LOG_FILE));
+                *self.rows.write().unwrap() = rows;
+                *replayed = size;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::TempStore;
+
+    fn row(key: &[u8], value: &[u8]) -> Row {
+        Row {
+            key: key.to_vec(),
+            value: value.to_vec(),
+        }
+    }
+
+    fn keys(db: &LogDB, prefix: &[u8]) -> Vec<Bytes> {
+        db.iter_scan(prefix).map(|row| row.key).collect()
+    }
+
+    #[test]
+    fn test_replay_and_compact() {
+        let temp = TempStore::new("logdb");
+        let path = temp.dir().join("log");
+        {
+            let db = LogDB::open(&path);
+            db.write(vec![row(b"a1", b"x"), row(b"a2", b"y"), row(b"b1", b"z")], vec![], false);
+            db.write(vec![row(b"a3", b"w")], vec![b"a2".to_vec()], true);
+            assert_eq!(keys(&db, b"a"), vec![b"a1".to_vec(), b"a3".to_vec()]);
+        }
+        // simulate a crash during a write
+        let mut file = OpenOptions::new().append(true).open(path.join(LOG_FILE)).unwrap();
+        file.write_all(&encode_batch(&[row(b"a4", b"v")], &[])[..6]).unwrap();
+        drop(file);
+        {
+            let db = LogDB::open(&path);
+            assert_eq!(keys(&db, b"a"), vec![b"a1".to_vec(), b"a3".to_vec()]);
+            assert_eq!(db.get(b"b1"), Some(b"z".to_vec()));
+            let size = db.size();
+            db.compact();
+            assert!(db.size() < size);
+            db.write(vec![row(b"b2", b"u")], vec![], true);
+        }
+        let db = LogDB::open(&path);
+        let all = vec![b"a1".to_vec(), b"a3".to_vec(), b"b1".to_vec(), b"b2".to_vec()];
+        assert_eq!(keys(&db, b""), all);
+
+        let snapshot = path.join("snapshot");
+        db.checkpoint(&snapshot).unwrap();
+        assert!(db.checkpoint(&snapshot).is_err());
+        db.write(vec![row(b"b3", b"t")], vec![], true);
+        assert_eq!(keys(&LogDB::open(&snapshot), b""), all);
+    }
+
+    #[test]
+    fn test_read_only() {
+        let temp = TempStore::new("logdb-ro");
+        let path = temp.dir().join("log");
+        let db = LogDB::open(&path);
+        db.write(vec![row(b"a1", b"x")], vec![], true);
+        let reader = LogDB::open_read_only(&path);
+        db.write(vec![row(b"a2", b"y")], vec![b"a1".to_vec()], true);
+        assert_eq!(keys(&reader, b""), vec![b"a1".to_vec()]);
+        reader.catch_up();
+        assert_eq!(keys(&reader, b""), vec![b"a2".to_vec()]);
+        db.compact();
+        db.write(vec![row(b"a3", b"z")], vec![], true);
+        reader.catch_up();
+        assert_eq!(keys(&reader, b""), vec![b"a2".to_vec(), b"a3".to_vec()]);
+    }
+}
@@ -0,0 +1,209 @@
+use log::{Log, Metadata, Record, SetLoggerError};
+use serde_json::Value;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+
+use crate::config::LogFormat;
+
+const ROTATED_FILES: usize = 5; // kept as <path>.1 (most recent) to <path>.5
+
+// RFC 3339 time, in milliseconds
+fn format_time(tm: &time::Tm) -> String {
+    let seconds = time::strftime("%Y-%m-%dT%H:%M:%S", tm).expect("invalid time format");
+    format!("{}.{:03}Z", seconds, tm.tm_nsec / 1_000_000)
+}
+
+fn json_line(record: &Record, tm: &time::Tm, thread: Option<&str>) -> Value {
+    json!({
+        "timestamp": format_time(tm),
+        "level": record.level().to_string(),
+        "module": record.module_path().unwrap_or_else(|| record.target()),
+        "message": record.args().to_string(),
+        "fields": {
+            "thread": thread,
+            "file": record.file(),
+            "line": record.line(),
+        },
+    })
+}
+
+// Same layout as stderrlog's lines
+fn text_line(record: &Record, tm: Option<&time::Tm>) -> String {
+    match tm {
+        Some(tm) => format!("{} - {} - {}", format_time(tm), record.level(), record.args()),
+        None => format!("{} - {}", record.level(), record.args()),
+    }
+}
+
+//
+// Log file, renamed to <path>.1 once it reaches `max_size` bytes
+// (shifting the previous ones, and removing the oldest)
+//
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64, // 0 for no rotation
+    file: File,
+    size: u64,
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+impl RotatingFile {
+    pub fn open(path: &Path, max_size: u64) -> io::Result<RotatingFile> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            max_size,
+            file,
+            size,
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for index in (1..ROTATED_FILES).rev() {
+            let from = rotated_path(&self.path, index);
+            if from.exists() {
+                fs::rename(&from, rotated_path(&self.path, index + 1))?;
+            }
+        }
+        fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        *self = RotatingFile::open(&self.path, self.max_size)?;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.max_size > 0 && self.size > 0 && self.size + len > self.max_size {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += len;
+        Ok(())
+    }
+}
+
+//
+// Logger used instead of stderrlog for JSON lines, or for a log file
+//
+pub struct Logger {
+    format: LogFormat,
+    timestamp: bool, // always set in JSON lines
+    file: Option<Mutex<RotatingFile>>,
+}
+
+impl Logger {
+    pub fn new(format: LogFormat, timestamp: bool, file: Option<RotatingFile>) -> Logger {
+        Logger {
+            format,
+            timestamp,
+            file: file.map(Mutex::new),
+        }
+    }
+
+    pub fn init(self) -> Result<(), SetLoggerError> {
+        log::set_boxed_logger(Box::new(self))
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let tm = time::now_utc();
+        let line = match self.format {
+            LogFormat::Json => json_line(record, &tm, thread::current().name()).to_string(),
+            LogFormat::Text => text_line(record, Some(&tm).filter(|_| self.timestamp)),
+        };
+        match self.file {
+            Some(ref file) => {
+                if let Err(e) = file.lock().unwrap().write_line(&line) {
+                    eprintln!("failed to write log: {}", e);
+                }
+            }
+            None => {
+                let stderr = io::stderr();
+                let _ = writeln!(stderr.lock(), "{}", line);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        match self.file {
+            Some(ref file) => {
+                let _ = file.lock().unwrap().file.flush();
+            }
+            None => {
+                let _ = io::stderr().flush();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+
+    #[test]
+    fn test_json_line() {
+        let tm = time::at_utc(time::Timespec::new(1_600_000_000, 123_456_789));
+        let line = json_line(
+            &Record::builder()
+                .args(format_args!("indexed {} blocks", 10))
+                .level(Level::Info)
+                .module_path(Some("addrindexrs::index"))
+                .line(Some(42))
+                .build(),
+            &tm,
+            Some("main"),
+        );
+        assert_eq!(
+            line,
+            json!({
+                "timestamp": "2020-09-13T12:26:40.123Z",
+                "level": "INFO",
+                "module": "addrindexrs::index",
+                "message": "indexed 10 blocks",
+                "fields": {"thread": "main", "file": null, "line": 42},
+            })
+        );
+    }
+
+    #[test]
+    fn test_rotating_file() {
+        let dir = std::env::temp_dir().join(format!("addrindexrs-logger-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("addrindexrs.log");
+        let mut file = RotatingFile::open(&path, 10).unwrap();
+        for line in &["line 1", "line 2", "line 3"] {
+            file.write_line(line).unwrap();
+        }
+        drop(file);
+        // appended to, after a restart
+        let mut file = RotatingFile::open(&path, 20).unwrap();
+        file.write_line("line 4").unwrap();
+        let read = |path: PathBuf| fs::read_to_string(path).unwrap();
+        assert_eq!(read(path.clone()), "line 3\nline 4\n");
+        assert_eq!(read(rotated_path(&path, 1)), "line 2\n");
+        assert_eq!(read(rotated_path(&path, 2)), "line 1\n");
+        for i in 5..20 {
+            file.write_line(&format!("line {}", i)).unwrap();
+        }
+        assert!(!rotated_path(&path, ROTATED_FILES + 1).exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -1,15 +1,49 @@
-use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::blockdata::transaction::{OutPoint, Transaction};
+use bitcoin::consensus::encode::{deserialize, serialize};
 use bitcoin_hashes::sha256d::Hash as Sha256dHash;
 use hex;
-use std::collections::{BTreeMap, HashMap, HashSet};
-use std::iter::FromIterator;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::convert::TryInto;
 use std::ops::Bound;
 
-use crate::daemon::Daemon;
+use crate::daemon::{Daemon, MempoolEntry};
 use crate::errors::*;
-use crate::index::index_transaction;
+use crate::index::{compute_script_hash, index_transaction, tx_num, TxNum, MEMPOOL_HEIGHT};
 use crate::store::{ReadStore, Row};
-use crate::util::Bytes;
+use crate::util::{Bytes, FullHash};
+
+// Conflicts kept for the subscribers and blockchain.transaction.get_replacement
+const MAX_CONFLICTS: usize = 1000;
+
+// Code of the rows saving the tracked transactions across restarts (see doc/schema.md)
+pub const SNAPSHOT_CODE: &[u8] = b"M";
+
+fn snapshot_row(txid: &Sha256dHash, tx: &Transaction, entry: &MempoolEntry) -> Row {
+    Row {
+        key: [SNAPSHOT_CODE, &txid[..]].concat(),
+        value: [
+            &entry.fee().to_le_bytes()[..],
+            &entry.vsize().to_le_bytes()[..],
+            &serialize(tx)[..],
+        ]
+        .concat(),
+    }
+}
+
+fn parse_snapshot_row(row: &Row) -> Option<(Transaction, MempoolEntry)> {
+    if row.value.len() < 16 {
+        return None;
+    }
+    let (fee, rest) = row.value.split_at(8);
+    let (vsize, tx) = rest.split_at(8);
+    let tx: Transaction = deserialize(tx).ok()?;
+    if row.key[SNAPSHOT_CODE.len()..] != tx.txid()[..] {
+        return None;
+    }
+    let fee = u64::from_le_bytes(fee.try_into().unwrap());
+    let vsize = u64::from_le_bytes(vsize.try_into().unwrap());
+    Some((tx, MempoolEntry::new(fee, vsize)))
+}
 
 //
 // BTree emulating a db store
@@ -17,17 +51,24 @@ use crate::util::Bytes;
 //
 struct MempoolStore {
     map: BTreeMap<Bytes, Vec<Bytes>>,
+    positions: HashMap<Sha256dHash, usize>, // numbering the transactions
+    free_positions: Vec<usize>,
 }
 
 impl MempoolStore {
     fn new() -> MempoolStore {
         MempoolStore {
             map: BTreeMap::new(),
+            positions: HashMap::new(),
+            free_positions: vec![],
         }
     }
 
     fn add(&mut self, tx: &Transaction) {
-        let rows = index_transaction(tx, &Sha256dHash::default());
+        // the used and free positions are 0..N
+        let position = self.free_positions.pop().unwrap_or(self.positions.len());
+        self.positions.insert(tx.txid(), position);
+        let rows = index_transaction(tx, mempool_tx_num(position));
         for row in rows {
             let (key, value) = row.into_pair();
             self.map.entry(key).or_insert_with(|| vec![]).push(value);
@@ -35,7 +76,12 @@ impl MempoolStore {
     }
 
     fn remove(&mut self, tx: &Transaction) {
-        let rows = index_transaction(tx, &Sha256dHash::default());
+        let position = self
+            .positions
+            .remove(&tx.txid())
+            .unwrap_or_else(|| panic!("missing tx {} in mempool", tx.txid()));
+        self.free_positions.push(position);
+        let rows = index_transaction(tx, mempool_tx_num(position));
         for row in rows {
             let (key, value) = row.into_pair();
             let no_values_left = {
@@ -46,7 +92,7 @@ impl MempoolStore {
                 let last_value = values
                     .pop()
                     .unwrap_or_else(|| panic!("no values found for key {}", hex::encode(&key)));
-                // TxInRow and TxOutRow have an empty value, TxRow has height=0 as value.
+                // TxInRow and TxOutRow have an empty value, TxRow has the txid as value.
                 assert_eq!(
                     value,
                     last_value,
@@ -63,6 +109,10 @@ impl MempoolStore {
     }
 }
 
+fn mempool_tx_num(position: usize) -> TxNum {
+    tx_num(MEMPOOL_HEIGHT, position)
+}
+
 impl ReadStore for MempoolStore {
     fn get(&self, key: &[u8]) -> Option<Bytes> {
         Some(self.map.get(key)?.last()?.to_vec())
@@ -88,12 +138,123 @@ impl ReadStore for MempoolStore {
     }
 }
 
+// Approximate vsize of each fee histogram bin
+const VSIZE_BIN_WIDTH: u64 = 100_000;
+
+//
+// Electrum fee histogram: (fee rate in sat/vB, vsize) pairs, by
+// decreasing fee rate. Each bin contains the transactions paying
+// at least its fee rate (and less than the previous bin's one).
+//
+fn fee_histogram<'a>(entries: impl Iterator<Item = &'a MempoolEntry>) -> Vec<(f64, u64)> {
+    let mut entries: Vec<&MempoolEntry> = entries.collect();
+    entries.sort_by(|a, b| b.fee_per_vbyte().total_cmp(&a.fee_per_vbyte()));
+    let mut histogram = vec![];
+    let mut bin_size = 0;
+    let mut last_fee_rate = None;
+    for entry in entries {
+        let fee_rate = entry.fee_per_vbyte();
+        // don't split transactions with the same fee rate between bins
+        if bin_size > VSIZE_BIN_WIDTH && last_fee_rate != Some(fee_rate) {
+            histogram.push((last_fee_rate.unwrap(), bin_size));
+            bin_size = 0;
+        }
+        bin_size += entry.vsize();
+        last_fee_rate = Some(fee_rate);
+    }
+    if let Some(fee_rate) = last_fee_rate {
+        histogram.push((fee_rate, bin_size));
+    }
+    histogram
+}
+
+// Transactions fetched by a single batch of requests
+const FETCH_BATCH_SIZE: usize = 1000;
+
+// New mempool transactions, with their fees and sizes
+pub type Fetched = Vec<(Sha256dHash, Transaction, MempoolEntry)>;
+
+fn fetch_batch(daemon: &Daemon, txids: &[&Sha256dHash]) -> Result<Fetched> {
+    let txs = daemon.gettransactions(txids)?;
+    let entries = daemon.getmempoolentries(txids)?;
+    Ok(txids
+        .iter()
+        .zip(txs.into_iter().zip(entries))
+        .map(|(txid, (tx, entry))| {
+            assert_eq!(tx.txid(), **txid);
+            (**txid, tx, entry)
+        })
+        .collect())
+}
+
+// Fetch the new transactions of bitcoind's mempool by batches, skipping the
+// ones which left it since it was listed (e.g. mined or replaced): a batch
+// failing with a bitcoind error is fetched again one transaction at a time,
+// so that the other ones are still tracked
+pub fn fetch_txs(daemon: &Daemon, txids: &[Sha256dHash]) -> Result<Fetched> {
+    let txids: Vec<&Sha256dHash> = txids.iter().collect();
+    let mut fetched = vec![];
+    for batch in txids.chunks(FETCH_BATCH_SIZE) {
+        match fetch_batch(daemon, batch) {
+            Ok(txs) => fetched.extend(txs),
+            Err(Error(ErrorKind::Daemon(..), _)) => {
+                for txid in batch {
+                    match fetch_batch(daemon, &[txid]) {
+                        Ok(txs) => fetched.extend(txs),
+                        Err(Error(ErrorKind::Daemon(_, _, msg), _)) => {
+                            debug!("skipping mempool tx {}: {}", txid, msg)
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+            Err(e) => return Err(e),
+        }
+        if txids.len() > FETCH_BATCH_SIZE {
+            debug!(
+                "fetched {}/{} new mempool transactions",
+                fetched.len(),
+                txids.len()
+            );
+        }
+    }
+    Ok(fetched)
+}
+
+//
+// Mempool transaction replaced by a new one spending some of the same outputs
+// (i.e. an RBF fee bump, or a double-spend)
+//
+#[derive(Clone, Debug, PartialEq)]
+pub struct Conflict {
+    pub id: u64, // numbered from 1, in detection order
+    pub replaced: Sha256dHash,
+    pub replacing: Sha256dHash,
+    pub outpoints: Vec<OutPoint>,     // spent by both transactions
+    pub script_hashes: Vec<FullHash>, // funded by the replaced transaction
+}
+
+//
+// Unconfirmed ancestors of a mempool transaction, including itself
+// (i.e. bitcoind's ancestorcount, ancestorfees and ancestorsize)
+//
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Ancestors {
+    pub count: usize,
+    pub fee: u64,   // in satoshis
+    pub vsize: u64, // in virtual bytes
+}
+
 //
 // Tracker managing mempool transactions
 //
 pub struct Tracker {
-    items: HashMap<Sha256dHash, Transaction>,
+    items: HashMap<Sha256dHash, (Transaction, MempoolEntry)>,
     index: MempoolStore,
+    histogram: Vec<(f64, u64)>,
+    spenders: HashMap<OutPoint, Sha256dHash>, // of the outputs spent by the mempool
+    conflicts: VecDeque<Conflict>,            // the last MAX_CONFLICTS ones
+    last_conflict_id: u64,
 }
 
 impl Tracker {
@@ -101,6 +262,10 @@ impl Tracker {
         Tracker {
             items: HashMap::new(),
             index: MempoolStore::new(),
+            histogram: vec![],
+            spenders: HashMap::new(),
+            conflicts: VecDeque::new(),
+            last_conflict_id: 0,
         }
     }
 
@@ -108,49 +273,403 @@ impl Tracker {
         &self.index
     }
 
-    pub fn update(&mut self, daemon: &Daemon) -> Result<()> {
-        let new_txids = daemon
-            .getmempooltxids()
-            .chain_err(|| "failed to update mempool from daemon")?;
+    pub fn get_txn(&self, txid: &Sha256dHash) -> Option<&Transaction> {
+        self.items.get(txid).map(|(tx, _)| tx)
+    }
 
-        let old_txids = HashSet::from_iter(self.items.keys().cloned());
+    pub fn get_entry(&self, txid: &Sha256dHash) -> Option<&MempoolEntry> {
+        self.items.get(txid).map(|(_, entry)| entry)
+    }
 
-        let txids_iter = new_txids.difference(&old_txids);
+    // Mempool transactions whose outputs are spent by the transaction
+    fn parents<'a>(&'a self, tx: &'a Transaction) -> impl Iterator<Item = &'a Sha256dHash> {
+        tx.input
+            .iter()
+            .map(|txin| &txin.previous_output.txid)
+            .filter(move |txid| self.items.contains_key(*txid))
+    }
 
-        let txids: Vec<&Sha256dHash> = txids_iter.collect();
+    // Whether the transaction spends outputs of other mempool transactions
+    pub fn has_unconfirmed_inputs(&self, txid: &Sha256dHash) -> bool {
+        self.items
+            .get(txid)
+            .is_some_and(|(tx, _)| self.parents(tx).next().is_some())
+    }
 
-        let txs = match daemon.gettransactions(&txids) {
-            Ok(txs) => txs,
-            Err(err) => {
-                warn!("failed to get transactions {:?}: {}", txids, err); // e.g. new block or RBF
-                return Ok(()); // keep the mempool until next update()
-            }
+    // Walks the mempool dependency graph from the transaction to its parents
+    pub fn get_ancestors(&self, txid: &Sha256dHash) -> Option<Ancestors> {
+        self.items.get(txid)?;
+        let mut ancestors = Ancestors {
+            count: 0,
+            fee: 0,
+            vsize: 0,
         };
+        let mut visited = HashSet::new();
+        let mut pending = vec![*txid];
+        while let Some(txid) = pending.pop() {
+            if !visited.insert(txid) {
+                continue;
+            }
+            let (tx, entry) = &self.items[&txid];
+            ancestors.count += 1;
+            ancestors.fee += entry.fee();
+            ancestors.vsize += entry.vsize();
+            pending.extend(self.parents(tx).filter(|parent| !visited.contains(*parent)));
+        }
+        Some(ancestors)
+    }
 
-        trace!("updated mempool with {} transactions from daemon", txs.len());
+    // Conflicts detected after the one numbered `after` (0 for all of them)
+    pub fn conflicts_after(&self, after: u64) -> impl Iterator<Item = &Conflict> {
+        self.conflicts
+            .iter()
+            .filter(move |conflict| conflict.id > after)
+    }
+
+    pub fn last_conflict_id(&self) -> u64 {
+        self.last_conflict_id
+    }
+
+    // Last conflict replacing a transaction (if it is still kept)
+    pub fn get_replacement(&self, txid: &Sha256dHash) -> Option<&Conflict> {
+        self.conflicts
+            .iter()
+            .rev()
+            .find(|conflict| conflict.replaced == *txid)
+    }
+
+    pub fn fee_histogram(&self) -> &[(f64, u64)] {
+        &self.histogram
+    }
 
-        for (txid, tx) in txids.into_iter().zip(txs.into_iter()) {
-            assert_eq!(tx.txid(), *txid);
-            self.add(txid, tx);
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    // Rows of the tracked transactions, with their fees and sizes
+    pub fn snapshot(&self) -> Vec<Row> {
+        self.items
+            .iter()
+            .map(|(txid, (tx, entry))| snapshot_row(txid, tx, entry))
+            .collect()
+    }
+
+    // Track the transactions of a snapshot (skipping the invalid rows), until
+    // the next update() removes the ones which left the mempool since
+    pub fn load(&mut self, rows: &[Row]) -> usize {
+        let mut count = 0;
+        for row in rows {
+            match parse_snapshot_row(row) {
+                Some((tx, entry)) => {
+                    let txid = tx.txid();
+                    if !self.items.contains_key(&txid) {
+                        self.add(&txid, tx, entry);
+                        count += 1;
+                    }
+                }
+                None => warn!("invalid mempool snapshot row {}", hex::encode(&row.key)),
+            }
         }
+        self.histogram = fee_histogram(self.items.values().map(|(_, entry)| entry));
+        count
+    }
+
+    // Txids of bitcoind's mempool which aren't tracked yet
+    pub fn untracked(&self, txids: &HashSet<Sha256dHash>) -> Vec<Sha256dHash> {
+        txids
+            .iter()
+            .filter(|txid| !self.items.contains_key(*txid))
+            .copied()
+            .collect()
+    }
 
-        for txid in old_txids.difference(&new_txids) {
+    // Track the fetched transactions, and drop the ones which left bitcoind's
+    // mempool (of `txids`), returning the txids added and removed
+    pub fn apply(&mut self, txids: &HashSet<Sha256dHash>, fetched: Fetched) -> Vec<Sha256dHash> {
+        let mut changed = vec![];
+        for (txid, tx, entry) in fetched {
+            if !self.items.contains_key(&txid) {
+                self.add(&txid, tx, entry);
+                changed.push(txid);
+            }
+        }
+        let stale_txids: Vec<Sha256dHash> = self
+            .items
+            .keys()
+            .filter(|txid| !txids.contains(*txid))
+            .copied()
+            .collect();
+        for txid in &stale_txids {
             self.remove(txid);
         }
-
-        Ok(())
+        trace!(
+            "updated mempool with {} new and {} stale transactions",
+            changed.len(),
+            stale_txids.len()
+        );
+        changed.extend(stale_txids);
+        if !changed.is_empty() {
+            self.histogram = fee_histogram(self.items.values().map(|(_, entry)| entry));
+        }
+        changed
     }
 
-    fn add(&mut self, txid: &Sha256dHash, tx: Transaction) {
+    // The replaced transactions are still tracked when their replacements are
+    // added, since the stale transactions are removed last by apply()
+    fn add(&mut self, txid: &Sha256dHash, tx: Transaction, entry: MempoolEntry) {
+        let mut conflicting = BTreeMap::<Sha256dHash, Vec<OutPoint>>::new();
+        for txin in &tx.input {
+            match self.spenders.insert(txin.previous_output, *txid) {
+                Some(spender) if spender != *txid => conflicting
+                    .entry(spender)
+                    .or_default()
+                    .push(txin.previous_output),
+                _ => (),
+            }
+        }
+        for (replaced, outpoints) in conflicting {
+            self.add_conflict(replaced, *txid, outpoints);
+        }
         self.index.add(&tx);
-        self.items.insert(*txid, tx);
+        self.items.insert(*txid, (tx, entry));
+    }
+
+    fn add_conflict(
+        &mut self,
+        replaced: Sha256dHash,
+        replacing: Sha256dHash,
+        outpoints: Vec<OutPoint>,
+    ) {
+        let mut script_hashes: Vec<FullHash> = match self.items.get(&replaced) {
+            Some((tx, _)) => tx
+                .output
+                .iter()
+                .map(|output| compute_script_hash(&output.script_pubkey[..]))
+                .collect(),
+            None => vec![],
+        };
+        script_hashes.sort_unstable();
+        script_hashes.dedup();
+        debug!("mempool tx {} replaced by {}", replaced, replacing);
+        self.last_conflict_id += 1;
+        self.conflicts.push_back(Conflict {
+            id: self.last_conflict_id,
+            replaced,
+            replacing,
+            outpoints,
+            script_hashes,
+        });
+        if self.conflicts.len() > MAX_CONFLICTS {
+            self.conflicts.pop_front();
+        }
     }
 
     fn remove(&mut self, txid: &Sha256dHash) {
-        let tx = self
+        let (tx, _) = self
             .items
             .remove(txid)
             .unwrap_or_else(|| panic!("missing mempool tx {}", txid));
+        for txin in &tx.input {
+            if self.spenders.get(&txin.previous_output) == Some(txid) {
+                self.spenders.remove(&txin.previous_output);
+            }
+        }
         self.index.remove(&tx);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::blockdata::script::Script;
+    use bitcoin::blockdata::transaction::{TxIn, TxOut};
+    use bitcoin_hashes::Hash;
+
+    use crate::index::TxOutRow;
+
+    #[test]
+    fn test_fee_histogram() {
+        assert!(fee_histogram(std::iter::empty()).is_empty());
+
+        // (fee, vsize) => fee rates of 10, 5, 5, 2 and 1 sat/vB
+        let entries: Vec<MempoolEntry> = [
+            (500_000, 50_000),
+            (600_000, 120_000),
+            (50_000, 10_000),
+            (200_000, 100_000),
+            (1_000, 1_000),
+        ]
+        .iter()
+        .map(|&(fee, vsize)| MempoolEntry::new(fee, vsize))
+        .collect();
+        // the second 5 sat/vB transaction stays in the first (full) bin
+        assert_eq!(
+            fee_histogram(entries.iter()),
+            vec![(5.0, 180_000), (1.0, 101_000)]
+        );
+    }
+
+    fn spending(outpoints: &[OutPoint], script: &[u8]) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: outpoints
+                .iter()
+                .map(|outpoint| TxIn {
+                    previous_output: *outpoint,
+                    script_sig: Script::new(),
+                    sequence: 0xffff_fffd,
+                    witness: vec![],
+                })
+                .collect(),
+            output: vec![TxOut {
+                value: 1000,
+                script_pubkey: Script::from(script.to_vec()),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_conflicts() {
+        let outpoint = |vout| OutPoint::new(Sha256dHash::hash(b"funding"), vout);
+        let original = spending(&[outpoint(0), outpoint(1)], b"original");
+        let bump = spending(&[outpoint(1), outpoint(2)], b"bump");
+        let unrelated = spending(&[outpoint(3)], b"unrelated");
+        let mut tracker = Tracker::new();
+        for tx in &[&original, &unrelated, &bump] {
+            tracker.add(&tx.txid(), (*tx).clone(), MempoolEntry::new(1000, 100));
+        }
+        tracker.remove(&original.txid());
+
+        assert_eq!(tracker.last_conflict_id(), 1);
+        let conflict = tracker.get_replacement(&original.txid()).unwrap();
+        assert_eq!(conflict.replacing, bump.txid());
+        assert_eq!(conflict.outpoints, vec![outpoint(1)]);
+        assert_eq!(
+            conflict.script_hashes,
+            vec![compute_script_hash(b"original")]
+        );
+        assert_eq!(tracker.conflicts_after(1).count(), 0);
+        assert!(tracker.get_replacement(&unrelated.txid()).is_none());
+        // outpoint 0 isn't spent anymore, so spending it is no conflict
+        tracker.add(
+            &Sha256dHash::hash(b"other"),
+            spending(&[outpoint(0)], b"other"),
+            MempoolEntry::new(1000, 100),
+        );
+        assert_eq!(tracker.last_conflict_id(), 1);
+    }
+
+    #[test]
+    fn test_ancestors() {
+        let funding = OutPoint::new(Sha256dHash::hash(b"funding"), 0);
+        let parent = spending(&[funding], b"parent");
+        let child_a = spending(&[OutPoint::new(parent.txid(), 0)], b"a");
+        let child_b = spending(&[OutPoint::new(parent.txid(), 1)], b"b");
+        let both = spending(
+            &[
+                OutPoint::new(child_a.txid(), 0),
+                OutPoint::new(child_b.txid(), 0),
+            ],
+            b"both",
+        );
+        let mut tracker = Tracker::new();
+        let txs = [
+            (&parent, 100, 200),
+            (&child_a, 300, 100),
+            (&child_b, 50, 150),
+        ];
+        for (tx, fee, vsize) in &txs {
+            tracker.add(&tx.txid(), (*tx).clone(), MempoolEntry::new(*fee, *vsize));
+        }
+        tracker.add(&both.txid(), both.clone(), MempoolEntry::new(1000, 50));
+
+        let ancestors = |tx: &Transaction| tracker.get_ancestors(&tx.txid()).unwrap();
+        assert!(!tracker.has_unconfirmed_inputs(&parent.txid()));
+        assert_eq!(
+            ancestors(&parent),
+            Ancestors {
+                count: 1,
+                fee: 100,
+                vsize: 200,
+            }
+        );
+        assert!(tracker.has_unconfirmed_inputs(&child_a.txid()));
+        assert_eq!(ancestors(&child_a).fee, 400);
+        // the parent is only counted once
+        assert_eq!(
+            ancestors(&both),
+            Ancestors {
+                count: 4,
+                fee: 1450,
+                vsize: 500,
+            }
+        );
+        assert!(tracker.get_ancestors(&funding.txid).is_none());
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let funding = OutPoint::new(Sha256dHash::hash(b"funding"), 0);
+        let parent = spending(&[funding], b"parent");
+        let child = spending(&[OutPoint::new(parent.txid(), 0)], b"child");
+        let mut tracker = Tracker::new();
+        tracker.add(&parent.txid(), parent.clone(), MempoolEntry::new(500, 100));
+        tracker.add(&child.txid(), child.clone(), MempoolEntry::new(1500, 100));
+
+        let mut rows = tracker.snapshot();
+        rows.push(Row {
+            key: [SNAPSHOT_CODE, &Sha256dHash::hash(b"other")[..]].concat(),
+            value: rows[0].value.clone(), // of another txid
+        });
+        let mut restored = Tracker::new();
+        assert_eq!(restored.load(&rows), 2);
+        assert_eq!(restored.len(), 2);
+        assert_eq!(restored.get_txn(&child.txid()), Some(&child));
+        assert_eq!(restored.get_entry(&parent.txid()).unwrap().fee(), 500);
+        assert!(restored.has_unconfirmed_inputs(&child.txid()));
+        assert_eq!(restored.fee_histogram(), &[(5.0, 200)]);
+        let prefix = TxOutRow::filter(&compute_script_hash(b"child")[..]);
+        assert_eq!(restored.index().scan(&prefix).len(), 1);
+        assert_eq!(restored.load(&rows), 0); // already tracked
+    }
+
+    #[test]
+    fn test_apply() {
+        let funding = OutPoint::new(Sha256dHash::hash(b"funding"), 0);
+        let original = spending(&[funding], b"original");
+        let bump = spending(&[funding], b"bump");
+        let other = spending(&[OutPoint::new(funding.txid, 1)], b"other");
+        let fetched = |txs: &[&Transaction]| -> Fetched {
+            txs.iter()
+                .map(|tx| (tx.txid(), (*tx).clone(), MempoolEntry::new(1000, 100)))
+                .collect()
+        };
+        let mut tracker = Tracker::new();
+        let txids: HashSet<Sha256dHash> = [original.txid(), other.txid()].iter().copied().collect();
+        assert_eq!(tracker.untracked(&txids).len(), 2);
+        assert_eq!(
+            tracker.apply(&txids, fetched(&[&original, &other])).len(),
+            2
+        );
+        assert!(tracker.untracked(&txids).is_empty());
+        assert_eq!(tracker.fee_histogram(), &[(10.0, 200)]);
+
+        // the original transaction is replaced by the bump, which failed to be fetched
+        let txids: HashSet<Sha256dHash> = [bump.txid(), other.txid()].iter().copied().collect();
+        assert_eq!(tracker.untracked(&txids), vec![bump.txid()]);
+        assert_eq!(tracker.apply(&txids, vec![]), vec![original.txid()]);
+        assert_eq!(tracker.len(), 1);
+        // fetched again by the next update
+        assert_eq!(
+            tracker.apply(&txids, fetched(&[&bump, &other])),
+            vec![bump.txid()]
+        );
+        assert_eq!(tracker.len(), 2);
+        assert!(tracker.get_replacement(&original.txid()).is_none()); // removed first
+    }
+}
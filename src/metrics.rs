@@ -0,0 +1,388 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tiny_http;
+
+use crate::errors::*;
+use crate::util::spawn_thread;
+
+//
+// Monotonic counter
+//
+#[derive(Default)]
+pub struct Counter {
+    value: AtomicU64,
+}
+
+impl Counter {
+    pub fn inc(&self) {
+        self.inc_by(1)
+    }
+
+    pub fn inc_by(&self, value: u64) {
+        self.value.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+//
+// Value that can go up and down (stored as f64 bits)
+//
+#[derive(Default)]
+pub struct Gauge {
+    bits: AtomicU64,
+}
+
+impl Gauge {
+    pub fn set(&self, value: f64) {
+        self.bits.store(value.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> f64 {
+        f64::from_bits(self.bits.load(Ordering::Relaxed))
+    }
+}
+
+//
+// Counters partitioned by the value of a single label
+//
+pub struct CounterVec {
+    label: &'static str,
+    values: Mutex<BTreeMap<String, u64>>,
+}
+
+impl CounterVec {
+    pub fn inc(&self, label_value: &str) {
+        let mut values = self.values.lock().unwrap();
+        *values.entry(label_value.to_owned()).or_insert(0) += 1;
+    }
+}
+
+//
+// Gauges partitioned by the value of a single label
+//
+pub struct GaugeVec {
+    label: &'static str,
+    values: Mutex<BTreeMap<String, f64>>,
+}
+
+impl GaugeVec {
+    pub fn set(&self, label_value: &str, value: f64) {
+        let mut values = self.values.lock().unwrap();
+        values.insert(label_value.to_owned(), value);
+    }
+}
+
+// Upper bounds of the latency histograms' buckets (in seconds)
+const DURATION_BUCKETS: &[f64] = &[
+    0.001, 0.002, 0.005, 0.01, 0.02, 0.05, 0.1, 0.2, 0.5, 1.0, 2.0, 5.0, 10.0, 30.0, 60.0,
+];
+
+struct Histogram {
+    counts: Vec<u64>, // by bucket, not cumulative
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new() -> Histogram {
+        Histogram {
+            counts: vec![0; DURATION_BUCKETS.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+}
+
+//
+// Latency histograms partitioned by the value of a single label
+//
+pub struct HistogramVec {
+    label: &'static str,
+    values: Mutex<BTreeMap<String, Histogram>>,
+}
+
+impl HistogramVec {
+    pub fn observe(&self, label_value: &str, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let mut values = self.values.lock().unwrap();
+        let histogram = values
+            .entry(label_value.to_owned())
+            .or_insert_with(Histogram::new);
+        if let Some(bucket) = DURATION_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            histogram.counts[bucket] += 1;
+        }
+        histogram.sum += seconds;
+        histogram.count += 1;
+    }
+
+    // Observe the duration of `func`
+    pub fn time<T>(&self, label_value: &str, func: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = func();
+        self.observe(label_value, start.elapsed());
+        result
+    }
+}
+
+enum Metric {
+    Counter(Arc<Counter>),
+    Gauge(Arc<Gauge>),
+    CounterVec(Arc<CounterVec>),
+    GaugeVec(Arc<GaugeVec>),
+    HistogramVec(Arc<HistogramVec>),
+}
+
+struct Entry {
+    name: &'static str,
+    help: &'static str,
+    metric: Metric,
+}
+
+//
+// Registry of the metrics exported to Prometheus
+//
+pub struct Metrics {
+    addr: Option<SocketAddr>,
+    entries: Arc<Mutex<Vec<Entry>>>,
+}
+
+impl Metrics {
+    pub fn new(addr: Option<SocketAddr>) -> Metrics {
+        Metrics {
+            addr,
+            entries: Arc::new(Mutex::new(vec![])),
+        }
+    }
+
+    fn register(&self, name: &'static str, help: &'static str, metric: Metric) {
+        self.entries
+            .lock()
+            .unwrap()
+            .push(Entry { name, help, metric });
+    }
+
+    pub fn counter(&self, name: &'static str, help: &'static str) -> Arc<Counter> {
+        let counter = Arc::new(Counter::default());
+        self.register(name, help, Metric::Counter(Arc::clone(&counter)));
+        counter
+    }
+
+    pub fn gauge(&self, name: &'static str, help: &'static str) -> Arc<Gauge> {
+        let gauge = Arc::new(Gauge::default());
+        self.register(name, help, Metric::Gauge(Arc::clone(&gauge)));
+        gauge
+    }
+
+    pub fn counter_vec(
+        &self,
+        name: &'static str,
+        help: &'static str,
+        label: &'static str,
+    ) -> Arc<CounterVec> {
+        let counter_vec = Arc::new(CounterVec {
+            label,
+            values: Mutex::new(BTreeMap::new()),
+        });
+        self.register(name, help, Metric::CounterVec(Arc::clone(&counter_vec)));
+        counter_vec
+    }
+
+    pub fn gauge_vec(
+        &self,
+        name: &'static str,
+        help: &'static str,
+        label: &'static str,
+    ) -> Arc<GaugeVec> {
+        let gauge_vec = Arc::new(GaugeVec {
+            label,
+            values: Mutex::new(BTreeMap::new()),
+        });
+        self.register(name, help, Metric::GaugeVec(Arc::clone(&gauge_vec)));
+        gauge_vec
+    }
+
+    pub fn histogram_vec(
+        &self,
+        name: &'static str,
+        help: &'static str,
+        label: &'static str,
+    ) -> Arc<HistogramVec> {
+        let histogram_vec = Arc::new(HistogramVec {
+            label,
+            values: Mutex::new(BTreeMap::new()),
+        });
+        self.register(name, help, Metric::HistogramVec(Arc::clone(&histogram_vec)));
+        histogram_vec
+    }
+
+    // Prometheus text exposition format
+    fn render(entries: &[Entry]) -> String {
+        let mut output = String::new();
+        for entry in entries {
+            let kind = match entry.metric {
+                Metric::Gauge(_) | Metric::GaugeVec(_) => "gauge",
+                Metric::HistogramVec(_) => "histogram",
+                _ => "counter",
+            };
+            let _ = writeln!(output, "# HELP {} {}", entry.name, entry.help);
+            let _ = writeln!(output, "# TYPE {} {}", entry.name, kind);
+            match entry.metric {
+                Metric::Counter(ref c) => {
+                    let _ = writeln!(output, "{} {}", entry.name, c.get());
+                }
+                Metric::Gauge(ref g) => {
+                    let _ = writeln!(output, "{} {}", entry.name, g.get());
+                }
+                Metric::CounterVec(ref v) => {
+                    for (value, count) in v.values.lock().unwrap().iter() {
+                        let value = escape_label(value);
+                        let _ = writeln!(output, "{}{{{}=\"{}\"}} {}", entry.name, v.label, value, count);
+                    }
+                }
+                Metric::GaugeVec(ref v) => {
+                    for (value, gauge) in v.values.lock().unwrap().iter() {
+                        let value = escape_label(value);
+                        let _ = writeln!(output, "{}{{{}=\"{}\"}} {}", entry.name, v.label, value, gauge);
+                    }
+                }
+                Metric::HistogramVec(ref v) => {
+                    for (value, histogram) in v.values.lock().unwrap().iter() {
+                        let (name, label, value) = (entry.name, v.label, escape_label(value));
+                        let mut cumulative = 0;
+                        for (bound, count) in DURATION_BUCKETS.iter().zip(&histogram.counts) {
+                            cumulative += count;
+                            let _ = writeln!(
+                                output,
+                                "{}_bucket{{{}=\"{}\",le=\"{}\"}} {}",
+                                name, label, value, bound, cumulative
+                            );
+                        }
+                        let _ = writeln!(
+                            output,
+                            "{}_bucket{{{}=\"{}\",le=\"+Inf\"}} {}",
+                            name, label, value, histogram.count
+                        );
+                        let _ = writeln!(
+                            output,
+                            "{}_sum{{{}=\"{}\"}} {}",
+                            name, label, value, histogram.sum
+                        );
+                        let _ = writeln!(
+                            output,
+                            "{}_count{{{}=\"{}\"}} {}",
+                            name, label, value, histogram.count
+                        );
+                    }
+                }
+            }
+        }
+        output
+    }
+
+    pub fn start(&self) -> Result<()> {
+        let addr = match self.addr {
+            Some(addr) => addr,
+            None => return Ok(()),
+        };
+        let server = tiny_http::Server::http(addr)
+            .map_err(|e| format!("failed to start monitoring HTTP server at {}: {}", addr, e))?;
+        info!("Prometheus metrics available on http://{}/metrics", addr);
+        let entries = Arc::clone(&self.entries);
+        spawn_thread("metrics", move || loop {
+            let request = match server.recv() {
+                Ok(request) => request,
+                Err(e) => {
+                    warn!("monitoring HTTP server failed: {}", e);
+                    continue;
+                }
+            };
+            let response = if request.url() == "/metrics" {
+                let body = Metrics::render(&entries.lock().unwrap());
+                tiny_http::Response::from_string(body)
+            } else {
+                tiny_http::Response::from_string("not found").with_status_code(404)
+            };
+            if let Err(e) = request.respond(response) {
+                debug!("failed to send metrics: {}", e);
+            }
+        });
+        Ok(())
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new(None);
+        let counter = metrics.counter("blocks_total", "Indexed blocks");
+        let gauge = metrics.gauge("height", "Indexed height");
+        let methods = metrics.counter_vec("rpc_requests_total", "RPC requests", "method");
+        let levels = metrics.gauge_vec("level_files", "SST files", "level");
+        counter.inc_by(3);
+        gauge.set(42.0);
+        methods.inc("server.ping");
+        methods.inc("server.ping");
+        methods.inc("server.version");
+        levels.set("1", 4.0);
+        levels.set("0", 2.0);
+        levels.set("1", 3.0);
+        let output = Metrics::render(&metrics.entries.lock().unwrap());
+        assert_eq!(
+            output,
+            "# HELP blocks_total Indexed blocks\n\
+             # TYPE blocks_total counter\n\
+             blocks_total 3\n\
+             # HELP height Indexed height\n\
+             # TYPE height gauge\n\
+             height 42\n\
+             # HELP rpc_requests_total RPC requests\n\
+             # TYPE rpc_requests_total counter\n\
+             rpc_requests_total{method=\"server.ping\"} 2\n\
+             rpc_requests_total{method=\"server.version\"} 1\n\
+             # HELP level_files SST files\n\
+             # TYPE level_files gauge\n\
+             level_files{level=\"0\"} 2\n\
+             level_files{level=\"1\"} 3\n"
+        );
+    }
+
+    #[test]
+    fn test_render_histogram() {
+        let metrics = Metrics::new(None);
+        let durations = metrics.histogram_vec("rpc_duration_seconds", "RPC latency", "method");
+        durations.observe("server.ping", Duration::from_millis(3));
+        durations.observe("server.ping", Duration::from_millis(40));
+        durations.observe("server.ping", Duration::from_secs(100));
+        assert_eq!(durations.time("server.version", || 7), 7);
+        let output = Metrics::render(&metrics.entries.lock().unwrap());
+        let lines: Vec<&str> = output.lines().collect();
+        let bucket = |le: &str, count: u64| {
+            format!("rpc_duration_seconds_bucket{{method=\"server.ping\",le=\"{}\"}} {}", le, count)
+        };
+        assert_eq!(lines[0], "# HELP rpc_duration_seconds RPC latency");
+        assert_eq!(lines[1], "# TYPE rpc_duration_seconds histogram");
+        assert_eq!(lines[2], bucket("0.001", 0));
+        assert_eq!(lines[4], bucket("0.005", 1));
+        assert_eq!(lines[7], bucket("0.05", 2));
+        assert_eq!(lines[16], bucket("60", 2));
+        assert_eq!(lines[17], bucket("+Inf", 3));
+        assert!(lines[18].starts_with("rpc_duration_seconds_sum{method=\"server.ping\"} 100.04"));
+        assert_eq!(lines[19], "rpc_duration_seconds_count{method=\"server.ping\"} 3");
+        assert_eq!(lines[37], "rpc_duration_seconds_count{method=\"server.version\"} 1");
+        assert_eq!(lines.len(), 38);
+    }
+}
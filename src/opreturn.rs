@@ -0,0 +1,417 @@
+use bincode;
+use bitcoin::blockdata::block::Block;
+use bitcoin::blockdata::opcodes;
+use bitcoin::blockdata::script::{Instruction, Script};
+use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::consensus::encode::deserialize;
+use bitcoin::util::hash::BitcoinHash;
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use crypto::rc4::Rc4;
+use crypto::symmetriccipher::SynchronousStreamCipher;
+
+use crate::daemon::Daemon;
+use crate::errors::*;
+use crate::index::Index;
+use crate::signal::Waiter;
+use crate::store::{DBStore, ReadStore, Row};
+use crate::util::{full_hash, Bytes, FullHash};
+
+// Bytes of the data stored in the keys (longer prefixes are matched on the values)
+const PREFIX_LEN: usize = 8;
+
+// Blocks indexed again after a reorg (the rows of the stale ones are skipped by `find`)
+const REORG_DEPTH: usize = 100;
+
+// Last block indexed, with its height
+const TIP_KEY: &[u8] = b"Z";
+
+// Prefix of the (decrypted) Counterparty messages
+const COUNTERPARTY_PREFIX: &[u8] = b"CNTRPRTY";
+
+//
+// Key of a row storing the data of an OP_RETURN output
+// (the value is an `OpReturnValue`)
+//
+#[derive(Serialize, Deserialize)]
+struct OpReturnKey {
+    code: u8,
+    prefix: [u8; PREFIX_LEN], // zero-padded
+    txid: FullHash,
+    vout: u16,
+}
+
+#[derive(Serialize, Deserialize)]
+struct OpReturnValue {
+    blockhash: FullHash,
+    height: u32,
+    data: Bytes,
+}
+
+//
+// OP_RETURN output of a confirmed transaction
+//
+#[derive(Debug, PartialEq)]
+pub struct OpReturn {
+    pub txid: Sha256dHash,
+    pub vout: usize,
+    pub blockhash: Sha256dHash,
+    pub height: usize,
+    pub data: Bytes,
+}
+
+// The data pushed after the OP_RETURN, concatenated
+pub fn op_return_data(script: &Script) -> Option<Bytes> {
+    if !script.is_op_return() {
+        return None;
+    }
+    let mut data = vec![];
+    for instruction in Script::from(script[1..].to_vec()).iter(false) {
+        match instruction {
+            Instruction::PushBytes(bytes) => data.extend_from_slice(bytes),
+            Instruction::Op(_) => (),
+            Instruction::Error(_) => break,
+        }
+    }
+    Some(data)
+}
+
+//
+// Key of a row storing the message of a Counterparty transaction, ordered
+// by height and position in the block (the value is a `CounterpartyValue`)
+//
+fn counterparty_key(height: usize, position: usize) -> Bytes {
+    [&[b'C'][..], &(height as u32).to_be_bytes(), &(position as u32).to_be_bytes()].concat()
+}
+
+#[derive(Serialize, Deserialize)]
+struct CounterpartyValue {
+    txid: FullHash,
+    blockhash: FullHash,
+    message: Bytes,
+}
+
+//
+// Counterparty transaction, with its message (without the prefix)
+//
+#[derive(Debug, PartialEq)]
+pub struct CounterpartyTx {
+    pub txid: Sha256dHash,
+    pub blockhash: Sha256dHash,
+    pub height: usize,
+    pub message: Bytes,
+}
+
+fn arc4(key: &[u8], data: &[u8]) -> Bytes {
+    let mut output = vec![0u8; data.len()];
+    Rc4::new(key).process(data, &mut output);
+    output
+}
+
+// The data keys of a bare multisig output (all but the last key), without
+// their first and last bytes
+fn multisig_data(script: &Script) -> Option<Bytes> {
+    let instructions: Vec<Instruction> = script.iter(false).collect();
+    match instructions.last() {
+        Some(Instruction::Op(opcodes::all::OP_CHECKMULTISIG)) => (),
+        _ => return None,
+    }
+    let keys: Vec<&[u8]> = instructions
+        .iter()
+        .filter_map(|instruction| match instruction {
+            Instruction::PushBytes(key) if key.len() == 33 || key.len() == 65 => Some(*key),
+            _ => None,
+        })
+        .collect();
+    let (_, data_keys) = keys.split_last()?;
+    let mut data = vec![];
+    for key in data_keys {
+        data.extend_from_slice(&key[1..key.len() - 1]);
+    }
+    Some(data)
+}
+
+// The message of a Counterparty transaction, embedded in its OP_RETURN or
+// bare multisig outputs (ARC4-encrypted with its first input's txid)
+pub fn counterparty_message(txn: &Transaction) -> Option<Bytes> {
+    if txn.is_coin_base() {
+        return None;
+    }
+    let mut key = txn.input[0].previous_output.txid[..].to_vec();
+    key.reverse(); // in hex (display) order
+    let mut message: Option<Bytes> = None;
+    for output in &txn.output {
+        let script = &output.script_pubkey;
+        let payload = if let Some(data) = op_return_data(script) {
+            arc4(&key, &data)
+        } else if let Some(data) = multisig_data(script) {
+            // the chunks are prefixed by their length
+            let chunk = arc4(&key, &data);
+            match chunk.split_first() {
+                Some((&len, rest)) if len as usize <= rest.len() => rest[..len as usize].to_vec(),
+                _ => continue,
+            }
+        } else {
+            continue;
+        };
+        if let Some(data) = payload.strip_prefix(COUNTERPARTY_PREFIX) {
+            message.get_or_insert_with(Vec::new).extend_from_slice(data);
+        }
+    }
+    message
+}
+
+fn block_rows(block: &Block, height: usize) -> Vec<Row> {
+    let blockhash = full_hash(&block.bitcoin_hash()[..]);
+    let mut rows = vec![];
+    for (position, txn) in block.txdata.iter().enumerate() {
+        let txid = full_hash(&txn.txid()[..]);
+        if let Some(message) = counterparty_message(txn) {
+            let value = CounterpartyValue {
+                txid,
+                blockhash,
+                message,
+            };
+            rows.push(Row {
+                key: counterparty_key(height, position),
+                value: bincode::serialize(&value).unwrap(),
+            });
+        }
+        for (vout, output) in txn.output.iter().enumerate() {
+            let data = match op_return_data(&output.script_pubkey) {
+                Some(data) => data,
+                None => continue,
+            };
+            let mut prefix = [0u8; PREFIX_LEN];
+            let len = data.len().min(PREFIX_LEN);
+            prefix[..len].copy_from_slice(&data[..len]);
+            let key = OpReturnKey {
+                code: b'N',
+                prefix,
+                txid,
+                vout: vout as u16,
+            };
+            let value = OpReturnValue {
+                blockhash,
+                height: height as u32,
+                data,
+            };
+            rows.push(Row {
+                key: bincode::serialize(&key).unwrap(),
+                value: bincode::serialize(&value).unwrap(),
+            });
+        }
+    }
+    rows
+}
+
+// OP_RETURN outputs whose data starts with `prefix`, within the heights range
+// (including the ones of stale blocks, which the caller should skip)
+pub fn find(store: &dyn ReadStore, prefix: &[u8], from: usize, to: usize) -> Vec<OpReturn> {
+    let key_prefix = [&[b'N'][..], &prefix[..prefix.len().min(PREFIX_LEN)]].concat();
+    let mut result: Vec<OpReturn> = store
+        .scan(&key_prefix)
+        .iter()
+        .filter_map(|row| {
+            let key: OpReturnKey =
+                bincode::deserialize(&row.key).expect("failed to parse OpReturnKey");
+            let value: OpReturnValue =
+                bincode::deserialize(&row.value).expect("failed to parse OpReturnValue");
+            let height = value.height as usize;
+            if height < from || height > to || !value.data.starts_with(prefix) {
+                return None;
+            }
+            Some(OpReturn {
+                txid: deserialize(&key.txid).unwrap(),
+                vout: key.vout as usize,
+                blockhash: deserialize(&value.blockhash).unwrap(),
+                height,
+                data: value.data,
+            })
+        })
+        .collect();
+    result.sort_by_key(|op_return| (op_return.height, op_return.txid, op_return.vout));
+    result
+}
+
+// Counterparty transactions within the heights range, in the blocks' order
+// (including the ones of stale blocks, which the caller should skip)
+pub fn find_counterparty(store: &dyn ReadStore, from: usize, to: usize) -> Vec<CounterpartyTx> {
+    let mut result = vec![];
+    for height in from..=to {
+        let prefix = [&[b'C'][..], &(height as u32).to_be_bytes()].concat();
+        for row in store.scan(&prefix) {
+            let value: CounterpartyValue =
+                bincode::deserialize(&row.value).expect("failed to parse CounterpartyValue");
+            result.push(CounterpartyTx {
+                txid: deserialize(&value.txid).unwrap(),
+                blockhash: deserialize(&value.blockhash).unwrap(),
+                height,
+                message: value.message,
+            });
+        }
+    }
+    result
+}
+
+//
+// Index of the OP_RETURN outputs' data (and of the Counterparty
+// transactions), updated per block
+//
+pub struct OpReturnIndex {
+    daemon: Daemon,
+    batch_size: usize,
+}
+
+impl OpReturnIndex {
+    pub fn new(daemon: &Daemon, batch_size: usize) -> Result<OpReturnIndex> {
+        Ok(OpReturnIndex {
+            daemon: daemon.reconnect()?,
+            batch_size,
+        })
+    }
+
+    fn tip(store: &DBStore) -> Option<(Sha256dHash, usize)> {
+        store.get(TIP_KEY).map(|tip| {
+            let (hash, height): (FullHash, u32) = bincode::deserialize(&tip).unwrap();
+            (deserialize(&hash).unwrap(), height as usize)
+        })
+    }
+
+    pub fn update(&self, store: &DBStore, index: &Index, waiter: &Waiter) -> Result<()> {
+        let best = match index.best_header() {
+            Some(best) => best,
+            None => return Ok(()),
+        };
+        let start = match OpReturnIndex::tip(store) {
+            None => 0,
+            Some((hash, height)) => match index.get_header(height) {
+                Some(header) if *header.hash() == hash => height + 1,
+                _ => height.saturating_sub(REORG_DEPTH),
+            },
+        };
+        if start > best.height() {
+            return Ok(());
+        }
+        info!("indexing OP_RETURN outputs from height {} to {}", start, best.height());
+        let heights: Vec<usize> = (start..=best.height()).collect();
+        for chunk in heights.chunks(self.batch_size) {
+            waiter.poll()?;
+            let headers = chunk
+                .iter()
+                .map(|height| index.get_header(*height).chain_err(|| "missing header"))
+                .collect::<Result<Vec<_>>>()?;
+            let blockhashes: Vec<Sha256dHash> = headers.iter().map(|h| *h.hash()).collect();
+            let mut rows = vec![];
+            for (header, block) in headers.iter().zip(self.daemon.getblocks(&blockhashes)?) {
+                rows.extend(block_rows(&block, header.height()));
+            }
+            let last = headers.last().unwrap();
+            rows.push(Row {
+                key: TIP_KEY.to_vec(),
+                value: bincode::serialize(&(full_hash(&last.hash()[..]), last.height() as u32))
+                    .unwrap(),
+            });
+            store.write_batch(rows, vec![]);
+            debug!("OP_RETURN outputs indexed up to height {}", last.height());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::blockdata::script::Builder;
+    use bitcoin::blockdata::opcodes;
+    use bitcoin::blockdata::transaction::{OutPoint, TxIn, TxOut};
+    use bitcoin::network::constants::Network;
+    use bitcoin_hashes::Hash;
+
+    use crate::store::{TempStore, WriteStore};
+
+    fn op_return(pushes: &[&[u8]]) -> Script {
+        let mut builder = Builder::new().push_opcode(opcodes::all::OP_RETURN);
+        for push in pushes {
+            builder = builder.push_slice(push);
+        }
+        builder.into_script()
+    }
+
+    #[test]
+    fn test_find() {
+        assert_eq!(op_return_data(&op_return(&[b"ab", b"cd"])), Some(b"abcd".to_vec()));
+        assert_eq!(op_return_data(&op_return(&[])), Some(vec![]));
+        let mut block = genesis_block(Network::Regtest);
+        assert_eq!(op_return_data(&block.txdata[0].output[0].script_pubkey), None);
+
+        let store = TempStore::new("opreturn");
+        for data in &[&b"CNTRPRTY-long-message"[..], b"CN", b"omni"] {
+            block.txdata[0].output.push(TxOut {
+                value: 0,
+                script_pubkey: op_return(&[data]),
+            });
+        }
+        store.write(block_rows(&block, 5));
+        let txid = block.txdata[0].txid();
+        let vouts = |prefix: &[u8], from, to| -> Vec<usize> {
+            find(&store, prefix, from, to).iter().map(|o| o.vout).collect()
+        };
+        assert_eq!(vouts(b"CN", 0, 10), vec![1, 2]);
+        assert_eq!(vouts(b"CNTRPRTY-long", 0, 10), vec![1]);
+        assert_eq!(vouts(b"CN\0", 0, 10), Vec::<usize>::new());
+        assert_eq!(vouts(b"omni", 6, 10), Vec::<usize>::new());
+        let found = find(&store, b"omni", 5, 5);
+        assert_eq!((found[0].txid, found[0].height), (txid, 5));
+        assert_eq!(found[0].data, b"omni".to_vec());
+    }
+
+    #[test]
+    fn test_counterparty() {
+        let prev_txid = Sha256dHash::from_slice(&[7u8; 32]).unwrap();
+        let key = prev_txid[..].iter().rev().cloned().collect::<Vec<u8>>();
+        // a 1-of-2 multisig, whose first key holds a chunk
+        let mut chunk = vec![14];
+        chunk.extend_from_slice(b"CNTRPRTY world");
+        chunk.resize(31, 0);
+        let data_key = [&[2u8][..], &arc4(&key, &chunk), &[0u8]].concat();
+        let multisig = Builder::new()
+            .push_int(1)
+            .push_slice(&data_key)
+            .push_slice(&[3u8; 33])
+            .push_int(2)
+            .push_opcode(opcodes::all::OP_CHECKMULTISIG)
+            .into_script();
+        let outputs = vec![
+            op_return(&[&arc4(&key, b"CNTRPRTYhello")]),
+            multisig,
+            op_return(&[b"CNTRPRTYplain"]),
+        ];
+        let txn = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint { txid: prev_txid, vout: 0 },
+                script_sig: Script::new(),
+                sequence: 0xFFFFFFFF,
+                witness: vec![],
+            }],
+            output: outputs
+                .into_iter()
+                .map(|script_pubkey| TxOut { value: 0, script_pubkey })
+                .collect(),
+        };
+        assert_eq!(counterparty_message(&txn), Some(b"hello world".to_vec()));
+
+        let mut block = genesis_block(Network::Regtest);
+        assert_eq!(counterparty_message(&block.txdata[0]), None);
+        block.txdata.push(txn.clone());
+        let store = TempStore::new("xcp");
+        store.write(block_rows(&block, 5));
+        assert!(find_counterparty(&store, 0, 4).is_empty());
+        let found = find_counterparty(&store, 3, 7);
+        assert_eq!(found.len(), 1);
+        assert_eq!((found[0].txid, found[0].height), (txn.txid(), 5));
+        assert_eq!(found[0].message, b"hello world".to_vec());
+    }
+}
@@ -0,0 +1,275 @@
+use bitcoin::blockdata::block::Block;
+use bitcoin::consensus::encode::{deserialize, serialize};
+use bitcoin::network::address::Address;
+use bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
+use bitcoin::network::message_blockdata::{InvType, Inventory};
+use bitcoin::network::message_network::VersionMessage;
+use bitcoin::BitcoinHash;
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use bitcoin_hashes::Hash;
+use std::collections::HashMap;
+use std::io::{BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::errors::*;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+const NODE_NETWORK: u64 = 1; // peer serves the full block chain
+const MAX_PAYLOAD_SIZE: usize = 32 << 20;
+
+//
+// Messages received from peers (only the ones used here are decoded,
+// since rust-bitcoin panics on some inventory types)
+//
+enum Message {
+    Version(VersionMessage),
+    Verack,
+    Block(Block),
+    Ping(u64),
+    NotFound,
+    Other(String),
+}
+
+//
+// Connection to a single Bitcoin P2P peer
+//
+struct Peer {
+    addr: SocketAddr,
+    magic: u32,
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+impl Peer {
+    fn connect(addr: SocketAddr, magic: u32) -> Result<Peer> {
+        let stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)
+            .chain_err(|| format!("failed to connect to peer {}", addr))?;
+        stream
+            .set_read_timeout(Some(READ_TIMEOUT))
+            .chain_err(|| "failed to set read timeout")?;
+        let reader = BufReader::new(stream.try_clone().chain_err(|| "failed to clone stream")?);
+        let mut peer = Peer {
+            addr,
+            magic,
+            stream,
+            reader,
+        };
+        peer.handshake()?;
+        Ok(peer)
+    }
+
+    fn send(&mut self, payload: NetworkMessage) -> Result<()> {
+        let message = RawNetworkMessage {
+            magic: self.magic,
+            payload,
+        };
+        self.stream
+            .write_all(&serialize(&message))
+            .chain_err(|| format!("failed to send to peer {}", self.addr))
+    }
+
+    fn recv(&mut self) -> Result<Message> {
+        let mut header = [0u8; 24]; // magic, command, length and checksum
+        self.reader
+            .read_exact(&mut header)
+            .chain_err(|| format!("failed to receive from peer {}", self.addr))?;
+        let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        if magic != self.magic {
+            bail!("unexpected magic {:x} from peer {}", magic, self.addr);
+        }
+        let command = String::from_utf8_lossy(&header[4..16])
+            .trim_end_matches('\0')
+            .to_owned();
+        let len = u32::from_le_bytes([header[16], header[17], header[18], header[19]]) as usize;
+        if len > MAX_PAYLOAD_SIZE {
+            bail!("too large {} message ({} bytes) from peer {}", command, len, self.addr);
+        }
+        let mut payload = vec![0u8; len];
+        self.reader
+            .read_exact(&mut payload)
+            .chain_err(|| format!("failed to receive from peer {}", self.addr))?;
+        if Sha256dHash::hash(&payload)[..4] != header[20..] {
+            bail!("invalid {} message checksum from peer {}", command, self.addr);
+        }
+        let invalid = || format!("invalid {} message from peer {}", command, self.addr);
+        Ok(match command.as_str() {
+            "version" => Message::Version(deserialize(&payload).chain_err(invalid)?),
+            "verack" => Message::Verack,
+            "block" => Message::Block(deserialize(&payload).chain_err(invalid)?),
+            "ping" => Message::Ping(deserialize(&payload).chain_err(invalid)?),
+            "notfound" => Message::NotFound,
+            _ => Message::Other(command),
+        })
+    }
+
+    fn handshake(&mut self) -> Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let version = VersionMessage::new(
+            /*services=*/ 0,
+            timestamp.as_secs() as i64,
+            Address::new(&self.addr, 0),
+            Address::new(&self.stream.local_addr().unwrap_or(self.addr), 0),
+            /*nonce=*/ timestamp.subsec_nanos() as u64,
+            format!("/addrindexrs:{}/", env!("CARGO_PKG_VERSION")),
+            /*start_height=*/ 0,
+        );
+        self.send(NetworkMessage::Version(version))?;
+        let (mut version_received, mut verack_received) = (false, false);
+        while !(version_received && verack_received) {
+            match self.recv()? {
+                Message::Version(version) => {
+                    if version.services & NODE_NETWORK == 0 {
+                        bail!("peer {} does not serve historical blocks", self.addr);
+                    }
+                    self.send(NetworkMessage::Verack)?;
+                    version_received = true;
+                }
+                Message::Verack => verack_received = true,
+                _ => (),
+            }
+        }
+        debug!("connected to peer {}", self.addr);
+        Ok(())
+    }
+
+    fn getblocks(&mut self, blockhashes: &[Sha256dHash]) -> Result<Vec<Block>> {
+        let inventory = blockhashes
+            .iter()
+            .map(|hash| Inventory {
+                inv_type: InvType::WitnessBlock,
+                hash: *hash,
+            })
+            .collect();
+        self.send(NetworkMessage::GetData(inventory))?;
+        // blocks may be received in any order
+        let mut blocks: HashMap<Sha256dHash, Block> = HashMap::new();
+        while blocks.len() < blockhashes.len() {
+            match self.recv()? {
+                Message::Block(block) => {
+                    let hash = block.bitcoin_hash();
+                    if !block.check_merkle_root() || !block.check_witness_commitment() {
+                        bail!("invalid block {} from peer {}", hash, self.addr);
+                    }
+                    blocks.insert(hash, block);
+                }
+                Message::NotFound => bail!("peer {} does not have the requested blocks", self.addr),
+                Message::Ping(nonce) => self.send(NetworkMessage::Pong(nonce))?,
+                Message::Other(command) => trace!("ignoring {} from peer {}", command, self.addr),
+                _ => (),
+            }
+        }
+        blockhashes
+            .iter()
+            .map(|hash| {
+                blocks
+                    .remove(hash)
+                    .chain_err(|| format!("block {} not received from peer {}", hash, self.addr))
+            })
+            .collect()
+    }
+}
+
+//
+// Download blocks (pruned by the local bitcoind) from the configured peers
+//
+pub struct BlockFetcher {
+    peers: Vec<SocketAddr>,
+    magic: u32,
+    peer: Mutex<Option<Peer>>,
+}
+
+impl BlockFetcher {
+    pub fn new(peers: Vec<SocketAddr>, magic: u32) -> BlockFetcher {
+        BlockFetcher {
+            peers,
+            magic,
+            peer: Mutex::new(None),
+        }
+    }
+
+    pub fn getblocks(&self, blockhashes: &[Sha256dHash]) -> Result<Vec<Block>> {
+        let mut peer = self.peer.lock().unwrap();
+        // keep using the current peer, and try the other ones on failure
+        let mut last_error = None;
+        for addr in &self.peers {
+            if peer.is_none() {
+                match Peer::connect(*addr, self.magic) {
+                    Ok(p) => *peer = Some(p),
+                    Err(e) => {
+                        warn!("{}", e);
+                        last_error = Some(e);
+                        continue;
+                    }
+                }
+            }
+            match peer.as_mut().unwrap().getblocks(blockhashes) {
+                Ok(blocks) => return Ok(blocks),
+                Err(e) => {
+                    warn!("{}", e);
+                    last_error = Some(e);
+                    *peer = None;
+                }
+            }
+        }
+        let error = last_error.unwrap_or_else(|| "no P2P peers configured".into());
+        Err(error).chain_err(|| format!("failed to download {} blocks", blockhashes.len()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::network::constants::Network;
+    use std::net::TcpListener;
+    use std::thread;
+
+    // Serve the genesis block to a single client, and nothing else
+    fn fake_peer(listener: TcpListener, magic: u32) {
+        let (stream, _) = listener.accept().unwrap();
+        let mut peer = Peer {
+            addr: stream.peer_addr().unwrap(),
+            magic,
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            stream,
+        };
+        let mut genesis = Some(genesis_block(Network::Regtest));
+        loop {
+            match peer.recv() {
+                Ok(Message::Version(version)) => {
+                    let version = VersionMessage { services: NODE_NETWORK, ..version };
+                    peer.send(NetworkMessage::Version(version)).unwrap();
+                    peer.send(NetworkMessage::Verack).unwrap();
+                }
+                Ok(Message::Other(ref command)) if command == "getdata" => match genesis.take() {
+                    Some(block) => {
+                        peer.send(NetworkMessage::Ping(1)).unwrap();
+                        peer.send(NetworkMessage::Block(block)).unwrap();
+                    }
+                    None => peer.send(NetworkMessage::NotFound(vec![])).unwrap(),
+                },
+                Ok(_) => (),
+                Err(_) => return,
+            }
+        }
+    }
+
+    #[test]
+    fn test_getblocks() {
+        let magic = Network::Regtest.magic();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || fake_peer(listener, magic));
+
+        let fetcher = BlockFetcher::new(vec![addr], magic);
+        let genesis = genesis_block(Network::Regtest);
+        let blocks = fetcher.getblocks(&[genesis.bitcoin_hash()]).unwrap();
+        assert_eq!(blocks, vec![genesis]);
+        assert!(fetcher.getblocks(&[Sha256dHash::default()]).is_err());
+    }
+}
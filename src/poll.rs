@@ -0,0 +1,304 @@
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::errors::*;
+use crate::tls::TlsStream;
+use crate::websocket;
+
+//
+// Non-blocking sockets of the RPC server, multiplexed by poll(2)
+//
+
+// Requests (and WebSocket messages) larger than this close their connection
+const MAX_REQUEST_SIZE: usize = 16 << 20;
+// Read from a socket before letting the other ones be served
+const MAX_READ_SIZE: usize = 1 << 20;
+
+pub fn pollfd(fd: RawFd, events: libc::c_short) -> libc::pollfd {
+    libc::pollfd {
+        fd,
+        events,
+        revents: 0,
+    }
+}
+
+// Wait until the given fds are ready (or the timeout is elapsed)
+pub fn wait(fds: &mut [libc::pollfd], timeout: Option<Duration>) -> Result<()> {
+    let timeout = timeout.map_or(-1, |timeout| timeout.as_millis().min(i32::MAX as u128) as i32);
+    let ret = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+    if ret < 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err).chain_err(|| "poll failed");
+        }
+    }
+    Ok(())
+}
+
+//
+// Wakes up a poll loop from the other threads, by making its socket readable
+//
+#[derive(Clone)]
+pub struct Waker {
+    writer: Arc<UnixStream>,
+}
+
+impl Waker {
+    // Returns the waker, and the socket to be polled (and drained) by the loop
+    pub fn new() -> Result<(Waker, UnixStream)> {
+        let (reader, writer) = UnixStream::pair().chain_err(|| "failed to create socket pair")?;
+        for socket in &[&reader, &writer] {
+            socket
+                .set_nonblocking(true)
+                .chain_err(|| "failed to set socket as non-blocking")?;
+        }
+        let writer = Arc::new(writer);
+        Ok((Waker { writer }, reader))
+    }
+
+    pub fn wake(&self) {
+        // a full socket is readable already
+        let _ = (&*self.writer).write(&[0]);
+    }
+
+    pub fn drain(reader: &UnixStream) {
+        let mut buf = [0u8; 256];
+        while let Ok(n) = (&*reader).read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+        }
+    }
+}
+
+//
+// Accepted connection (set as non-blocking)
+//
+pub enum Socket {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    Tls(TlsStream),
+}
+
+impl Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Socket::Tcp(stream) => stream.read(buf),
+            Socket::Unix(stream) => stream.read(buf),
+            Socket::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Socket::Tcp(stream) => stream.write(buf),
+            Socket::Unix(stream) => stream.write(buf),
+            Socket::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsRawFd for Socket {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Socket::Tcp(stream) => stream.as_raw_fd(),
+            Socket::Unix(stream) => stream.as_raw_fd(),
+            Socket::Tls(stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+enum Framing {
+    Lines, // newline-delimited messages
+    WebSocket(websocket::Codec),
+}
+
+//
+// Buffered messages of a connection: the requests are read into its input
+// buffer, and the replies are written from its output buffer.
+//
+pub struct Stream {
+    socket: Socket,
+    framing: Framing,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    eof: bool,
+    more: bool, // whether the socket may have more data than it signals
+}
+
+impl Stream {
+    pub fn new(socket: Socket, websocket: bool) -> Stream {
+        let framing = if websocket {
+            Framing::WebSocket(websocket::Codec::new())
+        } else {
+            Framing::Lines
+        };
+        Stream {
+            socket,
+            framing,
+            input: vec![],
+            output: vec![],
+            eof: false,
+            more: true,
+        }
+    }
+
+    // Read the available data, returning the complete messages
+    pub fn receive(&mut self) -> Result<Vec<String>> {
+        let mut buf = [0u8; 16 << 10];
+        let mut size = 0;
+        self.more = false;
+        while !self.eof {
+            if size >= MAX_READ_SIZE {
+                // TLS sessions may keep a decrypted record, so it is read again
+                self.more = true;
+                break;
+            }
+            match self.socket.read(&mut buf) {
+                Ok(0) => self.eof = true,
+                Ok(n) => {
+                    self.input.extend_from_slice(&buf[..n]);
+                    size += n;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e).chain_err(|| "failed to read a request"),
+            }
+        }
+        let messages = match self.framing {
+            Framing::Lines => {
+                if self.input.starts_with(&[22, 3, 1]) {
+                    // (very) naive SSL handshake detection
+                    bail!("invalid request - maybe SSL-encrypted data?: {:?}", self.input);
+                }
+                let mut end = self.input.iter().rposition(|b| *b == b'\n').map_or(0, |i| i + 1);
+                if self.eof {
+                    end = self.input.len(); // including the unterminated last line
+                } else if end == 0 && self.input.len() > MAX_REQUEST_SIZE {
+                    bail!("too large request: more than {} bytes", MAX_REQUEST_SIZE);
+                }
+                let lines: Vec<u8> = self.input.drain(..end).collect();
+                lines
+                    .split(|b| *b == b'\n')
+                    .filter(|line| !line.is_empty())
+                    .map(<[u8]>::to_vec)
+                    .collect()
+            }
+            Framing::WebSocket(ref mut codec) => codec.decode(&mut self.input, &mut self.output)?,
+        };
+        messages
+            .into_iter()
+            .map(|message| String::from_utf8(message).chain_err(|| "invalid UTF8"))
+            .collect()
+    }
+
+    pub fn send(&mut self, message: &str) {
+        match self.framing {
+            Framing::Lines => {
+                self.output.extend_from_slice(message.as_bytes());
+                self.output.push(b'\n');
+            }
+            Framing::WebSocket(ref codec) => codec.encode(message.as_bytes(), &mut self.output),
+        }
+    }
+
+    // Write the buffered output, until the socket is full
+    pub fn flush(&mut self) -> Result<()> {
+        let mut written = 0;
+        while written < self.output.len() {
+            match self.socket.write(&self.output[written..]) {
+                Ok(0) => bail!("failed to send a reply: connection closed"),
+                Ok(n) => written += n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e).chain_err(|| "failed to send a reply"),
+            }
+        }
+        self.output.drain(..written);
+        Ok(())
+    }
+
+    // Send a close frame to the WebSocket clients
+    pub fn close(&mut self) {
+        if let Framing::WebSocket(ref mut codec) = self.framing {
+            codec.close(&mut self.output);
+        }
+    }
+
+    // Whether the client closed the connection
+    pub fn is_closed(&self) -> bool {
+        match self.framing {
+            Framing::Lines => self.eof,
+            Framing::WebSocket(ref codec) => self.eof || codec.is_closed(),
+        }
+    }
+
+    pub fn has_more(&self) -> bool {
+        self.more
+    }
+
+    pub fn pending_output(&self) -> usize {
+        self.output.len()
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stream() {
+        let (local, mut remote) = UnixStream::pair().unwrap();
+        local.set_nonblocking(true).unwrap();
+        let mut stream = Stream::new(Socket::Unix(local), false);
+        assert!(stream.receive().unwrap().is_empty());
+        assert!(!stream.has_more());
+
+        remote.write_all(b"{\"id\":1}\n\n{\"id\":").unwrap();
+        assert_eq!(stream.receive().unwrap(), vec!["{\"id\":1}"]);
+        remote.write_all(b"2}\r\n{\"id\":3}").unwrap();
+        assert_eq!(stream.receive().unwrap(), vec!["{\"id\":2}\r"]);
+
+        stream.send("{\"result\":null}");
+        stream.flush().unwrap();
+        assert_eq!(stream.pending_output(), 0);
+        let mut reply = [0u8; 16];
+        remote.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"{\"result\":null}\n");
+
+        // the last request may be unterminated
+        drop(remote);
+        assert_eq!(stream.receive().unwrap(), vec!["{\"id\":3}"]);
+        assert!(stream.is_closed());
+    }
+
+    #[test]
+    fn test_waker() {
+        let (waker, reader) = Waker::new().unwrap();
+        let mut fds = [pollfd(reader.as_raw_fd(), libc::POLLIN)];
+        wait(&mut fds, Some(Duration::from_millis(0))).unwrap();
+        assert_eq!(fds[0].revents, 0);
+        let other = waker.clone();
+        std::thread::spawn(move || other.wake()).join().unwrap();
+        wait(&mut fds, None).unwrap();
+        assert_eq!(fds[0].revents, libc::POLLIN);
+        Waker::drain(&reader);
+        fds[0].revents = 0;
+        wait(&mut fds, Some(Duration::from_millis(0))).unwrap();
+        assert_eq!(fds[0].revents, 0);
+    }
+}
@@ -0,0 +1,140 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::metrics::{Gauge, Metrics};
+
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+struct State {
+    start: Instant,
+    last_log: Instant,
+    indexed: usize, // blocks indexed so far
+    total: usize,   // blocks of the daemon's chain
+    blocks: usize,  // blocks indexed since start
+    rows: usize,    // rows written since start
+}
+
+//
+// Progress of the indexing, logged periodically and exposed as metrics
+//
+pub struct Progress {
+    state: Mutex<Option<State>>,
+    ratio: Arc<Gauge>,
+    blocks_per_second: Arc<Gauge>,
+    rows_per_second: Arc<Gauge>,
+    eta: Arc<Gauge>,
+}
+
+impl Progress {
+    pub fn new(metrics: &Metrics) -> Arc<Progress> {
+        Arc::new(Progress {
+            state: Mutex::new(None),
+            ratio: metrics.gauge(
+                "addrindexrs_sync_progress_ratio",
+                "Indexed blocks, as a fraction of the daemon's blocks",
+            ),
+            blocks_per_second: metrics.gauge(
+                "addrindexrs_sync_blocks_per_second",
+                "Average indexing rate since the sync started (in blocks)",
+            ),
+            rows_per_second: metrics.gauge(
+                "addrindexrs_sync_rows_per_second",
+                "Average indexing rate since the sync started (in DB rows)",
+            ),
+            eta: metrics.gauge(
+                "addrindexrs_sync_eta_seconds",
+                "Estimated time until the sync is over",
+            ),
+        })
+    }
+
+    pub fn start(&self, indexed: usize, total: usize) {
+        let now = Instant::now();
+        *self.state.lock().unwrap() = Some(State {
+            start: now,
+            last_log: now,
+            indexed,
+            total,
+            blocks: 0,
+            rows: 0,
+        });
+        self.ratio.set(ratio(indexed, total));
+    }
+
+    pub fn add(&self, blocks: usize, rows: usize) {
+        let mut state = self.state.lock().unwrap();
+        let state = match state.as_mut() {
+            Some(state) => state,
+            None => return,
+        };
+        state.indexed += blocks;
+        state.blocks += blocks;
+        state.rows += rows;
+
+        let elapsed = state.start.elapsed().as_secs_f64();
+        let blocks_per_second = state.blocks as f64 / elapsed.max(1e-3);
+        let rows_per_second = state.rows as f64 / elapsed.max(1e-3);
+        let eta = eta_seconds(state.total.saturating_sub(state.indexed), blocks_per_second);
+        self.ratio.set(ratio(state.indexed, state.total));
+        self.blocks_per_second.set(blocks_per_second);
+        self.rows_per_second.set(rows_per_second);
+        self.eta.set(eta.unwrap_or(0.0));
+
+        if state.last_log.elapsed() >= LOG_INTERVAL {
+            state.last_log = Instant::now();
+            info!(
+                "indexed {}/{} blocks ({:.1}%), {:.1} blocks/s, {:.0} rows/s, ETA {}",
+                state.indexed,
+                state.total,
+                100.0 * ratio(state.indexed, state.total),
+                blocks_per_second,
+                rows_per_second,
+                eta.map(format_duration).unwrap_or_else(|| "unknown".to_owned()),
+            );
+        }
+    }
+}
+
+fn ratio(indexed: usize, total: usize) -> f64 {
+    match total {
+        0 => 1.0,
+        _ => (indexed as f64 / total as f64).min(1.0),
+    }
+}
+
+fn eta_seconds(remaining: usize, blocks_per_second: f64) -> Option<f64> {
+    if remaining == 0 {
+        return Some(0.0);
+    }
+    if blocks_per_second > 0.0 {
+        Some(remaining as f64 / blocks_per_second)
+    } else {
+        None
+    }
+}
+
+fn format_duration(seconds: f64) -> String {
+    let minutes = (seconds / 60.0).ceil() as u64;
+    match (minutes / (24 * 60), minutes / 60 % 24, minutes % 60) {
+        (0, 0, m) => format!("{}m", m),
+        (0, h, m) => format!("{}h {}m", h, m),
+        (d, h, m) => format!("{}d {}h {}m", d, h, m),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_eta() {
+        assert_eq!(ratio(50, 200), 0.25);
+        assert_eq!(ratio(0, 0), 1.0);
+        assert_eq!(eta_seconds(0, 0.0), Some(0.0));
+        assert_eq!(eta_seconds(100, 0.0), None);
+        assert_eq!(eta_seconds(100, 4.0), Some(25.0));
+        assert_eq!(format_duration(25.0), "1m");
+        assert_eq!(format_duration(2.0 * 3600.0 + 59.0), "2h 1m");
+        assert_eq!(format_duration(3.0 * 86400.0 + 3600.0), "3d 1h 0m");
+    }
+}
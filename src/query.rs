@@ -1,13 +1,50 @@
-use bitcoin::consensus::encode::deserialize;
+use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::consensus::encode::serialize;
+use bitcoin::network::constants::Network;
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::util::address::Address;
+use bitcoin_hashes::hex::ToHex;
 use bitcoin_hashes::sha256d::Hash as Sha256dHash;
-use std::sync::{Arc, RwLock};
+use bitcoin_hashes::Hash;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use serde_json::Value;
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::thread;
+use std::time::Instant;
 
 use crate::app::App;
+use crate::cache::{HistoryCache, TransactionCache};
+use crate::descriptor::Descriptor;
 use crate::errors::*;
-use crate::index::{TxInRow, TxOutRow, TxRow};
-use crate::mempool::Tracker;
+use crate::index::{
+    compute_script_hash, lookup_txid, read_reorgs, tx_num_height, tx_num_position, Reorg, TxInRow,
+    TxNum, TxOutRow, MEMPOOL_HEIGHT,
+};
+use crate::mempool::{self, Ancestors, Conflict, Tracker};
+use crate::metrics::{Counter, Gauge, Metrics};
+use crate::opreturn::{CounterpartyTx, OpReturn};
+use crate::stats::{index_stats, IndexStats};
 use crate::store::ReadStore;
-use crate::util::{HashPrefix, HeaderEntry};
+use crate::utxo::Spenders;
+use crate::trace;
+use crate::util::{full_hash, hash_prefix, FullHash, HeaderEntry};
+use crate::watch::Watchlist;
+use crate::zmq::Publisher;
+
+// More new blocks than this clear the history cache, instead of being fetched
+const MAX_INVALIDATED_BLOCKS: usize = 10;
+// Threads looking up the histories of a batch of script hashes
+const BATCH_THREADS: usize = 8;
+// Funding outputs looked up at once when exporting a history
+const EXPORT_BATCH_SIZE: usize = 1000;
+// Confirmations needed to spend the outputs of a coinbase
+const COINBASE_MATURITY: usize = 100;
 
 //
 // Output of a Transaction
@@ -15,7 +52,24 @@ use crate::util::{HashPrefix, HeaderEntry};
 pub struct Txo {
     pub txid: Sha256dHash,
     pub vout: usize,
-    pub blockindex: usize
+    pub blockindex: usize,
+    pub position: Option<usize>, // in its block (None if unknown, or in the mempool)
+}
+
+impl Txo {
+    // Whether it is created by the coinbase of its block (i.e. its first transaction)
+    pub fn is_coinbase(&self) -> bool {
+        self.blockindex > 0 && self.position == Some(0)
+    }
+
+    // None for the outputs of a regular transaction, or whether the outputs of
+    // a coinbase are mature, with the chain tip at `tip_height`
+    pub fn maturity(&self, tip_height: usize) -> Option<bool> {
+        match self.is_coinbase() {
+            true => Some(is_mature(self.blockindex, tip_height)),
+            false => None,
+        }
+    }
 }
 
 //
@@ -26,7 +80,8 @@ pub type OutPoint = (Sha256dHash, usize); // (txid, vout)
 pub struct SpendingInput {
     pub txid: Sha256dHash,
     pub outpoint: OutPoint,
-    pub blockindex: usize
+    pub blockindex: usize,
+    pub position: Option<usize>, // in its block (None in the mempool)
 }
 
 
@@ -35,6 +90,364 @@ pub struct TxBlockIndex {
     pub blockindex: usize
 }
 
+//
+// Transaction of an address history
+// (height is 0 for mempool transactions, -1 if they have unconfirmed inputs)
+//
+#[derive(Clone)]
+pub struct HistoryEntry {
+    pub txid: Sha256dHash,
+    pub height: i64,
+    pub position: Option<usize>,      // in the block, for confirmed transactions
+    pub fee: Option<u64>,             // for mempool transactions
+    pub ancestors: Option<Ancestors>, // for mempool transactions, including themselves
+}
+
+//
+// Transaction funding (or spending) the outputs of a script, when exporting its history
+//
+pub struct Transfer {
+    pub txid: Sha256dHash,
+    pub height: usize,      // 0 for mempool transactions
+    pub received: bool,     // false for a transaction spending the outputs
+    pub value: Option<u64>, // unless bitcoind can't serve the funding transactions
+}
+
+//
+// Amounts funded and spent by the transactions of a block, for one script
+//
+pub struct ScriptDelta {
+    pub script_hash: FullHash,
+    pub funded: u64, // in satoshis
+    pub spent: u64,
+    pub txids: Vec<Sha256dHash>, // in the block's order
+}
+
+// Transfers of the transactions (by number, then spending before funding)
+type Transfers = BTreeMap<(TxNum, bool), (Sha256dHash, Option<u64>)>;
+// Electrum status and history of a script hash (None if larger than `txid_limit`)
+type BatchHistory = Option<(Option<FullHash>, Vec<HistoryEntry>)>;
+
+fn add_transfer(transfers: &mut Transfers, key: (TxNum, bool), txid: Sha256dHash, value: Option<u64>) {
+    let entry = transfers.entry(key).or_insert((txid, Some(0)));
+    entry.1 = entry.1.and_then(|sum| Some(sum + value?));
+}
+
+fn write_transfers(
+    transfers: Transfers,
+    write: &mut impl FnMut(Transfer) -> Result<()>,
+) -> Result<usize> {
+    let count = transfers.len();
+    for ((tx_num, received), (txid, value)) in transfers {
+        let height = tx_num_height(&tx_num);
+        write(Transfer { txid, height, received, value })?;
+    }
+    Ok(count)
+}
+
+impl HistoryEntry {
+    // Whether it is the coinbase of its block (i.e. its first transaction)
+    pub fn is_coinbase(&self) -> bool {
+        self.height > 0 && self.position == Some(0)
+    }
+
+    // In the format of `blockchain.scripthash.get_history`, with the chain tip
+    // at `tip_height`
+    pub fn to_json(&self, tip_height: usize) -> Value {
+        let mut item = json!({"tx_hash": self.txid.to_hex(), "height": self.height});
+        if let Some(position) = self.position {
+            item["pos"] = json!(position);
+        }
+        if let Some(fee) = self.fee {
+            item["fee"] = json!(fee);
+        }
+        if let Some(ancestors) = self.ancestors {
+            item["ancestor_count"] = json!(ancestors.count);
+            item["ancestor_fee"] = json!(ancestors.fee);
+            item["ancestor_vsize"] = json!(ancestors.vsize);
+        }
+        if self.is_coinbase() {
+            set_maturity(&mut item, Some(is_mature(self.height as usize, tip_height)));
+        }
+        item
+    }
+}
+
+// Whether the outputs of a coinbase confirmed at `height` can be spent by the
+// next block, with the chain tip at `tip_height`
+pub fn is_mature(height: usize, tip_height: usize) -> bool {
+    tip_height + 1 >= height + COINBASE_MATURITY
+}
+
+// Flag the JSON of a coinbase's history entry (or output), given its maturity
+pub fn set_maturity(item: &mut Value, maturity: Option<bool>) {
+    if let Some(mature) = maturity {
+        item["coinbase"] = json!(true);
+        item["mature"] = json!(mature);
+    }
+}
+
+// Position of a transaction in its block, from its number (None in the mempool)
+fn block_position(tx_num: &TxNum) -> Option<usize> {
+    match tx_num_height(tx_num) {
+        MEMPOOL_HEIGHT => None,
+        _ => Some(tx_num_position(tx_num)),
+    }
+}
+
+// Confirmed transactions in blockchain order, then mempool ones
+pub fn sort_history(entries: &mut [HistoryEntry]) {
+    entries.sort_by_key(|entry| (entry.height <= 0, entry.height.abs(), entry.position));
+}
+
+// Transaction of an address history: its height (0 in the mempool), position
+// in its block (from its number) and txid
+type HistoryTx = (usize, Option<usize>, Sha256dHash);
+
+// Transactions of an address, by height and position (mempool ones first)
+fn history_txs(status: &Status) -> Vec<HistoryTx> {
+    let mut txs: Vec<HistoryTx> = status
+        .funding()
+        .map(|f| (f.blockindex, f.position, f.txid))
+        .chain(status.spending().map(|s| (s.blockindex, s.position, s.txid)))
+        .collect();
+    txs.sort_unstable();
+    txs.dedup();
+    txs
+}
+
+fn history_page_txs(
+    txs: Vec<HistoryTx>,
+    from_height: usize,
+    limit: usize,
+) -> (Vec<HistoryTx>, Option<usize>) {
+    let (mempool, confirmed): (Vec<_>, Vec<_>) =
+        txs.into_iter().partition(|(height, _, _)| *height == 0);
+    let mut page: Vec<HistoryTx> = vec![];
+    for tx in confirmed.into_iter().filter(|(height, _, _)| *height >= from_height) {
+        if page.len() >= limit && page.last().map(|(last, _, _)| *last) != Some(tx.0) {
+            return (page, Some(tx.0));
+        }
+        page.push(tx);
+    }
+    page.extend(mempool);
+    (page, None)
+}
+
+//
+// Summary of an address history
+// (heights of its first and last confirmed transactions)
+//
+#[derive(Debug, PartialEq)]
+pub struct AddressInfo {
+    pub tx_count: usize,
+    pub mempool_tx_count: usize,
+    pub first_height: Option<usize>,
+    pub last_height: Option<usize>,
+}
+
+// Summarize the transactions of an address, sorted by height (0 for the mempool ones)
+fn address_info(txs: &[HistoryTx]) -> AddressInfo {
+    let confirmed: Vec<usize> = txs
+        .iter()
+        .map(|(height, _, _)| *height)
+        .filter(|height| *height > 0)
+        .collect();
+    AddressInfo {
+        tx_count: txs.len(),
+        mempool_tx_count: txs.len() - confirmed.len(),
+        first_height: confirmed.first().copied(),
+        last_height: confirmed.last().copied(),
+    }
+}
+
+thread_local! {
+    // Deadline of the query run by the current thread (if any)
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+// Run `f`, making the lookups of the current thread fail with ErrorKind::Timeout after `deadline`
+pub fn with_deadline<T>(deadline: Option<Instant>, f: impl FnOnce() -> T) -> T {
+    let previous = DEADLINE.with(|cell| cell.replace(deadline));
+    let result = f();
+    DEADLINE.with(|cell| cell.set(previous));
+    result
+}
+
+// Called between the DB lookups, to abort the queries running past their deadline
+fn check_deadline() -> Result<()> {
+    if DEADLINE.with(Cell::get).is_some_and(|deadline| Instant::now() >= deadline) {
+        bail!(ErrorKind::Timeout);
+    }
+    Ok(())
+}
+
+// bitcoind's name of the type of an output script
+fn script_type(script: &Script) -> &'static str {
+    if script.is_p2pkh() {
+        "pubkeyhash"
+    } else if script.is_p2sh() {
+        "scripthash"
+    } else if script.is_v0_p2wpkh() {
+        "witness_v0_keyhash"
+    } else if script.is_v0_p2wsh() {
+        "witness_v0_scripthash"
+    } else if script.is_witness_program() {
+        "witness_unknown"
+    } else if script.is_op_return() {
+        "nulldata"
+    } else if script.is_p2pk() {
+        "pubkey"
+    } else {
+        "nonstandard"
+    }
+}
+
+// A transaction in the format of bitcoind's verbose `getrawtransaction`
+// (without the fields about its block)
+pub fn transaction_json(tx: &Transaction, network: Network) -> Value {
+    let bytes = serialize(tx);
+    let weight = tx.get_weight();
+    let vin: Vec<Value> = tx
+        .input
+        .iter()
+        .map(|txin| {
+            let mut input = if tx.is_coin_base() {
+                json!({"coinbase": hex::encode(txin.script_sig.as_bytes())})
+            } else {
+                json!({
+                    "txid": txin.previous_output.txid.to_hex(),
+                    "vout": txin.previous_output.vout,
+                    "scriptSig": {"hex": hex::encode(txin.script_sig.as_bytes())},
+                })
+            };
+            if !txin.witness.is_empty() {
+                let witness: Vec<String> = txin.witness.iter().map(hex::encode).collect();
+                input["txinwitness"] = json!(witness);
+            }
+            input["sequence"] = json!(txin.sequence);
+            input
+        })
+        .collect();
+    let vout: Vec<Value> = tx
+        .output
+        .iter()
+        .enumerate()
+        .map(|(n, txout)| {
+            let script = &txout.script_pubkey;
+            let mut script_pubkey = json!({
+                "hex": hex::encode(script.as_bytes()),
+                "type": script_type(script),
+            });
+            if let Some(address) = Address::from_script(script, network) {
+                script_pubkey["address"] = json!(address.to_string());
+            }
+            json!({
+                "value": txout.value as f64 / 100_000_000.0,
+                "n": n,
+                "scriptPubKey": script_pubkey,
+            })
+        })
+        .collect();
+    json!({
+        "txid": tx.txid().to_hex(),
+        "hash": Sha256dHash::hash(&bytes).to_hex(),
+        "version": tx.version,
+        "size": bytes.len(),
+        "vsize": weight.div_ceil(4),
+        "weight": weight,
+        "locktime": tx.lock_time,
+        "vin": vin,
+        "vout": vout,
+        "hex": hex::encode(&bytes),
+    })
+}
+
+// Amounts funded and spent by `txs` by script hash, given the transactions
+// they spend (except the ones of `txs`)
+fn script_deltas(
+    txs: &[Transaction],
+    prev_txs: &HashMap<Sha256dHash, Transaction>,
+) -> Result<Vec<ScriptDelta>> {
+    let txids: Vec<Sha256dHash> = txs.iter().map(|tx| tx.txid()).collect();
+    let batch: HashMap<&Sha256dHash, &Transaction> = txids.iter().zip(txs).collect();
+    let mut deltas = BTreeMap::<FullHash, ScriptDelta>::new();
+    let mut add = |script: &Script, txid: &Sha256dHash, funded: u64, spent: u64| {
+        let script_hash = compute_script_hash(&script[..]);
+        let delta = deltas.entry(script_hash).or_insert_with(|| ScriptDelta {
+            script_hash,
+            funded: 0,
+            spent: 0,
+            txids: vec![],
+        });
+        delta.funded += funded;
+        delta.spent += spent;
+        if delta.txids.last() != Some(txid) {
+            delta.txids.push(*txid);
+        }
+    };
+    for (txid, tx) in txids.iter().zip(txs) {
+        check_deadline()?;
+        if !tx.is_coin_base() {
+            for input in &tx.input {
+                let prevout = &input.previous_output;
+                let prev_tx = match batch.get(&prevout.txid) {
+                    Some(prev_tx) => Some(*prev_tx),
+                    None => prev_txs.get(&prevout.txid),
+                };
+                let output = prev_tx
+                    .and_then(|t| t.output.get(prevout.vout as usize))
+                    .chain_err(|| format!("missing output {}:{}", prevout.txid, prevout.vout))?;
+                add(&output.script_pubkey, txid, 0, output.value);
+            }
+        }
+        for output in &tx.output {
+            add(&output.script_pubkey, txid, output.value, 0);
+        }
+    }
+    Ok(deltas.into_values().collect())
+}
+
+// Electrum status of a history (None if it is empty)
+fn history_status_hash(history: &[HistoryEntry]) -> Option<FullHash> {
+    if history.is_empty() {
+        return None;
+    }
+    let mut sha2 = Sha256::new();
+    for entry in history {
+        sha2.input(format!("{}:{}:", entry.txid.to_hex(), entry.height).as_bytes());
+    }
+    let mut hash = FullHash::default();
+    sha2.result(&mut hash);
+    Some(hash)
+}
+
+// Merkle branch of the transaction at `pos` in a block's txids (from the leaves up)
+fn merkle_branch(mut hashes: Vec<Sha256dHash>, mut pos: usize) -> Vec<Sha256dHash> {
+    let mut branch = vec![];
+    while hashes.len() > 1 {
+        if hashes.len() % 2 == 1 {
+            hashes.push(*hashes.last().unwrap()); // the last hash is paired with itself
+        }
+        branch.push(hashes[pos ^ 1]);
+        hashes = hashes
+            .chunks(2)
+            .map(|pair| Sha256dHash::hash(&[&pair[0][..], &pair[1][..]].concat()))
+            .collect();
+        pos /= 2;
+    }
+    branch
+}
+
+//
+// Used script of a scanned wallet
+//
+pub struct ScannedScript {
+    pub path: String,
+    pub script_hash: FullHash,
+    pub status: Status,
+}
+
 //
 // Status of an Address
 // (vectors of confirmed and unconfirmed outputs and inputs)
@@ -45,6 +458,14 @@ pub struct Status {
 }
 
 impl Status {
+    pub fn confirmed(&self) -> (&[Txo], &[SpendingInput]) {
+        (&self.confirmed.0, &self.confirmed.1)
+    }
+
+    pub fn mempool(&self) -> (&[Txo], &[SpendingInput]) {
+        (&self.mempool.0, &self.mempool.1)
+    }
+
     pub fn funding(&self) -> impl Iterator<Item = &Txo> {
         self.confirmed.0.iter().chain(self.mempool.0.iter())
     }
@@ -53,6 +474,13 @@ impl Status {
         self.confirmed.1.iter().chain(self.mempool.1.iter())
     }
 
+    pub fn unspent(&self) -> Vec<&Txo> {
+        let spent: HashSet<OutPoint> = self.spending().map(|s| s.outpoint).collect();
+        self.funding()
+            .filter(|f| !spent.contains(&(f.txid, f.vout)))
+            .collect()
+    }
+
     pub fn history(&self) -> Vec<Sha256dHash> {
         let mut txns = vec![];
         for f in self.funding() {
@@ -99,37 +527,93 @@ impl Status {
     }
 }
 
+// Whether the spenders of the UTXO set (applied up to `spenders_height`) cover
+// the blocks up to `current_block_index` (9999999999 for the whole chain)
+fn spenders_up_to_date(
+    spenders_height: usize,
+    current_block_index: usize,
+    tip_height: usize,
+) -> bool {
+    spenders_height >= current_block_index.min(tip_height)
+}
+
+// Numbers of the transactions spending an output, from the spenders of its
+// script (without scanning the inputs' index) or else from the `I` rows
+fn spending_tx_nums(
+    store: &dyn ReadStore,
+    spenders: Option<&Spenders>,
+    txid: &Sha256dHash,
+    vout: usize,
+) -> Vec<TxNum> {
+    match spenders {
+        Some(spenders) => {
+            let outpoint = (hash_prefix(&txid[..]), vout as u16);
+            spenders.get(&outpoint).cloned().unwrap_or_default()
+        }
+        None => store
+            .scan(&TxInRow::filter(txid, vout))
+            .iter()
+            .map(|row| TxInRow::from_row(row).tx_num)
+            .collect(),
+    }
+}
+
 //
-// QUery tool for the indexer
+// QUery tool for the indexer, also serving the applications embedding it
+// (see `app::App::open`)
 //
 pub struct Query {
     app: Arc<App>,
     tracker: RwLock<Tracker>,
-    txid_limit: usize,
+    mempool_txs: Arc<Gauge>,
+    mempool_conflicts: Arc<Counter>,
+    txid_limit: AtomicUsize,
+    tx_cache: Arc<TransactionCache>,
+    history_cache: Arc<HistoryCache>,
+    history_tip: Mutex<Sha256dHash>, // of the cached histories
+    publisher: OnceLock<Arc<Publisher>>, // of the new blocks and mempool transactions
 }
 
 impl Query {
+    // The caches may be shared by the networks served by the process
     pub fn new(
         app: Arc<App>,
+        metrics: &Metrics,
         txid_limit: usize,
+        tx_cache: Arc<TransactionCache>,
+        history_cache: Arc<HistoryCache>,
     ) -> Arc<Query> {
         Arc::new(Query {
             app,
             tracker: RwLock::new(Tracker::new()),
-            txid_limit,
+            mempool_txs: metrics.gauge(
+                "addrindexrs_mempool_txs",
+                "Number of transactions in the mempool tracker",
+            ),
+            mempool_conflicts: metrics.counter(
+                "addrindexrs_mempool_conflicts_total",
+                "Number of mempool transactions replaced by conflicting ones",
+            ),
+            txid_limit: AtomicUsize::new(txid_limit),
+            tx_cache,
+            history_cache,
+            history_tip: Mutex::new(Sha256dHash::default()),
+            publisher: OnceLock::new(),
         })
     }
 
-    fn get_txrows_by_prefix(
-        &self,
-        store: &dyn ReadStore,
-        prefix: HashPrefix
-    ) -> Vec<TxRow> {
-        store
-            .scan(&TxRow::filter_prefix(prefix))
-            .iter()
-            .map(|row| TxRow::from_row(row))
-            .collect()
+    // Publish the next indexed blocks and mempool transactions
+    pub fn publish_to(&self, publisher: Arc<Publisher>) {
+        let _ = self.publisher.set(publisher);
+    }
+
+    // Maximum number of transactions returned at once for a script (0 for no limit)
+    pub fn txid_limit(&self) -> usize {
+        self.txid_limit.load(Ordering::Relaxed)
+    }
+
+    pub fn set_txid_limit(&self, txid_limit: usize) {
+        self.txid_limit.store(txid_limit, Ordering::Relaxed);
     }
 
     fn get_txoutrows_by_script_hash(
@@ -144,73 +628,62 @@ impl Query {
             .collect()
     }
 
-    fn get_prefixes_by_funding_txo(
+    fn get_tx_nums_by_funding_txo(
         &self,
         store: &dyn ReadStore,
         txid: &Sha256dHash,
         vout: usize,
-    ) -> Vec<HashPrefix> {
-        store
-            .scan(&TxInRow::filter(&txid, vout))
-            .iter()
-            .map(|row| TxInRow::from_row(row).txid_prefix)
-            .collect()
+    ) -> Vec<TxNum> {
+        spending_tx_nums(store, None, txid, vout)
     }
 
-    /*fn get_txids_by_prefix(
+    // The txid, height and position of each (indexed) transaction number
+    fn get_txs_by_nums(
         &self,
         store: &dyn ReadStore,
-        prefixes: Vec<HashPrefix>,
-    ) -> Result<Vec<Sha256dHash>> {
-        let mut txns = vec![];
-        for prefix in prefixes {
-            for tx_row in self.get_txrows_by_prefix(store, prefix) {
-                let txid: Sha256dHash = deserialize(&tx_row.key.txid).unwrap();
-                txns.push(txid)
-            }
-        }
-        Ok(txns)
-    }*/
+        tx_nums: Vec<TxNum>,
+    ) -> Vec<(Sha256dHash, usize, Option<usize>)> {
+        tx_nums
+            .into_iter()
+            .filter_map(|tx_num| {
+                let txid = lookup_txid(store, tx_num)?;
+                let height = match tx_num_height(&tx_num) {
+                    MEMPOOL_HEIGHT => 0,
+                    height => height,
+                };
+                Some((txid, height, block_position(&tx_num)))
+            })
+            .collect()
+    }
 
-    fn get_txrows_by_prefixes(
+    fn find_spending_input(
         &self,
         store: &dyn ReadStore,
-        prefixes: Vec<HashPrefix>,
-    ) -> Result<Vec<TxRow>> {
-        let mut txns = vec![];
-        for prefix in prefixes {
-            for tx_row in self.get_txrows_by_prefix(store, prefix) {
-                txns.push(tx_row)
-            }
-        }
-        Ok(txns)
+        txo: &Txo,
+        current_block_index: usize
+    ) -> Result<Option<SpendingInput>> {
+        let tx_nums = self.get_tx_nums_by_funding_txo(store, &txo.txid, txo.vout);
+        self.find_spending_input_by_nums(store, txo, tx_nums, current_block_index)
     }
 
-
-    fn find_spending_input(
+    fn find_spending_input_by_nums(
         &self,
         store: &dyn ReadStore,
         txo: &Txo,
+        tx_nums: Vec<TxNum>,
         current_block_index: usize
     ) -> Result<Option<SpendingInput>> {
 
         let mut spendings = vec![];
-        let prefixes = self.get_prefixes_by_funding_txo(store, &txo.txid, txo.vout);
-        //let txids = self.get_txids_by_prefix(store, prefixes)?;
-        let txrows = self.get_txrows_by_prefixes(store, prefixes)?;
-
-        for txrow in &txrows {
-            let block_index = match self.get_block_index(deserialize(&txrow.block_hash).unwrap()){
-                Ok(header) => header.height(),
-                Err(_error) => 0
-            };
+        for (txid, block_index, position) in self.get_txs_by_nums(store, tx_nums) {
             if block_index > current_block_index {
                 continue;
             }
             spendings.push(SpendingInput {
-                txid: deserialize(&txrow.key.txid).unwrap(),
+                txid,
                 outpoint: (txo.txid, txo.vout),
-                blockindex: block_index
+                blockindex: block_index,
+                position,
             })
         }
 
@@ -234,21 +707,16 @@ impl Query {
         let mut result = vec![];
 
         for row in &txout_rows {
-            //let txids = self.get_txids_by_prefix(store, vec![row.txid_prefix])?;
-            let txrows = self.get_txrows_by_prefixes(store, vec![row.txid_prefix])?;
-            
-            for txrow in &txrows {
-                let block_index = match self.get_block_index(deserialize(&txrow.block_hash).unwrap()){
-                    Ok(header) => header.height(),
-                    Err(_error) => 0
-                };
+            check_deadline()?;
+            for (txid, block_index, position) in self.get_txs_by_nums(store, vec![row.tx_num]) {
                 if block_index > current_block_index {
                     continue;
                 }
                 result.push(Txo {
-                    txid: deserialize(&txrow.key.txid).unwrap(),
+                    txid,
                     vout: row.vout as usize,
-                    blockindex: block_index
+                    blockindex: block_index,
+                    position,
                 })
             }
         }
@@ -256,6 +724,15 @@ impl Query {
         Ok(result)
     }
 
+    // Fails if `use_txid_limit` is set, and more outputs than `txid_limit` are found
+    fn check_txid_limit(&self, use_txid_limit: bool, count: usize) -> Result<()> {
+        let txid_limit = self.txid_limit();
+        if use_txid_limit && txid_limit > 0 && count > txid_limit {
+            bail!(ErrorKind::TooManyTxs(txid_limit));
+        }
+        Ok(())
+    }
+
     fn confirmed_status(
         &self,
         script_hash: &[u8],
@@ -267,16 +744,24 @@ impl Query {
         let read_store = self.app.read_store();
 
         let txos = self.find_funding_outputs(read_store, script_hash, current_block_index)?;
-        if use_txid_limit && self.txid_limit > 0 && txos.len() > self.txid_limit {
-            bail!(
-                "{}+ transactions found, query may take a long time",
-                txos.len()
-            );
-        }
+        self.check_txid_limit(use_txid_limit, txos.len())?;
         funding.extend(txos);
 
+        // the UTXO set indexes the spending inputs of the script, if up to date
+        let tip_height = self.app.index().best_header().map_or(0, |header| header.height());
+        let spenders = self
+            .app
+            .get_spenders(script_hash)
+            .filter(|(_, height)| {
+                spenders_up_to_date(*height, current_block_index, tip_height)
+            });
         for txo in &funding {
-            if let Some(spent) = self.find_spending_input(read_store, &txo, current_block_index)? {
+            check_deadline()?;
+            let spenders = spenders.as_ref().map(|(spenders, _)| spenders);
+            let tx_nums = spending_tx_nums(read_store, spenders, &txo.txid, txo.vout);
+            let spent =
+                self.find_spending_input_by_nums(read_store, txo, tx_nums, current_block_index)?;
+            if let Some(spent) = spent {
                 spending.push(spent);
             }
         }
@@ -296,15 +781,11 @@ impl Query {
         let tracker = self.tracker.read().unwrap();
 
         let txos = self.find_funding_outputs(tracker.index(), script_hash, 9999999999)?;
-        if use_txid_limit && self.txid_limit > 0 && txos.len() > self.txid_limit {
-            bail!(
-                "{}+ transactions found, query may take a long time",
-                txos.len()
-            );
-        }
+        self.check_txid_limit(use_txid_limit, txos.len() + confirmed_funding.len())?;
         funding.extend(txos);
 
         for txo in funding.iter().chain(confirmed_funding.iter()) {
+            check_deadline()?;
             if let Some(spent) = self.find_spending_input(tracker.index(), &txo, 9999999999)? {
                 spending.push(spent);
             }
@@ -313,7 +794,15 @@ impl Query {
         Ok((funding, spending))
     }
 
-    pub fn status(&self, script_hash: &[u8], current_block_index: usize, use_txid_limit: bool) -> Result<Status> {
+    // Confirmed and mempool outputs of a script (its Electrum script hash,
+    // i.e. the SHA256 of the script), and the inputs spending them. With
+    // `use_txid_limit`, scripts with more outputs than `txid_limit` fail early.
+    pub fn status(
+        &self,
+        script_hash: &[u8],
+        current_block_index: usize,
+        use_txid_limit: bool,
+    ) -> Result<Status> {
         let confirmed = self
             .confirmed_status(script_hash, current_block_index, use_txid_limit)
             .chain_err(|| "failed to get confirmed status")?;
@@ -326,11 +815,101 @@ impl Query {
     }
     
     pub fn oldest_tx(&self, script_hash: &[u8], current_block_index: usize) -> Result<TxBlockIndex> {
-        let all_status = self.status(script_hash, current_block_index, true).unwrap();
+        let all_status = self.status(script_hash, current_block_index, false)?;
         
         all_status.oldest().chain_err(|| "no txs for address")
     }
     
+    // Transaction spending a given output (confirmed or in the mempool)
+    pub fn get_spender(&self, txid: &Sha256dHash, vout: usize) -> Result<Option<SpendingInput>> {
+        let txo = Txo {
+            txid: *txid,
+            vout,
+            blockindex: 0,
+            position: None,
+        };
+        if let Some(spent) = self.find_spending_input(self.app.read_store(), &txo, 9999999999)? {
+            return Ok(Some(spent));
+        }
+        let tracker = self.tracker.read().unwrap();
+        self.find_spending_input(tracker.index(), &txo, 9999999999)
+    }
+
+    // Indexer status, for monitoring and load balancers
+    pub fn get_status(&self) -> Result<Value> {
+        let index_tip = self.get_best_header()?;
+        let daemon = self.app.daemon();
+        // don't wait for bitcoind to be reachable again
+        let daemon_tip = match daemon.is_connected() {
+            true => Some(daemon.getbestblockhash()?),
+            false => None,
+        };
+        let daemon_height = daemon_tip
+            .and_then(|tip| self.app.index().get_header_by_block_hash(tip))
+            .map(|entry| entry.height());
+        Ok(json!({
+            "index": {"height": index_tip.height(), "hash": index_tip.hash().to_hex()},
+            "daemon": {
+                "connected": daemon_tip.is_some(),
+                "height": daemon_height,
+                "hash": daemon_tip.map(|tip| tip.to_hex()),
+            },
+            "synced": Some(*index_tip.hash()) == daemon_tip,
+            "mempool_txs": self.tracker.read().unwrap().len(),
+            "db_size": self.app.db_size(),
+            "history_start_height": self.history_start_height(),
+            "watched_scripts": self.watchlist().map(|watchlist| watchlist.len()),
+        }))
+    }
+
+    // Index DB statistics, for capacity planning
+    pub fn get_db_stats(&self) -> Value {
+        let stats = self.app.db_stats();
+        let levels: Vec<Value> = stats
+            .levels
+            .iter()
+            .map(|level| json!({"files": level.files, "size": level.size}))
+            .collect();
+        json!({
+            "size": self.app.db_size(),
+            "levels": levels,
+            "memtables_size": stats.memtables_size,
+            "pending_compaction_bytes": stats.pending_compaction_bytes,
+            "block_cache": {
+                "usage": stats.block_cache_usage,
+                "hits": stats.block_cache_hits,
+                "misses": stats.block_cache_misses,
+                "hit_ratio": stats.block_cache_hit_ratio(),
+            },
+        })
+    }
+
+    // Aggregate statistics of the index (scanning all its rows), with the rows
+    // of the blocks in `heights`
+    pub fn get_index_stats(&self, heights: Range<usize>) -> IndexStats {
+        index_stats(self.app.iter_scan(b""), heights)
+    }
+
+    pub fn get_op_returns(&self, prefix: &[u8], from: usize, to: usize) -> Result<Vec<OpReturn>> {
+        self.app.get_op_returns(prefix, from, to)
+    }
+
+    pub fn get_counterparty_txs(&self, from: usize, to: usize) -> Result<Vec<CounterpartyTx>> {
+        self.app.get_counterparty_txs(from, to)
+    }
+
+    pub fn get_reorgs(&self, from: u32) -> Vec<(u32, Reorg)> {
+        read_reorgs(self.app.read_store(), from)
+    }
+
+    pub fn watchlist(&self) -> Option<&Arc<Watchlist>> {
+        self.app.index().watchlist()
+    }
+
+    pub fn history_start_height(&self) -> usize {
+        self.app.history_start_height()
+    }
+
     pub fn get_best_header(&self) -> Result<HeaderEntry> {
         let last_header = self.app.index().best_header();
         Ok(last_header.chain_err(|| "no headers indexed")?)
@@ -341,7 +920,863 @@ impl Query {
         Ok(block_header.chain_err(|| "no headers indexed")?)
     }
 
-    pub fn update_mempool(&self) -> Result<()> {
-        self.tracker.write().unwrap().update(self.app.daemon())
+    pub fn get_header(&self, height: usize) -> Option<HeaderEntry> {
+        self.app.index().get_header(height)
+    }
+
+    fn get_block_txids(&self, height: usize) -> Result<(HeaderEntry, Vec<Sha256dHash>)> {
+        let header = self
+            .get_header(height)
+            .chain_err(|| format!("missing header at height {}", height))?;
+        let txids = self.app.daemon().getblocktxids(header.hash())?;
+        Ok((header, txids))
+    }
+
+    // Merkle branch and position of a transaction in the block at `height`
+    pub fn get_merkle(&self, txid: &Sha256dHash, height: usize) -> Result<(Vec<Sha256dHash>, usize)> {
+        let (header, txids) = self.get_block_txids(height)?;
+        let pos = txids
+            .iter()
+            .position(|blocktxid| blocktxid == txid)
+            .chain_err(|| format!("tx {} is not in block {}", txid, header.hash()))?;
+        Ok((merkle_branch(txids, pos), pos))
+    }
+
+    // Transaction at `pos` in the block at `height`, and its merkle branch (if requested)
+    pub fn get_id_from_pos(
+        &self,
+        height: usize,
+        pos: usize,
+        merkle: bool,
+    ) -> Result<(Sha256dHash, Vec<Sha256dHash>)> {
+        let (header, txids) = self.get_block_txids(height)?;
+        let txid = *txids
+            .get(pos)
+            .chain_err(|| format!("no tx at position {} in block {}", pos, header.hash()))?;
+        let branch = match merkle {
+            true => merkle_branch(txids, pos),
+            false => vec![],
+        };
+        Ok((txid, branch))
+    }
+
+    pub fn get_block_filter(&self, blockhash: &Sha256dHash) -> Result<Vec<u8>> {
+        self.app.get_block_filter(blockhash)
+    }
+
+    pub fn get_transaction(&self, txid: &Sha256dHash) -> Result<Transaction> {
+        self.tx_cache
+            .get_or_else(txid, || self.app.daemon().gettransaction(txid, None))
+    }
+
+    pub fn get_transaction_json(&self, txid: &Sha256dHash) -> Result<Value> {
+        self.app.daemon().gettransaction_raw(txid, None, true)
+    }
+
+    // Decoded transaction, proxied from bitcoind (with its block and
+    // confirmations), or decoded locally if bitcoind can't serve it but it is
+    // cached (e.g. in a block pruned since)
+    pub fn get_transaction_verbose(&self, txid: &Sha256dHash, network: Network) -> Result<Value> {
+        match self.get_transaction_json(txid) {
+            Ok(value) => Ok(value),
+            Err(e) => match self.tx_cache.get(txid) {
+                Some(tx) => Ok(transaction_json(&tx, network)),
+                None => Err(e),
+            },
+        }
+    }
+
+    // Mempool conflicts detected after the one numbered `after`
+    pub fn get_conflicts(&self, after: u64) -> Vec<Conflict> {
+        let tracker = self.tracker.read().unwrap();
+        tracker.conflicts_after(after).cloned().collect()
+    }
+
+    pub fn last_conflict_id(&self) -> u64 {
+        self.tracker.read().unwrap().last_conflict_id()
+    }
+
+    pub fn get_replacement(&self, txid: &Sha256dHash) -> Option<Conflict> {
+        self.tracker.read().unwrap().get_replacement(txid).cloned()
+    }
+
+    pub fn get_fee_histogram(&self) -> Vec<(f64, u64)> {
+        self.tracker.read().unwrap().fee_histogram().to_vec()
+    }
+
+    pub fn estimate_fee(&self, blocks: u16) -> Result<Option<f64>> {
+        self.app.daemon().estimatesmartfee(blocks)
+    }
+
+    pub fn broadcast(&self, tx: &Transaction) -> Result<Sha256dHash> {
+        self.app.daemon().broadcast(tx)
+    }
+
+    // Values (in satoshis) of the given outputs, fetching each uncached transaction once
+    pub fn txo_values(&self, txos: &[&Txo]) -> Result<Vec<u64>> {
+        let mut txids: Vec<&Sha256dHash> = txos.iter().map(|txo| &txo.txid).collect();
+        txids.sort_unstable();
+        txids.dedup();
+        let mut txs = HashMap::<Sha256dHash, Transaction>::new();
+        let mut missing = vec![];
+        for txid in txids {
+            match self.tx_cache.get(txid) {
+                Some(tx) => {
+                    txs.insert(*txid, tx);
+                }
+                None => missing.push(txid),
+            }
+        }
+        if !missing.is_empty() {
+            for (txid, tx) in missing.iter().zip(self.app.daemon().gettransactions(&missing)?) {
+                self.tx_cache.put(**txid, &tx);
+                txs.insert(**txid, tx);
+            }
+        }
+        txos.iter()
+            .map(|txo| {
+                txs[&txo.txid]
+                    .output
+                    .get(txo.vout)
+                    .map(|output| output.value)
+                    .chain_err(|| format!("missing output {}:{}", txo.txid, txo.vout))
+            })
+            .collect()
+    }
+
+    // Transactions ordered by confirmation (mempool ones last)
+    pub fn history(&self, status: &Status) -> Result<Vec<HistoryEntry>> {
+        self.history_entries(history_txs(status))
+    }
+
+    // Mempool transactions of a script, without looking up the spending inputs
+    // of its confirmed outputs (only the outputs, which they may spend)
+    pub fn mempool_history(&self, script_hash: &[u8]) -> Result<Vec<HistoryEntry>> {
+        let confirmed = match self.app.get_unspent(&full_hash(script_hash)) {
+            // only the unspent outputs can be spent by mempool transactions
+            Some(utxos) => utxos
+                .into_iter()
+                .map(|utxo| Txo {
+                    txid: utxo.txid,
+                    vout: utxo.vout,
+                    blockindex: utxo.height,
+                    position: utxo.position(),
+                })
+                .collect(),
+            None => self.find_funding_outputs(self.app.read_store(), script_hash, 9999999999)?,
+        };
+        let (funding, spending) = self
+            .mempool_status(script_hash, &confirmed, false)
+            .chain_err(|| "failed to get mempool status")?;
+        let mut txs: Vec<HistoryTx> = funding
+            .iter()
+            .map(|f| (0, None, f.txid))
+            .chain(spending.iter().map(|s| (0, None, s.txid)))
+            .collect();
+        txs.sort_unstable();
+        txs.dedup();
+        let mut entries = self.history_entries(txs)?;
+        sort_history(&mut entries);
+        Ok(entries)
+    }
+
+    // Page of the history, made of whole blocks from `from_height` until there are
+    // `limit` transactions (the mempool ones are in the last page), and the next
+    // page's height.
+    pub fn history_page(
+        &self,
+        status: &Status,
+        from_height: usize,
+        limit: usize,
+    ) -> Result<(Vec<HistoryEntry>, Option<usize>)> {
+        let (txs, next_height) = history_page_txs(history_txs(status), from_height, limit);
+        Ok((self.history_entries(txs)?, next_height))
+    }
+
+    fn history_entries(&self, txs: Vec<HistoryTx>) -> Result<Vec<HistoryEntry>> {
+        let (mempool, confirmed): (Vec<_>, Vec<_>) =
+            txs.into_iter().partition(|(height, _, _)| *height == 0);
+        let tracker = self.tracker.read().unwrap();
+        let mut entries: Vec<HistoryEntry> = mempool
+            .into_iter()
+            .map(|(_, _, txid)| HistoryEntry {
+                txid,
+                height: if tracker.has_unconfirmed_inputs(&txid) { -1 } else { 0 },
+                position: None,
+                fee: tracker.get_entry(&txid).map(|entry| entry.fee()),
+                ancestors: tracker.get_ancestors(&txid),
+            })
+            .collect();
+        entries.extend(confirmed.into_iter().map(|(height, position, txid)| HistoryEntry {
+            txid,
+            height: height as i64,
+            position,
+            fee: None,
+            ancestors: None,
+        }));
+        sort_history(&mut entries);
+        Ok(entries)
+    }
+
+    // Derive the scripts of a wallet until `gap_limit` consecutive ones
+    // have no history, and return the used ones.
+    pub fn scan(&self, descriptor: &Descriptor, gap_limit: u32) -> Result<Vec<ScannedScript>> {
+        let secp = Secp256k1::verification_only();
+        let mut result = vec![];
+        for chain in 0..descriptor.chains() {
+            let mut unused = 0;
+            let mut index = 0;
+            while unused < gap_limit {
+                let script = descriptor.derive(&secp, chain, index)?;
+                let script_hash = compute_script_hash(&script[..]);
+                let status = self.status(&script_hash, 9999999999, false)?;
+                if status.funding().next().is_some() {
+                    unused = 0;
+                    result.push(ScannedScript {
+                        path: descriptor.path(chain, index),
+                        script_hash,
+                        status,
+                    });
+                } else {
+                    unused += 1;
+                }
+                index += 1;
+            }
+        }
+        Ok(result)
+    }
+
+    // Unspent outputs, with their values (in satoshis)
+    pub fn utxos<'a>(&self, status: &'a Status) -> Result<Vec<(&'a Txo, u64)>> {
+        let unspent = status.unspent();
+        let values = self.txo_values(&unspent)?;
+        Ok(unspent.into_iter().zip(values).collect())
+    }
+
+    // Unspent outputs of a script, using the UTXO set when available
+    pub fn unspent(&self, script_hash: &[u8]) -> Result<Vec<(Txo, u64)>> {
+        let confirmed = match self.app.get_unspent(&full_hash(script_hash)) {
+            Some(confirmed) => confirmed,
+            None => {
+                let status = self.status(script_hash, 9999999999, false)?;
+                return Ok(self
+                    .utxos(&status)?
+                    .into_iter()
+                    .map(|(txo, value)| (Txo { ..*txo }, value))
+                    .collect());
+            }
+        };
+        let tracker = self.tracker.read().unwrap();
+        let mempool = self.find_funding_outputs(tracker.index(), script_hash, 9999999999)?;
+        let values = self.txo_values(&mempool.iter().collect::<Vec<_>>())?;
+        let mut result = vec![];
+        let utxos = confirmed
+            .into_iter()
+            .map(|utxo| {
+                let txo = Txo {
+                    txid: utxo.txid,
+                    vout: utxo.vout,
+                    blockindex: utxo.height,
+                    position: utxo.position(),
+                };
+                (txo, utxo.value)
+            })
+            .chain(mempool.into_iter().zip(values));
+        for (txo, value) in utxos {
+            // skip the outputs spent by mempool transactions
+            if self.find_spending_input(tracker.index(), &txo, 9999999999)?.is_none() {
+                result.push((txo, value));
+            }
+        }
+        Ok(result)
+    }
+
+    // Balance of a script, using the stored confirmed balance when available
+    pub fn get_balance(&self, script_hash: &[u8]) -> Result<(u64, i64)> {
+        let confirmed = match self.app.get_balance(&full_hash(script_hash)) {
+            Some(confirmed) => confirmed,
+            None => return self.balance(&self.status(script_hash, 9999999999, false)?),
+        };
+        let unspent = self.app.get_unspent(&full_hash(script_hash)).unwrap_or_default();
+        let tracker = self.tracker.read().unwrap();
+        let funding = self.find_funding_outputs(tracker.index(), script_hash, 9999999999)?;
+        let values = self.txo_values(&funding.iter().collect::<Vec<_>>())?;
+        let mut unconfirmed = values.iter().sum::<u64>() as i64;
+        let confirmed_utxos = unspent.into_iter().map(|utxo| {
+            let txo = Txo {
+                txid: utxo.txid,
+                vout: utxo.vout,
+                blockindex: utxo.height,
+                position: utxo.position(),
+            };
+            (txo, utxo.value)
+        });
+        for (txo, value) in funding.into_iter().zip(values).chain(confirmed_utxos) {
+            if self.find_spending_input(tracker.index(), &txo, 9999999999)?.is_some() {
+                unconfirmed -= value as i64;
+            }
+        }
+        Ok((confirmed, unconfirmed))
+    }
+
+    // Confirmed and unconfirmed (mempool delta) balances, in satoshis
+    pub fn balance(&self, status: &Status) -> Result<(u64, i64)> {
+        let funding: Vec<&Txo> = status.funding().collect();
+        let values: HashMap<OutPoint, u64> = funding
+            .iter()
+            .map(|txo| (txo.txid, txo.vout))
+            .zip(self.txo_values(&funding)?)
+            .collect();
+        let sum_funding = |txos: &[Txo]| -> u64 {
+            txos.iter().map(|txo| values[&(txo.txid, txo.vout)]).sum()
+        };
+        let sum_spending = |inputs: &[SpendingInput]| -> u64 {
+            inputs.iter().map(|input| values[&input.outpoint]).sum()
+        };
+        let (funding, spending) = status.confirmed();
+        let confirmed = sum_funding(funding) - sum_spending(spending);
+        let (funding, spending) = status.mempool();
+        let unconfirmed = sum_funding(funding) as i64 - sum_spending(spending) as i64;
+        Ok((confirmed, unconfirmed))
+    }
+
+    // Summary of the history of a script hash (regardless of `txid_limit`)
+    pub fn get_address_info(&self, script_hash: &[u8]) -> Result<AddressInfo> {
+        let status = self.status(script_hash, 9999999999, false)?;
+        Ok(address_info(&history_txs(&status)))
+    }
+
+    // Values of the outputs, unless bitcoind can't serve their transactions (e.g. pruned)
+    fn export_values(&self, txos: &[&Txo]) -> Vec<Option<u64>> {
+        match self.txo_values(txos) {
+            Ok(values) => values.into_iter().map(Some).collect(),
+            Err(e) => {
+                debug!("exporting {} outputs without their values: {}", txos.len(), e);
+                vec![None; txos.len()]
+            }
+        }
+    }
+
+    // Stream the transfers of a script hash in blockchain order, then the mempool ones,
+    // returning how many were written. The whole history isn't loaded: only the unspent
+    // outputs are kept, and the spent ones until their spending transaction is reached.
+    pub fn export_transfers(
+        &self,
+        script_hash: &[u8],
+        mut write: impl FnMut(Transfer) -> Result<()>,
+    ) -> Result<usize> {
+        let store = self.app.read_store();
+        let mut rows = self
+            .app
+            .iter_scan(&TxOutRow::filter(script_hash))
+            .map(|row| TxOutRow::from_row(&row));
+        let mut pending = Transfers::new();
+        let mut unspent: Vec<Txo> = vec![];
+        let mut values: HashMap<OutPoint, Option<u64>> = HashMap::new(); // of the unspent ones
+        let mut count = 0;
+        loop {
+            let chunk: Vec<TxOutRow> = rows.by_ref().take(EXPORT_BATCH_SIZE).collect();
+            let last = match chunk.last() {
+                Some(row) => row.tx_num,
+                None => break,
+            };
+            let txos: Vec<(TxNum, Txo)> = chunk
+                .iter()
+                .filter_map(|row| {
+                    let txo = Txo {
+                        txid: lookup_txid(store, row.tx_num)?,
+                        vout: row.vout as usize,
+                        blockindex: tx_num_height(&row.tx_num),
+                        position: block_position(&row.tx_num),
+                    };
+                    Some((row.tx_num, txo))
+                })
+                .collect();
+            let txo_values =
+                self.export_values(&txos.iter().map(|(_, txo)| txo).collect::<Vec<_>>());
+            for ((tx_num, txo), value) in txos.into_iter().zip(txo_values) {
+                add_transfer(&mut pending, (tx_num, true), txo.txid, value);
+                let spender = self
+                    .get_tx_nums_by_funding_txo(store, &txo.txid, txo.vout)
+                    .into_iter()
+                    .find_map(|num| Some((num, lookup_txid(store, num)?)));
+                match spender {
+                    Some((num, txid)) => add_transfer(&mut pending, (num, false), txid, value),
+                    None => {
+                        values.insert((txo.txid, txo.vout), value);
+                        unspent.push(txo);
+                    }
+                }
+            }
+            // the next rows can't fund (or spend) the script before the last transaction
+            let later = pending.split_off(&(last, false));
+            count += write_transfers(std::mem::replace(&mut pending, later), &mut write)?;
+        }
+        count += write_transfers(pending, &mut write)?;
+
+        let (funding, spending) = self.mempool_status(script_hash, &unspent, false)?;
+        let mut mempool: BTreeMap<(Sha256dHash, bool), Option<u64>> = BTreeMap::new();
+        let funding_values = self.export_values(&funding.iter().collect::<Vec<_>>());
+        for (txo, value) in funding.iter().zip(funding_values) {
+            values.insert((txo.txid, txo.vout), value);
+            let sum = mempool.entry((txo.txid, true)).or_insert(Some(0));
+            *sum = sum.and_then(|sum| Some(sum + value?));
+        }
+        for input in &spending {
+            let value = values.get(&input.outpoint).cloned().flatten();
+            let sum = mempool.entry((input.txid, false)).or_insert(Some(0));
+            *sum = sum.and_then(|sum| Some(sum + value?));
+        }
+        count += mempool.len();
+        for ((txid, received), value) in mempool {
+            write(Transfer { txid, height: 0, received, value })?;
+        }
+        Ok(count)
+    }
+
+    // Whole history of a script hash, cached until it is invalidated by update_mempool()
+    pub fn get_history(&self, script_hash: &[u8]) -> Result<Vec<HistoryEntry>> {
+        let script_hash = full_hash(script_hash);
+        if let Some(history) = self.history_cache.get(&script_hash) {
+            return Ok(history);
+        }
+        let generation = self.history_cache.generation();
+        let status = self.status(&script_hash, 9999999999, true)?;
+        let history = self.history(&status)?;
+        let outpoints = status.funding().map(|txo| (txo.txid, txo.vout)).collect();
+        self.history_cache
+            .put(script_hash, history.clone(), outpoints, generation);
+        Ok(history)
+    }
+
+    // Electrum status of a script hash (None if it has no history)
+    pub fn status_hash(&self, script_hash: &[u8]) -> Result<Option<FullHash>> {
+        Ok(history_status_hash(&self.get_history(script_hash)?))
+    }
+
+    // Electrum statuses and histories of many script hashes, looked up in parallel
+    // (None for the histories larger than `txid_limit`)
+    pub fn get_history_batch(
+        &self,
+        script_hashes: &[FullHash],
+    ) -> Result<Vec<BatchHistory>> {
+        if script_hashes.is_empty() {
+            return Ok(vec![]);
+        }
+        let chunk_size = script_hashes.len().div_ceil(BATCH_THREADS);
+        let deadline = DEADLINE.with(Cell::get);
+        thread::scope(|scope| {
+            let lookups: Vec<_> = script_hashes
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    let counters = trace::current();
+                    scope.spawn(move || {
+                        let lookup = || {
+                            chunk
+                                .iter()
+                                .map(|script_hash| match self.get_history(script_hash) {
+                                    Ok(history) => {
+                                        Ok(Some((history_status_hash(&history), history)))
+                                    }
+                                    Err(ref e) if too_many_txs(e).is_some() => Ok(None),
+                                    Err(e) => Err(e),
+                                })
+                                .collect::<Result<Vec<_>>>()
+                        };
+                        trace::with_counters(counters, || with_deadline(deadline, lookup))
+                    })
+                })
+                .collect();
+            let mut result = vec![];
+            for lookup in lookups {
+                result.extend(lookup.join().expect("history lookup panicked")?);
+            }
+            Ok(result)
+        })
+    }
+
+    // Transactions spent by `txs` (outside of `batch`, their txids), from the
+    // mempool or the transactions cache when possible, or else fetched from bitcoind
+    fn previous_txs(
+        &self,
+        txs: &[Transaction],
+        batch: &HashMap<&Sha256dHash, &Transaction>,
+    ) -> Result<HashMap<Sha256dHash, Transaction>> {
+        let mut missing: Vec<Sha256dHash> = txs
+            .iter()
+            .filter(|tx| !tx.is_coin_base())
+            .flat_map(|tx| tx.input.iter().map(|input| input.previous_output.txid))
+            .filter(|txid| !batch.contains_key(txid))
+            .collect();
+        missing.sort_unstable();
+        missing.dedup();
+        let mut prev_txs = HashMap::<Sha256dHash, Transaction>::new();
+        {
+            let tracker = self.tracker.read().unwrap();
+            missing.retain(|txid| {
+                match tracker.get_txn(txid).cloned().or_else(|| self.tx_cache.get(txid)) {
+                    Some(tx) => {
+                        prev_txs.insert(*txid, tx);
+                        false
+                    }
+                    None => true,
+                }
+            });
+        }
+        if !missing.is_empty() {
+            let missing: Vec<&Sha256dHash> = missing.iter().collect();
+            for (txid, tx) in missing.iter().zip(self.app.daemon().gettransactions(&missing)?) {
+                prev_txs.insert(**txid, tx);
+            }
+        }
+        Ok(prev_txs)
+    }
+
+    // Amounts funded and spent by the transactions of a block, by script hash
+    pub fn block_deltas(&self, header: &HeaderEntry) -> Result<Vec<ScriptDelta>> {
+        let block = self.app.daemon().getblock(header.hash())?;
+        let txids: Vec<Sha256dHash> = block.txdata.iter().map(|tx| tx.txid()).collect();
+        let batch: HashMap<&Sha256dHash, &Transaction> = txids.iter().zip(&block.txdata).collect();
+        let prev_txs = self.previous_txs(&block.txdata, &batch)?;
+        script_deltas(&block.txdata, &prev_txs)
+    }
+
+    // Publish the scripts funded or spent by the transactions (confirmed in
+    // the same block, or new in the mempool at height 0)
+    fn publish_txs(&self, publisher: &Publisher, txs: &[Transaction], height: usize) -> Result<()> {
+        let txids: Vec<Sha256dHash> = txs.iter().map(|tx| tx.txid()).collect();
+        let batch: HashMap<&Sha256dHash, &Transaction> = txids.iter().zip(txs).collect();
+        let prev_txs = self.previous_txs(txs, &batch)?;
+        for (txid, tx) in txids.iter().zip(txs) {
+            let mut script_hashes: Vec<FullHash> = tx
+                .output
+                .iter()
+                .map(|output| compute_script_hash(&output.script_pubkey[..]))
+                .collect();
+            if !tx.is_coin_base() {
+                for input in &tx.input {
+                    let prevout = &input.previous_output;
+                    let prev_tx = match batch.get(&prevout.txid) {
+                        Some(prev_tx) => Some(*prev_tx),
+                        None => prev_txs.get(&prevout.txid),
+                    };
+                    if let Some(output) = prev_tx.and_then(|t| t.output.get(prevout.vout as usize)) {
+                        script_hashes.push(compute_script_hash(&output.script_pubkey[..]));
+                    }
+                }
+            }
+            script_hashes.sort_unstable();
+            script_hashes.dedup();
+            for script_hash in &script_hashes {
+                publisher.script_hash_update(script_hash, txid, height);
+            }
+        }
+        Ok(())
+    }
+
+    // Drop the cached histories touched by the blocks indexed since the last call
+    // (all of them after a reorg, or after more than MAX_INVALIDATED_BLOCKS blocks),
+    // and publish these blocks (only the new tip after a reorg)
+    fn invalidate_new_blocks(&self) -> Result<()> {
+        let best = match self.app.index().best_header() {
+            Some(best) => best,
+            None => return Ok(()),
+        };
+        let mut tip = self.history_tip.lock().unwrap();
+        if *tip == *best.hash() {
+            return Ok(());
+        }
+        let publisher = self.publisher.get();
+        let (start, cleared) = match self.app.index().get_header_by_block_hash(*tip) {
+            Some(entry) => {
+                let cleared = best.height() - entry.height() > MAX_INVALIDATED_BLOCKS;
+                (entry.height() + 1, cleared)
+            }
+            None => (best.height(), true),
+        };
+        if cleared {
+            self.history_cache.clear();
+        }
+        if cleared && publisher.is_none() {
+            *tip = *best.hash();
+            return Ok(());
+        }
+        let mut blockhashes = vec![];
+        for height in start..=best.height() {
+            let header = self.get_header(height).chain_err(|| "missing indexed header")?;
+            blockhashes.push(*header.hash());
+        }
+        for (chunk, hashes) in blockhashes.chunks(MAX_INVALIDATED_BLOCKS).enumerate() {
+            let blocks = self.app.daemon().getblocks(hashes)?;
+            for (i, (blockhash, block)) in hashes.iter().zip(&blocks).enumerate() {
+                if !cleared {
+                    for tx in &block.txdata {
+                        self.history_cache.invalidate_tx(tx);
+                    }
+                }
+                if let Some(publisher) = publisher {
+                    let height = start + chunk * MAX_INVALIDATED_BLOCKS + i;
+                    self.publish_txs(publisher, &block.txdata, height)?;
+                    publisher.indexed_block(blockhash, height);
+                }
+            }
+        }
+        // the mempool transactions may have been confirmed
+        self.history_cache.invalidate_mempool();
+        *tip = *best.hash();
+        Ok(())
+    }
+
+    // Track the mempool transactions saved by save_mempool() before the last
+    // shutdown, so that only the new ones are fetched by the next update
+    pub fn load_mempool(&self) {
+        let rows = self.app.read_mempool_snapshot();
+        if rows.is_empty() {
+            return;
+        }
+        let mut tracker = self.tracker.write().unwrap();
+        let count = tracker.load(&rows);
+        self.mempool_txs.set(tracker.len() as f64);
+        info!("restored {} mempool transactions", count);
+    }
+
+    // Save the mempool transactions of the tracker, restored by load_mempool()
+    pub fn save_mempool(&self) {
+        let rows = self.tracker.read().unwrap().snapshot();
+        let count = rows.len();
+        if self.app.write_mempool_snapshot(rows) {
+            info!("saved {} mempool transactions", count);
+        }
+    }
+
+    // Returns whether the mempool has changed
+    // (the cached histories are updated with the new blocks first)
+    pub fn update_mempool(&self) -> Result<bool> {
+        self.invalidate_new_blocks()?;
+        let daemon = self.app.daemon();
+        let txids = daemon
+            .getmempooltxids()
+            .chain_err(|| "failed to update mempool from daemon")?;
+        // only the new transactions are fetched, without blocking the queries
+        let untracked = self.tracker.read().unwrap().untracked(&txids);
+        let fetched = mempool::fetch_txs(daemon, &untracked)?;
+        let mut tracker = self.tracker.write().unwrap();
+        let last_conflict_id = tracker.last_conflict_id();
+        let changed = tracker.apply(&txids, fetched);
+        self.mempool_txs.set(tracker.len() as f64);
+        self.mempool_conflicts.inc_by(tracker.last_conflict_id() - last_conflict_id);
+        let publisher = self.publisher.get();
+        let mut added = vec![]; // to be published
+        if !changed.is_empty() {
+            for txid in &changed {
+                if let Some(tx) = tracker.get_txn(txid) {
+                    self.history_cache.invalidate_tx(tx);
+                    if publisher.is_some() {
+                        added.push(tx.clone());
+                    }
+                }
+            }
+            self.history_cache.invalidate_mempool();
+        }
+        drop(tracker);
+        if let Some(publisher) = publisher {
+            self.publish_txs(publisher, &added, 0)?;
+            for tx in &added {
+                publisher.mempool_tx(&tx.txid());
+            }
+        }
+        Ok(!changed.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::util::hash::bitcoin_merkle_root;
+    use std::str::FromStr;
+
+    use crate::index::{tx_num, TxRow};
+    use crate::store::TempStore;
+
+    #[test]
+    fn test_spenders() {
+        assert!(spenders_up_to_date(100, 9999999999, 100));
+        assert!(spenders_up_to_date(100, 50, 100));
+        assert!(!spenders_up_to_date(99, 9999999999, 100)); // lagging behind the tip
+
+        let store = TempStore::new("spenders");
+        let (funding, spending) = (Sha256dHash::hash(b"funding"), Sha256dHash::hash(b"spending"));
+        // only the spending transaction's number is indexed, without any `I` row
+        store.write_batch(vec![TxRow::new(tx_num(7, 1), &spending).to_row()], vec![]);
+        let mut spenders = Spenders::new();
+        spenders.insert((hash_prefix(&funding[..]), 1), vec![tx_num(7, 1)]);
+
+        let tx_nums = spending_tx_nums(&store, Some(&spenders), &funding, 1);
+        assert_eq!(tx_nums, vec![tx_num(7, 1)]);
+        assert_eq!(lookup_txid(&store, tx_nums[0]), Some(spending));
+        assert!(spending_tx_nums(&store, Some(&spenders), &funding, 0).is_empty());
+        assert!(spending_tx_nums(&store, None, &funding, 1).is_empty()); // no `I` row to scan
+    }
+
+    #[test]
+    fn test_merkle_branch() {
+        for len in 1..=7 {
+            let txids: Vec<Sha256dHash> = (0..len).map(|n| Sha256dHash::hash(&[n])).collect();
+            let root = bitcoin_merkle_root(txids.clone());
+            for pos in 0..txids.len() {
+                let branch = merkle_branch(txids.clone(), pos);
+                let computed = branch.iter().enumerate().fold(txids[pos], |hash, (level, sibling)| {
+                    let pair = match (pos >> level) & 1 {
+                        0 => [&hash[..], &sibling[..]].concat(),
+                        _ => [&sibling[..], &hash[..]].concat(),
+                    };
+                    Sha256dHash::hash(&pair)
+                });
+                assert_eq!(computed, root, "len {}, pos {}", len, pos);
+            }
+        }
+    }
+
+    #[test]
+    fn test_transaction_json() {
+        let genesis = bitcoin::blockdata::constants::genesis_block(Network::Bitcoin);
+        let tx = transaction_json(&genesis.txdata[0], Network::Bitcoin);
+        let txid = "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b";
+        assert_eq!(tx["txid"], json!(txid));
+        assert_eq!(tx["hash"], json!(txid));
+        assert_eq!(
+            (&tx["size"], &tx["vsize"], &tx["weight"]),
+            (&json!(204), &json!(204), &json!(816))
+        );
+        let coinbase = tx["vin"][0]["coinbase"].as_str().unwrap();
+        assert!(coinbase.starts_with("04ffff001d"));
+        assert_eq!(tx["vin"][0]["sequence"], json!(0xffffffffu32));
+        assert_eq!(tx["vout"][0]["value"], json!(50.0));
+        assert_eq!(tx["vout"][0]["scriptPubKey"]["type"], json!("pubkey"));
+        assert_eq!(tx["hex"].as_str().unwrap().len(), 2 * 204);
+
+        let script = Address::from_str("bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq")
+            .unwrap()
+            .script_pubkey();
+        assert_eq!(script_type(&script), "witness_v0_keyhash");
+    }
+
+    #[test]
+    fn test_coinbase_maturity() {
+        assert!(!is_mature(100, 198));
+        assert!(is_mature(100, 199));
+        let entry = |height: i64, position: Option<usize>| HistoryEntry {
+            txid: Sha256dHash::hash(b"tx"),
+            height,
+            position,
+            fee: None,
+            ancestors: None,
+        };
+        let coinbase = entry(100, Some(0)).to_json(150);
+        assert_eq!(coinbase["coinbase"], json!(true));
+        assert_eq!(coinbase["mature"], json!(false));
+        assert_eq!(entry(100, Some(0)).to_json(200)["mature"], json!(true));
+        assert!(entry(100, Some(1)).to_json(150).get("coinbase").is_none());
+        assert!(!entry(0, None).is_coinbase());
+
+        let txo = |blockindex: usize, position: Option<usize>| Txo {
+            txid: Sha256dHash::hash(b"tx"),
+            vout: 0,
+            blockindex,
+            position,
+        };
+        assert_eq!(txo(100, Some(0)).maturity(150), Some(false));
+        assert_eq!(txo(100, Some(0)).maturity(200), Some(true));
+        assert_eq!(txo(100, Some(1)).maturity(200), None);
+        assert_eq!(txo(100, None).maturity(200), None);
+        assert_eq!(txo(0, None).maturity(200), None);
+    }
+
+    #[test]
+    fn test_script_deltas() {
+        use bitcoin::blockdata::transaction::{OutPoint, TxIn, TxOut};
+
+        let genesis = bitcoin::blockdata::constants::genesis_block(Network::Bitcoin);
+        let coinbase = genesis.txdata[0].clone();
+        let output = |value: u64, script: &Script| TxOut {
+            value,
+            script_pubkey: script.clone(),
+        };
+        let spend = |prev: &Transaction, outputs: Vec<TxOut>| Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(prev.txid(), 0),
+                script_sig: Script::new(),
+                sequence: 0xffffffff,
+                witness: vec![],
+            }],
+            output: outputs,
+        };
+        let genesis_script = coinbase.output[0].script_pubkey.clone();
+        let other_script = Address::from_str("bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq")
+            .unwrap()
+            .script_pubkey();
+        // pays 10 BTC to another script, then spends it in the same block
+        let tx1 = spend(
+            &coinbase,
+            vec![output(1_000_000_000, &other_script), output(3_999_990_000, &genesis_script)],
+        );
+        let tx2 = spend(&tx1, vec![output(999_990_000, &genesis_script)]);
+        let prev_txs: HashMap<Sha256dHash, Transaction> = vec![(coinbase.txid(), coinbase.clone())]
+            .into_iter()
+            .collect();
+
+        let deltas = script_deltas(&[tx1.clone(), tx2.clone()], &prev_txs).unwrap();
+        let delta = |script: &Script| {
+            let script_hash = compute_script_hash(&script[..]);
+            let delta = deltas.iter().find(|d| d.script_hash == script_hash).unwrap();
+            (delta.funded, delta.spent, delta.txids.clone())
+        };
+        assert_eq!(deltas.len(), 2);
+        assert_eq!(
+            delta(&genesis_script),
+            (4_999_980_000, 5_000_000_000, vec![tx1.txid(), tx2.txid()])
+        );
+        assert_eq!(
+            delta(&other_script),
+            (1_000_000_000, 1_000_000_000, vec![tx1.txid(), tx2.txid()])
+        );
+        assert!(script_deltas(&[tx2], &prev_txs).is_err()); // tx1 is missing
+    }
+
+    #[test]
+    fn test_address_info() {
+        let tx = |height: usize, n: u8| {
+            let position = if height > 0 { Some(n as usize) } else { None };
+            (height, position, Sha256dHash::from_slice(&[n; 32]).unwrap())
+        };
+        let info = address_info(&[tx(0, 1), tx(0, 2), tx(10, 3), tx(12, 4), tx(15, 5)]);
+        assert_eq!(
+            info,
+            AddressInfo {
+                tx_count: 5,
+                mempool_tx_count: 2,
+                first_height: Some(10),
+                last_height: Some(15),
+            }
+        );
+        let info = address_info(&[tx(0, 1)]);
+        assert_eq!((info.first_height, info.last_height), (None, None));
+        assert_eq!(address_info(&[]).tx_count, 0);
+    }
+
+    #[test]
+    fn test_history_page_txs() {
+        let tx = |height: usize, n: u8| {
+            let position = if height > 0 { Some(n as usize) } else { None };
+            (height, position, Sha256dHash::from_slice(&[n; 32]).unwrap())
+        };
+        let txs = vec![tx(0, 1), tx(10, 2), tx(10, 3), tx(12, 4), tx(15, 5)];
+        assert_eq!(history_page_txs(txs.clone(), 0, 1), (vec![tx(10, 2), tx(10, 3)], Some(12)));
+        assert_eq!(history_page_txs(txs.clone(), 12, 1), (vec![tx(12, 4)], Some(15)));
+        assert_eq!(history_page_txs(txs.clone(), 13, 5), (vec![tx(15, 5), tx(0, 1)], None));
+        assert_eq!(history_page_txs(txs.clone(), 20, 5), (vec![tx(0, 1)], None));
+        assert_eq!(history_page_txs(txs, 0, usize::MAX).0.len(), 5);
     }
 }
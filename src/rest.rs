@@ -0,0 +1,384 @@
+use bitcoin::consensus::encode::serialize;
+use bitcoin_hashes::hex::{FromHex, ToHex};
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use error_chain::ChainedError;
+use serde_json::Value;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+use tiny_http;
+
+use crate::errors::*;
+use crate::index::address_script_hash;
+use crate::query::{self, set_maturity, HistoryEntry, Query, SpendingInput, Status, Txo};
+use crate::rpc::{is_timeout, same_secret, RateLimiter, ServerSettings};
+use crate::util::spawn_thread;
+
+const REST_THREADS: usize = 4;
+// Transactions of an address history per page, as Esplora does
+const CHAIN_TXS_PER_PAGE: usize = 25;
+const MAX_MEMPOOL_TXS: usize = 50;
+
+//
+// Errors returned to the HTTP client
+//
+enum HttpError {
+    BadRequest(String),
+    Unauthorized,
+    NotFound(String),
+    TooManyRequests,
+    Unavailable, // no query thread is available
+    Internal(Error),
+}
+
+impl From<Error> for HttpError {
+    fn from(e: Error) -> Self {
+        HttpError::Internal(e)
+    }
+}
+
+enum Reply {
+    Json(Value),
+    Text(String),
+}
+
+type HttpResult = std::result::Result<Reply, HttpError>;
+
+//
+// Esplora-style REST API
+//
+struct Handler {
+    query: Arc<Query>,
+    rate_limiter: Arc<RateLimiter>,
+    settings: Arc<ServerSettings>,
+}
+
+// Esplora's "status" object for a given height (0 means unconfirmed)
+fn tx_status(query: &Query, height: usize) -> Value {
+    match query.get_header(height).filter(|_| height > 0) {
+        Some(entry) => json!({
+            "confirmed": true,
+            "block_height": height,
+            "block_hash": entry.hash().to_hex(),
+            "block_time": entry.header().time,
+        }),
+        None => json!({"confirmed": false}),
+    }
+}
+
+// The `Authorization: Bearer <token>` of a request (if any)
+fn bearer_token(headers: &[tiny_http::Header]) -> Option<&str> {
+    headers
+        .iter()
+        .find(|header| header.field.equiv("Authorization"))
+        .and_then(|header| header.value.as_str().strip_prefix("Bearer "))
+}
+
+// Page of the confirmed transactions (newest first), after the `last_seen` one
+fn chain_page<'a>(
+    chain: &'a [HistoryEntry],
+    last_seen: Option<&Sha256dHash>,
+) -> Option<&'a [HistoryEntry]> {
+    let start = match last_seen {
+        Some(txid) => chain.iter().position(|entry| entry.txid == *txid)? + 1,
+        None => 0,
+    };
+    let end = chain.len().min(start + CHAIN_TXS_PER_PAGE);
+    Some(&chain[start..end])
+}
+
+fn txs_json(query: &Query, entries: &[HistoryEntry]) -> Vec<Value> {
+    entries
+        .iter()
+        .map(|entry| {
+            json!({
+                "txid": entry.txid.to_hex(),
+                "status": tx_status(query, entry.height.max(0) as usize),
+            })
+        })
+        .collect()
+}
+
+fn txo_stats(query: &Query, funding: &[Txo], spending: &[SpendingInput]) -> Result<Value> {
+    let funded: Vec<&Txo> = funding.iter().collect();
+    let funded_sum: u64 = query.txo_values(&funded)?.iter().sum();
+    // spent outputs may be funded by confirmed or mempool transactions
+    let spent: Vec<Txo> = spending
+        .iter()
+        .map(|s| Txo {
+            txid: s.outpoint.0,
+            vout: s.outpoint.1,
+            blockindex: 0,
+            position: None,
+        })
+        .collect();
+    let spent_sum: u64 = query.txo_values(&spent.iter().collect::<Vec<_>>())?.iter().sum();
+    let mut txids: Vec<&Sha256dHash> = funding
+        .iter()
+        .map(|f| &f.txid)
+        .chain(spending.iter().map(|s| &s.txid))
+        .collect();
+    txids.sort_unstable();
+    txids.dedup();
+    Ok(json!({
+        "funded_txo_count": funding.len(),
+        "funded_txo_sum": funded_sum,
+        "spent_txo_count": spending.len(),
+        "spent_txo_sum": spent_sum,
+        "tx_count": txids.len(),
+    }))
+}
+
+impl Handler {
+    fn status(&self, address: &str) -> std::result::Result<Status, HttpError> {
+        let script_hash = address_script_hash(address, self.settings.network)
+            .map_err(|e| HttpError::BadRequest(e.to_string()))?;
+        Ok(self.query.status(&script_hash[..], 9999999999, false)?)
+    }
+
+    fn address(&self, address: &str) -> HttpResult {
+        let status = self.status(address)?;
+        let (funding, spending) = status.confirmed();
+        let chain_stats = txo_stats(&self.query, funding, spending)?;
+        let (funding, spending) = status.mempool();
+        let mempool_stats = txo_stats(&self.query, funding, spending)?;
+        Ok(Reply::Json(json!({
+            "address": address,
+            "chain_stats": chain_stats,
+            "mempool_stats": mempool_stats,
+            "history_start_height": self.query.history_start_height(),
+        })))
+    }
+
+    // The mempool transactions of an address, and its confirmed ones (newest first)
+    fn history(
+        &self,
+        address: &str,
+    ) -> std::result::Result<(Vec<HistoryEntry>, Vec<HistoryEntry>), HttpError> {
+        let mut entries = self.query.history(&self.status(address)?)?;
+        entries.reverse();
+        Ok(entries.into_iter().partition(|entry| entry.height <= 0))
+    }
+
+    fn address_txs(&self, address: &str) -> HttpResult {
+        let (mempool, chain) = self.history(address)?;
+        let mempool = &mempool[..mempool.len().min(MAX_MEMPOOL_TXS)];
+        let chain = chain_page(&chain, None).unwrap_or_default();
+        let mut txs = txs_json(&self.query, mempool);
+        txs.extend(txs_json(&self.query, chain));
+        Ok(Reply::Json(json!(txs)))
+    }
+
+    fn address_txs_chain(&self, address: &str, last_seen: Option<&str>) -> HttpResult {
+        let last_seen = last_seen.map(Handler::parse_txid).transpose()?;
+        let (_, chain) = self.history(address)?;
+        let page = chain_page(&chain, last_seen.as_ref()).ok_or_else(|| {
+            HttpError::NotFound(format!("transaction {} not in the history", last_seen.unwrap()))
+        })?;
+        Ok(Reply::Json(json!(txs_json(&self.query, page))))
+    }
+
+    fn address_txs_mempool(&self, address: &str) -> HttpResult {
+        let (mempool, _) = self.history(address)?;
+        let mempool = &mempool[..mempool.len().min(MAX_MEMPOOL_TXS)];
+        Ok(Reply::Json(json!(txs_json(&self.query, mempool))))
+    }
+
+    fn address_utxo(&self, address: &str) -> HttpResult {
+        let script_hash = address_script_hash(address, self.settings.network)
+            .map_err(|e| HttpError::BadRequest(e.to_string()))?;
+        let tip_height = self.query.get_best_header()?.height();
+        let mut utxos = vec![];
+        for (txo, value) in self.query.unspent(&script_hash[..])? {
+            let mut utxo = json!({
+                "txid": txo.txid.to_hex(),
+                "vout": txo.vout,
+                "value": value,
+                "status": tx_status(&self.query, txo.blockindex),
+            });
+            set_maturity(&mut utxo, txo.maturity(tip_height));
+            utxos.push(utxo);
+        }
+        Ok(Reply::Json(json!(utxos)))
+    }
+
+    fn parse_txid(txid: &str) -> std::result::Result<Sha256dHash, HttpError> {
+        Sha256dHash::from_hex(txid).map_err(|_| HttpError::BadRequest(format!("invalid txid {}", txid)))
+    }
+
+    fn tx(&self, txid: &str) -> HttpResult {
+        let txid = Handler::parse_txid(txid)?;
+        let tx = self
+            .query
+            .get_transaction_json(&txid)
+            .map_err(|_| HttpError::NotFound(format!("transaction {} not found", txid)))?;
+        Ok(Reply::Json(tx))
+    }
+
+    fn tx_hex(&self, txid: &str) -> HttpResult {
+        let txid = Handler::parse_txid(txid)?;
+        let tx = self
+            .query
+            .get_transaction(&txid)
+            .map_err(|_| HttpError::NotFound(format!("transaction {} not found", txid)))?;
+        Ok(Reply::Text(hex::encode(serialize(&tx))))
+    }
+
+    fn tx_outspend(&self, txid: &str, vout: &str) -> HttpResult {
+        let txid = Handler::parse_txid(txid)?;
+        let vout = vout
+            .parse::<usize>()
+            .map_err(|_| HttpError::BadRequest(format!("invalid vout {}", vout)))?;
+        Ok(Reply::Json(match self.query.get_spender(&txid, vout)? {
+            Some(spent) => json!({
+                "spent": true,
+                "txid": spent.txid.to_hex(),
+                "status": tx_status(&self.query, spent.blockindex),
+            }),
+            None => json!({"spent": false}),
+        }))
+    }
+
+    fn route(&self, path: &str) -> HttpResult {
+        let parts: Vec<&str> = path.trim_matches('/').split('/').collect();
+        match parts.as_slice() {
+            ["address", address] => self.address(address),
+            ["address", address, "txs"] => self.address_txs(address),
+            ["address", address, "txs", "chain"] => self.address_txs_chain(address, None),
+            ["address", address, "txs", "chain", txid] => {
+                self.address_txs_chain(address, Some(txid))
+            }
+            ["address", address, "txs", "mempool"] => self.address_txs_mempool(address),
+            ["address", address, "utxo"] => self.address_utxo(address),
+            ["tx", txid] => self.tx(txid),
+            ["tx", txid, "hex"] => self.tx_hex(txid),
+            ["tx", txid, "outspend", vout] => self.tx_outspend(txid, vout),
+            _ => Err(HttpError::NotFound(format!("unknown path {}", path))),
+        }
+    }
+
+    // Check the token and rate limit of the client, as for the RPC requests
+    fn authorize(&self, request: &tiny_http::Request) -> std::result::Result<(), HttpError> {
+        if let Some(ref expected) = self.settings.auth_token {
+            let token = bearer_token(request.headers());
+            if !token.is_some_and(|token| same_secret(token.as_bytes(), expected.as_bytes())) {
+                return Err(HttpError::Unauthorized);
+            }
+        }
+        if !self.rate_limiter.allow(request.remote_addr().ip(), Instant::now()) {
+            return Err(HttpError::TooManyRequests);
+        }
+        Ok(())
+    }
+
+    // Route the request in a `query_threads` slot, until `query_timeout`
+    fn serve(&self, path: &str) -> HttpResult {
+        let result = self.settings.query_pool.run(|| {
+            let deadline = self.settings.query_timeout.map(|timeout| Instant::now() + timeout);
+            query::with_deadline(deadline, || self.route(path))
+        });
+        result.unwrap_or(Err(HttpError::Unavailable))
+    }
+
+    fn handle(&self, request: tiny_http::Request) {
+        let url = request.url().to_owned();
+        let path = url.split('?').next().unwrap_or_default();
+        let result = if *request.method() == tiny_http::Method::Get {
+            self.authorize(&request).and_then(|()| self.serve(path))
+        } else {
+            Err(HttpError::BadRequest(format!("unsupported method {}", request.method())))
+        };
+        let (code, content_type, body) = match result {
+            Ok(Reply::Json(value)) => (200, "application/json", value.to_string()),
+            Ok(Reply::Text(text)) => (200, "text/plain", text),
+            Err(HttpError::BadRequest(msg)) => (400, "text/plain", msg),
+            Err(HttpError::Unauthorized) => {
+                (401, "text/plain", "invalid or missing bearer token".to_owned())
+            }
+            Err(HttpError::NotFound(msg)) => (404, "text/plain", msg),
+            Err(HttpError::TooManyRequests) => (429, "text/plain", "rate limit exceeded".to_owned()),
+            Err(HttpError::Unavailable) => (503, "text/plain", "server busy".to_owned()),
+            Err(HttpError::Internal(ref e)) if is_timeout(e) => {
+                (504, "text/plain", "query timed out".to_owned())
+            }
+            Err(HttpError::Internal(e)) => {
+                warn!("REST {} failed: {}", url, e.display_chain());
+                (500, "text/plain", e.to_string())
+            }
+        };
+        let header = tiny_http::Header::from_bytes(&b"Content-Type"[..], content_type.as_bytes())
+            .expect("invalid header");
+        let response = tiny_http::Response::from_string(body)
+            .with_status_code(code)
+            .with_header(header);
+        if let Err(e) = request.respond(response) {
+            debug!("failed to send REST response: {}", e);
+        }
+    }
+}
+
+pub fn start(
+    addr: SocketAddr,
+    query: Arc<Query>,
+    rate_limiter: Arc<RateLimiter>,
+    settings: Arc<ServerSettings>,
+) -> Result<()> {
+    let server = Arc::new(
+        tiny_http::Server::http(addr)
+            .map_err(|e| format!("failed to start REST server at {}: {}", addr, e))?,
+    );
+    info!("REST server running on {}", addr);
+    let handler = Arc::new(Handler {
+        query,
+        rate_limiter,
+        settings,
+    });
+    for _ in 0..REST_THREADS {
+        let server = Arc::clone(&server);
+        let handler = Arc::clone(&handler);
+        spawn_thread("rest", move || loop {
+            match server.recv() {
+                Ok(request) => handler.handle(request),
+                Err(e) => warn!("REST server failed: {}", e),
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin_hashes::Hash;
+
+    use super::*;
+
+    #[test]
+    fn test_chain_page() {
+        let entry = |n: u8| HistoryEntry {
+            txid: Sha256dHash::from_slice(&[n; 32]).unwrap(),
+            height: 100 - n as i64,
+            position: Some(0),
+            fee: None,
+            ancestors: None,
+        };
+        let chain: Vec<HistoryEntry> = (0..30).map(entry).collect();
+        let page = chain_page(&chain, None).unwrap();
+        assert_eq!(page.len(), CHAIN_TXS_PER_PAGE);
+        let last_seen = page.last().unwrap().txid;
+        let page = chain_page(&chain, Some(&last_seen)).unwrap();
+        assert_eq!(page.len(), 5);
+        assert_eq!(page[0].txid, chain[CHAIN_TXS_PER_PAGE].txid);
+        assert!(chain_page(&chain, Some(&chain[29].txid)).unwrap().is_empty());
+        assert!(chain_page(&chain, Some(&entry(30).txid)).is_none());
+    }
+
+    #[test]
+    fn test_bearer_token() {
+        let header = |field: &str, value: &str| {
+            tiny_http::Header::from_bytes(field.as_bytes(), value.as_bytes()).unwrap()
+        };
+        assert_eq!(bearer_token(&[header("authorization", "Bearer secret")]), Some("secret"));
+        assert_eq!(bearer_token(&[header("Authorization", "Basic secret")]), None);
+        assert_eq!(bearer_token(&[header("Host", "localhost")]), None);
+    }
+}
@@ -1,23 +1,54 @@
-use bitcoin::consensus::encode::serialize;
+use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::network::constants::Network;
+use bitcoin::consensus::encode::{deserialize, serialize};
 use bitcoin_hashes::hex::{FromHex, ToHex};
 use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use bitcoin_hashes::Hash;
 use error_chain::ChainedError;
 use serde_json::{from_str, Value};
-use std::collections::HashMap;
-use std::io::{BufRead, BufReader, Write};
-use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
-use std::sync::mpsc::SyncSender;
-use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io::{self, Write};
+use std::net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
+use crate::descriptor::Descriptor;
 use crate::errors::*;
-use crate::query::Query;
-use crate::util::{spawn_thread, Channel, SyncChannel};
+use crate::index::address_script_hash;
+use crate::mempool::Conflict;
+use crate::metrics::{CounterVec, HistogramVec, Metrics};
+use crate::poll::{self, Socket, Waker};
+use crate::query::{self, set_maturity, sort_history, HistoryEntry, Query};
+use crate::tls::TlsAcceptor;
+use crate::trace;
+use crate::util::{full_hash, spawn_thread, Channel, FullHash, HeaderEntry};
+use crate::webhook::Webhooks;
+use crate::zmq::Refresher;
 
 // Indexer version
 const ADDRINDEXRS_VERSION: &str = env!("CARGO_PKG_VERSION");
-// Version of the simulated electrum protocol
-const PROTOCOL_VERSION: &str = "1.4";
+// Versions of the simulated electrum protocol (compared component-wise)
+const PROTOCOL_MIN: &[u32] = &[1, 2];
+const PROTOCOL_MAX: &[u32] = &[1, 4];
+// Consecutive unused scripts ending a wallet scan
+const DEFAULT_GAP_LIMIT: u64 = 20;
+const MAX_GAP_LIMIT: u64 = 1000;
+// Headers returned at once by blockchain.block.headers
+const MAX_HEADERS: usize = 2016;
+// Blocks scanned at once by blockchain.counterparty.get_txs
+const MAX_COUNTERPARTY_BLOCKS: usize = 1000;
+// Script hashes looked up at once by blockchain.scripthash.get_status_batch
+const MAX_BATCH_SCRIPT_HASHES: usize = 1000;
+// Blocks whose rows are counted at once by server.index_stats
+const MAX_STATS_BLOCKS: usize = 1000;
 
 //
 // Get a script hash from a given value
@@ -30,60 +61,645 @@ fn hash_from_value(val: Option<&Value>) -> Result<Sha256dHash> {
     Ok(script_hash)
 }
 
+// Parse an electrum protocol version (e.g. "1.4.2")
+fn parse_version(version: &str) -> Result<Vec<u32>> {
+    version
+        .split('.')
+        .map(|part| part.parse::<u32>())
+        .collect::<std::result::Result<Vec<u32>, _>>()
+        .chain_err(|| format!("bad protocol version {:?}", version))
+}
+
+fn format_version(version: &[u32]) -> String {
+    let parts: Vec<String> = version.iter().map(|part| part.to_string()).collect();
+    parts.join(".")
+}
+
+// Latest version supported by both the client and the server (if any)
+fn negotiate_version(client_min: &[u32], client_max: &[u32]) -> Option<Vec<u32>> {
+    let version = client_max.min(PROTOCOL_MAX);
+    if version < client_min || version < PROTOCOL_MIN {
+        return None;
+    }
+    Some(version.to_vec())
+}
+
+// Version introducing each electrum method (the other ones are always available)
+fn method_min_version(method: &str) -> &'static [u32] {
+    match method {
+        "blockchain.block.header" => &[1, 3],
+        "blockchain.scripthash.unsubscribe" | "blockchain.transaction.id_from_pos" => &[1, 4],
+        _ => PROTOCOL_MIN,
+    }
+}
+
+// Whether the error was caused by a query's deadline
+pub fn is_timeout(e: &Error) -> bool {
+    if let ErrorKind::Timeout = e.kind() {
+        return true;
+    }
+    let source = std::error::Error::source(e).and_then(|cause| cause.downcast_ref::<Error>());
+    source.is_some_and(is_timeout)
+}
+
+//
+// Settings of the RPC server, shared by its connections
+//
+pub struct ServerSettings {
+    pub max_connections: usize,     // 0 for no limit
+    pub auth_token: Option<String>, // required by server.auth
+    pub banner: Option<String>,     // returned by server.banner
+    pub network: Network,           // of the addresses taken by blockchain.address.*
+    pub query_timeout: Option<Duration>,
+    pub query_pool: Arc<QueryPool>,
+    pub webhooks: Option<Arc<Webhooks>>, // managed by the webhook.* RPCs
+    pub refresher: Arc<Refresher>,       // of the main loop, for server.refresh
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+struct Limits {
+    rate: f64,  // requests per second (0 for no limit)
+    burst: f64, // size of the buckets
+    buckets: HashMap<IpAddr, Bucket>,
+}
+
+//
+// Token buckets of the requests, shared by the connections of each client IP
+//
+pub struct RateLimiter {
+    limits: Mutex<Limits>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: usize) -> Arc<RateLimiter> {
+        let limiter = RateLimiter {
+            limits: Mutex::new(Limits {
+                rate: 0.0,
+                burst: 0.0,
+                buckets: HashMap::new(),
+            }),
+        };
+        limiter.set_limits(rate, burst);
+        Arc::new(limiter)
+    }
+
+    pub fn set_limits(&self, rate: f64, burst: usize) {
+        let mut limits = self.limits.lock().unwrap();
+        limits.rate = rate.max(0.0);
+        limits.burst = burst.max(1) as f64;
+        limits.buckets.clear();
+    }
+
+    // Takes a token from the client's bucket, refilled since its last request
+    pub fn allow(&self, ip: IpAddr, now: Instant) -> bool {
+        let mut limits = self.limits.lock().unwrap();
+        if limits.rate == 0.0 {
+            return true;
+        }
+        let (rate, burst) = (limits.rate, limits.burst);
+        if limits.buckets.len() >= 10_000 {
+            // forget the clients whose buckets are full again
+            limits.buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate < burst
+            });
+        }
+        let bucket = limits.buckets.entry(ip).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+struct PoolState {
+    running: usize,
+    next_ticket: u64,           // given to the next queued request
+    serving: u64,               // ticket of the first queued request
+    jobs: VecDeque<(u64, Job)>, // queued by spawn, with their tickets
+    executors: usize,           // threads running the jobs
+}
+
 //
-// Connection with a RPC client
+// Execution slots of the RPC requests, shared by the connections: at most
+// `workers` requests run at once, and the other ones wait in a FIFO queue.
+// The requests either wait for their slot (run), or are queued as jobs,
+// executed by the pool's own threads (spawn).
+//
+pub struct QueryPool {
+    state: Mutex<PoolState>,
+    changed: Condvar,
+    workers: usize,
+    max_queued: usize, // 0 for no limit
+}
+
+impl QueryPool {
+    pub fn new(workers: usize, max_queued: usize) -> QueryPool {
+        QueryPool {
+            state: Mutex::new(PoolState {
+                running: 0,
+                next_ticket: 0,
+                serving: 0,
+                jobs: VecDeque::new(),
+                executors: 0,
+            }),
+            changed: Condvar::new(),
+            workers: workers.max(1),
+            max_queued,
+        }
+    }
+
+    // Returns None if the queue is full
+    pub fn run<T>(&self, f: impl FnOnce() -> T) -> Option<T> {
+        let mut state = self.state.lock().unwrap();
+        let queued = (state.next_ticket - state.serving) as usize;
+        if self.max_queued > 0 && queued >= self.max_queued {
+            return None;
+        }
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        while ticket != state.serving || state.running >= self.workers {
+            state = self.changed.wait(state).unwrap();
+        }
+        state.serving += 1;
+        state.running += 1;
+        drop(state);
+        self.changed.notify_all(); // the next request may start too
+
+        let result = f();
+
+        self.state.lock().unwrap().running -= 1;
+        self.changed.notify_all();
+        Some(result)
+    }
+
+    // Whether the new requests are rejected
+    pub fn is_full(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        self.max_queued > 0 && (state.next_ticket - state.serving) as usize >= self.max_queued
+    }
+
+    // Queue a job (regardless of the queue size), run by one of the pool's threads
+    pub fn spawn(self: &Arc<Self>, job: impl FnOnce() + Send + 'static) {
+        let mut state = self.state.lock().unwrap();
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.jobs.push_back((ticket, Box::new(job)));
+        if state.executors < self.workers {
+            state.executors += 1;
+            let pool = Arc::clone(self);
+            spawn_thread("query", move || pool.execute());
+        }
+        drop(state);
+        self.changed.notify_all();
+    }
+
+    fn execute(&self) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let next = state.jobs.front().map(|(ticket, _)| *ticket);
+            if next != Some(state.serving) || state.running >= self.workers {
+                state = self.changed.wait(state).unwrap();
+                continue;
+            }
+            let (_, job) = state.jobs.pop_front().unwrap();
+            state.serving += 1;
+            state.running += 1;
+            drop(state);
+            self.changed.notify_all();
+
+            if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                error!("RPC job panicked");
+            }
+
+            state = self.state.lock().unwrap();
+            state.running -= 1;
+            self.changed.notify_all();
+        }
+    }
+}
+
+// The method, params and id of a request (None if it is invalid)
+fn parse_request(cmd: &Value) -> Option<(&str, &[Value], &Value)> {
+    let params = match cmd.get("params") {
+        Some(Value::Array(params)) => &params[..],
+        Some(_) => return None,
+        None => &[],
+    };
+    Some((cmd.get("method")?.as_str()?, params, cmd.get("id")?))
+}
+
+// Error replied to an invalid request of a batch (or to an empty batch)
+fn invalid_request(cmd: &Value) -> Value {
+    let error = json!({"code": -32600, "message": "invalid request"});
+    json!({"jsonrpc": "2.0", "id": cmd.get("id"), "error": error})
+}
+
+// Compare the secrets in constant time (for a given length)
+pub fn same_secret(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+//
+// Metrics of the RPC requests, shared by the connections
+//
+struct RpcMetrics {
+    requests: Arc<CounterVec>,
+    durations: Arc<HistogramVec>,
+}
+
+impl RpcMetrics {
+    fn new(metrics: &Metrics) -> RpcMetrics {
+        RpcMetrics {
+            requests: metrics.counter_vec(
+                "addrindexrs_rpc_requests_total",
+                "Number of RPC requests by method",
+                "method",
+            ),
+            durations: metrics.histogram_vec(
+                "addrindexrs_rpc_request_duration_seconds",
+                "Duration of the RPC requests by method",
+                "method",
+            ),
+        }
+    }
+}
+
+//
+// Session of a RPC client, handling its requests on the query pool
 //
 struct Connection {
     query: Arc<Query>,
-    stream: TcpStream,
     addr: SocketAddr,
-    chan: SyncChannel<Message>,
+    id: usize, // for the logs and traces
+    metrics: Arc<RpcMetrics>,
+    rate_limiter: Arc<RateLimiter>,
+    settings: Arc<ServerSettings>,
+    auth_token: Option<String>, // reset once the client is authenticated
+    protocol_version: Option<Vec<u32>>, // negotiated by server.version
+    status_hashes: HashMap<Sha256dHash, Value>, // subscribed script hashes
+    last_header_entry: Option<HeaderEntry>,     // set when subscribed to headers
+    last_conflict_id: u64,                      // of the notified mempool conflicts
 }
 
 impl Connection {
     pub fn new(
         query: Arc<Query>,
-        stream: TcpStream,
         addr: SocketAddr,
+        id: usize,
+        metrics: Arc<RpcMetrics>,
+        rate_limiter: Arc<RateLimiter>,
+        settings: Arc<ServerSettings>,
     ) -> Connection {
+        let last_conflict_id = query.last_conflict_id();
         Connection {
             query,
-            stream,
             addr,
-            chan: SyncChannel::new(10),
+            id,
+            metrics,
+            rate_limiter,
+            auth_token: settings.auth_token.clone(),
+            settings,
+            protocol_version: None,
+            status_hashes: HashMap::new(),
+            last_header_entry: None,
+            last_conflict_id,
         }
     }
 
-    fn server_version(&self) -> Result<Value> {
-        Ok(json!([
+    fn server_auth(&mut self, params: &[Value]) -> Result<Value> {
+        let token = params
+            .first()
+            .and_then(Value::as_str)
+            .chain_err(|| "missing token")?;
+        match self.auth_token {
+            Some(ref expected) if !same_secret(token.as_bytes(), expected.as_bytes()) => {
+                warn!("[{}] authentication failed", self.addr);
+                bail!("invalid token")
+            }
+            _ => self.auth_token = None,
+        }
+        Ok(Value::Bool(true))
+    }
+
+    // Negotiate the protocol version, from the client's version or [min, max] range
+    fn server_version(&mut self, params: &[Value]) -> Result<Value> {
+        if self.protocol_version.is_some() {
+            bail!("server.version already sent");
+        }
+        if let Some(client) = params.first().and_then(Value::as_str) {
+            debug!("[{}] client {:?}", self.addr, client);
+        }
+        let (client_min, client_max) = match params.get(1) {
+            None => (PROTOCOL_MAX.to_vec(), PROTOCOL_MAX.to_vec()),
+            Some(Value::String(version)) => (parse_version(version)?, parse_version(version)?),
+            Some(Value::Array(range)) if range.len() == 2 => {
+                let parse = |value: &Value| {
+                    parse_version(value.as_str().chain_err(|| "non-string protocol version")?)
+                };
+                (parse(&range[0])?, parse(&range[1])?)
+            }
+            Some(_) => bail!("bad protocol_version"),
+        };
+        let version = negotiate_version(&client_min, &client_max).chain_err(|| {
+            format!(
+                "unsupported protocol version (supported: {} to {})",
+                format_version(PROTOCOL_MIN),
+                format_version(PROTOCOL_MAX)
+            )
+        })?;
+        let result = json!([
             format!("addrindexrs {}", ADDRINDEXRS_VERSION),
-            PROTOCOL_VERSION
-        ]))
+            format_version(&version)
+        ]);
+        self.protocol_version = Some(version);
+        Ok(result)
+    }
+
+    fn server_banner(&self) -> Result<Value> {
+        Ok(json!(match self.settings.banner {
+            Some(ref banner) => banner.clone(),
+            None => format!("Welcome to addrindexrs {}", ADDRINDEXRS_VERSION),
+        }))
+    }
+
+    // Watch more scripts (in watch-only mode), from their next transactions
+    fn watchlist_add(&self, params: &[Value]) -> Result<Value> {
+        if self.settings.auth_token.is_none() {
+            bail!("watchlist.add requires an auth_token");
+        }
+        let watchlist = self.query.watchlist().chain_err(|| "watch-only mode is disabled")?;
+        let entries = params
+            .iter()
+            .map(|entry| entry.as_str().chain_err(|| "non-string watchlist entry"))
+            .collect::<Result<Vec<&str>>>()?;
+        let added = watchlist.add(&entries)?;
+        Ok(json!({"added": added, "scripts": watchlist.len()}))
+    }
+
+    // Webhooks, for the operators (like watchlist.add)
+    fn webhooks(&self, method: &str) -> Result<&Webhooks> {
+        if self.settings.auth_token.is_none() {
+            bail!("{} requires an auth_token", method);
+        }
+        let webhooks = self.settings.webhooks.as_ref();
+        Ok(webhooks.chain_err(|| "webhooks are disabled (see webhook_file)")?)
+    }
+
+    fn webhook_register(&self, params: &[Value]) -> Result<Value> {
+        let webhooks = self.webhooks("webhook.register")?;
+        let url = params.first().and_then(Value::as_str).chain_err(|| "missing URL")?;
+        let entries = params
+            .get(1)
+            .and_then(Value::as_array)
+            .chain_err(|| "missing addresses or script hashes")?
+            .iter()
+            .map(|entry| entry.as_str().chain_err(|| "non-string webhook entry"))
+            .collect::<Result<Vec<&str>>>()?;
+        let added = webhooks.register(&self.query, url, &entries)?;
+        Ok(json!({"added": added}))
+    }
+
+    fn webhook_unregister(&self, params: &[Value]) -> Result<Value> {
+        let webhooks = self.webhooks("webhook.unregister")?;
+        let url = params.first().and_then(Value::as_str).chain_err(|| "missing URL")?;
+        Ok(json!(webhooks.unregister(url)?))
+    }
+
+    // Index DB statistics, for the operators (like watchlist.add)
+    fn server_db_stats(&self) -> Result<Value> {
+        if self.settings.auth_token.is_none() {
+            bail!("server.db_stats requires an auth_token");
+        }
+        Ok(self.query.get_db_stats())
+    }
+
+    // Index the new blocks and sync the mempool now (e.g. for regtest tests
+    // querying the blocks they just mined), returning the new status.
+    // Concurrent requests are coalesced into the same update.
+    fn server_refresh(&self) -> Result<Value> {
+        if !self.settings.refresher.refresh(self.settings.query_timeout) {
+            bail!(ErrorKind::Timeout);
+        }
+        self.query.get_status()
+    }
+
+    // Rows of the index by code, and of the `count` blocks from `from_height`
+    fn server_index_stats(&self, params: &[Value]) -> Result<Value> {
+        if self.settings.auth_token.is_none() {
+            bail!("server.index_stats requires an auth_token");
+        }
+        let from_height = match params.first() {
+            Some(value) => value.as_u64().chain_err(|| "bad from_height")? as usize,
+            None => 0,
+        };
+        let count = match params.get(1) {
+            Some(value) => value.as_u64().chain_err(|| "bad count")? as usize,
+            None => 0,
+        };
+        if count > MAX_STATS_BLOCKS {
+            bail!("too many blocks (max {})", MAX_STATS_BLOCKS);
+        }
+        let height = self.query.get_best_header()?.height();
+        let stats = self.query.get_index_stats(from_height..from_height + count);
+        let rows: serde_json::Map<String, Value> = stats
+            .rows
+            .iter()
+            .map(|(code, rows)| {
+                let counts = json!({"count": rows.count, "size": rows.size});
+                ((*code as char).to_string(), counts)
+            })
+            .collect();
+        let blocks: Vec<Value> = stats
+            .blocks
+            .iter()
+            .map(|(height, block)| {
+                json!({
+                    "height": height,
+                    "transactions": block.transactions,
+                    "outputs": block.outputs,
+                    "inputs": block.inputs,
+                })
+            })
+            .collect();
+        Ok(json!({
+            "height": height,
+            "rows": rows,
+            "outputs": stats.outputs,
+            "script_hashes": stats.script_hashes,
+            "blocks": blocks,
+        }))
+    }
+
+    // The servers of other hosts aren't announced
+    fn server_features(&self) -> Result<Value> {
+        let genesis = self.query.get_header(0).chain_err(|| "missing genesis header")?;
+        Ok(json!({
+            "genesis_hash": genesis.hash().to_hex(),
+            "hosts": {},
+            "protocol_min": format_version(PROTOCOL_MIN),
+            "protocol_max": format_version(PROTOCOL_MAX),
+            "pruning": match self.query.history_start_height() {
+                0 => Value::Null,
+                height => json!(height),
+            },
+            "server_version": format!("addrindexrs {}", ADDRINDEXRS_VERSION),
+            "hash_function": "sha256",
+        }))
+    }
+
+    fn header_json(entry: &HeaderEntry) -> Value {
+        let hex_header = hex::encode(serialize(entry.header()));
+        json!({"hex": hex_header, "height": entry.height()})
+    }
+
+    // Checkpoint proofs (i.e. a non-zero `cp_height`) aren't supported
+    fn check_cp_height(cp_height: Option<&Value>) -> Result<()> {
+        match cp_height.map(|value| value.as_u64().chain_err(|| "bad cp_height")) {
+            None => Ok(()),
+            Some(Ok(0)) => Ok(()),
+            Some(Ok(_)) => bail!("cp_height is not supported"),
+            Some(Err(e)) => Err(e),
+        }
+    }
+
+    fn blockchain_block_header(&self, params: &[Value]) -> Result<Value> {
+        let height = params
+            .first()
+            .and_then(Value::as_u64)
+            .chain_err(|| "missing height")?;
+        Connection::check_cp_height(params.get(1))?;
+        let entry = self
+            .query
+            .get_header(height as usize)
+            .chain_err(|| format!("missing header at height {}", height))?;
+        Ok(json!(hex::encode(serialize(entry.header()))))
+    }
+
+    // Scripts funded or spent by a block (given by height or hash), with their amounts
+    fn blockchain_block_get_deltas(&self, params: &[Value]) -> Result<Value> {
+        let entry = match params.first() {
+            Some(Value::Number(height)) => {
+                let height = height.as_u64().chain_err(|| "bad height")?;
+                self.query
+                    .get_header(height as usize)
+                    .chain_err(|| format!("missing header at height {}", height))?
+            }
+            Some(Value::String(_)) => {
+                let blockhash = hash_from_value(params.first()).chain_err(|| "bad block hash")?;
+                self.query.get_block_index(blockhash)?
+            }
+            _ => bail!("missing height or block hash"),
+        };
+        let deltas: Vec<Value> = self
+            .query
+            .block_deltas(&entry)?
+            .into_iter()
+            .map(|delta| {
+                let mut script_hash = delta.script_hash;
+                script_hash.reverse(); // displayed like Electrum script hashes
+                let txids: Vec<String> = delta.txids.iter().map(|txid| txid.to_hex()).collect();
+                json!({
+                    "scripthash": hex::encode(script_hash),
+                    "funded": delta.funded,
+                    "spent": delta.spent,
+                    "txids": txids,
+                })
+            })
+            .collect();
+        Ok(json!({
+            "height": entry.height(),
+            "blockhash": entry.hash().to_hex(),
+            "deltas": deltas,
+        }))
+    }
+
+    // Concatenated headers from `start_height`, up to the tip
+    fn blockchain_block_headers(&self, params: &[Value]) -> Result<Value> {
+        let start_height = params
+            .first()
+            .and_then(Value::as_u64)
+            .chain_err(|| "missing start_height")? as usize;
+        let count = params
+            .get(1)
+            .and_then(Value::as_u64)
+            .chain_err(|| "missing count")? as usize;
+        Connection::check_cp_height(params.get(2))?;
+        let mut hex_headers = String::new();
+        let mut headers = 0;
+        for height in (start_height..).take(count.min(MAX_HEADERS)) {
+            match self.query.get_header(height) {
+                Some(entry) => hex_headers.push_str(&hex::encode(serialize(entry.header()))),
+                None => break,
+            }
+            headers += 1;
+        }
+        Ok(json!({"count": headers, "hex": hex_headers, "max": MAX_HEADERS}))
     }
 
     fn blockchain_headers_subscribe(&mut self) -> Result<Value> {
         let entry = self.query.get_best_header()?;
-        let hex_header = hex::encode(serialize(entry.header()));
-        let result = json!({"hex": hex_header, "height": entry.height()});
+        let result = Connection::header_json(&entry);
+        self.last_header_entry = Some(entry);
         Ok(result)
     }
 
-    fn blockchain_scripthash_get_balance(&self, _params: &[Value]) -> Result<Value> {
-        Ok(
-            json!({ "confirmed": null, "unconfirmed": null }),
-        )
+    fn blockchain_scripthash_get_balance(&self, params: &[Value]) -> Result<Value> {
+        let script_hash = hash_from_value(params.first()).chain_err(|| "bad script_hash")?;
+        let (confirmed, unconfirmed) = self.query.get_balance(&script_hash[..])?;
+        Ok(json!({ "confirmed": confirmed, "unconfirmed": unconfirmed }))
     }
 
+    // Paginated by the optional `from_height` and `limit` params
     fn blockchain_scripthash_get_history(&self, params: &[Value]) -> Result<Value> {
         let script_hash = hash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
+        let from_height = match params.get(1) {
+            Some(value) => Some(value.as_u64().chain_err(|| "bad from_height")? as usize),
+            None => None,
+        };
+        let limit = match params.get(2) {
+            Some(value) => Some(value.as_u64().filter(|limit| *limit > 0).chain_err(|| "bad limit")?),
+            None => None,
+        };
+        let tip_height = self.query.get_best_header()?.height();
+        if from_height.is_none() && limit.is_none() {
+            // the whole history is cached, for the wallets polling it (and
+            // refused if larger than `txid_limit`, to be fetched by pages)
+            let entries = self.query.get_history(&script_hash[..])?;
+            let history: Vec<Value> =
+                entries.iter().map(|entry| entry.to_json(tip_height)).collect();
+            return Ok(json!(history));
+        }
+        // larger pages are truncated, and continued by the next ones
+        let limit = match (limit.map(|limit| limit as usize), self.query.txid_limit()) {
+            (limit, 0) => limit.unwrap_or(usize::MAX),
+            (limit, txid_limit) => limit.unwrap_or(txid_limit).min(txid_limit),
+        };
         let status = self.query.status(&script_hash[..], 9999999999, false)?;
-        Ok(json!(Value::Array(
-            status
-                .history()
-                .into_iter()
-                .map(|item| json!({"tx_hash": item.to_hex()}))
-                .collect()
-        )))
+        let from_height = from_height.unwrap_or(0);
+        let (entries, next_height) = self.query.history_page(&status, from_height, limit)?;
+        let history: Vec<Value> = entries.iter().map(|entry| entry.to_json(tip_height)).collect();
+        Ok(json!({"history": history, "next_height": next_height}))
+    }
+
+    fn blockchain_scripthash_get_mempool(&self, params: &[Value]) -> Result<Value> {
+        let script_hash = hash_from_value(params.first()).chain_err(|| "bad script_hash")?;
+        let entries = self.query.mempool_history(&script_hash[..])?;
+        // without coinbases, so the tip height is unused
+        let mempool: Vec<Value> = entries.iter().map(|entry| entry.to_json(0)).collect();
+        Ok(json!(mempool))
     }
 
     fn blockchain_scripthash_get_oldest_tx(&self, params: &[Value]) -> Result<Value> {
@@ -102,41 +718,443 @@ impl Connection {
         Ok(json!({"tx_hash":oldest_tx.txid.to_hex(),"block_index":oldest_tx.blockindex}))
     }
 
+    fn blockchain_scripthash_get_address_info(&self, params: &[Value]) -> Result<Value> {
+        let script_hash = hash_from_value(params.first()).chain_err(|| "bad script_hash")?;
+        let info = self.query.get_address_info(&script_hash[..])?;
+        Ok(json!({
+            "tx_count": info.tx_count,
+            "mempool_tx_count": info.mempool_tx_count,
+            "first_height": info.first_height,
+            "last_height": info.last_height,
+            "history_start_height": self.query.history_start_height(),
+        }))
+    }
+
+    fn blockchain_outpoint_get_spender(&self, params: &[Value]) -> Result<Value> {
+        let txid = hash_from_value(params.first()).chain_err(|| "bad tx_hash")?;
+        let vout = params
+            .get(1)
+            .and_then(Value::as_u64)
+            .chain_err(|| "bad tx_pos")?;
+        Ok(match self.query.get_spender(&txid, vout as usize)? {
+            Some(spent) => json!({"tx_hash": spent.txid.to_hex(), "height": spent.blockindex}),
+            None => Value::Null,
+        })
+    }
+
+    // Journaled reorgs, from the `from_id` one (the oldest by default)
+    fn blockchain_reorg_get_journal(&self, params: &[Value]) -> Result<Value> {
+        let from = match params.first() {
+            Some(value) => value.as_u64().chain_err(|| "bad from_id")?,
+            None => 0,
+        };
+        let hex = |hash: &FullHash| Sha256dHash::from_slice(hash).unwrap().to_hex();
+        let reorgs: Vec<Value> = self
+            .query
+            .get_reorgs(from.min(u32::MAX as u64) as u32)
+            .iter()
+            .map(|(id, reorg)| {
+                json!({
+                    "id": id,
+                    "time": reorg.time,
+                    "height": reorg.height,
+                    "blocks": reorg.blockhashes.iter().map(hex).collect::<Vec<String>>(),
+                    "scripthashes": reorg.script_hashes.iter().map(hex).collect::<Vec<String>>(),
+                    "txids": reorg.txids.iter().map(hex).collect::<Vec<String>>(),
+                })
+            })
+            .collect();
+        Ok(json!(reorgs))
+    }
+
+    // Transactions whose OP_RETURN data starts with a prefix (hex), in a heights range
+    fn blockchain_opreturn_get_txids(&self, params: &[Value]) -> Result<Value> {
+        let prefix = params.first().and_then(Value::as_str).chain_err(|| "missing prefix")?;
+        let prefix = hex::decode(prefix).chain_err(|| "non-hex prefix")?;
+        if prefix.is_empty() {
+            bail!("empty prefix");
+        }
+        let height = |i: usize, name: &str| -> Result<usize> {
+            match params.get(i) {
+                Some(value) => Ok(value.as_u64().chain_err(|| format!("bad {}", name))? as usize),
+                None => bail!("missing {}", name),
+            }
+        };
+        let (from, to) = (height(1, "from_height")?, height(2, "to_height")?);
+        let op_returns = self.query.get_op_returns(&prefix, from, to)?;
+        let txid_limit = self.query.txid_limit();
+        if txid_limit > 0 && op_returns.len() > txid_limit {
+            bail!("more than {} OP_RETURN outputs, narrow the heights range", txid_limit);
+        }
+        let result: Vec<Value> = op_returns
+            .iter()
+            .map(|op_return| {
+                json!({
+                    "tx_hash": op_return.txid.to_hex(),
+                    "tx_pos": op_return.vout,
+                    "height": op_return.height,
+                    "data": hex::encode(&op_return.data),
+                })
+            })
+            .collect();
+        Ok(json!(result))
+    }
+
+    // Counterparty transactions in a heights range, continued from `next_height`
+    // when the range is too long
+    fn blockchain_counterparty_get_txs(&self, params: &[Value]) -> Result<Value> {
+        let height = |i: usize, name: &str| -> Result<usize> {
+            match params.get(i) {
+                Some(value) => Ok(value.as_u64().chain_err(|| format!("bad {}", name))? as usize),
+                None => bail!("missing {}", name),
+            }
+        };
+        let (from, to) = (height(0, "from_height")?, height(1, "to_height")?);
+        let last = to.min(from.saturating_add(MAX_COUNTERPARTY_BLOCKS - 1));
+        let txs: Vec<Value> = self
+            .query
+            .get_counterparty_txs(from, last)?
+            .iter()
+            .map(|txn| {
+                json!({
+                    "tx_hash": txn.txid.to_hex(),
+                    "height": txn.height,
+                    "block_hash": txn.blockhash.to_hex(),
+                    "data": hex::encode(&txn.message),
+                })
+            })
+            .collect();
+        let next_height = match last < to {
+            true => Some(last + 1),
+            false => None,
+        };
+        Ok(json!({"txs": txs, "next_height": next_height}))
+    }
+
     fn blockchain_scripthash_get_utxos(&self, params: &[Value]) -> Result<Value> {
         let script_hash = hash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
-        let status = self.query.status(&script_hash[..], 9999999999, false)?;
+        let utxos: Vec<String> = self
+            .query
+            .unspent(&script_hash[..])?
+            .into_iter()
+            .map(|(txo, _)| txo.txid.to_hex() + ":" + &txo.vout.to_string())
+            .collect();
 
-        let mut dict = HashMap::new();
-        for item in status.funding().into_iter() {
-            dict.insert(item.txid.to_hex() + ":" + &item.vout.to_string(), "");
+        Ok(json!(utxos))
+    }
+
+    fn blockchain_scripthash_listunspent(&self, params: &[Value]) -> Result<Value> {
+        let script_hash = hash_from_value(params.first()).chain_err(|| "bad script_hash")?;
+        let tip_height = self.query.get_best_header()?.height();
+        let mut utxos = vec![];
+        for (txo, value) in self.query.unspent(&script_hash[..])? {
+            let mut utxo = json!({
+                "tx_hash": txo.txid.to_hex(),
+                "tx_pos": txo.vout,
+                "value": value,
+                "height": txo.blockindex,
+            });
+            set_maturity(&mut utxo, txo.maturity(tip_height));
+            utxos.push(utxo);
         }
+        Ok(json!(utxos))
+    }
+
+    fn status_hash(&self, script_hash: &Sha256dHash) -> Result<Value> {
+        Ok(match self.query.status_hash(&script_hash[..])? {
+            Some(hash) => json!(hex::encode(hash)),
+            None => Value::Null,
+        })
+    }
 
-        for item in status.spending().into_iter() {
-            dict.remove( &(item.outpoint.0.to_hex() + ":" + &item.outpoint.1.to_string()) );
+    // Statuses (and histories, if `with_history` is true) of a list of script hashes.
+    // Histories larger than `txid_limit` are null, to be fetched by pages (with an
+    // `error` and no status if they have more outputs than `txid_limit`).
+    fn blockchain_scripthash_get_status_batch(&self, params: &[Value]) -> Result<Value> {
+        let values = params
+            .first()
+            .and_then(Value::as_array)
+            .chain_err(|| "missing script hashes")?;
+        if values.len() > MAX_BATCH_SCRIPT_HASHES {
+            bail!("too many script hashes (max {})", MAX_BATCH_SCRIPT_HASHES);
         }
+        let with_history = match params.get(1) {
+            Some(value) => value.as_bool().chain_err(|| "bad with_history")?,
+            None => false,
+        };
+        let script_hashes = values
+            .iter()
+            .map(|value| hash_from_value(Some(value)).chain_err(|| "bad script_hash"))
+            .collect::<Result<Vec<Sha256dHash>>>()?;
+        let full_hashes: Vec<FullHash> = script_hashes.iter().map(|hash| full_hash(&hash[..])).collect();
+        let txid_limit = self.query.txid_limit();
+        let tip_height = self.query.get_best_header()?.height();
+        let statuses = self.query.get_history_batch(&full_hashes)?;
+        let result: Vec<Value> = script_hashes
+            .iter()
+            .zip(statuses)
+            .map(|(script_hash, status)| {
+                let (status_hash, history) = match status {
+                    Some(status) => status,
+                    None => {
+                        let error = ErrorKind::TooManyTxs(txid_limit).to_string();
+                        return json!({
+                            "scripthash": script_hash.to_hex(),
+                            "status": Value::Null,
+                            "history": Value::Null,
+                            "error": error,
+                        });
+                    }
+                };
+                let mut result = json!({
+                    "scripthash": script_hash.to_hex(),
+                    "status": status_hash.map(hex::encode),
+                });
+                if with_history {
+                    result["history"] = match txid_limit {
+                        limit if limit > 0 && history.len() > limit => Value::Null,
+                        _ => json!(history
+                            .iter()
+                            .map(|entry| entry.to_json(tip_height))
+                            .collect::<Vec<Value>>()),
+                    };
+                }
+                result
+            })
+            .collect();
+        Ok(json!(result))
+    }
 
+    fn blockchain_scripthash_subscribe(&mut self, params: &[Value]) -> Result<Value> {
+        let script_hash = hash_from_value(params.first()).chain_err(|| "bad script_hash")?;
+        let status_hash = self.status_hash(&script_hash)?;
+        self.status_hashes.insert(script_hash, status_hash.clone());
+        Ok(status_hash)
+    }
+
+    fn blockchain_scripthash_unsubscribe(&mut self, params: &[Value]) -> Result<Value> {
+        let script_hash = hash_from_value(params.first()).chain_err(|| "bad script_hash")?;
+        Ok(json!(self.status_hashes.remove(&script_hash).is_some()))
+    }
+
+    fn blockchain_descriptor_scan(&self, params: &[Value]) -> Result<Value> {
+        let descriptor = params
+            .first()
+            .and_then(Value::as_str)
+            .chain_err(|| "missing descriptor")?;
+        let descriptor = Descriptor::parse(descriptor)?;
+        let gap_limit = match params.get(1) {
+            Some(value) => value.as_u64().chain_err(|| "bad gap_limit")?,
+            None => DEFAULT_GAP_LIMIT,
+        };
+        if gap_limit == 0 || gap_limit > MAX_GAP_LIMIT {
+            bail!("gap_limit must be between 1 and {}", MAX_GAP_LIMIT);
+        }
+
+        let scripts = self.query.scan(&descriptor, gap_limit as u32)?;
+        let tip_height = self.query.get_best_header()?.height();
+        let mut history: Vec<HistoryEntry> = vec![];
         let mut utxos = vec![];
-        for (outpoint, _drop) in &dict {
-            utxos.push(outpoint)
+        let (mut confirmed, mut unconfirmed) = (0, 0);
+        for script in &scripts {
+            history.extend(self.query.history(&script.status)?);
+            for (txo, value) in self.query.utxos(&script.status)? {
+                let mut utxo = json!({
+                    "tx_hash": txo.txid.to_hex(),
+                    "tx_pos": txo.vout,
+                    "value": value,
+                    "height": txo.blockindex,
+                    "path": script.path,
+                });
+                set_maturity(&mut utxo, txo.maturity(tip_height));
+                utxos.push(utxo);
+            }
+            let balance = self.query.balance(&script.status)?;
+            confirmed += balance.0;
+            unconfirmed += balance.1;
         }
+        // transactions spending from several scripts of the wallet
+        history.sort_by_key(|entry| entry.txid);
+        history.dedup_by_key(|entry| entry.txid);
+        sort_history(&mut history);
 
-        Ok(json!(utxos))
+        Ok(json!({
+            "scripts": scripts
+                .iter()
+                .map(|script| {
+                    let mut script_hash = script.script_hash;
+                    script_hash.reverse(); // displayed like Electrum script hashes
+                    json!({"path": script.path, "scripthash": hex::encode(script_hash)})
+                })
+                .collect::<Vec<Value>>(),
+            "history": history
+                .iter()
+                .map(|entry| entry.to_json(tip_height))
+                .collect::<Vec<Value>>(),
+            "utxos": utxos,
+            "balance": {"confirmed": confirmed, "unconfirmed": unconfirmed},
+        }))
     }
 
-    fn handle_command(&mut self, method: &str, params: &[Value], id: &Value) -> Result<Value> {
-        let result = match method {
-            "blockchain.headers.subscribe" => self.blockchain_headers_subscribe(),
-            "blockchain.scripthash.get_balance" => self.blockchain_scripthash_get_balance(&params),
-            "blockchain.scripthash.get_history" => self.blockchain_scripthash_get_history(&params),
-            "blockchain.scripthash.get_oldest_tx" => self.blockchain_scripthash_get_oldest_tx(&params),
-            "blockchain.scripthash.get_utxos" => self.blockchain_scripthash_get_utxos(&params),
-            "server.ping" => Ok(Value::Null),
-            "server.version" => self.server_version(),
-            &_ => bail!("unknown method {} {:?}", method, params),
+    fn blockchain_block_get_filter(&self, params: &[Value]) -> Result<Value> {
+        let height = params
+            .first()
+            .and_then(Value::as_u64)
+            .chain_err(|| "missing height")?;
+        let entry = self
+            .query
+            .get_header(height as usize)
+            .chain_err(|| format!("missing header at height {}", height))?;
+        let filter = self.query.get_block_filter(entry.hash())?;
+        Ok(json!({"blockhash": entry.hash().to_hex(), "filter": hex::encode(filter)}))
+    }
+
+    fn blockchain_estimatefee(&self, params: &[Value]) -> Result<Value> {
+        let blocks = params
+            .first()
+            .and_then(Value::as_u64)
+            .chain_err(|| "missing number of blocks")?;
+        // bitcoind accepts targets between 1 and 1008 blocks
+        let blocks = blocks.clamp(1, 1008) as u16;
+        Ok(match self.query.estimate_fee(blocks)? {
+            Some(fee_rate) => json!(fee_rate),
+            None => json!(-1), // not enough data for an estimate
+        })
+    }
+
+    fn mempool_get_fee_histogram(&self) -> Result<Value> {
+        Ok(json!(self.query.get_fee_histogram()))
+    }
+
+    fn blockchain_transaction_get(&self, params: &[Value]) -> Result<Value> {
+        let txid = hash_from_value(params.first()).chain_err(|| "bad tx_hash")?;
+        let verbose = match params.get(1) {
+            Some(value) => value.as_bool().chain_err(|| "bad verbose")?,
+            None => false,
+        };
+        if verbose {
+            return self.query.get_transaction_verbose(&txid, self.settings.network);
+        }
+        let tx = self.query.get_transaction(&txid)?;
+        Ok(json!(hex::encode(serialize(&tx))))
+    }
+
+    fn blockchain_transaction_get_replacement(&self, params: &[Value]) -> Result<Value> {
+        let txid = hash_from_value(params.first()).chain_err(|| "bad tx_hash")?;
+        Ok(match self.query.get_replacement(&txid) {
+            Some(conflict) => Connection::conflict_json(&conflict),
+            None => Value::Null,
+        })
+    }
+
+    fn conflict_json(conflict: &Conflict) -> Value {
+        let outpoints: Vec<Value> = conflict
+            .outpoints
+            .iter()
+            .map(|outpoint| json!({"tx_hash": outpoint.txid.to_hex(), "tx_pos": outpoint.vout}))
+            .collect();
+        json!({
+            "replaced": conflict.replaced.to_hex(),
+            "replacing": conflict.replacing.to_hex(),
+            "outpoints": outpoints,
+        })
+    }
+
+    fn blockchain_transaction_get_merkle(&self, params: &[Value]) -> Result<Value> {
+        let txid = hash_from_value(params.first()).chain_err(|| "bad tx_hash")?;
+        let height = params
+            .get(1)
+            .and_then(Value::as_u64)
+            .chain_err(|| "missing height")? as usize;
+        let (merkle, pos) = self.query.get_merkle(&txid, height)?;
+        let merkle: Vec<String> = merkle.iter().map(|hash| hash.to_hex()).collect();
+        Ok(json!({"block_height": height, "merkle": merkle, "pos": pos}))
+    }
+
+    fn blockchain_transaction_id_from_pos(&self, params: &[Value]) -> Result<Value> {
+        let height = params
+            .first()
+            .and_then(Value::as_u64)
+            .chain_err(|| "missing height")? as usize;
+        let tx_pos = params
+            .get(1)
+            .and_then(Value::as_u64)
+            .chain_err(|| "missing tx_pos")? as usize;
+        let merkle = match params.get(2) {
+            Some(value) => value.as_bool().chain_err(|| "bad merkle")?,
+            None => false,
         };
+        let (txid, branch) = self.query.get_id_from_pos(height, tx_pos, merkle)?;
+        if !merkle {
+            return Ok(json!(txid.to_hex()));
+        }
+        let branch: Vec<String> = branch.iter().map(|hash| hash.to_hex()).collect();
+        Ok(json!({"tx_hash": txid.to_hex(), "merkle": branch}))
+    }
+
+    fn blockchain_transaction_broadcast(&self, params: &[Value]) -> Result<Value> {
+        let tx_hex = params.first().chain_err(|| "missing tx")?;
+        let tx_hex = tx_hex.as_str().chain_err(|| "non-string tx")?;
+        let tx_bytes = hex::decode(tx_hex).chain_err(|| "non-hex tx")?;
+        let tx: Transaction = deserialize(&tx_bytes).chain_err(|| "failed to parse tx")?;
+        let txid = self.query.broadcast(&tx)?;
+        Ok(json!(txid.to_hex()))
+    }
+
+    fn start_span(&self, method: &str, params: &[Value]) -> trace::Span {
+        let mut span = trace::Span::start(method);
+        span.set("rpc.connection", json!(self.id));
+        span.set("net.peer", json!(self.addr.to_string()));
+        let param = params.first().and_then(Value::as_str);
+        match param {
+            Some(param) if method.starts_with("blockchain.scripthash.") => {
+                span.set("scripthash", json!(param))
+            }
+            Some(param) if method.starts_with("blockchain.address.") => {
+                span.set("address", json!(param))
+            }
+            _ => (),
+        }
+        span
+    }
+
+    fn handle_command(
+        &mut self,
+        method: &str,
+        params: &[Value],
+        id: &Value,
+        received: Instant,
+    ) -> Result<Value> {
+        self.metrics.requests.inc(method);
+        if !self.rate_limiter.allow(self.addr.ip(), Instant::now()) {
+            self.metrics.requests.inc("rate_limited");
+            let error = json!({"code": -32000, "message": "rate limit exceeded"});
+            return Ok(json!({"jsonrpc": "2.0", "id": id, "error": error}));
+        }
+        if self.auth_token.is_some() && method != "server.auth" {
+            let error = json!({"code": -32001, "message": "authentication required"});
+            return Ok(json!({"jsonrpc": "2.0", "id": id, "error": error}));
+        }
+        let span = self.start_span(method, params);
+        let deadline = self.settings.query_timeout.map(|timeout| Instant::now() + timeout);
+        let result =
+            span.enter(|| query::with_deadline(deadline, || self.dispatch(method, params)));
+        // including the wait for an execution slot
+        self.metrics.durations.observe(method, received.elapsed());
+        span.finish(result.as_ref().err().map(|e| e.to_string()));
         // TODO: return application errors should be sent to the client
         Ok(match result {
             Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+            Err(ref e) if is_timeout(e) => {
+                warn!("rpc #{} {} {:?} timed out", id, method, params);
+                self.metrics.requests.inc("timed_out");
+                let error = json!({"code": -32002, "message": "server busy: query timed out"});
+                json!({"jsonrpc": "2.0", "id": id, "error": error})
+            }
+            Err(ref e) if too_many_txs(e).is_some() => {
+                let message = too_many_txs(e).unwrap().to_string();
+                json!({"jsonrpc": "2.0", "id": id, "error": message})
+            }
             Err(e) => {
                 warn!(
                     "rpc #{} {} {:?} failed: {}",
@@ -145,194 +1163,940 @@ impl Connection {
                     params,
                     e.display_chain()
                 );
-                json!({"jsonrpc": "2.0", "id": id, "error": format!("{}", e)})
+                let error = match e.kind() {
+                    // e.g. a transaction rejected by bitcoind
+                    ErrorKind::Daemon(_, code, msg) => json!({"code": code, "message": msg}),
+                    _ => json!(format!("{}", e)),
+                };
+                json!({"jsonrpc": "2.0", "id": id, "error": error})
+            }
+        })
+    }
+
+    // blockchain.address.* methods are the blockchain.scripthash.* ones,
+    // taking an address of the indexed network instead of a script hash
+    fn blockchain_address(&mut self, method: &str, params: &[Value]) -> Result<Value> {
+        let address = params
+            .first()
+            .and_then(Value::as_str)
+            .chain_err(|| "missing address")?;
+        let script_hash = address_script_hash(address, self.settings.network)?;
+        let script_hash = Sha256dHash::from_slice(&script_hash).chain_err(|| "bad script hash")?;
+        let mut params = params.to_vec();
+        params[0] = json!(script_hash.to_hex());
+        let method = method.replacen("blockchain.address.", "blockchain.scripthash.", 1);
+        self.dispatch(&method, &params)
+    }
+
+    fn dispatch(&mut self, method: &str, params: &[Value]) -> Result<Value> {
+        // clients not sending server.version get the latest protocol
+        let version = self.protocol_version.clone().unwrap_or_else(|| PROTOCOL_MAX.to_vec());
+        match method {
+            _ if version[..] < *method_min_version(method) => Err(format!(
+                "{} requires protocol version {}",
+                method,
+                format_version(method_min_version(method))
+            )
+            .into()),
+            _ if method.starts_with("blockchain.address.") => {
+                self.blockchain_address(method, params)
+            }
+            "blockchain.block.get_deltas" => self.blockchain_block_get_deltas(params),
+            "blockchain.block.get_filter" => self.blockchain_block_get_filter(params),
+            "blockchain.block.header" => self.blockchain_block_header(params),
+            "blockchain.block.headers" => self.blockchain_block_headers(params),
+            "blockchain.counterparty.get_txs" => self.blockchain_counterparty_get_txs(params),
+            "blockchain.descriptor.scan" => self.blockchain_descriptor_scan(params),
+            "blockchain.estimatefee" => self.blockchain_estimatefee(params),
+            "blockchain.headers.subscribe" => self.blockchain_headers_subscribe(),
+            "blockchain.opreturn.get_txids" => self.blockchain_opreturn_get_txids(params),
+            "blockchain.outpoint.get_spender" => self.blockchain_outpoint_get_spender(params),
+            "blockchain.reorg.get_journal" => self.blockchain_reorg_get_journal(params),
+            "blockchain.scripthash.get_address_info" => {
+                self.blockchain_scripthash_get_address_info(params)
+            }
+            "blockchain.scripthash.get_balance" => self.blockchain_scripthash_get_balance(&params),
+            "blockchain.scripthash.get_history" => self.blockchain_scripthash_get_history(&params),
+            "blockchain.scripthash.get_mempool" => self.blockchain_scripthash_get_mempool(params),
+            "blockchain.scripthash.get_status_batch" => {
+                self.blockchain_scripthash_get_status_batch(params)
+            }
+            "blockchain.scripthash.get_oldest_tx" => self.blockchain_scripthash_get_oldest_tx(&params),
+            "blockchain.scripthash.get_utxos" => self.blockchain_scripthash_get_utxos(&params),
+            "blockchain.scripthash.listunspent" => self.blockchain_scripthash_listunspent(params),
+            "blockchain.scripthash.subscribe" => self.blockchain_scripthash_subscribe(params),
+            "blockchain.scripthash.unsubscribe" => self.blockchain_scripthash_unsubscribe(params),
+            "blockchain.transaction.broadcast" => self.blockchain_transaction_broadcast(params),
+            "blockchain.transaction.get" => self.blockchain_transaction_get(params),
+            "blockchain.transaction.get_merkle" => self.blockchain_transaction_get_merkle(params),
+            "blockchain.transaction.get_replacement" => {
+                self.blockchain_transaction_get_replacement(params)
+            }
+            "blockchain.transaction.id_from_pos" => {
+                self.blockchain_transaction_id_from_pos(params)
+            }
+            "mempool.get_fee_histogram" => self.mempool_get_fee_histogram(),
+            "server.auth" => self.server_auth(params),
+            "server.banner" => self.server_banner(),
+            "server.db_stats" => self.server_db_stats(),
+            "server.features" => self.server_features(),
+            "server.index_stats" => self.server_index_stats(params),
+            "server.ping" => Ok(Value::Null),
+            "server.refresh" => self.server_refresh(),
+            "server.status" => self.query.get_status(),
+            "server.version" => self.server_version(params),
+            "watchlist.add" => self.watchlist_add(params),
+            "webhook.list" => Ok(self.webhooks("webhook.list")?.list()),
+            "webhook.register" => self.webhook_register(params),
+            "webhook.unregister" => self.webhook_unregister(params),
+            &_ => bail!("unknown method {} {:?}", method, params),
+        }
+    }
+
+
+    // Notifications for a new chain tip, for the subscribed script hashes
+    // whose status has changed, and for their replaced mempool transactions
+    fn update_subscriptions(&mut self) -> Result<Vec<Value>> {
+        let mut result = vec![];
+        if let Some(ref last_entry) = self.last_header_entry {
+            let entry = self.query.get_best_header()?;
+            // the tip hash also changes on reorgs at the same height
+            if *last_entry.hash() != *entry.hash() {
+                result.push(json!({
+                    "jsonrpc": "2.0",
+                    "method": "blockchain.headers.subscribe",
+                    "params": [Connection::header_json(&entry)]}));
+                self.last_header_entry = Some(entry);
+            }
+        }
+        let script_hashes: Vec<Sha256dHash> = self.status_hashes.keys().cloned().collect();
+        for script_hash in script_hashes {
+            let status_hash = match self.status_hash(&script_hash) {
+                Ok(status_hash) => status_hash,
+                // it can't be tracked anymore
+                Err(ref e) if too_many_txs(e).is_some() => {
+                    warn!("unsubscribed {}: {}", script_hash.to_hex(), e);
+                    self.status_hashes.remove(&script_hash);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            if self.status_hashes.get(&script_hash) == Some(&status_hash) {
+                continue;
+            }
+            result.push(json!({
+                "jsonrpc": "2.0",
+                "method": "blockchain.scripthash.subscribe",
+                "params": [script_hash.to_hex(), status_hash]}));
+            self.status_hashes.insert(script_hash, status_hash);
+        }
+        for conflict in self.query.get_conflicts(self.last_conflict_id) {
+            self.last_conflict_id = conflict.id;
+            let subscribed = conflict.script_hashes.iter().any(|script_hash| {
+                let script_hash = Sha256dHash::from_slice(script_hash).unwrap();
+                self.status_hashes.contains_key(&script_hash)
+            });
+            if subscribed {
+                result.push(json!({
+                    "jsonrpc": "2.0",
+                    "method": "blockchain.transaction.replaced",
+                    "params": [Connection::conflict_json(&conflict)]}));
             }
+        }
+        Ok(result)
+    }
+
+    fn handle_request(&mut self, cmd: &Value, received: Instant) -> Result<Value> {
+        match parse_request(cmd) {
+            Some((method, params, id)) => self.handle_command(method, params, id, received),
+            None => bail!("invalid command: {}", cmd),
+        }
+    }
+
+    // Batch requests are replied in a single array, in order: their invalid
+    // requests get an error each, instead of failing the whole batch
+    fn handle_line(&mut self, line: &str, received: Instant) -> Result<Value> {
+        let cmd: Value = from_str(line).chain_err(|| "invalid JSON format")?;
+        Ok(match cmd {
+            Value::Array(ref cmds) if cmds.is_empty() => invalid_request(&cmd),
+            Value::Array(ref cmds) => Value::Array(
+                cmds.iter()
+                    .map(|cmd| match parse_request(cmd) {
+                        Some((method, params, id)) => {
+                            self.handle_command(method, params, id, received)
+                        }
+                        None => Ok(invalid_request(cmd)),
+                    })
+                    .collect::<Result<Vec<Value>>>()?,
+            ),
+            _ => self.handle_request(&cmd, received)?,
         })
     }
 
-    fn send_values(&mut self, values: &[Value]) -> Result<()> {
-        for value in values {
-            let line = value.to_string() + "\n";
-            self.stream
-                .write_all(line.as_bytes())
-                .chain_err(|| format!("failed to send {}", value))?;
+    // Reject the request(s) of a line without running them, when the query pool is full
+    fn busy_reply(&self, line: &str) -> Value {
+        self.metrics.requests.inc("busy");
+        let reply = |cmd: &Value| {
+            let error = json!({"code": -32002, "message": "server busy"});
+            json!({"jsonrpc": "2.0", "id": cmd.get("id"), "error": error})
+        };
+        match from_str(line) {
+            Ok(Value::Array(ref cmds)) if !cmds.is_empty() => {
+                Value::Array(cmds.iter().map(reply).collect())
+            }
+            Ok(cmd) => reply(&cmd),
+            Err(_) => reply(&Value::Null),
         }
-        Ok(())
     }
 
-    fn handle_replies(&mut self) -> Result<()> {
-        let empty_params = json!([]);
+    fn has_subscriptions(&self) -> bool {
+        self.last_header_entry.is_some() || !self.status_hashes.is_empty()
+    }
+}
+
+//
+// Protocol carrying the JSONRPC messages of a listening address
+//
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transport {
+    Tcp,       // newline-delimited messages
+    WebSocket, // one message per WebSocket text frame
+}
+
+//
+// Accepted connection, before its TLS or WebSocket handshake
+//
+enum Peer {
+    Tcp(TcpStream),
+    Unix(UnixStream), // plaintext, newline-delimited messages
+}
+
+// Address of the Unix socket peers, for the logs and rate limits (like local TCP peers)
+const UNIX_PEER_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+// Requests read ahead of their replies, per connection
+const MAX_PENDING_REQUESTS: usize = 100;
+// Replies not read by their client yet, before reading its next requests
+const MAX_PENDING_OUTPUT: usize = 16 << 20;
+
+enum Listener {
+    Tcp(TcpListener, Transport),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    fn fd(&self) -> RawFd {
+        match self {
+            Listener::Tcp(listener, _) => listener.as_raw_fd(),
+            Listener::Unix(listener) => listener.as_raw_fd(),
+        }
+    }
+
+    fn set_nonblocking(&self) -> io::Result<()> {
+        match self {
+            Listener::Tcp(listener, _) => listener.set_nonblocking(true),
+            Listener::Unix(listener) => listener.set_nonblocking(true),
+        }
+    }
+
+    fn accept(&self) -> io::Result<(Peer, SocketAddr, Transport)> {
+        match self {
+            Listener::Tcp(listener, transport) => {
+                let (stream, addr) = listener.accept()?;
+                Ok((Peer::Tcp(stream), addr, *transport))
+            }
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept()?;
+                Ok((Peer::Unix(stream), UNIX_PEER_ADDR, Transport::Tcp))
+            }
+        }
+    }
+}
+
+//
+// Notifications sent to the RPC server
+//
+pub enum Notification {
+    Periodic,
+    Exit,
+}
+
+//
+// RPC client, served by the event loop of the RPC server
+//
+struct Client {
+    stream: poll::Stream,
+    addr: SocketAddr,
+    conn: Option<Connection>,              // None while it runs on the query pool
+    requests: VecDeque<(String, Instant)>, // with their reception time
+    update: bool,                          // whether its subscriptions should be updated
+    closing: bool,                         // once the client is done (or failed)
+    broken: bool,                          // once its replies can't be sent anymore
+}
+
+impl Client {
+    fn events(&self) -> libc::c_short {
+        let mut events = 0;
+        let paused = self.requests.len() >= MAX_PENDING_REQUESTS
+            || self.stream.pending_output() >= MAX_PENDING_OUTPUT;
+        if !self.closing && !paused {
+            events |= libc::POLLIN;
+        }
+        if self.stream.pending_output() > 0 && !self.broken {
+            events |= libc::POLLOUT;
+        }
+        events
+    }
+
+    fn flush(&mut self) {
+        if self.broken {
+            return;
+        }
+        if let Err(e) = self.stream.flush() {
+            debug!("[{}] {}", self.addr, e);
+            self.requests.clear();
+            self.closing = true;
+            self.broken = true;
+        }
+    }
+}
+
+enum Work {
+    Request(String, Instant), // with its reception time
+    Update,                   // of the subscriptions
+}
+
+// Replies of a client's request (or subscriptions update), run on the query pool
+struct Done {
+    id: usize,
+    conn: Connection,
+    replies: Result<Vec<Value>>,
+}
+
+//
+// Event loop of the RPC server: a single thread reads the requests of all the
+// clients and sends their replies, while the requests run on the query pool.
+//
+struct Server {
+    listeners: Vec<Listener>,
+    tls: Option<Arc<TlsAcceptor>>,
+    clients: HashMap<usize, Client>,
+    next_id: usize,
+    running: usize, // jobs of the clients on the query pool
+    done: Channel<Done>,
+    waker: Waker,
+    wakeups: UnixStream, // readable once woken up
+    notification: Channel<Notification>,
+    query: Arc<Query>,
+    metrics: Arc<RpcMetrics>,
+    rate_limiter: Arc<RateLimiter>,
+    settings: Arc<ServerSettings>,
+}
+
+impl Server {
+    fn run(mut self) {
         loop {
-            let msg = self.chan.receiver().recv().chain_err(|| "channel closed")?;
-            trace!("RPC {:?}", msg);
+            let ids: Vec<usize> = self.clients.keys().cloned().collect();
+            let mut fds = vec![poll::pollfd(self.wakeups.as_raw_fd(), libc::POLLIN)];
+            for listener in &self.listeners {
+                fds.push(poll::pollfd(listener.fd(), libc::POLLIN));
+            }
+            let mut timeout = None;
+            for id in &ids {
+                let client = &self.clients[id];
+                let events = client.events();
+                if events & libc::POLLIN != 0 && client.stream.has_more() {
+                    timeout = Some(Duration::from_millis(0));
+                }
+                // hung up clients with a running request are not polled meanwhile
+                let fd = if events != 0 { client.stream.fd() } else { -1 };
+                fds.push(poll::pollfd(fd, events));
+            }
+            poll::wait(&mut fds, timeout).unwrap_or_else(|e| panic!("{}", e.display_chain()));
+
+            if fds[0].revents != 0 {
+                Waker::drain(&self.wakeups);
+                if !self.handle_notifications() {
+                    break;
+                }
+                self.handle_results();
+            }
+            for index in 0..self.listeners.len() {
+                if fds[1 + index].revents != 0 {
+                    self.accept(index);
+                }
+            }
+            for (id, fd) in ids.iter().zip(&fds[1 + self.listeners.len()..]) {
+                self.handle_events(*id, fd.revents);
+            }
+            self.dispatch();
+            self.close_clients();
+        }
+        self.shutdown();
+    }
+
+    // Returns false once the server should exit
+    fn handle_notifications(&mut self) -> bool {
+        for msg in self.notification.receiver().try_iter() {
             match msg {
-                Message::Request(line) => {
-                    let cmd: Value = from_str(&line).chain_err(|| "invalid JSON format")?;
-                    let reply = match (
-                        cmd.get("method"),
-                        cmd.get("params").unwrap_or_else(|| &empty_params),
-                        cmd.get("id"),
-                    ) {
-                        (
-                            Some(&Value::String(ref method)),
-                            &Value::Array(ref params),
-                            Some(ref id),
-                        ) => self.handle_command(method, params, id)?,
-                        _ => bail!("invalid command: {}", cmd),
-                    };
-                    self.send_values(&[reply])?
+                Notification::Periodic => {
+                    let last_conflict_id = self.query.last_conflict_id();
+                    for client in self.clients.values_mut() {
+                        match client.conn {
+                            // nothing to notify
+                            Some(ref mut conn) if !conn.has_subscriptions() => {
+                                conn.last_conflict_id = last_conflict_id
+                            }
+                            _ => client.update = true,
+                        }
+                    }
+                }
+                Notification::Exit => return false,
+            }
+        }
+        true
+    }
+
+    fn handle_results(&mut self) {
+        let results: Vec<Done> = self.done.receiver().try_iter().collect();
+        for done in results {
+            self.running -= 1;
+            let client = self.clients.get_mut(&done.id).expect("missing client");
+            match done.replies {
+                Ok(replies) => {
+                    for reply in replies {
+                        client.stream.send(&reply.to_string());
+                    }
+                }
+                Err(e) => {
+                    error!(
+                        "[{}] connection handling failed: {}",
+                        client.addr,
+                        e.display_chain().to_string()
+                    );
+                    client.requests.clear();
+                    client.closing = true;
                 }
-                Message::Done => return Ok(()),
             }
+            client.conn = Some(done.conn);
+            client.flush();
         }
     }
 
-    fn handle_requests(mut reader: BufReader<TcpStream>, tx: SyncSender<Message>) -> Result<()> {
+    fn accept(&mut self, index: usize) {
         loop {
-            let mut line = Vec::<u8>::new();
-            reader
-                .read_until(b'\n', &mut line)
-                .chain_err(|| "failed to read a request")?;
-            if line.is_empty() {
-                tx.send(Message::Done).chain_err(|| "channel closed")?;
-                return Ok(());
-            } else {
-                if line.starts_with(&[22, 3, 1]) {
-                    // (very) naive SSL handshake detection
-                    let _ = tx.send(Message::Done);
-                    bail!("invalid request - maybe SSL-encrypted data?: {:?}", line)
+            let (peer, addr, transport) = match self.listeners[index].accept() {
+                Ok(peer) => peer,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    warn!("accept failed: {}", e);
+                    return;
                 }
-                match String::from_utf8(line) {
-                    Ok(req) => tx
-                        .send(Message::Request(req))
-                        .chain_err(|| "channel closed")?,
-                    Err(err) => {
-                        let _ = tx.send(Message::Done);
-                        bail!("invalid UTF8: {}", err)
+            };
+            let max_connections = self.settings.max_connections;
+            if max_connections > 0 && self.clients.len() >= max_connections {
+                let plaintext = match peer {
+                    Peer::Tcp(_) => self.tls.is_none(),
+                    Peer::Unix(_) => true,
+                };
+                RPC::reject(peer, addr, plaintext && transport == Transport::Tcp);
+                continue;
+            }
+            let socket = match (peer, &self.tls) {
+                (Peer::Tcp(stream), tls) => {
+                    if let Err(e) = stream.set_nonblocking(true) {
+                        warn!("[{}] failed to set connection as non-blocking: {}", addr, e);
+                        continue;
+                    }
+                    match tls {
+                        Some(tls) => match tls.accept(stream) {
+                            Ok(stream) => Socket::Tls(stream),
+                            Err(e) => {
+                                warn!("[{}] TLS handshake failed: {}", addr, e);
+                                continue;
+                            }
+                        },
+                        None => Socket::Tcp(stream),
+                    }
+                }
+                // local, no TLS
+                (Peer::Unix(stream), _) => {
+                    if let Err(e) = stream.set_nonblocking(true) {
+                        warn!("[{}] failed to set connection as non-blocking: {}", addr, e);
+                        continue;
                     }
+                    Socket::Unix(stream)
+                }
+            };
+            let id = self.next_id;
+            self.next_id += 1;
+            info!("[{}] connected peer #{}", addr, id);
+            let conn = Connection::new(
+                Arc::clone(&self.query),
+                addr,
+                id,
+                Arc::clone(&self.metrics),
+                Arc::clone(&self.rate_limiter),
+                Arc::clone(&self.settings),
+            );
+            let client = Client {
+                stream: poll::Stream::new(socket, transport == Transport::WebSocket),
+                addr,
+                conn: Some(conn),
+                requests: VecDeque::new(),
+                update: false,
+                closing: false,
+                broken: false,
+            };
+            self.clients.insert(id, client);
+        }
+    }
+
+    fn handle_events(&mut self, id: usize, revents: libc::c_short) {
+        let client = self.clients.get_mut(&id).expect("missing client");
+        let events = client.events();
+        let readable = revents & (libc::POLLIN | libc::POLLHUP | libc::POLLERR) != 0;
+        if events & libc::POLLIN != 0 && (readable || client.stream.has_more()) {
+            match client.stream.receive() {
+                Ok(messages) => {
+                    let received = Instant::now();
+                    trace!("RPC {:?}", messages);
+                    client.requests.extend(messages.into_iter().map(|line| (line, received)));
+                    client.closing = client.stream.is_closed();
+                }
+                Err(e) => {
+                    warn!("[{}] receiver failed: {}", client.addr, e.display_chain());
+                    client.closing = true;
                 }
             }
         }
+        if events & libc::POLLOUT != 0 && revents != 0 {
+            client.flush();
+        }
     }
 
-    pub fn run(mut self) {
-        let reader = BufReader::new(self.stream.try_clone().expect("failed to clone TcpStream"));
-        let tx = self.chan.sender();
-        let child = spawn_thread("reader", || Connection::handle_requests(reader, tx));
-        if let Err(e) = self.handle_replies() {
-            error!(
-                "[{}] connection handling failed: {}",
-                self.addr,
-                e.display_chain().to_string()
-            );
+    // Run the next request (or subscriptions update) of each idle client
+    fn dispatch(&mut self) {
+        let pool = &self.settings.query_pool;
+        for (&id, client) in self.clients.iter_mut() {
+            if client.broken || client.conn.is_none() {
+                continue;
+            }
+            let mut work = None;
+            while let Some((line, received)) = client.requests.pop_front() {
+                if !pool.is_full() {
+                    work = Some(Work::Request(line, received));
+                    break;
+                }
+                let reply = client.conn.as_ref().unwrap().busy_reply(&line);
+                client.stream.send(&reply.to_string());
+            }
+            if work.is_none() && client.update && !client.closing {
+                client.update = false;
+                work = Some(Work::Update); // regardless of the queue size, at most once per client
+            }
+            if let Some(work) = work {
+                let conn = client.conn.take().unwrap();
+                Server::spawn(pool, &self.done, &self.waker, id, conn, work);
+                self.running += 1;
+            }
+            client.flush();
+        }
+    }
+
+    fn spawn(
+        pool: &Arc<QueryPool>,
+        done: &Channel<Done>,
+        waker: &Waker,
+        id: usize,
+        mut conn: Connection,
+        work: Work,
+    ) {
+        let (done, waker) = (done.sender(), waker.clone());
+        pool.spawn(move || {
+            let replies = panic::catch_unwind(AssertUnwindSafe(|| match work {
+                Work::Request(line, received) => {
+                    conn.handle_line(&line, received).map(|reply| vec![reply])
+                }
+                Work::Update => conn
+                    .update_subscriptions()
+                    .chain_err(|| "failed to update subscriptions"),
+            }))
+            .unwrap_or_else(|_| Err("RPC handling panicked".into()));
+            let _ = done.send(Done { id, conn, replies });
+            waker.wake();
+        });
+    }
+
+    // Drop the clients which are done, once their replies are sent
+    fn close_clients(&mut self) {
+        let mut closed = vec![];
+        for (&id, client) in self.clients.iter_mut() {
+            if !client.closing || client.conn.is_none() || !client.requests.is_empty() {
+                continue;
+            }
+            client.stream.close();
+            client.flush();
+            if client.broken || client.stream.pending_output() == 0 {
+                closed.push(id);
+            }
         }
-        debug!("[{}] shutting down connection", self.addr);
-        let _ = self.stream.shutdown(Shutdown::Both);
-        if let Err(err) = child.join().expect("receiver panicked") {
-            error!("[{}] receiver failed: {}", self.addr, err);
+        for id in closed {
+            let client = self.clients.remove(&id).unwrap();
+            debug!("[{}] shutting down connection", client.addr);
+            info!("[{}] disconnected peer #{}", client.addr, id);
         }
     }
-}
 
-//
-// Messages supported by the RPC API
-//
-#[derive(Debug)]
-pub enum Message {
-    Request(String),
-    Done,
+    fn shutdown(mut self) {
+        trace!("closing {} RPC connections", self.clients.len());
+        for client in self.clients.values_mut() {
+            client.stream.close();
+            client.flush();
+        }
+        trace!("waiting for {} RPC requests", self.running);
+        while self.running > 0 {
+            let _ = self.done.receiver().recv();
+            self.running -= 1;
+        }
+        trace!("RPC connections are closed");
+    }
 }
 
 //
 // RPC server
 //
 pub struct RPC {
+    notification: Sender<Notification>,
+    waker: Waker,                           // of the server's thread, once notified
     server: Option<thread::JoinHandle<()>>, // so we can join the server while dropping this ojbect
+    socket_path: Option<PathBuf>,           // removed once stopped
 }
 
 impl RPC {
-    fn start_acceptor(addr: SocketAddr) -> Channel<Option<(TcpStream, SocketAddr)>> {
-        let chan = Channel::unbounded();
-        let acceptor = chan.sender();
-        spawn_thread("acceptor", move || {
-            let listener =
-                TcpListener::bind(addr).unwrap_or_else(|e| panic!("bind({}) failed: {}", addr, e));
-            info!(
-                "Indexer RPC server running on {} (protocol {})",
-                addr, PROTOCOL_VERSION
-            );
-            loop {
-                let (stream, addr) = listener.accept().expect("accept failed");
-                stream
-                    .set_nonblocking(false)
-                    .expect("failed to set connection as blocking");
-                acceptor.send(Some((stream, addr))).expect("send failed");
-            }
-        });
-        chan
+    // Bind before returning from RPC::start, so the server accepts connections once started
+    fn bind(addrs: Vec<(SocketAddr, Transport)>) -> Vec<(TcpListener, Transport)> {
+        addrs
+            .into_iter()
+            .map(|(addr, transport)| {
+                let listener = TcpListener::bind(addr)
+                    .unwrap_or_else(|e| panic!("bind({}) failed: {}", addr, e));
+                info!(
+                    "Indexer RPC server running on {} (protocol {}, {:?})",
+                    addr,
+                    format_version(PROTOCOL_MAX),
+                    transport
+                );
+                (listener, transport)
+            })
+            .collect()
     }
 
-    pub fn start(addr: SocketAddr, query: Arc<Query>) -> RPC {
-        RPC {
-            server: Some(spawn_thread("rpc", move || {
-                let senders = Arc::new(Mutex::new(HashMap::<i32, SyncSender<Message>>::new()));
-                let handles = Arc::new(Mutex::new(
-                    HashMap::<i32, std::thread::JoinHandle<()>>::new(),
-                ));
-
-                let acceptor = RPC::start_acceptor(addr);
-                let mut handle_count = 0;
-
-                while let Some((stream, addr)) = acceptor.receiver().recv().unwrap() {
-                    let handle_id = handle_count;
-                    handle_count += 1;
-                    // explicitely scope the shadowed variables for the new thread
-                    let handle: thread::JoinHandle<()> = {
-                        let query = Arc::clone(&query);
-                        let senders = Arc::clone(&senders);
-                        let handles = Arc::clone(&handles);
-
-                        spawn_thread("peer", move || {
-                            info!("[{}] connected peer #{}", addr, handle_id);
-                            let conn = Connection::new(query, stream, addr);
-                            senders
-                                .lock()
-                                .unwrap()
-                                .insert(handle_id, conn.chan.sender());
-                            conn.run();
-                            info!("[{}] disconnected peer #{}", addr, handle_id);
-                            senders.lock().unwrap().remove(&handle_id);
-                            handles.lock().unwrap().remove(&handle_id);
-                        })
-                    };
-
-                    handles.lock().unwrap().insert(handle_id, handle);
-                }
+    // Access to the socket is controlled by its file permissions (and its directory's)
+    fn bind_unix(path: &Path, mode: u32) -> Result<UnixListener> {
+        match fs::symlink_metadata(path) {
+            // left over by a previous run
+            Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)
+                .chain_err(|| format!("failed to remove stale socket {:?}", path))?,
+            Ok(_) => bail!("{:?} already exists and is not a socket", path),
+            Err(_) => (),
+        }
+        let listener =
+            UnixListener::bind(path).chain_err(|| format!("failed to bind socket {:?}", path))?;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+            .chain_err(|| format!("failed to set permissions of {:?}", path))?;
+        info!(
+            "Indexer RPC server running on {:?} (protocol {}, mode {:o})",
+            path,
+            format_version(PROTOCOL_MAX),
+            mode
+        );
+        Ok(listener)
+    }
 
-                trace!("closing {} RPC connections", senders.lock().unwrap().len());
-                for sender in senders.lock().unwrap().values() {
-                    let _ = sender.send(Message::Done);
+    // Close a connection without serving it, replying with an error
+    // unless the client expects a TLS or WebSocket handshake first
+    fn reject(peer: Peer, addr: SocketAddr, plaintext: bool) {
+        warn!("[{}] rejected peer: too many connections", addr);
+        let error = json!({"code": -32000, "message": "too many connections"});
+        let reply = json!({"jsonrpc": "2.0", "id": null, "error": error}).to_string() + "\n";
+        // the accepted sockets are blocking, and the reply fits in their buffers
+        match peer {
+            Peer::Tcp(mut stream) => {
+                if plaintext {
+                    let _ = stream.write_all(reply.as_bytes());
                 }
-
-                trace!("waiting for {} RPC handling threads", handles.lock().unwrap().len());
-                for (_, handle) in handles.lock().unwrap().drain() {
-                    if let Err(e) = handle.join() {
-                        warn!("failed to join thread: {:?}", e);
-                    }
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+            Peer::Unix(mut stream) => {
+                if plaintext {
+                    let _ = stream.write_all(reply.as_bytes());
                 }
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        }
+    }
 
-                trace!("RPC connections are closed");
-            })),
+    pub fn start(
+        addrs: Vec<(SocketAddr, Transport)>,
+        socket: Option<(PathBuf, u32)>, // Unix socket path and permissions
+        query: Arc<Query>,
+        metrics: &Metrics,
+        tls: Option<Arc<TlsAcceptor>>,
+        rate_limiter: Arc<RateLimiter>,
+        settings: Arc<ServerSettings>,
+    ) -> RPC {
+        let mut listeners: Vec<Listener> = RPC::bind(addrs)
+            .into_iter()
+            .map(|(listener, transport)| Listener::Tcp(listener, transport))
+            .collect();
+        if let Some((ref path, mode)) = socket {
+            let listener =
+                RPC::bind_unix(path, mode).unwrap_or_else(|e| panic!("{}", e.display_chain()));
+            listeners.push(Listener::Unix(listener));
         }
+        for listener in &listeners {
+            listener
+                .set_nonblocking()
+                .expect("failed to set listener as non-blocking");
+        }
+        let (waker, wakeups) = Waker::new().unwrap_or_else(|e| panic!("{}", e.display_chain()));
+        let notification = Channel::unbounded();
+        let sender = notification.sender();
+        let server = Server {
+            listeners,
+            tls,
+            clients: HashMap::new(),
+            next_id: 0,
+            running: 0,
+            done: Channel::unbounded(),
+            waker: waker.clone(),
+            wakeups,
+            notification,
+            query,
+            metrics: Arc::new(RpcMetrics::new(metrics)),
+            rate_limiter,
+            settings,
+        };
+        RPC {
+            notification: sender,
+            waker,
+            socket_path: socket.map(|(path, _)| path),
+            server: Some(spawn_thread("rpc", move || server.run())),
+        }
+    }
+
+    pub fn notify(&self) {
+        self.notification.send(Notification::Periodic).unwrap();
+        self.waker.wake();
     }
 }
 
 impl Drop for RPC {
     fn drop(&mut self) {
         trace!("stop accepting new RPCs");
+        self.notification.send(Notification::Exit).unwrap();
+        self.waker.wake();
         if let Some(handle) = self.server.take() {
             handle.join().unwrap();
         }
+        if let Some(ref path) = self.socket_path {
+            let _ = fs::remove_file(path);
+        }
         trace!("RPC server is stopped");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Barrier;
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(2.0, 3);
+        let (ip1, ip2) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        let start = Instant::now();
+        assert_eq!((0..4).filter(|_| limiter.allow(ip1, start)).count(), 3);
+        assert!(limiter.allow(ip2, start));
+        assert!(!limiter.allow(ip1, start + Duration::from_millis(400)));
+        assert!(limiter.allow(ip1, start + Duration::from_millis(600)));
+        assert_eq!((0..5).filter(|_| limiter.allow(ip1, start + Duration::from_secs(10))).count(), 3);
+
+        limiter.set_limits(0.0, 0);
+        assert!((0..100).all(|_| limiter.allow(ip1, start)));
+    }
+
+    #[test]
+    fn test_negotiate_version() {
+        let v = |version: &str| parse_version(version).unwrap();
+        assert_eq!(v("1.4.2"), vec![1, 4, 2]);
+        assert!(parse_version("1.x").is_err());
+        assert_eq!(format_version(&v("1.4")), "1.4");
+
+        assert_eq!(negotiate_version(&v("1.4"), &v("1.4")), Some(v("1.4")));
+        assert_eq!(negotiate_version(&v("1.1"), &v("1.3")), Some(v("1.3")));
+        assert_eq!(negotiate_version(&v("1.2"), &v("1.5")), Some(v("1.4")));
+        assert_eq!(negotiate_version(&v("1.4.2"), &v("1.5")), None);
+        assert_eq!(negotiate_version(&v("1.0"), &v("1.1")), None);
+
+        assert!(v("1.2")[..] < *method_min_version("blockchain.block.header"));
+        assert!(v("1.4.2")[..] >= *method_min_version("blockchain.transaction.id_from_pos"));
+        assert_eq!(method_min_version("server.ping"), PROTOCOL_MIN);
+    }
+
+    #[test]
+    fn test_too_many_txs() {
+        let wrapped: Result<()> = Err(ErrorKind::TooManyTxs(100).into());
+        let e = wrapped.chain_err(|| "failed to get confirmed status").unwrap_err();
+        assert_eq!(
+            too_many_txs(&e).map(|e| e.to_string()),
+            Some("more than 100 transactions, fetch the history by pages".to_owned())
+        );
+        assert!(too_many_txs(&Error::from("other error")).is_none());
+    }
+
+    #[test]
+    fn test_is_timeout() {
+        assert!(is_timeout(&ErrorKind::Timeout.into()));
+        let wrapped: Result<()> = Err(ErrorKind::Timeout.into());
+        assert!(is_timeout(&wrapped.chain_err(|| "failed to get status").unwrap_err()));
+        assert!(!is_timeout(&Error::from("other error")));
+    }
+
+    #[test]
+    fn test_query_pool() {
+        let pool = Arc::new(QueryPool::new(2, 0));
+        let running = Arc::new(Mutex::new((0, 0))); // (current, max)
+        let threads: Vec<_> = (0..6)
+            .map(|_| {
+                let (pool, running) = (Arc::clone(&pool), Arc::clone(&running));
+                thread::spawn(move || {
+                    pool.run(|| {
+                        {
+                            let mut running = running.lock().unwrap();
+                            running.0 += 1;
+                            running.1 = running.1.max(running.0);
+                        }
+                        thread::sleep(Duration::from_millis(20));
+                        running.lock().unwrap().0 -= 1;
+                    })
+                })
+            })
+            .collect();
+        for thread in threads {
+            assert!(thread.join().unwrap().is_some());
+        }
+        assert_eq!(*running.lock().unwrap(), (0, 2));
+
+        // a full queue rejects the requests
+        let pool = Arc::new(QueryPool::new(1, 1));
+        let (started, release) = (Arc::new(Barrier::new(2)), Arc::new(Barrier::new(2)));
+        let worker = {
+            let (pool, started, release) = (Arc::clone(&pool), Arc::clone(&started), Arc::clone(&release));
+            thread::spawn(move || pool.run(|| {
+                started.wait();
+                release.wait();
+            }))
+        };
+        started.wait();
+        let queued = {
+            let pool = Arc::clone(&pool);
+            thread::spawn(move || pool.run(|| 1))
+        };
+        while pool.state.lock().unwrap().next_ticket < 2 {
+            thread::yield_now();
+        }
+        assert_eq!(pool.run(|| 2), None);
+        assert!(pool.is_full());
+        release.wait();
+        assert!(worker.join().unwrap().is_some());
+        assert_eq!(queued.join().unwrap(), Some(1));
+        assert_eq!(pool.run(|| 3), Some(3));
+        assert!(!pool.is_full());
+    }
+
+    #[test]
+    fn test_query_pool_jobs() {
+        let pool = Arc::new(QueryPool::new(2, 0));
+        let running = Arc::new(Mutex::new((0, 0))); // (current, max)
+        let done = Channel::unbounded();
+        for i in 0..6 {
+            let (running, done) = (Arc::clone(&running), done.sender());
+            pool.spawn(move || {
+                {
+                    let mut running = running.lock().unwrap();
+                    running.0 += 1;
+                    running.1 = running.1.max(running.0);
+                }
+                thread::sleep(Duration::from_millis(20));
+                running.lock().unwrap().0 -= 1;
+                done.send(i).unwrap();
+            });
+        }
+        // the jobs share the slots with the waiting requests, in FIFO order
+        assert_eq!(pool.run(|| 6), Some(6));
+        let mut finished: Vec<i32> = done.receiver().iter().take(6).collect();
+        finished.sort_unstable();
+        assert_eq!(finished, (0..6).collect::<Vec<i32>>());
+        assert_eq!(*running.lock().unwrap(), (0, 2));
+        assert_eq!(pool.state.lock().unwrap().executors, 2);
+
+        // a panicking job doesn't take its slot down
+        pool.spawn(|| panic!("job failed"));
+        let sender = done.sender();
+        pool.spawn(move || sender.send(7).unwrap());
+        assert_eq!(done.receiver().recv().unwrap(), 7);
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let cmd = json!({"id": 1, "method": "server.ping"});
+        let (method, params, id) = parse_request(&cmd).unwrap();
+        assert_eq!((method, params.len(), id), ("server.ping", 0, &json!(1)));
+        let cmd = json!({"id": 2, "method": "server.version", "params": ["x", "1.4"]});
+        assert_eq!(parse_request(&cmd).unwrap().1.len(), 2);
+        assert!(parse_request(&json!({"method": "server.ping"})).is_none()); // no id
+        assert!(parse_request(&json!({"id": 3, "method": 1})).is_none());
+        assert!(parse_request(&json!({"id": 4, "method": "server.ping", "params": 1})).is_none());
+        assert!(parse_request(&json!(5)).is_none());
+
+        let reply = invalid_request(&json!({"id": 6}));
+        assert_eq!(reply["id"], json!(6));
+        assert_eq!(reply["error"]["code"], json!(-32600));
+        assert_eq!(invalid_request(&json!([]))["id"], Value::Null);
+    }
+
+    #[test]
+    fn test_same_secret() {
+        assert!(same_secret(b"s3cret", b"s3cret"));
+        assert!(!same_secret(b"s3cret", b"s3creT"));
+        assert!(!same_secret(b"s3cret", b"s3cre"));
+        assert!(same_secret(b"", b""));
+    }
+
+    #[test]
+    fn test_bind_unix() {
+        let dir = std::env::temp_dir().join(format!("addrindexrs-rpc-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("rpc.sock");
+
+        let listener = RPC::bind_unix(&path, 0o660).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+        drop(listener);
+        // the stale socket is replaced
+        let _listener = RPC::bind_unix(&path, 0o600).unwrap();
+        UnixStream::connect(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // other files are kept
+        let file = dir.join("file");
+        fs::write(&file, b"data").unwrap();
+        assert!(RPC::bind_unix(&file, 0o660).is_err());
+        assert_eq!(fs::read(&file).unwrap(), b"data");
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -24,12 +24,29 @@ fn notify(signals: &[i32]) -> channel::Receiver<i32> {
     r
 }
 
+// Receives the operator's requests for a DB snapshot (e.g. `kill -USR1 <pid>`)
+pub fn backup_requests() -> channel::Receiver<i32> {
+    notify(&[signal_hook::SIGUSR1])
+}
+
+// Receives the operator's requests for reloading the config (e.g. `kill -HUP <pid>`)
+pub fn reload_requests() -> channel::Receiver<i32> {
+    notify(&[signal_hook::SIGHUP])
+}
+
 impl Waiter {
     pub fn start() -> Waiter {
         Waiter {
             receiver: notify(&[signal_hook::SIGINT, signal_hook::SIGTERM]),
         }
     }
+    // Never interrupted, for embedding the indexer (the signals are left to
+    // the application)
+    pub fn detached() -> Waiter {
+        Waiter {
+            receiver: channel::never(),
+        }
+    }
     pub fn wait(&self, duration: Duration) -> Result<()> {
         match self.receiver.recv_timeout(duration) {
             Ok(sig) => bail!(ErrorKind::Interrupt(sig)),
@@ -37,6 +54,17 @@ impl Waiter {
             Err(RecvTimeoutError::Disconnected) => bail!("signal hook channel disconnected"),
         }
     }
+    // Like wait(), but returns early when a notification is received
+    pub fn wait_or_notify(&self, duration: Duration, notify: &channel::Receiver<()>) -> Result<()> {
+        select! {
+            recv(self.receiver) -> sig => match sig {
+                Ok(sig) => bail!(ErrorKind::Interrupt(sig)),
+                Err(_) => bail!("signal hook channel disconnected"),
+            },
+            recv(notify) -> _ => Ok(()),
+            default(duration) => Ok(()),
+        }
+    }
     pub fn poll(&self) -> Result<()> {
         self.wait(Duration::from_secs(0))
     }
@@ -0,0 +1,119 @@
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::ops::Range;
+
+use crate::index::{tx_num_height, TxNum};
+use crate::store::Row;
+use crate::util::HASH_PREFIX_LEN;
+
+// Lengths of the keys of the output, input and transaction rows (see doc/schema.md)
+const TXOUT_KEY_LEN: usize = 1 + HASH_PREFIX_LEN + 6 + 2;
+const TXIN_KEY_LEN: usize = 1 + HASH_PREFIX_LEN + 2 + 6;
+const TX_KEY_LEN: usize = 1 + 6;
+
+//
+// Number and size (keys and values, in bytes) of the rows of a code
+//
+#[derive(Debug, Default, PartialEq)]
+pub struct RowStats {
+    pub count: u64,
+    pub size: u64,
+}
+
+//
+// Rows indexed for the transactions of a block
+//
+#[derive(Debug, Default, PartialEq)]
+pub struct BlockStats {
+    pub transactions: u64,
+    pub outputs: u64,
+    pub inputs: u64,
+}
+
+//
+// Aggregate statistics of the index, computed by a scan of all its rows
+//
+#[derive(Debug, Default, PartialEq)]
+pub struct IndexStats {
+    pub rows: BTreeMap<u8, RowStats>, // by code
+    pub outputs: u64,
+    pub script_hashes: u64, // distinct script hash prefixes of the outputs
+    pub blocks: BTreeMap<usize, BlockStats>,
+}
+
+fn height(key: &[u8]) -> usize {
+    let tx_num: TxNum = key.try_into().expect("bad tx_num length");
+    tx_num_height(&tx_num)
+}
+
+// Statistics of the rows (sorted by key, as scanned), with the rows of the
+// blocks in `heights`
+pub fn index_stats(rows: impl Iterator<Item = Row>, heights: Range<usize>) -> IndexStats {
+    let mut stats = IndexStats::default();
+    let mut last_prefix: Option<Vec<u8>> = None;
+    for row in rows {
+        let code = match row.key.first() {
+            Some(code) => *code,
+            None => continue,
+        };
+        let entry = stats.rows.entry(code).or_default();
+        entry.count += 1;
+        entry.size += (row.key.len() + row.value.len()) as u64;
+        let block_height = match (code, row.key.len()) {
+            (b'O', TXOUT_KEY_LEN) => {
+                stats.outputs += 1;
+                let prefix = &row.key[1..1 + HASH_PREFIX_LEN];
+                if last_prefix.as_deref() != Some(prefix) {
+                    stats.script_hashes += 1;
+                    last_prefix = Some(prefix.to_vec());
+                }
+                height(&row.key[1 + HASH_PREFIX_LEN..TXOUT_KEY_LEN - 2])
+            }
+            (b'I', TXIN_KEY_LEN) => height(&row.key[TXIN_KEY_LEN - 6..]),
+            (b'T', TX_KEY_LEN) => height(&row.key[1..]),
+            _ => continue,
+        };
+        if heights.contains(&block_height) {
+            let block = stats.blocks.entry(block_height).or_default();
+            match code {
+                b'O' => block.outputs += 1,
+                b'I' => block.inputs += 1,
+                _ => block.transactions += 1,
+            }
+        }
+    }
+    stats
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::network::constants::Network;
+
+    use crate::index::index_block;
+
+    #[test]
+    fn test_index_stats() {
+        let block = genesis_block(Network::Bitcoin);
+        let mut rows: Vec<Row> = index_block(&block, 0)
+            .chain(index_block(&block, 1))
+            .collect();
+        rows.sort_by(|a, b| a.key.cmp(&b.key));
+        let stats = index_stats(rows.into_iter(), 1..2);
+        assert_eq!(stats.rows[&b'O'].count, 2);
+        assert_eq!(stats.rows[&b'T'].count, 2);
+        assert_eq!(stats.rows[&b'T'].size, 2 * (7 + 32));
+        assert_eq!(stats.outputs, 2);
+        assert_eq!(stats.script_hashes, 1); // the same coinbase output twice
+        assert_eq!(stats.blocks.len(), 1);
+        assert_eq!(
+            stats.blocks[&1],
+            BlockStats {
+                transactions: 1,
+                outputs: 1,
+                inputs: 0,
+            }
+        );
+    }
+}
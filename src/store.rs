@@ -1,7 +1,17 @@
+#[cfg(feature = "rocksdb")]
 use rocksdb;
+use std::fs;
 use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use crate::config::{DbCompactionStyle, DbCompression};
+use crate::errors::*;
+use crate::filter;
+#[cfg(not(feature = "rocksdb"))]
+use crate::logdb::LogDB;
+use crate::trace;
 use crate::util::Bytes;
+use crate::utxo;
 
 //
 // A row from the Db store
@@ -31,35 +41,137 @@ pub trait WriteStore: Sync {
     fn flush(&self);
 }
 
+//
+// Key-value database holding the index
+//
+pub trait Backend: Send + Sync {
+    fn get(&self, key: &[u8]) -> Option<Bytes>;
+    fn iter_scan<'a>(&'a self, prefix: &[u8]) -> Box<dyn Iterator<Item = Row> + 'a>;
+    // Write the rows, then delete the keys, atomically
+    fn write(&self, rows: Vec<Row>, deleted: Vec<Bytes>, durable: bool);
+    fn flush(&self);
+    fn compact(&self);
+    fn enable_auto_compactions(&self);
+    fn size(&self) -> u64;
+    fn stats(&self) -> DBStats;
+    // Take a consistent snapshot of the DB into a new directory
+    fn checkpoint(&self, path: &Path) -> Result<()>;
+    // Apply the writes of the process updating a read-only DB
+    fn catch_up(&self);
+}
+
+//
+// Storage statistics, for capacity planning (only RocksDB has SST files
+// and a block cache, the other backends leave them empty)
+//
+#[derive(Debug, Default)]
+pub struct DBStats {
+    pub levels: Vec<LevelStats>, // from L0 to the last non-empty level
+    pub memtables_size: u64,
+    pub pending_compaction_bytes: u64,
+    pub block_cache_usage: u64,
+    pub block_cache_hits: u64,
+    pub block_cache_misses: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LevelStats {
+    pub files: usize,
+    pub size: u64,
+}
+
+impl DBStats {
+    // Since startup (none for an unused cache)
+    pub fn block_cache_hit_ratio(&self) -> Option<f64> {
+        match self.block_cache_hits + self.block_cache_misses {
+            0 => None,
+            total => Some(self.block_cache_hits as f64 / total as f64),
+        }
+    }
+}
+
+// Read a counter from RocksDB's statistics dump (e.g. "rocksdb.block.cache.hit COUNT : 42")
+#[cfg_attr(not(feature = "rocksdb"), allow(dead_code))]
+fn parse_ticker(statistics: &str, name: &str) -> u64 {
+    statistics
+        .lines()
+        .filter_map(|line| line.strip_prefix(name)?.trim().strip_prefix("COUNT :"))
+        .find_map(|count| count.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+//
+// RocksDB tuning (ignored by the other backends)
+//
+#[derive(Clone, Debug)]
+pub struct DBTuning {
+    pub block_cache_size: usize,
+    pub write_buffer_size: usize,
+    pub max_open_files: Option<i32>,
+    pub compression: DbCompression,
+    pub compaction_style: DbCompactionStyle,
+}
+
+impl Default for DBTuning {
+    fn default() -> Self {
+        DBTuning {
+            block_cache_size: 8 << 20,
+            write_buffer_size: 256 << 20,
+            max_open_files: None,
+            compression: DbCompression::Snappy,
+            compaction_style: DbCompactionStyle::Level,
+        }
+    }
+}
+
 //
 // Options
 //
 #[derive(Clone)]
+#[cfg_attr(not(feature = "rocksdb"), allow(dead_code))] // only tunes RocksDB
 struct Options {
     path: PathBuf,
     bulk_import: bool,
     low_memory: bool,
+    read_only: bool,
+    secondary_path: Option<PathBuf>, // of a RocksDB secondary instance (if read-only)
+    tuning: DBTuning,
 }
 
 //
-// Db store
+// RocksDB backend (the default one)
 //
-pub struct DBStore {
+#[cfg(feature = "rocksdb")]
+struct RocksDB {
     db: rocksdb::DB,
-    opts: Options,
+    opts: rocksdb::Options, // for reading the statistics
+    secondary: bool,
 }
 
-impl DBStore {
-    fn open_opts(opts: Options) -> Self {
-        debug!("opening DB at {:?}", opts.path);
+#[cfg(feature = "rocksdb")]
+impl RocksDB {
+    fn open(opts: &Options) -> Self {
         let mut db_opts = rocksdb::Options::default();
         db_opts.create_if_missing(true);
         // db_opts.set_keep_log_file_num(10);
-        db_opts.set_max_open_files(if opts.bulk_import { 16 } else { 256 });
-        db_opts.set_compaction_style(rocksdb::DBCompactionStyle::Level);
-        db_opts.set_compression_type(rocksdb::DBCompressionType::Snappy);
+        let tuning = &opts.tuning;
+        db_opts.set_max_open_files(
+            tuning
+                .max_open_files
+                .unwrap_or(if opts.bulk_import { 16 } else { 256 }),
+        );
+        db_opts.set_compaction_style(match tuning.compaction_style {
+            DbCompactionStyle::Level => rocksdb::DBCompactionStyle::Level,
+            DbCompactionStyle::Universal => rocksdb::DBCompactionStyle::Universal,
+        });
+        db_opts.set_compression_type(match tuning.compression {
+            DbCompression::None => rocksdb::DBCompressionType::None,
+            DbCompression::Snappy => rocksdb::DBCompressionType::Snappy,
+            DbCompression::Lz4 => rocksdb::DBCompressionType::Lz4,
+            DbCompression::Zstd => rocksdb::DBCompressionType::Zstd,
+        });
         db_opts.set_target_file_size_base(256 << 20);
-        db_opts.set_write_buffer_size(256 << 20);
+        db_opts.set_write_buffer_size(tuning.write_buffer_size);
         db_opts.set_disable_auto_compactions(opts.bulk_import); // for initial bulk load
         db_opts.set_advise_random_on_open(!opts.bulk_import); // bulk load uses sequential I/O
         if !opts.low_memory {
@@ -68,57 +180,131 @@ impl DBStore {
 
         let mut block_opts = rocksdb::BlockBasedOptions::default();
         block_opts.set_block_size(if opts.low_memory { 256 << 10 } else { 1 << 20 });
-        DBStore {
-            db: rocksdb::DB::open(&db_opts, &opts.path).unwrap(),
-            opts,
+        block_opts.set_block_cache(&rocksdb::Cache::new_lru_cache(tuning.block_cache_size));
+        db_opts.set_block_based_table_factory(&block_opts);
+        db_opts.enable_statistics();
+        let db = match (opts.read_only, &opts.secondary_path) {
+            // a secondary instance follows the writes of the primary one
+            (true, Some(secondary_path)) => {
+                db_opts.create_if_missing(false);
+                db_opts.set_max_open_files(-1);
+                rocksdb::DB::open_as_secondary(&db_opts, &opts.path, secondary_path)
+            }
+            // a read-only instance only sees the rows written before it was opened
+            (true, None) => rocksdb::DB::open_for_read_only(&db_opts, &opts.path, false),
+            (false, _) => rocksdb::DB::open(&db_opts, &opts.path),
+        };
+        RocksDB {
+            db: db.unwrap_or_else(|e| panic!("failed to open DB at {:?}: {}", opts.path, e)),
+            opts: db_opts,
+            secondary: opts.read_only && opts.secondary_path.is_some(),
         }
     }
+}
 
-    /// Opens a new RocksDB at the specified location.
-    pub fn open(path: &Path, low_memory: bool) -> Self {
-        DBStore::open_opts(Options {
-            path: path.to_path_buf(),
-            bulk_import: true,
-            low_memory,
+#[cfg(feature = "rocksdb")]
+impl Backend for RocksDB {
+    fn get(&self, key: &[u8]) -> Option<Bytes> {
+        self.db.get(key).unwrap().map(|v| v.to_vec())
+    }
+
+    fn iter_scan<'a>(&'a self, prefix: &[u8]) -> Box<dyn Iterator<Item = Row> + 'a> {
+        Box::new(ScanIterator {
+            prefix: prefix.to_vec(),
+            iter: self.db.iterator(rocksdb::IteratorMode::From(
+                prefix,
+                rocksdb::Direction::Forward,
+            )),
+            done: false,
         })
     }
 
-    pub fn enable_compaction(self) -> Self {
-        let mut opts = self.opts.clone();
-        if opts.bulk_import {
-            opts.bulk_import = false;
-            info!("enabling auto-compactions");
-            let opts = [("disable_auto_compactions", "false")];
-            self.db.set_options(&opts).unwrap();
+    fn write(&self, rows: Vec<Row>, deleted: Vec<Bytes>, durable: bool) {
+        let mut batch = rocksdb::WriteBatch::default();
+        for row in rows {
+            batch.put(row.key.as_slice(), row.value.as_slice());
         }
-        self
+        for key in deleted {
+            batch.delete(key.as_slice());
+        }
+        let mut opts = rocksdb::WriteOptions::new();
+        opts.set_sync(durable);
+        opts.disable_wal(!durable);
+        self.db.write_opt(batch, &opts).unwrap();
     }
 
-    pub fn compact(self) -> Self {
-        info!("starting full compaction");
+    fn flush(&self) {
+        self.db.flush().unwrap();
+    }
+
+    fn compact(&self) {
         self.db.compact_range(None::<&[u8]>, None::<&[u8]>); // would take a while
-        info!("finished full compaction");
-        self
     }
 
-    pub fn iter_scan(&self, prefix: &[u8]) -> ScanIterator {
-        ScanIterator {
-            prefix: prefix.to_vec(),
-            iter: self.db.prefix_iterator(prefix),
-            done: false,
+    fn enable_auto_compactions(&self) {
+        let opts = [("disable_auto_compactions", "false")];
+        self.db.set_options(&opts).unwrap();
+    }
+
+    // Total size of the SST files, plus the data not flushed yet
+    fn size(&self) -> u64 {
+        ["rocksdb.total-sst-files-size", "rocksdb.cur-size-all-mem-tables"]
+            .iter()
+            .copied()
+            .filter_map(|name| self.db.property_int_value(name).ok().flatten())
+            .sum()
+    }
+
+    fn stats(&self) -> DBStats {
+        let mut levels: Vec<LevelStats> = vec![];
+        for file in self.db.live_files().unwrap_or_default() {
+            let level = file.level.max(0) as usize;
+            if levels.len() <= level {
+                levels.resize(level + 1, LevelStats::default());
+            }
+            levels[level].files += 1;
+            levels[level].size += file.size as u64;
+        }
+        let property = |name| self.db.property_int_value(name).ok().flatten().unwrap_or(0);
+        let statistics = self.opts.get_statistics().unwrap_or_default();
+        DBStats {
+            levels,
+            memtables_size: property("rocksdb.cur-size-all-mem-tables"),
+            pending_compaction_bytes: property("rocksdb.estimate-pending-compaction-bytes"),
+            block_cache_usage: property("rocksdb.block-cache-usage"),
+            block_cache_hits: parse_ticker(&statistics, "rocksdb.block.cache.hit"),
+            block_cache_misses: parse_ticker(&statistics, "rocksdb.block.cache.miss"),
+        }
+    }
+
+    // Hard-links the SST files, so it's fast and doesn't block the writes for long
+    fn checkpoint(&self, path: &Path) -> Result<()> {
+        rocksdb::checkpoint::Checkpoint::new(&self.db)
+            .and_then(|checkpoint| checkpoint.create_checkpoint(path))
+            .map_err(|e| e.into_string().into())
+    }
+
+    // Replays the primary's new MANIFEST entries and WAL (a secondary only)
+    fn catch_up(&self) {
+        if self.secondary {
+            if let Err(e) = self.db.try_catch_up_with_primary() {
+                warn!("failed to catch up with the primary DB: {}", e);
+            }
         }
     }
 }
 
 //
-// Iterator supporting scans of the Db store
+// Iterator supporting scans of the RocksDB backend
 //
-pub struct ScanIterator<'a> {
+#[cfg(feature = "rocksdb")]
+struct ScanIterator<'a> {
     prefix: Vec<u8>,
     iter: rocksdb::DBIterator<'a>,
     done: bool,
 }
 
+#[cfg(feature = "rocksdb")]
 impl<'a> Iterator for ScanIterator<'a> {
     type Item = Row;
 
@@ -138,29 +324,137 @@ impl<'a> Iterator for ScanIterator<'a> {
     }
 }
 
+//
+// Db store
+//
+pub struct DBStore {
+    db: Box<dyn Backend>,
+    opts: Options,
+}
+
+impl DBStore {
+    fn open_opts(opts: Options) -> Self {
+        debug!("opening DB at {:?}", opts.path);
+        #[cfg(feature = "rocksdb")]
+        let db = Box::new(RocksDB::open(&opts));
+        #[cfg(not(feature = "rocksdb"))]
+        let db = Box::new(match opts.read_only {
+            true => LogDB::open_read_only(&opts.path),
+            false => LogDB::open(&opts.path),
+        });
+        DBStore { db, opts }
+    }
+
+    /// Opens a new DB at the specified location.
+    pub fn open(path: &Path, low_memory: bool, tuning: DBTuning) -> Self {
+        DBStore::open_opts(Options {
+            path: path.to_path_buf(),
+            bulk_import: true,
+            low_memory,
+            read_only: false,
+            secondary_path: None,
+            tuning,
+        })
+    }
+
+    /// Opens an existing DB read-only, as a RocksDB secondary instance (with
+    /// its own files at `secondary_path`) following the writes of the process
+    /// updating it, or else as it was when opened (e.g. a snapshot).
+    pub fn open_read_only(path: &Path, secondary_path: Option<&Path>, tuning: DBTuning) -> Self {
+        DBStore::open_opts(Options {
+            path: path.to_path_buf(),
+            bulk_import: false,
+            low_memory: false,
+            read_only: true,
+            secondary_path: secondary_path.map(Path::to_path_buf),
+            tuning,
+        })
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.opts.read_only
+    }
+
+    // Apply the writes of the process updating the DB (if read-only)
+    pub fn catch_up(&self) {
+        self.db.catch_up();
+    }
+
+    pub fn enable_compaction(mut self) -> Self {
+        if self.opts.bulk_import {
+            self.opts.bulk_import = false;
+            info!("enabling auto-compactions");
+            self.db.enable_auto_compactions();
+        }
+        self
+    }
+
+    pub fn compact(self) -> Self {
+        info!("starting full compaction");
+        self.db.compact();
+        info!("finished full compaction");
+        self
+    }
+
+    pub fn get_size(&self) -> u64 {
+        self.db.size()
+    }
+
+    pub fn get_stats(&self) -> DBStats {
+        self.db.stats()
+    }
+
+    pub fn iter_scan(&self, prefix: &[u8]) -> impl Iterator<Item = Row> + '_ {
+        self.db.iter_scan(prefix)
+    }
+
+    /// Takes a snapshot of the DB into a new subdirectory of `dir`, returning its path.
+    pub fn backup(&self, dir: &Path) -> Result<PathBuf> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        fs::create_dir_all(dir).chain_err(|| format!("failed to create {:?}", dir))?;
+        let path = dir.join(format!("snapshot-{}", timestamp));
+        self.db
+            .checkpoint(&path)
+            .chain_err(|| format!("failed to create snapshot at {:?}", path))?;
+        Ok(path)
+    }
+
+    // Write the rows, then delete the keys, atomically
+    pub fn write_batch(&self, rows: Vec<Row>, deleted: Vec<Bytes>) {
+        assert!(!self.opts.read_only, "write to read-only DB at {:?}", self.opts.path);
+        self.db.write(rows, deleted, /*durable=*/ !self.opts.bulk_import);
+    }
+
+    // Delete the rows starting with `prefix` (in batches, so not atomically)
+    pub fn delete_prefix(&self, prefix: &[u8]) {
+        let mut keys = vec![];
+        for row in self.iter_scan(prefix) {
+            keys.push(row.key);
+            if keys.len() == DELETE_BATCH_SIZE {
+                self.write_batch(vec![], std::mem::take(&mut keys));
+            }
+        }
+        self.write_batch(vec![], keys);
+    }
+}
+
 //
 // Read functions for the Db store
 //
 impl ReadStore for DBStore {
     fn get(&self, key: &[u8]) -> Option<Bytes> {
-        self.db.get(key).unwrap().map(|v| v.to_vec())
+        let value = self.db.get(key);
+        trace::add_rows(value.is_some() as usize);
+        value
     }
 
     // TODO: use generators
     fn scan(&self, prefix: &[u8]) -> Vec<Row> {
-        let mut rows = vec![];
-        for (key, value) in self.db.iterator(rocksdb::IteratorMode::From(
-            prefix,
-            rocksdb::Direction::Forward,
-        )).filter_map(Result::ok) {
-            if !key.starts_with(prefix) {
-                break;
-            }
-            rows.push(Row {
-                key: key.to_vec(),
-                value: value.to_vec(),
-            });
-        }
+        let rows: Vec<Row> = self.db.iter_scan(prefix).collect();
+        trace::add_rows(rows.len());
         rows
     }
 }
@@ -170,18 +464,11 @@ impl ReadStore for DBStore {
 //
 impl WriteStore for DBStore {
     fn write<I: IntoIterator<Item = Row>>(&self, rows: I) {
-        let mut batch = rocksdb::WriteBatch::default();
-        for row in rows {
-            batch.put(row.key.as_slice(), row.value.as_slice());
-        }
-        let mut opts = rocksdb::WriteOptions::new();
-        opts.set_sync(!self.opts.bulk_import);
-        opts.disable_wal(self.opts.bulk_import);
-        self.db.write_opt(batch, &opts).unwrap();
+        self.write_batch(rows.into_iter().collect(), vec![]);
     }
 
     fn flush(&self) {
-        self.db.flush().unwrap();
+        self.db.flush();
     }
 }
 
@@ -194,6 +481,10 @@ impl Drop for DBStore {
     }
 }
 
+
+// Keys deleted at once by `DBStore::delete_prefix`
+const DELETE_BATCH_SIZE: usize = 100_000;
+
 //
 // Compaction
 //
@@ -215,3 +506,237 @@ pub fn is_fully_compacted(store: &dyn ReadStore) -> bool {
     let marker = store.get(&full_compaction_marker().key);
     marker.is_some()
 }
+
+//
+// Schema versioning
+//
+const SCHEMA_KEY: &[u8] = b"V";
+
+// Bumped whenever the layout of the rows changes, with a new migration
+pub const SCHEMA_VERSION: u32 = 5;
+
+// MIGRATIONS[v] upgrades the rows of version `v` to version `v + 1`
+const MIGRATIONS: &[fn(&DBStore) -> Result<()>] = &[
+    // 0: indexes created before the versioning, with the same layout as 1
+    |_| Ok(()),
+    // 1: the UTXO set is rebuilt, with the inputs spending each script's outputs
+    |store| {
+        utxo::reset(store);
+        Ok(())
+    },
+    // 2: the history rows reference transaction numbers instead of txid prefixes,
+    // which can't be derived from the old rows (they don't store the positions
+    // of the transactions in their blocks): the headers are kept, and the history
+    // is indexed again from the genesis block, like a rewind to height 0 (but
+    // without the auto compactions, as a new index)
+    |store| {
+        for code in [b'O', b'I', b'T', b'H', b'D'] {
+            store.delete_prefix(&[code]);
+        }
+        utxo::reset(store);
+        let keys = [&b"L"[..], &b"R"[..], &full_compaction_marker().key[..]];
+        store.write_batch(vec![], keys.iter().map(|key| key.to_vec()).collect());
+        Ok(())
+    },
+    // 3: the unspent outputs are flagged when created by a coinbase
+    |store| {
+        utxo::reset(store);
+        Ok(())
+    },
+    // 4: the block filters don't share the code of the full compaction marker
+    |store| {
+        filter::migrate_rows(store);
+        Ok(())
+    },
+];
+
+fn schema_row(version: u32) -> Row {
+    Row {
+        key: SCHEMA_KEY.to_vec(),
+        value: version.to_le_bytes().to_vec(),
+    }
+}
+
+fn read_schema_version(store: &DBStore) -> Result<Option<u32>> {
+    if let Some(value) = store.get(SCHEMA_KEY) {
+        if value.len() != 4 {
+            bail!("invalid schema version row: {}", hex::encode(&value));
+        }
+        return Ok(Some(u32::from_le_bytes([value[0], value[1], value[2], value[3]])));
+    }
+    // an empty DB gets the current layout
+    if store.iter_scan(b"").next().is_none() {
+        return Ok(None);
+    }
+    Ok(Some(0))
+}
+
+// Migrate the index to the current schema version, if needed
+pub fn check_schema(store: &DBStore) -> Result<()> {
+    let mut version = match read_schema_version(store)? {
+        Some(version) => version,
+        None if store.is_read_only() => bail!("no index at {:?}", store.opts.path),
+        None => {
+            store.write_batch(vec![schema_row(SCHEMA_VERSION)], vec![]);
+            store.flush();
+            return Ok(());
+        }
+    };
+    if store.is_read_only() && version != SCHEMA_VERSION {
+        bail!(
+            "index at {:?} has schema version {}, but this release uses {}: \
+             it has to be migrated by a process that isn't read-only",
+            store.opts.path,
+            version,
+            SCHEMA_VERSION
+        );
+    }
+    if version > SCHEMA_VERSION {
+        bail!(
+            "index at {:?} has schema version {}, but this release only supports up to {}: \
+             upgrade addrindexrs, or remove the index to rebuild it",
+            store.opts.path,
+            version,
+            SCHEMA_VERSION
+        );
+    }
+    while version < SCHEMA_VERSION {
+        info!("migrating index schema from version {} to {}", version, version + 1);
+        MIGRATIONS[version as usize](store)
+            .chain_err(|| format!("failed to migrate index schema from version {}", version))?;
+        version += 1;
+        // each step is persisted, so an interrupted migration resumes from it
+        store.write_batch(vec![schema_row(version)], vec![]);
+        store.flush();
+    }
+    Ok(())
+}
+
+//
+// Store of a test, in a temporary directory removed once it is dropped
+//
+#[cfg(test)]
+pub struct TempStore {
+    store: Option<DBStore>,
+    dir: PathBuf,
+}
+
+#[cfg(test)]
+impl TempStore {
+    // A new store at `<tmp>/addrindexrs-<name>-<pid>/db`
+    pub fn new(name: &str) -> TempStore {
+        let dir = std::env::temp_dir().join(format!("addrindexrs-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let store = DBStore::open(&dir.join("db"), /*low_memory=*/ true, DBTuning::default());
+        TempStore {
+            store: Some(store),
+            dir,
+        }
+    }
+
+    // The directory of the store, for the other files of the test
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TempStore {
+    type Target = DBStore;
+
+    fn deref(&self) -> &DBStore {
+        self.store.as_ref().unwrap()
+    }
+}
+
+// for the functions taking a `&dyn ReadStore`
+#[cfg(test)]
+impl ReadStore for TempStore {
+    fn get(&self, key: &[u8]) -> Option<Bytes> {
+        ReadStore::get(&**self, key)
+    }
+
+    fn scan(&self, prefix: &[u8]) -> Vec<Row> {
+        ReadStore::scan(&**self, prefix)
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempStore {
+    fn drop(&mut self) {
+        drop(self.store.take()); // closed before its files are removed
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+
+    use super::*;
+
+    #[test]
+    fn test_check_schema() {
+        let store = TempStore::new("schema");
+        check_schema(&store).unwrap();
+        assert_eq!(read_schema_version(&store).unwrap(), Some(SCHEMA_VERSION));
+
+        // an index from before the versioning, migrated up to the transaction numbers
+        let header = Row {
+            key: b"Bheader".to_vec(),
+            value: vec![1],
+        };
+        let history = Row {
+            key: b"Oprefix".to_vec(),
+            value: vec![],
+        };
+        let last = Row {
+            key: b"L".to_vec(),
+            value: vec![2],
+        };
+        let rows = vec![full_compaction_marker(), header, history, last];
+        store.write_batch(rows, vec![SCHEMA_KEY.to_vec()]);
+        assert_eq!(read_schema_version(&store).unwrap(), Some(0));
+        check_schema(&store).unwrap();
+        assert_eq!(read_schema_version(&store).unwrap(), Some(SCHEMA_VERSION));
+        // its history is indexed again, over the same headers
+        assert!(store.get(b"Bheader").is_some());
+        assert!(store.get(b"Oprefix").is_none());
+        assert!(store.get(b"L").is_none());
+        assert!(!is_fully_compacted(&store));
+
+        // the block filters are moved from the code of the compaction marker
+        let blockhash = Sha256dHash::default();
+        let old_filter = Row {
+            key: [&b"F"[..], &blockhash[..]].concat(),
+            value: vec![3],
+        };
+        let rows = vec![full_compaction_marker(), old_filter, schema_row(4)];
+        store.write_batch(rows, vec![]);
+        check_schema(&store).unwrap();
+        assert!(is_fully_compacted(&store));
+        assert_eq!(store.iter_scan(b"F").count(), 1);
+        assert_eq!(store.get(&filter::filter_key(&blockhash)), Some(vec![3]));
+
+        // an index from a newer release
+        store.write_batch(vec![schema_row(SCHEMA_VERSION + 1)], vec![]);
+        assert!(check_schema(&store).is_err());
+    }
+
+    #[test]
+    fn test_stats() {
+        let statistics = "rocksdb.block.cache.miss COUNT : 25\n\
+                          rocksdb.block.cache.hit COUNT : 75\n\
+                          rocksdb.block.cache.add COUNT : 25\n";
+        assert_eq!(parse_ticker(statistics, "rocksdb.block.cache.hit"), 75);
+        assert_eq!(parse_ticker(statistics, "rocksdb.block.cache.miss"), 25);
+        assert_eq!(parse_ticker(statistics, "rocksdb.no.such.ticker"), 0);
+
+        let mut stats = DBStats::default();
+        assert_eq!(stats.block_cache_hit_ratio(), None);
+        stats.block_cache_hits = 75;
+        stats.block_cache_misses = 25;
+        assert_eq!(stats.block_cache_hit_ratio(), Some(0.75));
+    }
+}
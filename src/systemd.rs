@@ -0,0 +1,83 @@
+use std::env;
+use std::ffi::OsStr;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::UnixDatagram;
+use std::process;
+use std::time::Duration;
+
+#[cfg(target_os = "linux")]
+fn send_to_abstract(socket: &UnixDatagram, name: &[u8], state: &str) -> io::Result<()> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::SocketAddr;
+
+    let addr = SocketAddr::from_abstract_name(name)?;
+    socket.send_to_addr(state.as_bytes(), &addr).map(|_| ())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_to_abstract(_socket: &UnixDatagram, _name: &[u8], _state: &str) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "abstract sockets are not supported"))
+}
+
+fn send_to(path: &OsStr, state: &str) -> io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+    match path.as_bytes().split_first() {
+        Some((b'@', name)) => send_to_abstract(&socket, name, state),
+        _ => socket.send_to(state.as_bytes(), path).map(|_| ()),
+    }
+}
+
+//
+// Notifications to the service manager (see sd_notify(3)),
+// ignored unless the indexer is started by systemd
+//
+fn notify(state: &str) {
+    if let Some(path) = env::var_os("NOTIFY_SOCKET") {
+        if let Err(e) = send_to(&path, state) {
+            warn!("failed to notify systemd ({}): {}", state, e);
+        }
+    }
+}
+
+pub fn status(status: &str) {
+    notify(&format!("STATUS={}", status));
+}
+
+// The index is synced, and the RPC server accepts connections
+pub fn ready() {
+    notify("READY=1\nSTATUS=serving");
+}
+
+pub fn watchdog() {
+    notify("WATCHDOG=1");
+}
+
+// Half of the service's watchdog timeout (WatchdogSec), if it is enabled for this process
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Some(pid) = env::var_os("WATCHDOG_PID") {
+        if pid.to_str() != Some(&process::id().to_string()) {
+            return None;
+        }
+    }
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    Some(Duration::from_micros(usec / 2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_send_to() {
+        let path = env::temp_dir().join(format!("addrindexrs-notify-{}", process::id()));
+        let _ = std::fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+        send_to(path.as_os_str(), "READY=1").unwrap();
+        let mut buf = [0; 16];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+        drop(socket);
+        let _ = std::fs::remove_file(&path);
+    }
+}
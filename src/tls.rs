@@ -0,0 +1,282 @@
+//
+// Bindings to the subset of OpenSSL (1.1+) needed for TLS termination
+//
+#[cfg(feature = "tls")]
+mod ffi {
+    use libc::{c_char, c_int, c_long, c_ulong, c_void, size_t};
+
+    pub enum SslMethod {}
+    pub enum SslCtx {}
+    pub enum Ssl {}
+
+    pub const SSL_FILETYPE_PEM: c_int = 1;
+    pub const SSL_CTRL_SET_MIN_PROTO_VERSION: c_int = 123;
+    pub const SSL_CTRL_MODE: c_int = 33;
+    pub const SSL_MODE_ENABLE_PARTIAL_WRITE: c_long = 0x1;
+    pub const SSL_MODE_ACCEPT_MOVING_WRITE_BUFFER: c_long = 0x2;
+    pub const SSL_ERROR_WANT_READ: c_int = 2;
+    pub const SSL_ERROR_WANT_WRITE: c_int = 3;
+    pub const SSL_ERROR_ZERO_RETURN: c_int = 6;
+
+    #[link(name = "ssl")]
+    #[link(name = "crypto")]
+    extern "C" {
+        pub fn TLS_server_method() -> *const SslMethod;
+        pub fn SSL_CTX_new(method: *const SslMethod) -> *mut SslCtx;
+        pub fn SSL_CTX_free(ctx: *mut SslCtx);
+        pub fn SSL_CTX_ctrl(ctx: *mut SslCtx, cmd: c_int, larg: c_long, parg: *mut c_void)
+            -> c_long;
+        pub fn SSL_CTX_use_certificate_chain_file(ctx: *mut SslCtx, file: *const c_char) -> c_int;
+        pub fn SSL_CTX_use_PrivateKey_file(
+            ctx: *mut SslCtx,
+            file: *const c_char,
+            file_type: c_int,
+        ) -> c_int;
+        pub fn SSL_CTX_check_private_key(ctx: *const SslCtx) -> c_int;
+        pub fn SSL_new(ctx: *mut SslCtx) -> *mut Ssl;
+        pub fn SSL_free(ssl: *mut Ssl);
+        pub fn SSL_set_fd(ssl: *mut Ssl, fd: c_int) -> c_int;
+        pub fn SSL_set_accept_state(ssl: *mut Ssl);
+        pub fn SSL_read(ssl: *mut Ssl, buf: *mut c_void, num: c_int) -> c_int;
+        pub fn SSL_write(ssl: *mut Ssl, buf: *const c_void, num: c_int) -> c_int;
+        pub fn SSL_shutdown(ssl: *mut Ssl) -> c_int;
+        pub fn SSL_get_error(ssl: *const Ssl, ret: c_int) -> c_int;
+        pub fn ERR_get_error() -> c_ulong;
+        pub fn ERR_clear_error();
+        pub fn ERR_error_string_n(err: c_ulong, buf: *mut c_char, len: size_t);
+    }
+}
+
+#[cfg(feature = "tls")]
+mod imp {
+    use libc::{c_int, c_long, c_void};
+    use std::ffi::{CStr, CString};
+    use std::io::{self, Read, Write};
+    use std::net::{Shutdown, TcpStream};
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::{AsRawFd, RawFd};
+    use std::path::Path;
+    use std::ptr;
+
+    use super::ffi;
+    use crate::config::TlsVersion;
+    use crate::errors::*;
+
+    // Drain the OpenSSL error queue into a readable message
+    fn last_error(what: &str) -> Error {
+        let mut messages = vec![];
+        loop {
+            let code = unsafe { ffi::ERR_get_error() };
+            if code == 0 {
+                break;
+            }
+            let mut buf = [0; 256];
+            unsafe { ffi::ERR_error_string_n(code, buf.as_mut_ptr(), buf.len()) };
+            let msg = unsafe { CStr::from_ptr(buf.as_ptr()) };
+            messages.push(msg.to_string_lossy().into_owned());
+        }
+        format!("{}: {}", what, messages.join(", ")).into()
+    }
+
+    fn path_to_cstring(path: &Path) -> Result<CString> {
+        CString::new(path.as_os_str().as_bytes())
+            .chain_err(|| format!("invalid path {:?}", path))
+    }
+
+    pub struct TlsAcceptor {
+        ctx: *mut ffi::SslCtx,
+    }
+
+    // SSL_CTX is reference counted and thread-safe once configured
+    unsafe impl Send for TlsAcceptor {}
+    unsafe impl Sync for TlsAcceptor {}
+
+    impl TlsAcceptor {
+        pub fn new(cert: &Path, key: &Path, min_version: TlsVersion) -> Result<TlsAcceptor> {
+            let ctx = unsafe { ffi::SSL_CTX_new(ffi::TLS_server_method()) };
+            if ctx.is_null() {
+                return Err(last_error("failed to create TLS context"));
+            }
+            let acceptor = TlsAcceptor { ctx }; // make sure ctx is freed on error
+            let version = match min_version {
+                TlsVersion::Tls10 => 0x0301,
+                TlsVersion::Tls11 => 0x0302,
+                TlsVersion::Tls12 => 0x0303,
+                TlsVersion::Tls13 => 0x0304,
+            };
+            let cert_path = path_to_cstring(cert)?;
+            let key_path = path_to_cstring(key)?;
+            unsafe {
+                let cmd = ffi::SSL_CTRL_SET_MIN_PROTO_VERSION;
+                if ffi::SSL_CTX_ctrl(ctx, cmd, version as c_long, ptr::null_mut()) != 1 {
+                    return Err(last_error("failed to set minimum TLS version"));
+                }
+                // the writes of the non-blocking sessions are retried from
+                // the (growing) output buffers of their connections
+                let mode = ffi::SSL_MODE_ENABLE_PARTIAL_WRITE
+                    | ffi::SSL_MODE_ACCEPT_MOVING_WRITE_BUFFER;
+                ffi::SSL_CTX_ctrl(ctx, ffi::SSL_CTRL_MODE, mode, ptr::null_mut());
+                if ffi::SSL_CTX_use_certificate_chain_file(ctx, cert_path.as_ptr()) != 1 {
+                    return Err(last_error(&format!("failed to load certificate {:?}", cert)));
+                }
+                let file_type = ffi::SSL_FILETYPE_PEM;
+                if ffi::SSL_CTX_use_PrivateKey_file(ctx, key_path.as_ptr(), file_type) != 1 {
+                    return Err(last_error(&format!("failed to load private key {:?}", key)));
+                }
+                if ffi::SSL_CTX_check_private_key(ctx) != 1 {
+                    return Err(last_error("private key doesn't match certificate"));
+                }
+            }
+            Ok(acceptor)
+        }
+
+        // The handshake is done by the first reads and writes of the session
+        pub fn accept(&self, stream: TcpStream) -> Result<TlsStream> {
+            let ssl = unsafe { ffi::SSL_new(self.ctx) };
+            if ssl.is_null() {
+                return Err(last_error("failed to create TLS session"));
+            }
+            let session = TlsStream { ssl, stream };
+            unsafe {
+                if ffi::SSL_set_fd(ssl, session.stream.as_raw_fd()) != 1 {
+                    return Err(last_error("failed to attach TLS session"));
+                }
+                ffi::SSL_set_accept_state(ssl);
+            }
+            Ok(session)
+        }
+    }
+
+    impl Drop for TlsAcceptor {
+        fn drop(&mut self) {
+            unsafe { ffi::SSL_CTX_free(self.ctx) };
+        }
+    }
+
+    //
+    // A TLS session over a non-blocking socket: its reads and writes fail
+    // with WouldBlock until the socket is ready (for either direction,
+    // e.g. a read may have to send a handshake message).
+    //
+    pub struct TlsStream {
+        ssl: *mut ffi::Ssl,
+        stream: TcpStream,
+    }
+
+    // The session is used by a single thread at a time
+    unsafe impl Send for TlsStream {}
+
+    impl TlsStream {
+        // Map the SSL_read/SSL_write failures to I/O errors
+        fn error(&self, ret: c_int, what: &str) -> io::Error {
+            match unsafe { ffi::SSL_get_error(self.ssl, ret) } {
+                ffi::SSL_ERROR_WANT_READ | ffi::SSL_ERROR_WANT_WRITE => {
+                    io::ErrorKind::WouldBlock.into()
+                }
+                ffi::SSL_ERROR_ZERO_RETURN => io::ErrorKind::UnexpectedEof.into(),
+                _ => io::Error::other(last_error(what).to_string()),
+            }
+        }
+    }
+
+    impl Read for TlsStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let ret = unsafe {
+                ffi::ERR_clear_error();
+                ffi::SSL_read(self.ssl, buf.as_mut_ptr() as *mut c_void, buf.len() as c_int)
+            };
+            if ret > 0 {
+                return Ok(ret as usize);
+            }
+            match self.error(ret, "TLS read failed") {
+                e if e.kind() == io::ErrorKind::UnexpectedEof => Ok(0), // closed by the client
+                e => Err(e),
+            }
+        }
+    }
+
+    impl Write for TlsStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if buf.is_empty() {
+                return Ok(0);
+            }
+            let ret = unsafe {
+                ffi::ERR_clear_error();
+                ffi::SSL_write(self.ssl, buf.as_ptr() as *const c_void, buf.len() as c_int)
+            };
+            if ret > 0 {
+                return Ok(ret as usize);
+            }
+            Err(self.error(ret, "TLS write failed"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsRawFd for TlsStream {
+        fn as_raw_fd(&self) -> RawFd {
+            self.stream.as_raw_fd()
+        }
+    }
+
+    impl Drop for TlsStream {
+        fn drop(&mut self) {
+            unsafe {
+                ffi::SSL_shutdown(self.ssl); // best effort, without waiting for the client
+                ffi::SSL_free(self.ssl);
+            }
+            let _ = self.stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+#[cfg(not(feature = "tls"))]
+mod imp {
+    use std::io::{self, Read, Write};
+    use std::net::TcpStream;
+    use std::os::unix::io::{AsRawFd, RawFd};
+    use std::path::Path;
+
+    use crate::config::TlsVersion;
+    use crate::errors::*;
+
+    pub struct TlsAcceptor {}
+
+    impl TlsAcceptor {
+        pub fn new(_cert: &Path, _key: &Path, _min_version: TlsVersion) -> Result<TlsAcceptor> {
+            bail!("TLS support requires building addrindexrs with `--features tls`")
+        }
+
+        pub fn accept(&self, _stream: TcpStream) -> Result<TlsStream> {
+            unreachable!("TlsAcceptor can't be created without TLS support")
+        }
+    }
+
+    pub enum TlsStream {}
+
+    impl Read for TlsStream {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            match *self {}
+        }
+    }
+
+    impl Write for TlsStream {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            match *self {}
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            match *self {}
+        }
+    }
+
+    impl AsRawFd for TlsStream {
+        fn as_raw_fd(&self) -> RawFd {
+            match *self {}
+        }
+    }
+}
+
+pub use self::imp::{TlsAcceptor, TlsStream};
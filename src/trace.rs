@@ -0,0 +1,304 @@
+use error_chain::ChainedError;
+use serde_json::Value;
+use std::cell::RefCell;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::errors::*;
+use crate::util::spawn_thread;
+
+// Finished spans waiting to be exported (the newer ones are dropped once it's full)
+const EXPORT_QUEUE_SIZE: usize = 10_000;
+// Spans sent in a single OTLP request
+const EXPORT_BATCH_SIZE: usize = 512;
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+//
+// Work done on behalf of a span, shared with the threads it spawns
+//
+#[derive(Default)]
+pub struct Counters {
+    rows: AtomicU64,            // DB rows read
+    daemon_requests: AtomicU64, // bitcoind JSONRPC round trips
+}
+
+thread_local! {
+    // Counters of the span run by the current thread (if any)
+    static CURRENT: RefCell<Option<Arc<Counters>>> = const { RefCell::new(None) };
+}
+
+// Run `f`, counting the work of the current thread in `counters`
+pub fn with_counters<T>(counters: Option<Arc<Counters>>, f: impl FnOnce() -> T) -> T {
+    let previous = CURRENT.with(|cell| cell.replace(counters));
+    let result = f();
+    CURRENT.with(|cell| *cell.borrow_mut() = previous);
+    result
+}
+
+// For the threads spawned by a span, to count their work in it
+pub fn current() -> Option<Arc<Counters>> {
+    CURRENT.with(|cell| cell.borrow().clone())
+}
+
+pub fn add_rows(count: usize) {
+    CURRENT.with(|cell| {
+        if let Some(ref counters) = *cell.borrow() {
+            counters.rows.fetch_add(count as u64, Ordering::Relaxed);
+        }
+    })
+}
+
+pub fn add_daemon_request() {
+    CURRENT.with(|cell| {
+        if let Some(ref counters) = *cell.borrow() {
+            counters.daemon_requests.fetch_add(1, Ordering::Relaxed);
+        }
+    })
+}
+
+// Pseudo-random IDs (splitmix64), unique enough for the traces
+fn random_u64() -> u64 {
+    static STATE: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    let mut z = STATE
+        .fetch_add(0x9E37_79B9_7F4A_7C15, Ordering::Relaxed)
+        .wrapping_add(nanos);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+//
+// Span of a RPC request, logged at debug level once finished, and
+// exported over OTLP (if `otlp_endpoint` is set)
+//
+pub struct Span {
+    name: String,
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    start_time: SystemTime,
+    start: Instant,
+    attributes: Vec<(&'static str, Value)>,
+    counters: Arc<Counters>,
+}
+
+impl Span {
+    pub fn start(name: &str) -> Span {
+        let mut trace_id = [0u8; 16];
+        trace_id[..8].copy_from_slice(&random_u64().to_be_bytes());
+        trace_id[8..].copy_from_slice(&random_u64().to_be_bytes());
+        Span {
+            name: name.to_owned(),
+            trace_id,
+            span_id: random_u64().to_be_bytes(),
+            start_time: SystemTime::now(),
+            start: Instant::now(),
+            attributes: vec![],
+            counters: Arc::new(Counters::default()),
+        }
+    }
+
+    // Only strings and integers are exported
+    pub fn set(&mut self, key: &'static str, value: Value) {
+        self.attributes.push((key, value));
+    }
+
+    // Run `f`, counting the work of the current thread in this span
+    pub fn enter<T>(&self, f: impl FnOnce() -> T) -> T {
+        with_counters(Some(Arc::clone(&self.counters)), f)
+    }
+
+    pub fn finish(mut self, error: Option<String>) {
+        let elapsed = self.start.elapsed();
+        let rows = self.counters.rows.load(Ordering::Relaxed);
+        let daemon_requests = self.counters.daemon_requests.load(Ordering::Relaxed);
+        debug!(
+            "{} {} took {:?} ({} rows, {} bitcoind requests)",
+            self.name,
+            format_attributes(&self.attributes),
+            elapsed,
+            rows,
+            daemon_requests,
+        );
+        let exporter = match EXPORTER.get() {
+            Some(exporter) => exporter,
+            None => return,
+        };
+        self.set("db.rows_read", json!(rows));
+        self.set("daemon.requests", json!(daemon_requests));
+        let span = encode_span(&self, self.start_time + elapsed, error);
+        if exporter.try_send(span).is_err() {
+            debug!("dropped span {}: OTLP export queue is full", self.name);
+        }
+    }
+}
+
+fn format_attributes(attributes: &[(&'static str, Value)]) -> String {
+    let attributes: Vec<String> = attributes
+        .iter()
+        .map(|(key, value)| match value {
+            Value::String(s) => format!("{}={}", key, s), // unquoted
+            value => format!("{}={}", key, value),
+        })
+        .collect();
+    attributes.join(" ")
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    let nanos = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+    nanos.to_string()
+}
+
+// OTLP/JSON encoding (the 64-bit integers are strings, and the IDs hex strings)
+fn encode_span(span: &Span, end_time: SystemTime, error: Option<String>) -> Value {
+    let attributes: Vec<Value> = span
+        .attributes
+        .iter()
+        .map(|(key, value)| {
+            let value = match value {
+                Value::Number(n) => json!({"intValue": n.to_string()}),
+                Value::String(s) => json!({"stringValue": s}),
+                value => json!({"stringValue": value.to_string()}),
+            };
+            json!({"key": key, "value": value})
+        })
+        .collect();
+    let status = match error {
+        Some(message) => json!({"code": 2, "message": message}),
+        None => json!({"code": 1}),
+    };
+    json!({
+        "traceId": hex::encode(span.trace_id),
+        "spanId": hex::encode(span.span_id),
+        "name": span.name,
+        "kind": 2, // SPAN_KIND_SERVER
+        "startTimeUnixNano": unix_nanos(span.start_time),
+        "endTimeUnixNano": unix_nanos(end_time),
+        "attributes": attributes,
+        "status": status,
+    })
+}
+
+fn encode_request(spans: Vec<Value>) -> Value {
+    let service = json!({"key": "service.name", "value": {"stringValue": "addrindexrs"}});
+    json!({
+        "resourceSpans": [{
+            "resource": {"attributes": [service]},
+            "scopeSpans": [{"scope": {"name": "addrindexrs"}, "spans": spans}],
+        }]
+    })
+}
+
+static EXPORTER: OnceLock<SyncSender<Value>> = OnceLock::new();
+
+// POST the spans to the collector's OTLP/HTTP endpoint
+fn export(addr: SocketAddr, spans: Vec<Value>) -> Result<()> {
+    let body = encode_request(spans).to_string();
+    let mut stream = TcpStream::connect_timeout(&addr, EXPORT_TIMEOUT)
+        .chain_err(|| format!("failed to connect to {}", addr))?;
+    stream
+        .set_read_timeout(Some(EXPORT_TIMEOUT))
+        .chain_err(|| "failed to set timeout")?;
+    let request = format!(
+        "POST /v1/traces HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        addr,
+        body.len(),
+        body
+    );
+    stream
+        .write_all(request.as_bytes())
+        .chain_err(|| "failed to send spans")?;
+    let mut response = String::new();
+    stream
+        .read_to_string(&mut response)
+        .chain_err(|| "failed to read response")?;
+    let status = response.lines().next().unwrap_or_default();
+    if !status.contains(" 200 ") {
+        bail!("unexpected response {:?}", status);
+    }
+    Ok(())
+}
+
+fn run_exporter(addr: SocketAddr, receiver: Receiver<Value>) {
+    let mut spans = vec![];
+    let mut last_export = Instant::now();
+    loop {
+        let timeout = EXPORT_INTERVAL.saturating_sub(last_export.elapsed());
+        let disconnected = match receiver.recv_timeout(timeout) {
+            Ok(span) => {
+                spans.push(span);
+                false
+            }
+            Err(RecvTimeoutError::Timeout) => false,
+            Err(RecvTimeoutError::Disconnected) => true,
+        };
+        let due = spans.len() >= EXPORT_BATCH_SIZE || last_export.elapsed() >= EXPORT_INTERVAL;
+        if !spans.is_empty() && (due || disconnected) {
+            let count = spans.len();
+            if let Err(e) = export(addr, std::mem::take(&mut spans)) {
+                warn!("failed to export {} spans to {}: {}", count, addr, e.display_chain());
+            }
+        }
+        if due || spans.is_empty() {
+            last_export = Instant::now();
+        }
+        if disconnected {
+            return;
+        }
+    }
+}
+
+// Export the finished spans to an OTLP/HTTP collector (e.g. the OpenTelemetry Collector)
+pub fn start_exporter(addr: SocketAddr) {
+    let (sender, receiver) = mpsc::sync_channel(EXPORT_QUEUE_SIZE);
+    if EXPORTER.set(sender).is_err() {
+        return; // already started
+    }
+    info!("exporting traces to http://{}/v1/traces", addr);
+    spawn_thread("otlp", move || run_exporter(addr, receiver));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_counters() {
+        let mut span = Span::start("blockchain.scripthash.get_history");
+        span.set("rpc.connection", json!(3));
+        add_rows(5); // outside of any span
+        span.enter(|| {
+            add_rows(2);
+            add_daemon_request();
+            let counters = current();
+            thread::spawn(move || with_counters(counters, || add_rows(1)))
+                .join()
+                .unwrap();
+        });
+        add_rows(5);
+        assert_eq!(span.counters.rows.load(Ordering::Relaxed), 3);
+        assert_eq!(span.counters.daemon_requests.load(Ordering::Relaxed), 1);
+        assert!(current().is_none());
+        assert_ne!(Span::start("server.ping").span_id, span.span_id);
+
+        let encoded = encode_span(&span, span.start_time, Some("failed".to_owned()));
+        assert_eq!(encoded["traceId"].as_str().unwrap().len(), 32);
+        assert_eq!(encoded["spanId"].as_str().unwrap().len(), 16);
+        assert_eq!(
+            encoded["attributes"],
+            json!([{"key": "rpc.connection", "value": {"intValue": "3"}}])
+        );
+        assert_eq!(encoded["status"], json!({"code": 2, "message": "failed"}));
+        assert_eq!(encoded["startTimeUnixNano"], encoded["endTimeUnixNano"]);
+    }
+}
@@ -0,0 +1,734 @@
+use bincode;
+use bitcoin::blockdata::block::Block;
+use bitcoin::blockdata::transaction::TxOut;
+use bitcoin::consensus::encode::{deserialize, serialize};
+use bitcoin::util::hash::BitcoinHash;
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use std::collections::{HashMap, HashSet};
+
+use crate::daemon::Daemon;
+use crate::errors::*;
+use crate::index::{compute_script_hash, tx_num, Index, TxNum};
+use crate::signal::Waiter;
+use crate::store::{DBStore, ReadStore, Row};
+use crate::util::{full_hash, hash_prefix, Bytes, FullHash, HashPrefix, HASH_PREFIX_LEN};
+
+// Blocks whose changes can be rolled back on reorgs
+const UNDO_DEPTH: usize = 100;
+
+// Rows written at once when finishing the bulk import
+const FINISH_BATCH_SIZE: usize = 100_000;
+
+//
+// Key of a row storing an unspent output of a script
+// (the value is a `UtxoValue`)
+//
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+struct UtxoKey {
+    code: u8,
+    script_hash: FullHash,
+    txid: FullHash,
+    vout: u16,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+struct UtxoValue {
+    value: u64,
+    height: u32,
+    coinbase: bool, // whether it is created by the coinbase of its block
+}
+
+//
+// Key of a row storing the script hash of an unspent output
+//
+#[derive(Serialize, Deserialize)]
+struct OutPointKey {
+    code: u8,
+    txid: FullHash,
+    vout: u16,
+}
+
+//
+// Key of a row storing the input spending an output of a script
+// (the value is the spending transaction number), so that the spending side of
+// a script's history doesn't need a lookup per funding output
+//
+#[derive(Serialize, Deserialize)]
+struct SpenderKey {
+    code: u8,
+    script_hash_prefix: HashPrefix,
+    txid_prefix: HashPrefix,
+    vout: u16,
+}
+
+//
+// Key of a row storing the changes made by a block (as a `BlockUndo`)
+//
+#[derive(Serialize, Deserialize)]
+struct UndoKey {
+    code: u8,
+    hash: FullHash,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BlockUndo {
+    prev_blockhash: FullHash,
+    spent: Vec<(UtxoKey, UtxoValue)>,
+    created: Vec<(UtxoKey, UtxoValue)>,
+}
+
+//
+// Key of a row storing the confirmed balance of a script (as a u64)
+//
+#[derive(Serialize, Deserialize)]
+struct BalanceKey {
+    code: u8,
+    script_hash: FullHash,
+}
+
+// Last block applied to the UTXO set
+const TIP_KEY: &[u8] = b"Y";
+
+// Marks a UTXO set being built by the bulk import
+const BULK_KEY: &[u8] = b"G";
+
+// Output spent by a bulk-imported block, removed once all the blocks are imported
+fn spent_key(key: &UtxoKey) -> Bytes {
+    [b"Q", &bincode::serialize(key).unwrap()[..]].concat()
+}
+
+fn utxo_key(script_hash: FullHash, txid: &Sha256dHash, vout: u32) -> UtxoKey {
+    UtxoKey {
+        code: b'U',
+        script_hash,
+        txid: full_hash(&txid[..]),
+        vout: vout as u16,
+    }
+}
+
+fn outpoint_key(txid: &FullHash, vout: u16) -> Bytes {
+    bincode::serialize(&OutPointKey {
+        code: b'P',
+        txid: *txid,
+        vout,
+    })
+    .unwrap()
+}
+
+fn spender_key(key: &UtxoKey) -> Bytes {
+    bincode::serialize(&SpenderKey {
+        code: b'S',
+        script_hash_prefix: hash_prefix(&key.script_hash),
+        txid_prefix: hash_prefix(&key.txid),
+        vout: key.vout,
+    })
+    .unwrap()
+}
+
+fn balance_key(script_hash: &FullHash) -> Bytes {
+    bincode::serialize(&BalanceKey {
+        code: b'A',
+        script_hash: *script_hash,
+    })
+    .unwrap()
+}
+
+fn undo_key(blockhash: &Sha256dHash) -> Bytes {
+    bincode::serialize(&UndoKey {
+        code: b'X',
+        hash: full_hash(&blockhash[..]),
+    })
+    .unwrap()
+}
+
+//
+// Unspent output of a script, as stored in the UTXO set
+//
+pub struct Utxo {
+    pub txid: Sha256dHash,
+    pub vout: usize,
+    pub value: u64,
+    pub height: usize,
+    pub coinbase: bool,
+}
+
+impl Utxo {
+    // Position of its transaction in its block, only known for the coinbases
+    pub fn position(&self) -> Option<usize> {
+        match self.coinbase {
+            true => Some(0),
+            false => None,
+        }
+    }
+}
+
+pub fn unspent(store: &dyn ReadStore, script_hash: &FullHash) -> Vec<Utxo> {
+    let prefix = [&[b'U'][..], &script_hash[..]].concat();
+    store
+        .scan(&prefix)
+        .iter()
+        .map(|row| {
+            let key: UtxoKey = bincode::deserialize(&row.key).expect("failed to parse UtxoKey");
+            let value: UtxoValue =
+                bincode::deserialize(&row.value).expect("failed to parse UtxoValue");
+            Utxo {
+                txid: deserialize(&key.txid).unwrap(),
+                vout: key.vout as usize,
+                value: value.value,
+                height: value.height as usize,
+                coinbase: value.coinbase,
+            }
+        })
+        .collect()
+}
+
+// Spending transaction numbers of the outputs of a script, by (funding txid prefix, vout)
+pub type Spenders = HashMap<(HashPrefix, u16), Vec<TxNum>>;
+
+pub fn spenders(store: &dyn ReadStore, script_hash: &[u8]) -> Spenders {
+    let prefix = [&[b'S'][..], &script_hash[..HASH_PREFIX_LEN]].concat();
+    let mut spenders = Spenders::new();
+    for row in store.scan(&prefix) {
+        let key: SpenderKey = bincode::deserialize(&row.key).expect("failed to parse SpenderKey");
+        spenders
+            .entry((key.txid_prefix, key.vout))
+            .or_default()
+            .push(bincode::deserialize(&row.value).expect("failed to parse TxNum"));
+    }
+    spenders
+}
+
+// Height of the last block applied to the UTXO set (if it's part of the indexed chain)
+pub fn tip_height(store: &DBStore, index: &Index) -> Option<usize> {
+    index
+        .get_header_by_block_hash(UtxoIndex::tip(store))
+        .map(|header| header.height())
+}
+
+// Drop the UTXO set, so that it's rebuilt from the blocks on the next update
+pub fn reset(store: &DBStore) {
+    for code in [b'U', b'P', b'S', b'A', b'X', b'Q'] {
+        store.delete_prefix(&[code]);
+    }
+    store.write_batch(vec![], vec![TIP_KEY.to_vec(), BULK_KEY.to_vec()]);
+}
+
+//
+// Output spent by a transaction input, as recorded by bitcoind's undo data
+//
+pub struct SpentOutput {
+    pub output: TxOut,
+    pub height: u32,
+}
+
+// Start building the UTXO set during the initial bulk import (or resume an
+// interrupted build), returning the height it's built up to: the last blocks
+// are applied over JSONRPC, for their changes to be rolled back on reorgs.
+pub fn start_bulk_import(store: &DBStore, initial: bool, best_height: usize) -> Option<usize> {
+    if let Some(value) = store.get(BULK_KEY) {
+        let height: u32 = bincode::deserialize(&value).expect("failed to parse bulk height");
+        return Some(height as usize);
+    }
+    if !initial || UtxoIndex::tip(store) != Sha256dHash::default() {
+        return None;
+    }
+    let height = best_height.saturating_sub(UNDO_DEPTH);
+    store.write_batch(
+        vec![Row {
+            key: BULK_KEY.to_vec(),
+            value: bincode::serialize(&(height as u32)).unwrap(),
+        }],
+        vec![],
+    );
+    Some(height)
+}
+
+// Rows applying a block to the UTXO set during the bulk import, where blocks
+// are indexed in any order (see `finish_bulk_import`). `spent` holds the
+// outputs spent by each non-coinbase transaction.
+pub fn bulk_rows(block: &Block, height: usize, spent: &[Vec<SpentOutput>]) -> Vec<Row> {
+    let mut rows = vec![];
+    for ((position, tx), spent) in block.txdata.iter().enumerate().skip(1).zip(spent) {
+        let num = tx_num(height, position);
+        for (input, spent) in tx.input.iter().zip(spent) {
+            let prevout = &input.previous_output;
+            let script_hash = compute_script_hash(&spent.output.script_pubkey[..]);
+            let key = utxo_key(script_hash, &prevout.txid, prevout.vout);
+            rows.push(Row {
+                key: spent_key(&key),
+                value: vec![],
+            });
+            rows.push(Row {
+                key: spender_key(&key),
+                value: num.to_vec(),
+            });
+        }
+    }
+    for tx in &block.txdata {
+        let txid = tx.txid();
+        for (vout, output) in tx.output.iter().enumerate() {
+            if output.script_pubkey.is_provably_unspendable() {
+                continue;
+            }
+            let script_hash = compute_script_hash(&output.script_pubkey[..]);
+            let key = utxo_key(script_hash, &txid, vout as u32);
+            let value = UtxoValue {
+                value: output.value,
+                height: height as u32,
+                coinbase: tx.is_coin_base(),
+            };
+            rows.push(Row {
+                key: bincode::serialize(&key).unwrap(),
+                value: bincode::serialize(&value).unwrap(),
+            });
+            rows.push(Row {
+                key: outpoint_key(&key.txid, key.vout),
+                value: script_hash.to_vec(),
+            });
+        }
+    }
+    rows
+}
+
+// Once all the blocks up to `tip` are imported, remove their spent outputs and
+// compute the balances (the UTXO set is dropped if the blocks are incomplete)
+pub fn finish_bulk_import(store: &DBStore, tip: Option<&Sha256dHash>) {
+    let tip = match tip {
+        Some(tip) => tip,
+        None => {
+            warn!("UTXO set is incomplete, it will be rebuilt over JSONRPC");
+            reset(store);
+            return;
+        }
+    };
+    info!("removing spent outputs from the UTXO set");
+    let mut deleted = vec![];
+    for row in store.iter_scan(b"Q") {
+        let key: UtxoKey = bincode::deserialize(&row.key[1..]).expect("failed to parse UtxoKey");
+        deleted.push(bincode::serialize(&key).unwrap());
+        deleted.push(outpoint_key(&key.txid, key.vout));
+        deleted.push(row.key);
+        if deleted.len() >= FINISH_BATCH_SIZE {
+            store.write_batch(vec![], std::mem::take(&mut deleted));
+        }
+    }
+    store.write_batch(vec![], deleted);
+
+    info!("computing balances");
+    let mut rows = vec![];
+    let mut current: Option<(FullHash, u64)> = None;
+    // the unspent outputs are sorted by script hash
+    for row in store.iter_scan(b"U") {
+        let key: UtxoKey = bincode::deserialize(&row.key).expect("failed to parse UtxoKey");
+        let value: UtxoValue = bincode::deserialize(&row.value).expect("failed to parse UtxoValue");
+        match current {
+            Some((script_hash, ref mut total)) if script_hash == key.script_hash => {
+                *total += value.value
+            }
+            _ => {
+                rows.extend(current.take().and_then(nonzero_balance_row));
+                current = Some((key.script_hash, value.value));
+            }
+        }
+        if rows.len() >= FINISH_BATCH_SIZE {
+            store.write_batch(std::mem::take(&mut rows), vec![]);
+        }
+    }
+    rows.extend(current.and_then(nonzero_balance_row));
+    rows.push(Row {
+        key: TIP_KEY.to_vec(),
+        value: serialize(tip),
+    });
+    store.write_batch(rows, vec![BULK_KEY.to_vec()]);
+    info!("UTXO set built up to block {}", tip);
+}
+
+fn balance_row(script_hash: &FullHash, value: u64) -> Row {
+    Row {
+        key: balance_key(script_hash),
+        value: bincode::serialize(&value).unwrap(),
+    }
+}
+
+fn nonzero_balance_row((script_hash, value): (FullHash, u64)) -> Option<Row> {
+    match value {
+        0 => None,
+        _ => Some(balance_row(&script_hash, value)),
+    }
+}
+
+pub fn balance(store: &dyn ReadStore, script_hash: &FullHash) -> u64 {
+    store
+        .get(&balance_key(script_hash))
+        .map(|value| bincode::deserialize(&value).expect("failed to parse balance"))
+        .unwrap_or(0)
+}
+
+//
+// Changes to the UTXO set (and the balances), written atomically
+//
+#[derive(Default)]
+struct Batch {
+    created: HashMap<(FullHash, u16), (UtxoKey, UtxoValue)>,
+    spent: HashSet<UtxoKey>, // created before this batch
+    balances: HashMap<FullHash, i64>,
+    rows: Vec<Row>,
+    deleted: Vec<Bytes>,
+}
+
+impl Batch {
+    fn lookup(&self, store: &DBStore, txid: &Sha256dHash, vout: u32) -> Result<(UtxoKey, UtxoValue)> {
+        let outpoint = (full_hash(&txid[..]), vout as u16);
+        if let Some(utxo) = self.created.get(&outpoint) {
+            return Ok(*utxo);
+        }
+        let script_hash = store
+            .get(&outpoint_key(&outpoint.0, outpoint.1))
+            .chain_err(|| format!("missing UTXO {}:{}", txid, vout))?;
+        let key = utxo_key(full_hash(&script_hash), txid, vout);
+        let value = store
+            .get(&bincode::serialize(&key).unwrap())
+            .chain_err(|| format!("missing UTXO {}:{}", txid, vout))?;
+        Ok((key, bincode::deserialize(&value).unwrap()))
+    }
+
+    fn add(&mut self, key: UtxoKey, value: UtxoValue) {
+        *self.balances.entry(key.script_hash).or_insert(0) += value.value as i64;
+        self.created.insert((key.txid, key.vout), (key, value));
+    }
+
+    fn remove(&mut self, key: UtxoKey, value: UtxoValue) {
+        *self.balances.entry(key.script_hash).or_insert(0) -= value.value as i64;
+        if self.created.remove(&(key.txid, key.vout)).is_none() {
+            self.spent.insert(key);
+        }
+    }
+
+    fn write(mut self, store: &DBStore, tip: &Sha256dHash) {
+        for (key, value) in self.created.into_values() {
+            self.rows.push(Row {
+                key: bincode::serialize(&key).unwrap(),
+                value: bincode::serialize(&value).unwrap(),
+            });
+            self.rows.push(Row {
+                key: outpoint_key(&key.txid, key.vout),
+                value: key.script_hash.to_vec(),
+            });
+        }
+        for key in self.spent {
+            self.deleted.push(bincode::serialize(&key).unwrap());
+            self.deleted.push(outpoint_key(&key.txid, key.vout));
+        }
+        for (script_hash, delta) in self.balances {
+            let value = (balance(store, &script_hash) as i64 + delta) as u64;
+            match value {
+                0 => self.deleted.push(balance_key(&script_hash)),
+                _ => self.rows.push(balance_row(&script_hash, value)),
+            }
+        }
+        self.rows.push(Row {
+            key: TIP_KEY.to_vec(),
+            value: serialize(tip),
+        });
+        store.write_batch(self.rows, self.deleted);
+    }
+}
+
+//
+// UTXO set of the indexed chain, updated per block
+//
+pub struct UtxoIndex {
+    daemon: Daemon,
+    batch_size: usize,
+}
+
+impl UtxoIndex {
+    pub fn new(daemon: &Daemon, batch_size: usize) -> Result<UtxoIndex> {
+        Ok(UtxoIndex {
+            daemon: daemon.reconnect()?,
+            batch_size,
+        })
+    }
+
+    fn tip(store: &DBStore) -> Sha256dHash {
+        store
+            .get(TIP_KEY)
+            .map(|tip| deserialize(&tip).unwrap())
+            .unwrap_or_default()
+    }
+
+    fn connect(
+        batch: &mut Batch,
+        store: &DBStore,
+        block: &Block,
+        height: usize,
+        undo: bool,
+    ) -> Result<()> {
+        let mut spent = vec![];
+        let mut created = vec![];
+        for (position, tx) in block.txdata.iter().enumerate() {
+            let txid = tx.txid();
+            if !tx.is_coin_base() {
+                for input in &tx.input {
+                    let prevout = &input.previous_output;
+                    let (key, value) = batch.lookup(store, &prevout.txid, prevout.vout)?;
+                    batch.remove(key, value);
+                    batch.rows.push(Row {
+                        key: spender_key(&key),
+                        value: tx_num(height, position).to_vec(),
+                    });
+                    spent.push((key, value));
+                }
+            }
+            for (vout, output) in tx.output.iter().enumerate() {
+                if output.script_pubkey.is_provably_unspendable() {
+                    continue;
+                }
+                let script_hash = compute_script_hash(&output.script_pubkey[..]);
+                let key = utxo_key(script_hash, &txid, vout as u32);
+                let value = UtxoValue {
+                    value: output.value,
+                    height: height as u32,
+                    coinbase: tx.is_coin_base(),
+                };
+                batch.add(key, value);
+                created.push((key, value));
+            }
+        }
+        if undo {
+            let undo = BlockUndo {
+                prev_blockhash: full_hash(&block.header.prev_blockhash[..]),
+                spent,
+                created,
+            };
+            batch.rows.push(Row {
+                key: undo_key(&block.bitcoin_hash()),
+                value: bincode::serialize(&undo).unwrap(),
+            });
+        }
+        Ok(())
+    }
+
+    // Roll back the last applied block, returning its parent
+    fn disconnect(store: &DBStore, blockhash: &Sha256dHash) -> Result<Sha256dHash> {
+        let undo = store
+            .get(&undo_key(blockhash))
+            .chain_err(|| format!("cannot roll back UTXO set from {} (reorg too deep?)", blockhash))?;
+        let undo: BlockUndo = bincode::deserialize(&undo).unwrap();
+        info!("rolling back UTXO set from block {}", blockhash);
+        // outputs both created and spent by this block are added, then removed
+        let mut batch = Batch::default();
+        for (key, value) in undo.spent {
+            batch.deleted.push(spender_key(&key));
+            batch.add(key, value);
+        }
+        for (key, value) in undo.created {
+            batch.remove(key, value);
+        }
+        batch.deleted.push(undo_key(blockhash));
+        let prev_blockhash: Sha256dHash = deserialize(&undo.prev_blockhash).unwrap();
+        batch.write(store, &prev_blockhash);
+        Ok(prev_blockhash)
+    }
+
+    pub fn update(&self, store: &DBStore, index: &Index, waiter: &Waiter) -> Result<()> {
+        let best = match index.best_header() {
+            Some(best) => best,
+            None => return Ok(()),
+        };
+        // roll back the blocks which are not part of the indexed chain anymore
+        let mut tip = UtxoIndex::tip(store);
+        let start = loop {
+            if tip == Sha256dHash::default() {
+                break 0;
+            }
+            match index.get_header_by_block_hash(tip) {
+                Some(header) => break header.height() + 1,
+                None => tip = UtxoIndex::disconnect(store, &tip)?,
+            }
+        };
+        if start > best.height() {
+            return Ok(());
+        }
+        info!("updating UTXO set from height {} to {}", start, best.height());
+        let heights: Vec<usize> = (start..=best.height()).collect();
+        for chunk in heights.chunks(self.batch_size) {
+            waiter.poll()?;
+            let headers = chunk
+                .iter()
+                .map(|height| index.get_header(*height).chain_err(|| "missing header"))
+                .collect::<Result<Vec<_>>>()?;
+            let blockhashes: Vec<Sha256dHash> = headers.iter().map(|h| *h.hash()).collect();
+            let mut batch = Batch::default();
+            for (header, block) in headers.iter().zip(self.daemon.getblocks(&blockhashes)?) {
+                let height = header.height();
+                let undo = height + UNDO_DEPTH > best.height();
+                UtxoIndex::connect(&mut batch, store, &block, height, undo)?;
+                if let Some(old) = height.checked_sub(UNDO_DEPTH).and_then(|h| index.get_header(h)) {
+                    batch.deleted.push(undo_key(old.hash()));
+                }
+            }
+            batch.write(store, blockhashes.last().unwrap());
+            debug!("UTXO set updated to height {}", chunk.last().unwrap());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::blockdata::block::BlockHeader;
+    use bitcoin::blockdata::script::Script;
+    use bitcoin::blockdata::transaction::{OutPoint, Transaction, TxIn, TxOut};
+    use crate::store::TempStore;
+
+    fn tx(inputs: Vec<OutPoint>, outputs: &[(&Script, u64)]) -> Transaction {
+        Transaction {
+            version: 1,
+            lock_time: 0,
+            input: inputs
+                .into_iter()
+                .map(|previous_output| TxIn {
+                    previous_output,
+                    script_sig: Script::new(),
+                    sequence: 0xFFFFFFFF,
+                    witness: vec![],
+                })
+                .collect(),
+            output: outputs
+                .iter()
+                .map(|(script, value)| TxOut {
+                    value: *value,
+                    script_pubkey: (*script).clone(),
+                })
+                .collect(),
+        }
+    }
+
+    fn block(prev_blockhash: Sha256dHash, txdata: Vec<Transaction>) -> Block {
+        Block {
+            header: BlockHeader {
+                version: 1,
+                prev_blockhash,
+                merkle_root: Sha256dHash::default(),
+                time: 0,
+                bits: 0,
+                nonce: 0,
+            },
+            txdata,
+        }
+    }
+
+    fn values(store: &DBStore, script: &Script) -> Vec<(usize, u64)> {
+        let mut values: Vec<(usize, u64)> = unspent(store, &compute_script_hash(&script[..]))
+            .into_iter()
+            .map(|utxo| (utxo.height, utxo.value))
+            .collect();
+        values.sort_unstable();
+        values
+    }
+
+    // Values of the unspent outputs created by coinbases
+    fn coinbase_values(store: &DBStore, script: &Script) -> Vec<u64> {
+        let mut values: Vec<u64> = unspent(store, &compute_script_hash(&script[..]))
+            .into_iter()
+            .filter(|utxo| utxo.coinbase)
+            .map(|utxo| utxo.value)
+            .collect();
+        values.sort_unstable();
+        values
+    }
+
+    #[test]
+    fn test_connect_disconnect() {
+        let store = TempStore::new("utxo");
+        let (alice, bob) = (Script::from(vec![0x51]), Script::from(vec![0x52]));
+        let coinbase = |value| tx(vec![OutPoint::null()], &[(&alice, value)]);
+
+        let tx1 = coinbase(50);
+        let block1 = block(Sha256dHash::default(), vec![tx1.clone()]);
+        let mut batch = Batch::default();
+        UtxoIndex::connect(&mut batch, &store, &block1, 1, true).unwrap();
+        batch.write(&store, &block1.bitcoin_hash());
+        assert_eq!(values(&store, &alice), vec![(1, 50)]);
+
+        // spend an output created by a previous block, and one created in this block
+        let tx2 = tx(vec![OutPoint { txid: tx1.txid(), vout: 0 }], &[(&bob, 30), (&alice, 20)]);
+        let tx3 = tx(vec![OutPoint { txid: tx2.txid(), vout: 0 }], &[(&bob, 25)]);
+        let block2 = block(block1.bitcoin_hash(), vec![coinbase(51), tx2.clone(), tx3.clone()]);
+        let mut batch = Batch::default();
+        UtxoIndex::connect(&mut batch, &store, &block2, 2, true).unwrap();
+        batch.write(&store, &block2.bitcoin_hash());
+        assert_eq!(values(&store, &alice), vec![(2, 20), (2, 51)]);
+        assert_eq!(values(&store, &bob), vec![(2, 25)]);
+        assert_eq!(coinbase_values(&store, &alice), vec![51]);
+        assert!(coinbase_values(&store, &bob).is_empty());
+        assert_eq!(balance(&store, &compute_script_hash(&alice[..])), 71);
+        assert_eq!(balance(&store, &compute_script_hash(&bob[..])), 25);
+        let bob_spenders = spenders(&store, &compute_script_hash(&bob[..]));
+        let outpoint = (hash_prefix(&tx2.txid()[..]), 0);
+        assert_eq!(bob_spenders[&outpoint], vec![tx_num(2, 2)]);
+        assert_eq!(spenders(&store, &compute_script_hash(&alice[..])).len(), 1);
+
+        let tip = UtxoIndex::disconnect(&store, &block2.bitcoin_hash()).unwrap();
+        assert_eq!(tip, block1.bitcoin_hash());
+        assert_eq!(UtxoIndex::tip(&store), tip);
+        assert_eq!(values(&store, &alice), vec![(1, 50)]);
+        assert_eq!(coinbase_values(&store, &alice), vec![50]); // restored by the undo row
+        assert_eq!(values(&store, &bob), vec![]);
+        assert_eq!(balance(&store, &compute_script_hash(&alice[..])), 50);
+        assert_eq!(balance(&store, &compute_script_hash(&bob[..])), 0);
+        assert!(spenders(&store, &compute_script_hash(&bob[..])).is_empty());
+        assert!(spenders(&store, &compute_script_hash(&alice[..])).is_empty());
+
+        reset(&store);
+        assert_eq!(UtxoIndex::tip(&store), Sha256dHash::default());
+        assert_eq!(values(&store, &alice), vec![]);
+        assert!(store.get(&undo_key(&block2.bitcoin_hash())).is_none());
+    }
+
+    #[test]
+    fn test_bulk_import() {
+        let store = TempStore::new("utxo-bulk");
+        let (alice, bob) = (Script::from(vec![0x51]), Script::from(vec![0x52]));
+        let coinbase = |value| tx(vec![OutPoint::null()], &[(&alice, value)]);
+        let spent = |script: &Script, value, height| SpentOutput {
+            output: TxOut {
+                value,
+                script_pubkey: script.clone(),
+            },
+            height,
+        };
+        assert_eq!(start_bulk_import(&store, false, 200), None);
+        assert_eq!(start_bulk_import(&store, true, 200), Some(100));
+        assert_eq!(start_bulk_import(&store, false, 300), Some(100)); // resumed
+
+        let tx1 = coinbase(50);
+        let block1 = block(Sha256dHash::default(), vec![tx1.clone()]);
+        let tx2 = tx(vec![OutPoint { txid: tx1.txid(), vout: 0 }], &[(&bob, 30), (&alice, 20)]);
+        let tx3 = tx(vec![OutPoint { txid: tx2.txid(), vout: 0 }], &[(&bob, 25)]);
+        let block2 = block(block1.bitcoin_hash(), vec![coinbase(51), tx2, tx3]);
+        // the blocks are imported in any order
+        let undo2 = vec![vec![spent(&alice, 50, 1)], vec![spent(&bob, 30, 2)]];
+        store.write_batch(bulk_rows(&block2, 2, &undo2), vec![]);
+        store.write_batch(bulk_rows(&block1, 1, &[]), vec![]);
+        finish_bulk_import(&store, Some(&block2.bitcoin_hash()));
+
+        assert_eq!(values(&store, &alice), vec![(2, 20), (2, 51)]);
+        assert_eq!(values(&store, &bob), vec![(2, 25)]);
+        assert_eq!(coinbase_values(&store, &alice), vec![51]);
+        assert!(coinbase_values(&store, &bob).is_empty());
+        assert_eq!(balance(&store, &compute_script_hash(&alice[..])), 71);
+        assert_eq!(balance(&store, &compute_script_hash(&bob[..])), 25);
+        assert_eq!(spenders(&store, &compute_script_hash(&bob[..])).len(), 1);
+        assert_eq!(UtxoIndex::tip(&store), block2.bitcoin_hash());
+        assert!(store.iter_scan(b"Q").next().is_none());
+        assert_eq!(start_bulk_import(&store, true, 200), None);
+
+        // an incomplete import is dropped
+        finish_bulk_import(&store, None);
+        assert_eq!(UtxoIndex::tip(&store), Sha256dHash::default());
+        assert_eq!(values(&store, &alice), vec![]);
+    }
+}
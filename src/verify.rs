@@ -0,0 +1,157 @@
+use bitcoin::blockdata::block::Block;
+use bitcoin::util::hash::BitcoinHash;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::daemon::Daemon;
+use crate::errors::*;
+use crate::index::{compute_script_hash, index_transaction, tx_num, Index};
+use crate::signal::Waiter;
+use crate::store::ReadStore;
+use crate::util::FullHash;
+
+const HEADERS_BATCH_SIZE: usize = 1000;
+
+//
+// Pseudo-random numbers, good enough for picking the samples
+//
+struct XorShift(u64);
+
+impl XorShift {
+    fn new() -> XorShift {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        XorShift(nanos | 1)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % n as u64) as usize
+    }
+}
+
+// Compare the indexed headers with bitcoind's ones
+fn check_headers(index: &Index, daemon: &Daemon, signal: &Waiter) -> Result<Vec<String>> {
+    let mut mismatches = vec![];
+    let best_height = match index.best_header() {
+        Some(best) => best.height(),
+        None => return Ok(mismatches),
+    };
+    let heights: Vec<usize> = (0..=best_height).collect();
+    for heights in heights.chunks(HEADERS_BATCH_SIZE) {
+        signal.poll()?;
+        for (height, header) in heights.iter().zip(daemon.getblockheaders(heights)?) {
+            let indexed = index.get_header(*height).chain_err(|| "missing indexed header")?;
+            if *indexed.hash() != header.bitcoin_hash() {
+                mismatches.push(format!(
+                    "header {} at height {} is not in bitcoind's chain (expected {})",
+                    indexed.hash(),
+                    height,
+                    header.bitcoin_hash()
+                ));
+            }
+        }
+        debug!("checked headers up to height {}", heights[heights.len() - 1]);
+    }
+    Ok(mismatches)
+}
+
+// Re-derive the rows of a script's transactions in a block, and look them up
+fn check_script(
+    store: &dyn ReadStore,
+    block: &Block,
+    height: usize,
+    script_hash: &FullHash,
+) -> Vec<String> {
+    let blockhash = block.bitcoin_hash();
+    let mut mismatches = vec![];
+    for (position, txn) in block.txdata.iter().enumerate() {
+        let matches = txn
+            .output
+            .iter()
+            .any(|output| compute_script_hash(&output.script_pubkey[..]) == *script_hash);
+        if !matches {
+            continue;
+        }
+        for row in index_transaction(txn, tx_num(height, position)) {
+            if store.get(&row.key).as_ref() != Some(&row.value) {
+                mismatches.push(format!(
+                    "missing {} row of tx {} (block {}, script hash {})",
+                    row.key[0] as char,
+                    txn.txid(),
+                    blockhash,
+                    hex::encode(script_hash)
+                ));
+            }
+        }
+    }
+    mismatches
+}
+
+//
+// Check the indexed headers against bitcoind, and the history of
+// `samples` scripts (taken from random blocks) against the blocks.
+// Returns the mismatches found.
+//
+pub fn verify(
+    store: &dyn ReadStore,
+    index: &Index,
+    daemon: &Daemon,
+    samples: usize,
+    signal: &Waiter,
+) -> Result<Vec<String>> {
+    info!("verifying indexed headers");
+    let mut mismatches = check_headers(index, daemon, signal)?;
+    let best_height = match index.best_header() {
+        Some(best) => best.height(),
+        None => return Ok(mismatches),
+    };
+    info!("verifying {} random scripts", samples);
+    let mut rng = XorShift::new();
+    for _ in 0..samples {
+        signal.poll()?;
+        let height = rng.below(best_height + 1);
+        let header = index.get_header(height).chain_err(|| "missing indexed header")?;
+        let block = daemon.getblock(header.hash())?;
+        let txn = &block.txdata[rng.below(block.txdata.len())];
+        let output = &txn.output[rng.below(txn.output.len())];
+        let script_hash = compute_script_hash(&output.script_pubkey[..]);
+        mismatches.extend(check_script(store, &block, height, &script_hash));
+    }
+    Ok(mismatches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::network::constants::Network;
+
+    use crate::index::index_block;
+    use crate::store::{TempStore, WriteStore};
+
+    #[test]
+    fn test_check_script() {
+        let store = TempStore::new("verify");
+        let block = genesis_block(Network::Regtest);
+        let script_hash = compute_script_hash(&block.txdata[0].output[0].script_pubkey[..]);
+        assert_eq!(check_script(&store, &block, 0, &script_hash).len(), 2);
+
+        store.write(index_block(&block, 0));
+        assert!(check_script(&store, &block, 0, &script_hash).is_empty());
+        assert!(check_script(&store, &block, 0, &FullHash::default()).is_empty());
+        assert_eq!(check_script(&store, &block, 1, &script_hash).len(), 2);
+
+        // drop the funding output row
+        let row = index_transaction(&block.txdata[0], tx_num(0, 0))
+            .find(|row| row.key[0] == b'O')
+            .unwrap();
+        store.write_batch(vec![], vec![row.key]);
+        let mismatches = check_script(&store, &block, 0, &script_hash);
+        assert_eq!(mismatches.len(), 1);
+        assert!(mismatches[0].starts_with("missing O row"));
+    }
+}
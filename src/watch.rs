@@ -0,0 +1,196 @@
+use bitcoin::blockdata::block::Block;
+use bitcoin::network::constants::Network;
+use bitcoin::secp256k1::Secp256k1;
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+
+use crate::descriptor::Descriptor;
+use crate::errors::*;
+use crate::index::{
+    compute_script_hash, header_row, parse_script_hash, lookup_txid, tx_num, TxInRow, TxOutRow,
+    TxRow,
+};
+use crate::store::{ReadStore, Row};
+use crate::util::{hash_prefix, FullHash, HashPrefix};
+
+//
+// Scripts indexed in watch-only mode, loaded from `watch_file` (and
+// appended to it by the `watchlist.add` RPC). Each line holds an
+// address, an Electrum script hash, a `raw(<hex script>)`, or a wallet
+// descriptor (whose first `watch_range` keys of each chain are watched).
+//
+pub struct Watchlist {
+    path: PathBuf,
+    network: Network,
+    range: u32,
+    scripts: RwLock<HashSet<FullHash>>,
+    funded: RwLock<HashSet<(HashPrefix, u16)>>, // outputs paying to the scripts
+    file: Mutex<()>,
+}
+
+impl Watchlist {
+    pub fn load(
+        path: &Path,
+        network: Network,
+        range: u32,
+        store: &dyn ReadStore,
+    ) -> Result<Watchlist> {
+        let watchlist = Watchlist {
+            path: path.to_path_buf(),
+            network,
+            range,
+            scripts: RwLock::new(HashSet::new()),
+            funded: RwLock::new(HashSet::new()),
+            file: Mutex::new(()),
+        };
+        // a missing file is created by the first `watchlist.add`
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e).chain_err(|| format!("failed to read {:?}", path)),
+        };
+        {
+            let mut scripts = watchlist.scripts.write().unwrap();
+            for (i, line) in content.lines().enumerate() {
+                let entry = line.trim();
+                if entry.is_empty() || entry.starts_with('#') {
+                    continue;
+                }
+                let script_hashes = watchlist
+                    .parse_entry(entry)
+                    .chain_err(|| format!("{:?}, line {}", path, i + 1))?;
+                scripts.extend(script_hashes);
+            }
+        }
+        // the index only has the rows of the watched scripts' outputs
+        for row in store.scan(b"O") {
+            let row = TxOutRow::from_row(&row);
+            let txid = lookup_txid(store, row.tx_num).chain_err(|| "missing indexed tx")?;
+            watchlist.funded.write().unwrap().insert((hash_prefix(&txid[..]), row.vout));
+        }
+        info!(
+            "watching {} scripts ({} indexed outputs)",
+            watchlist.len(),
+            watchlist.funded.read().unwrap().len()
+        );
+        Ok(watchlist)
+    }
+
+    fn parse_entry(&self, entry: &str) -> Result<Vec<FullHash>> {
+        if let Some(script) = entry.strip_prefix("raw(").and_then(|s| s.strip_suffix(')')) {
+            let script = hex::decode(script).chain_err(|| format!("invalid script {}", script))?;
+            return Ok(vec![compute_script_hash(&script)]);
+        }
+        if let Ok(script_hash) = parse_script_hash(entry, self.network) {
+            return Ok(vec![script_hash]);
+        }
+        let descriptor = Descriptor::parse(entry)?;
+        let secp = Secp256k1::verification_only();
+        let mut script_hashes = vec![];
+        for chain in 0..descriptor.chains() {
+            for index in 0..self.range {
+                let script = descriptor.derive(&secp, chain, index)?;
+                script_hashes.push(compute_script_hash(&script[..]));
+            }
+        }
+        Ok(script_hashes)
+    }
+
+    // Watch more scripts (from their next transactions), returning how many are new
+    pub fn add(&self, entries: &[&str]) -> Result<usize> {
+        let mut script_hashes = vec![];
+        for entry in entries {
+            script_hashes.extend(self.parse_entry(entry.trim())?);
+        }
+        let _file = self.file.lock().unwrap();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .chain_err(|| format!("failed to open {:?}", self.path))?;
+        for entry in entries {
+            writeln!(file, "{}", entry.trim())
+                .chain_err(|| format!("failed to write {:?}", self.path))?;
+        }
+        let mut scripts = self.scripts.write().unwrap();
+        let count = scripts.len();
+        scripts.extend(script_hashes);
+        Ok(scripts.len() - count)
+    }
+
+    pub fn len(&self) -> usize {
+        self.scripts.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Index the transactions funding or spending the watched scripts (the
+    // blocks must be indexed in order, for their spending inputs to be found)
+    pub fn index_block(&self, block: &Block, height: usize) -> Vec<Row> {
+        let scripts = self.scripts.read().unwrap();
+        let mut funded = self.funded.write().unwrap();
+        let mut rows = vec![];
+        for (position, txn) in block.txdata.iter().enumerate() {
+            let txid = txn.txid();
+            let num = tx_num(height, position);
+            let count = rows.len();
+            for input in &txn.input {
+                let prevout = (
+                    hash_prefix(&input.previous_output.txid[..]),
+                    input.previous_output.vout as u16,
+                );
+                if funded.contains(&prevout) {
+                    rows.push(TxInRow::new(num, input).to_row());
+                }
+            }
+            for (vout, output) in txn.output.iter().enumerate() {
+                if scripts.contains(&compute_script_hash(&output.script_pubkey[..])) {
+                    funded.insert((hash_prefix(&txid[..]), vout as u16));
+                    rows.push(TxOutRow::new(num, vout as u32, output).to_row());
+                }
+            }
+            if rows.len() > count {
+                rows.push(TxRow::new(num, &txid).to_row());
+            }
+        }
+        rows.push(header_row(&block.header));
+        rows
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::blockdata::constants::genesis_block;
+
+    use crate::store::{TempStore, WriteStore};
+
+    #[test]
+    fn test_index_block() {
+        let store = TempStore::new("watch");
+        let path = store.dir().join("watchlist");
+        let block = genesis_block(Network::Regtest);
+        let script = &block.txdata[0].output[0].script_pubkey;
+
+        let watchlist = Watchlist::load(&path, Network::Regtest, 10, &store).unwrap();
+        assert!(watchlist.is_empty());
+        assert_eq!(watchlist.index_block(&block, 0).len(), 1); // only the header
+        assert!(watchlist.add(&["not a script"]).is_err());
+        let raw = format!("raw({})", hex::encode(&script[..]));
+        assert_eq!(watchlist.add(&[&raw, &raw]).unwrap(), 1);
+        let rows = watchlist.index_block(&block, 0);
+        let codes: Vec<u8> = rows.iter().map(|row| row.key[0]).collect();
+        assert_eq!(codes, b"OTB".to_vec());
+        store.write(rows);
+
+        // reloaded from the file, with the indexed outputs
+        let watchlist = Watchlist::load(&path, Network::Regtest, 10, &store).unwrap();
+        assert_eq!(watchlist.len(), 1);
+        assert_eq!(watchlist.funded.read().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,464 @@
+use bitcoin::network::constants::Network;
+use bitcoin_hashes::hex::ToHex;
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use bitcoin_hashes::Hash;
+use error_chain::ChainedError;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::errors::*;
+use crate::index::parse_script_hash;
+use crate::metrics::{CounterVec, Metrics};
+use crate::query::Query;
+use crate::util::{spawn_thread, FullHash};
+
+// Events waiting to be delivered to a webhook (the newer ones are dropped once it's full)
+const QUEUE_SIZE: usize = 10_000;
+const MAX_ATTEMPTS: u32 = 10;
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(600);
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+//
+// http://host[:port]/path URL of a webhook
+//
+#[derive(Clone, Debug, PartialEq)]
+struct Url {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl Url {
+    fn parse(url: &str) -> Result<Url> {
+        let rest = url
+            .strip_prefix("http://")
+            .chain_err(|| format!("unsupported webhook URL {} (expected http://...)", url))?;
+        // they would end the request line (or the line of the webhook file)
+        if rest.chars().any(|c| c.is_whitespace() || c.is_control()) {
+            bail!("invalid character in webhook URL {:?}", url);
+        }
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => {
+                let port = port
+                    .parse()
+                    .chain_err(|| format!("invalid port in {}", url))?;
+                (host, port)
+            }
+            _ => (authority, 80),
+        };
+        if host.is_empty() {
+            bail!("missing host in {}", url);
+        }
+        Ok(Url {
+            host: host.to_owned(),
+            port,
+            path: path.to_owned(),
+        })
+    }
+
+    // POST the event, failing unless the reply has a 2xx status
+    fn post(&self, body: &str) -> Result<()> {
+        let host = self.host.trim_start_matches('[').trim_end_matches(']');
+        let addr = (host, self.port)
+            .to_socket_addrs()
+            .chain_err(|| format!("failed to resolve {}", self.host))?
+            .next()
+            .chain_err(|| format!("no address for {}", self.host))?;
+        let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT)
+            .chain_err(|| format!("failed to connect to {}", addr))?;
+        stream
+            .set_read_timeout(Some(TIMEOUT))
+            .chain_err(|| "failed to set timeout")?;
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.path,
+            self.host,
+            self.port,
+            body.len(),
+            body
+        );
+        stream
+            .write_all(request.as_bytes())
+            .chain_err(|| "failed to send event")?;
+        let mut response = vec![];
+        stream
+            .read_to_end(&mut response)
+            .chain_err(|| "failed to read response")?;
+        let response = String::from_utf8_lossy(&response);
+        let status = response.lines().next().unwrap_or_default();
+        match status.split(' ').nth(1) {
+            Some(code) if code.starts_with('2') && code.len() == 3 => Ok(()),
+            _ => bail!("unexpected response {:?}", status),
+        }
+    }
+}
+
+// Deliver the events in order, retrying each one with an exponential backoff
+fn deliver(url: Url, events: Receiver<Value>, pending: Arc<AtomicUsize>, results: Arc<CounterVec>) {
+    for event in events.iter() {
+        let body = event.to_string();
+        let mut backoff = MIN_BACKOFF;
+        for attempt in 1..=MAX_ATTEMPTS {
+            match url.post(&body) {
+                Ok(()) => {
+                    results.inc("delivered");
+                    break;
+                }
+                Err(e) if attempt < MAX_ATTEMPTS => {
+                    debug!(
+                        "webhook {}:{}{} failed (attempt {}), retrying in {:?}: {}",
+                        url.host, url.port, url.path, attempt, backoff, e
+                    );
+                    results.inc("retried");
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+                Err(e) => {
+                    warn!(
+                        "dropped event for webhook {}:{}{} after {} attempts: {}",
+                        url.host,
+                        url.port,
+                        url.path,
+                        MAX_ATTEMPTS,
+                        e.display_chain()
+                    );
+                    results.inc("dropped");
+                }
+            }
+        }
+        pending.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Heights of the transactions of a script (None until the first update)
+type Known = Option<HashMap<Sha256dHash, i64>>;
+
+//
+// Webhook notified of the transactions of its scripts
+//
+struct Hook {
+    url: String,
+    scripts: HashMap<FullHash, Known>,
+    events: SyncSender<Value>,
+    pending: Arc<AtomicUsize>, // queued or being delivered
+}
+
+impl Hook {
+    fn send(&self, event: Value) {
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        if let Err(TrySendError::Full(_)) = self.events.try_send(event) {
+            self.pending.fetch_sub(1, Ordering::SeqCst);
+            warn!("dropped event for webhook {}: its queue is full", self.url);
+        }
+    }
+}
+
+//
+// Webhooks loaded from `webhook_file` (and registered by the `webhook.*`
+// RPCs). Each line holds a URL, followed by the addresses or Electrum
+// script hashes whose transactions are POSTed to it.
+//
+pub struct Webhooks {
+    path: PathBuf,
+    network: Network,
+    hooks: Mutex<Vec<Hook>>,
+    results: Arc<CounterVec>, // of the delivery attempts
+}
+
+impl Webhooks {
+    pub fn load(path: &Path, network: Network, metrics: &Metrics) -> Result<Webhooks> {
+        let webhooks = Webhooks {
+            path: path.to_path_buf(),
+            network,
+            hooks: Mutex::new(vec![]),
+            results: metrics.counter_vec(
+                "addrindexrs_webhook_attempts_total",
+                "Webhook delivery attempts by result (delivered, retried or dropped)",
+                "result",
+            ),
+        };
+        // a missing file is created by the first `webhook.register`
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e).chain_err(|| format!("failed to read {:?}", path)),
+        };
+        {
+            let mut hooks = webhooks.hooks.lock().unwrap();
+            for (i, line) in content.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let mut fields = line.split_whitespace();
+                let url = fields.next().unwrap();
+                let entries: Vec<&str> = fields.collect();
+                let scripts = webhooks
+                    .parse_hook(url, &entries)
+                    .chain_err(|| format!("{:?}, line {}", path, i + 1))?;
+                webhooks.add_scripts(&mut hooks, url, scripts, |_| None);
+            }
+            info!("loaded {} webhooks", hooks.len());
+        }
+        Ok(webhooks)
+    }
+
+    fn parse_hook(&self, url: &str, entries: &[&str]) -> Result<Vec<FullHash>> {
+        Url::parse(url)?;
+        if entries.is_empty() {
+            bail!("no address or script hash for webhook {}", url);
+        }
+        entries
+            .iter()
+            .map(|entry| parse_script_hash(entry, self.network))
+            .collect()
+    }
+
+    fn add_scripts(
+        &self,
+        hooks: &mut Vec<Hook>,
+        url: &str,
+        scripts: Vec<FullHash>,
+        known: impl Fn(&FullHash) -> Known,
+    ) -> usize {
+        let index = match hooks.iter().position(|hook| hook.url == url) {
+            Some(index) => index,
+            None => {
+                let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+                let pending = Arc::new(AtomicUsize::new(0));
+                let (parsed, results) = (Url::parse(url).unwrap(), Arc::clone(&self.results));
+                let counter = Arc::clone(&pending);
+                spawn_thread("webhook", move || {
+                    deliver(parsed, receiver, counter, results)
+                });
+                hooks.push(Hook {
+                    url: url.to_owned(),
+                    scripts: HashMap::new(),
+                    events: sender,
+                    pending,
+                });
+                hooks.len() - 1
+            }
+        };
+        let hook = &mut hooks[index];
+        let count = hook.scripts.len();
+        for script_hash in scripts {
+            let state = known(&script_hash);
+            hook.scripts.entry(script_hash).or_insert(state);
+        }
+        hook.scripts.len() - count
+    }
+
+    // Notify the URL of the next transactions of the entries, returning how many scripts are new
+    pub fn register(&self, query: &Query, url: &str, entries: &[&str]) -> Result<usize> {
+        let scripts = self.parse_hook(url, entries)?;
+        // the current transactions aren't notified
+        let known: HashMap<FullHash, Known> = scripts
+            .iter()
+            .cloned()
+            .zip(query.get_history_batch(&scripts)?)
+            .map(|(script_hash, status)| {
+                let (_, history) = status.chain_err(|| {
+                    ErrorKind::TooManyTxs(query.txid_limit())
+                })?;
+                let txs = history.iter().map(|entry| (entry.txid, entry.height));
+                Ok((script_hash, Some(txs.collect())))
+            })
+            .collect::<Result<_>>()?;
+        let mut hooks = self.hooks.lock().unwrap();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .chain_err(|| format!("failed to open {:?}", self.path))?;
+        writeln!(file, "{} {}", url, entries.join(" "))
+            .chain_err(|| format!("failed to write {:?}", self.path))?;
+        Ok(self.add_scripts(&mut hooks, url, scripts, |script_hash| {
+            known[script_hash].clone()
+        }))
+    }
+
+    // Returns false if the URL wasn't registered
+    pub fn unregister(&self, url: &str) -> Result<bool> {
+        let mut hooks = self.hooks.lock().unwrap();
+        let count = hooks.len();
+        hooks.retain(|hook| hook.url != url); // the queued events are still delivered
+        if hooks.len() == count {
+            return Ok(false);
+        }
+        let content = fs::read_to_string(&self.path)
+            .chain_err(|| format!("failed to read {:?}", self.path))?;
+        let lines: Vec<&str> = content
+            .lines()
+            .filter(|line| line.split_whitespace().next() != Some(url))
+            .collect();
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, lines.join("\n") + "\n")
+            .and_then(|()| fs::rename(&tmp, &self.path))
+            .chain_err(|| format!("failed to write {:?}", self.path))?;
+        Ok(true)
+    }
+
+    pub fn list(&self) -> Value {
+        let hooks = self.hooks.lock().unwrap();
+        let hooks: Vec<Value> = hooks
+            .iter()
+            .map(|hook| {
+                json!({
+                    "url": hook.url,
+                    "scripts": hook.scripts.len(),
+                    "pending": hook.pending.load(Ordering::SeqCst),
+                })
+            })
+            .collect();
+        json!(hooks)
+    }
+
+    // Queue the events of the transactions whose height changed since the
+    // previous call (and of the new ones), after new blocks or mempool transactions
+    pub fn update(&self, query: &Query) -> Result<()> {
+        let mut hooks = self.hooks.lock().unwrap();
+        let mut script_hashes: Vec<FullHash> = hooks
+            .iter()
+            .flat_map(|hook| hook.scripts.keys().cloned())
+            .collect();
+        script_hashes.sort_unstable();
+        script_hashes.dedup();
+        let histories: HashMap<FullHash, HashMap<Sha256dHash, (i64, Option<u64>)>> = script_hashes
+            .iter()
+            .cloned()
+            .zip(query.get_history_batch(&script_hashes)?)
+            // the histories larger than `txid_limit` aren't notified anymore
+            .filter_map(|(script_hash, status)| Some((script_hash, status?.1)))
+            .map(|(script_hash, history)| {
+                let txs = history
+                    .iter()
+                    .map(|entry| (entry.txid, (entry.height, entry.fee)));
+                (script_hash, txs.collect())
+            })
+            .collect();
+        for hook in hooks.iter_mut() {
+            let mut events = vec![];
+            for (script_hash, known) in hook.scripts.iter_mut() {
+                let history = match histories.get(script_hash) {
+                    Some(history) => history,
+                    None => continue,
+                };
+                if let Some(ref known) = *known {
+                    events.extend(changes(script_hash, known, history));
+                }
+                let txs = history.iter().map(|(txid, (height, _))| (*txid, *height));
+                *known = Some(txs.collect());
+            }
+            for event in events {
+                hook.send(event);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Events of the new transactions, and of the confirmed (or reorged) ones
+fn changes(
+    script_hash: &FullHash,
+    known: &HashMap<Sha256dHash, i64>,
+    history: &HashMap<Sha256dHash, (i64, Option<u64>)>,
+) -> Vec<Value> {
+    let mut events: Vec<(i64, Value)> = history
+        .iter()
+        .filter(|(txid, (height, _))| match known.get(*txid) {
+            Some(known_height) => (*known_height).max(0) != (*height).max(0), // -1 is in mempool
+            None => true,
+        })
+        .map(|(txid, (height, fee))| {
+            let mut event = json!({
+                "event": if *height > 0 { "confirmed" } else { "mempool" },
+                "scripthash": Sha256dHash::from_slice(script_hash).unwrap().to_hex(),
+                "txid": txid.to_hex(),
+                "height": height,
+            });
+            if let Some(fee) = fee {
+                event["fee"] = json!(fee);
+            }
+            (*height, event)
+        })
+        .collect();
+    // confirmed transactions in blockchain order, then mempool ones
+    events.sort_by_key(|(height, _)| if *height > 0 { *height } else { i64::MAX });
+    events.into_iter().map(|(_, event)| event).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_url() {
+        let url = Url::parse("http://127.0.0.1:8080/hooks/payments").unwrap();
+        assert_eq!(
+            (url.host.as_str(), url.port, url.path.as_str()),
+            ("127.0.0.1", 8080, "/hooks/payments")
+        );
+        let url = Url::parse("http://example.com").unwrap();
+        assert_eq!(
+            (url.host.as_str(), url.port, url.path.as_str()),
+            ("example.com", 80, "/")
+        );
+        let url = Url::parse("http://[::1]/").unwrap();
+        assert_eq!((url.host.as_str(), url.port), ("[::1]", 80));
+        assert_eq!(Url::parse("http://[::1]:81/").unwrap().port, 81);
+        assert!(Url::parse("https://example.com/").is_err());
+        assert!(Url::parse("http://:80/").is_err());
+        assert!(Url::parse("http://example.com:x/").is_err());
+        assert!(Url::parse("http://example.com/a b").is_err());
+        assert!(Url::parse("http://example.com/\r\nHost: other").is_err());
+        assert!(Url::parse("http://exa\tmple.com/").is_err());
+        assert!(Url::parse("http://example.com\0/").is_err());
+    }
+
+    #[test]
+    fn test_changes() {
+        let script_hash = [1u8; 32];
+        let (tx1, tx2, tx3) = (
+            Sha256dHash::hash(b"1"),
+            Sha256dHash::hash(b"2"),
+            Sha256dHash::hash(b"3"),
+        );
+        let known: HashMap<Sha256dHash, i64> = vec![(tx1, 100), (tx2, 0)].into_iter().collect();
+        let history = vec![
+            (tx1, (100, None)),
+            (tx2, (-1, Some(200))),
+            (tx3, (0, Some(300))),
+        ];
+        let events = changes(&script_hash, &known, &history.into_iter().collect());
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["event"], "mempool");
+        assert_eq!(events[0]["txid"], tx3.to_hex());
+        assert_eq!(events[0]["fee"], 300);
+        assert_eq!(events[0]["scripthash"], hex::encode([1u8; 32]));
+
+        // tx2 and tx3 confirm
+        let history = vec![(tx1, (100, None)), (tx3, (102, None)), (tx2, (101, None))];
+        let events = changes(&script_hash, &known, &history.into_iter().collect());
+        let txids: Vec<&Value> = events.iter().map(|event| &event["txid"]).collect();
+        assert_eq!(txids, vec![&json!(tx2.to_hex()), &json!(tx3.to_hex())]);
+        assert_eq!(events[0]["event"], "confirmed");
+        assert_eq!(events[0]["height"], 101);
+        assert!(events[0].get("fee").is_none());
+    }
+}
@@ -0,0 +1,279 @@
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+
+use crate::errors::*;
+
+//
+// Minimal RFC 6455 server, without I/O: the RPC server feeds it with the
+// bytes read from its sockets, and sends the encoded replies.
+//
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_HEADERS_SIZE: usize = 16 << 10;
+const MAX_MESSAGE_SIZE: usize = 16 << 20;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.input_str(key);
+    sha1.input_str(HANDSHAKE_GUID);
+    let mut digest = [0u8; 20];
+    sha1.result(&mut digest);
+    base64::encode(&digest)
+}
+
+// Parse the HTTP upgrade request at the start of `buf`, returning the
+// client's key and the size of the request (None until it's complete)
+fn parse_handshake(buf: &[u8]) -> Result<Option<(String, usize)>> {
+    let size = match buf.windows(4).position(|end| end == b"\r\n\r\n") {
+        Some(pos) if pos + 4 <= MAX_HEADERS_SIZE => pos + 4,
+        Some(_) => bail!("invalid WebSocket handshake"),
+        None if buf.len() >= MAX_HEADERS_SIZE => bail!("invalid WebSocket handshake"),
+        None => return Ok(None),
+    };
+    let headers = std::str::from_utf8(&buf[..size]).chain_err(|| "invalid WebSocket handshake")?;
+    let key = headers.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        if name.trim().eq_ignore_ascii_case("Sec-WebSocket-Key") {
+            Some(value.trim().to_owned())
+        } else {
+            None
+        }
+    });
+    Ok(Some((key.chain_err(|| "missing Sec-WebSocket-Key header")?, size)))
+}
+
+fn write_frame(output: &mut Vec<u8>, opcode: u8, payload: &[u8]) {
+    output.push(0x80 | opcode); // FIN (server frames are never fragmented)
+    match payload.len() {
+        len if len < 126 => output.push(len as u8),
+        len if len <= 0xFFFF => {
+            output.push(126);
+            output.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            output.push(127);
+            output.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    output.extend_from_slice(payload); // server frames are not masked
+}
+
+// (FIN, opcode, unmasked payload)
+type Frame = (bool, u8, Vec<u8>);
+
+// Parse the frame at the start of `buf`, returning it with its size (None until it's complete)
+fn parse_frame(buf: &[u8]) -> Result<Option<(Frame, usize)>> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    let fin = buf[0] & 0x80 != 0;
+    let opcode = buf[0] & 0x0F;
+    let masked = buf[1] & 0x80 != 0;
+    let (len, offset) = match buf[1] & 0x7F {
+        126 if buf.len() >= 4 => (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4),
+        127 if buf.len() >= 10 => {
+            let mut len = [0u8; 8];
+            len.copy_from_slice(&buf[2..10]);
+            (u64::from_be_bytes(len), 10)
+        }
+        126 | 127 => return Ok(None),
+        len => (len as u64, 2),
+    };
+    if len > MAX_MESSAGE_SIZE as u64 {
+        bail!("too large WebSocket frame: {} bytes", len);
+    }
+    if !masked {
+        bail!("unmasked WebSocket frame from client");
+    }
+    let start = offset + 4; // after the mask
+    let size = start + len as usize;
+    if buf.len() < size {
+        return Ok(None);
+    }
+    let mask = &buf[offset..start];
+    let payload = buf[start..size]
+        .iter()
+        .enumerate()
+        .map(|(i, byte)| byte ^ mask[i % 4])
+        .collect();
+    Ok(Some(((fin, opcode, payload), size)))
+}
+
+//
+// Server side of a WebSocket connection, over the (non-blocking) buffers
+// of its socket: each text message carries a RPC message.
+//
+#[derive(Default)]
+pub struct Codec {
+    established: bool, // once the handshake is replied
+    closed: bool,      // once a close frame is exchanged
+    message: Vec<u8>,  // of the received fragments
+}
+
+impl Codec {
+    pub fn new() -> Codec {
+        Codec::default()
+    }
+
+    // Consume the complete handshake and frames at the start of `input`,
+    // returning the received messages. The handshake response, the pongs
+    // and the close frame are appended to `output`.
+    pub fn decode(&mut self, input: &mut Vec<u8>, output: &mut Vec<u8>) -> Result<Vec<Vec<u8>>> {
+        let mut messages = vec![];
+        let mut consumed = 0;
+        if !self.established {
+            let (key, size) = match parse_handshake(input)? {
+                Some(handshake) => handshake,
+                None => return Ok(messages),
+            };
+            let response = format!(
+                "HTTP/1.1 101 Switching Protocols\r\n\
+                 Upgrade: websocket\r\n\
+                 Connection: Upgrade\r\n\
+                 Sec-WebSocket-Accept: {}\r\n\r\n",
+                accept_key(&key)
+            );
+            output.extend_from_slice(response.as_bytes());
+            self.established = true;
+            consumed = size;
+        }
+        while !self.closed {
+            let ((fin, opcode, payload), size) = match parse_frame(&input[consumed..])? {
+                Some(frame) => frame,
+                None => break,
+            };
+            consumed += size;
+            match opcode {
+                OPCODE_TEXT | OPCODE_BINARY | OPCODE_CONTINUATION => {
+                    if self.message.len() + payload.len() > MAX_MESSAGE_SIZE {
+                        bail!("too large WebSocket message");
+                    }
+                    self.message.extend_from_slice(&payload);
+                    if fin {
+                        messages.push(std::mem::take(&mut self.message));
+                    }
+                }
+                OPCODE_PING => write_frame(output, OPCODE_PONG, &payload),
+                OPCODE_PONG => (),
+                OPCODE_CLOSE => {
+                    write_frame(output, OPCODE_CLOSE, &payload);
+                    self.closed = true;
+                }
+                _ => bail!("unsupported WebSocket opcode {}", opcode),
+            }
+        }
+        input.drain(..consumed);
+        Ok(messages)
+    }
+
+    pub fn encode(&self, message: &[u8], output: &mut Vec<u8>) {
+        write_frame(output, OPCODE_TEXT, message);
+    }
+
+    // Append a close frame, unless the client closed the connection first
+    pub fn close(&mut self, output: &mut Vec<u8>) {
+        if self.established && !self.closed {
+            write_frame(output, OPCODE_CLOSE, &[]);
+            self.closed = true;
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn client_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [1u8, 2, 3, 4];
+        let mut frame = vec![0x80 | opcode, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    #[test]
+    fn test_accept_key() {
+        // example from RFC 6455
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn test_codec() {
+        let mut codec = Codec::new();
+        let (mut input, mut output) = (vec![], vec![]);
+        let handshake = b"GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+                          Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                          Sec-WebSocket-Version: 13\r\n\r\n";
+        // partial handshake
+        input.extend_from_slice(&handshake[..20]);
+        assert!(codec.decode(&mut input, &mut output).unwrap().is_empty());
+        assert!(output.is_empty());
+        input.extend_from_slice(&handshake[20..]);
+
+        // fragmented request, with a ping in between
+        let mut first = client_frame(OPCODE_TEXT, b"{\"id\":");
+        first[0] &= 0x7F; // clear FIN
+        input.extend_from_slice(&first);
+        input.extend_from_slice(&client_frame(OPCODE_PING, b"hi"));
+        let last = client_frame(OPCODE_CONTINUATION, b"1}");
+        input.extend_from_slice(&last[..3]); // partial frame
+        assert!(codec.decode(&mut input, &mut output).unwrap().is_empty());
+        assert_eq!(input, last[..3].to_vec());
+        input.extend_from_slice(&last[3..]);
+        assert_eq!(codec.decode(&mut input, &mut output).unwrap(), vec![b"{\"id\":1}".to_vec()]);
+        assert!(input.is_empty());
+
+        let mut response = &output[..];
+        let end = response.windows(4).position(|end| end == b"\r\n\r\n").unwrap() + 4;
+        let headers = std::str::from_utf8(&response[..end]).unwrap();
+        assert!(headers.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(headers.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        response = &response[end..];
+        assert_eq!(read_frame_unmasked(&mut response), (OPCODE_PONG, b"hi".to_vec()));
+        assert!(response.is_empty());
+
+        output.clear();
+        codec.encode(b"{\"result\":null}", &mut output);
+        assert_eq!(
+            read_frame_unmasked(&mut &output[..]),
+            (OPCODE_TEXT, b"{\"result\":null}".to_vec())
+        );
+
+        output.clear();
+        input.extend_from_slice(&client_frame(OPCODE_CLOSE, b""));
+        assert!(codec.decode(&mut input, &mut output).unwrap().is_empty());
+        assert!(codec.is_closed());
+        assert_eq!(read_frame_unmasked(&mut &output[..]), (OPCODE_CLOSE, vec![]));
+        codec.close(&mut output); // already closed
+        assert_eq!(output.len(), 2);
+
+        let mut unmasked = client_frame(OPCODE_TEXT, b"x");
+        unmasked[1] &= 0x7F;
+        assert!(Codec { established: true, ..Codec::new() }
+            .decode(&mut unmasked, &mut output)
+            .is_err());
+    }
+
+    fn read_frame_unmasked(reader: &mut impl Read) -> (u8, Vec<u8>) {
+        let mut header = [0u8; 2];
+        reader.read_exact(&mut header).unwrap();
+        assert_eq!(header[1] & 0x80, 0);
+        let mut payload = vec![0u8; header[1] as usize];
+        reader.read_exact(&mut payload).unwrap();
+        (header[0] & 0x0F, payload)
+    }
+}
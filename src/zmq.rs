@@ -0,0 +1,569 @@
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use crossbeam_channel as channel;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::errors::*;
+use crate::util::{spawn_thread, FullHash};
+
+//
+// Minimal ZMTP 3.0 subscriber and publisher (NULL security mechanism),
+// enough to receive the notifications published by bitcoind's -zmqpub*
+// options, and to publish the index events the same way.
+//
+const FLAG_MORE: u8 = 0x01;
+const FLAG_LONG: u8 = 0x02;
+const FLAG_COMMAND: u8 = 0x04;
+
+fn greeting() -> [u8; 64] {
+    let mut greeting = [0u8; 64];
+    greeting[0] = 0xff; // signature
+    greeting[9] = 0x7f;
+    greeting[10] = 3; // version 3.0
+    greeting[11] = 0;
+    greeting[12..16].copy_from_slice(b"NULL"); // mechanism
+    greeting
+}
+
+// Largest frames accepted from bitcoind (twice the largest block), and from the
+// subscribers of the publisher (whose commands and subscriptions are short)
+const MAX_NOTIFICATION_SIZE: usize = 8_000_000;
+const MAX_COMMAND_SIZE: usize = 4096;
+
+// Messages queued for a slow subscriber, before the next ones are dropped
+// (like ZMQ's default high-water mark)
+const SEND_QUEUE_SIZE: usize = 1000;
+
+fn encode_frame(frame: &mut Vec<u8>, flags: u8, body: &[u8]) {
+    if body.len() > 255 {
+        frame.push(flags | FLAG_LONG);
+        frame.extend_from_slice(&(body.len() as u64).to_be_bytes());
+    } else {
+        frame.push(flags);
+        frame.push(body.len() as u8);
+    }
+    frame.extend_from_slice(body);
+}
+
+fn write_frame(stream: &mut TcpStream, flags: u8, body: &[u8]) -> Result<()> {
+    let mut frame = vec![];
+    encode_frame(&mut frame, flags, body);
+    stream
+        .write_all(&frame)
+        .chain_err(|| "failed to send ZMQ frame")
+}
+
+fn read_frame(stream: &mut TcpStream, max_size: usize) -> Result<(u8, Vec<u8>)> {
+    let mut flags = [0u8; 1];
+    stream
+        .read_exact(&mut flags)
+        .chain_err(|| "failed to read ZMQ frame")?;
+    let flags = flags[0];
+    let size = if flags & FLAG_LONG != 0 {
+        let mut size = [0u8; 8];
+        stream
+            .read_exact(&mut size)
+            .chain_err(|| "failed to read ZMQ frame size")?;
+        u64::from_be_bytes(size) as usize
+    } else {
+        let mut size = [0u8; 1];
+        stream
+            .read_exact(&mut size)
+            .chain_err(|| "failed to read ZMQ frame size")?;
+        size[0] as usize
+    };
+    if size > max_size {
+        bail!("too large ZMQ frame: {} bytes", size);
+    }
+    let mut body = vec![0u8; size];
+    stream
+        .read_exact(&mut body)
+        .chain_err(|| "failed to read ZMQ frame body")?;
+    Ok((flags, body))
+}
+
+// Command frames start with the length-prefixed command name
+fn command_name(body: &[u8]) -> &[u8] {
+    match body.first() {
+        Some(&len) if body.len() > len as usize => &body[1..=len as usize],
+        _ => b"",
+    }
+}
+
+fn handshake(stream: &mut TcpStream, socket_type: &str) -> Result<()> {
+    stream
+        .write_all(&greeting())
+        .chain_err(|| "failed to send ZMQ greeting")?;
+    let mut peer = [0u8; 64];
+    stream
+        .read_exact(&mut peer)
+        .chain_err(|| "failed to read ZMQ greeting")?;
+    if peer[0] != 0xff || peer[9] & 0x01 != 0x01 || peer[10] < 3 {
+        bail!("unsupported ZMQ peer greeting: {}", hex::encode(&peer[..]));
+    }
+
+    let mut ready = vec![5];
+    ready.extend_from_slice(b"READY");
+    ready.push(11);
+    ready.extend_from_slice(b"Socket-Type");
+    ready.extend_from_slice(&(socket_type.len() as u32).to_be_bytes());
+    ready.extend_from_slice(socket_type.as_bytes());
+    write_frame(stream, FLAG_COMMAND, &ready)?;
+
+    loop {
+        let (flags, body) = read_frame(stream, MAX_COMMAND_SIZE)?;
+        if flags & FLAG_COMMAND == 0 {
+            bail!("unexpected ZMQ message during handshake");
+        }
+        match command_name(&body) {
+            b"READY" => return Ok(()),
+            b"ERROR" => bail!("ZMQ handshake failed: {:?}", String::from_utf8_lossy(&body)),
+            _ => continue,
+        }
+    }
+}
+
+//
+// Subscribe to `topic` at `addr`, and notify each received message
+// (until the connection fails).
+//
+fn subscribe(addr: SocketAddr, topic: &str, notify: &channel::Sender<()>) -> Result<()> {
+    let mut stream =
+        TcpStream::connect(addr).chain_err(|| format!("failed to connect ZMQ at {}", addr))?;
+    handshake(&mut stream, "SUB")?;
+
+    // ZMTP 3.0 subscriptions are messages prefixed by 0x01
+    let subscription = [&[1u8][..], topic.as_bytes()].concat();
+    write_frame(&mut stream, 0, &subscription)?;
+    info!("subscribed to ZMQ {:?} notifications at {}", topic, addr);
+
+    let mut parts = vec![];
+    loop {
+        let (flags, body) = read_frame(&mut stream, MAX_NOTIFICATION_SIZE)?;
+        if flags & FLAG_COMMAND != 0 {
+            continue; // e.g. heartbeats
+        }
+        parts.push(body);
+        if flags & FLAG_MORE != 0 {
+            continue;
+        }
+        // bitcoind sends [topic, body, sequence]
+        if parts[0] == topic.as_bytes() {
+            trace!("ZMQ {:?} notification", topic);
+            // a pending notification is enough to trigger the next update
+            let _ = notify.try_send(());
+        }
+        parts.clear();
+    }
+}
+
+//
+// ZMQ notifications of new blocks and transactions
+//
+pub struct Notifier {
+    sender: channel::Sender<()>,
+    receiver: channel::Receiver<()>,
+    subscriptions: usize,
+}
+
+impl Notifier {
+    pub fn new() -> Notifier {
+        let (sender, receiver) = channel::bounded(1);
+        Notifier {
+            sender,
+            receiver,
+            subscriptions: 0,
+        }
+    }
+
+    pub fn subscribe(&mut self, addr: SocketAddr, topic: &'static str) {
+        let notify = self.sender.clone();
+        self.subscriptions += 1;
+        spawn_thread("zmq", move || loop {
+            if let Err(e) = subscribe(addr, topic, &notify) {
+                warn!("ZMQ {:?} subscription failed: {}", topic, e);
+            }
+            // don't use the signal Waiter here, so signals are left to the main thread
+            thread::sleep(Duration::from_secs(3));
+        });
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.subscriptions > 0
+    }
+
+    pub fn receiver(&self) -> &channel::Receiver<()> {
+        &self.receiver
+    }
+}
+
+impl Default for Notifier {
+    fn default() -> Self {
+        Notifier::new()
+    }
+}
+
+//
+// Requests of an immediate update (by `server.refresh`), waking the main loop
+// like the ZMQ notifications, and waiting until an update started since is over
+//
+pub struct Refresher {
+    notify: channel::Sender<()>,
+    updates: Mutex<(u64, u64)>, // started and finished by the main loop
+    finished: Condvar,
+}
+
+impl Refresher {
+    pub fn new(notifier: &Notifier) -> Arc<Refresher> {
+        Arc::new(Refresher {
+            notify: notifier.sender.clone(),
+            updates: Mutex::new((0, 0)),
+            finished: Condvar::new(),
+        })
+    }
+
+    // Called by the main loop around each update
+    pub fn start(&self) {
+        self.updates.lock().unwrap().0 += 1;
+    }
+
+    pub fn finish(&self) {
+        self.updates.lock().unwrap().1 += 1;
+        self.finished.notify_all();
+    }
+
+    // Returns false if the update isn't over after `timeout`
+    pub fn refresh(&self, timeout: Option<Duration>) -> bool {
+        let updates = self.updates.lock().unwrap();
+        // the ongoing update may have missed the latest changes
+        let target = updates.0 + 1;
+        let _ = self.notify.try_send(());
+        let pending = |updates: &mut (u64, u64)| updates.1 < target;
+        match timeout {
+            Some(timeout) => {
+                let (_updates, result) = self
+                    .finished
+                    .wait_timeout_while(updates, timeout, pending)
+                    .unwrap();
+                !result.timed_out()
+            }
+            None => {
+                drop(self.finished.wait_while(updates, pending).unwrap());
+                true
+            }
+        }
+    }
+}
+
+//
+// Subscriber connected to the publisher, with its topic prefixes
+//
+struct Subscriber {
+    sender: channel::Sender<Arc<Vec<u8>>>,
+    topics: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+// Like ZMQ, subscriptions are topic prefixes (the empty one matches all)
+fn matches(topics: &[Vec<u8>], topic: &str) -> bool {
+    topics
+        .iter()
+        .any(|prefix| topic.as_bytes().starts_with(prefix))
+}
+
+// Apply the (un)subscriptions sent by the subscriber, until it disconnects
+fn read_subscriptions(stream: &mut TcpStream, topics: &Mutex<Vec<Vec<u8>>>) -> Result<()> {
+    loop {
+        let (flags, body) = read_frame(stream, MAX_COMMAND_SIZE)?;
+        // ZMTP 3.0 uses messages prefixed by 0x01 (or 0x00 to unsubscribe),
+        // ZMTP 3.1 SUBSCRIBE and CANCEL commands
+        let (subscribe, topic) = if flags & FLAG_COMMAND != 0 {
+            let name = command_name(&body);
+            let topic = body.get(1 + name.len()..).unwrap_or_default().to_vec();
+            match name {
+                b"SUBSCRIBE" => (true, topic),
+                b"CANCEL" => (false, topic),
+                _ => continue, // e.g. heartbeats
+            }
+        } else {
+            match body.split_first() {
+                Some((1, topic)) => (true, topic.to_vec()),
+                Some((0, topic)) => (false, topic.to_vec()),
+                _ => continue, // other messages are ignored by PUB sockets
+            }
+        };
+        let mut topics = topics.lock().unwrap();
+        if subscribe {
+            topics.push(topic);
+        } else if let Some(index) = topics.iter().position(|t| *t == topic) {
+            topics.remove(index);
+        }
+    }
+}
+
+fn serve_subscriber(mut stream: TcpStream, subscribers: &Mutex<Vec<Subscriber>>) -> Result<()> {
+    handshake(&mut stream, "PUB")?;
+    let topics = Arc::new(Mutex::new(vec![]));
+    let mut reader = stream
+        .try_clone()
+        .chain_err(|| "failed to clone ZMQ socket")?;
+    let reader_topics = Arc::clone(&topics);
+    spawn_thread("zmq_pub", move || {
+        if let Err(e) = read_subscriptions(&mut reader, &reader_topics) {
+            debug!("ZMQ subscriber disconnected: {}", e);
+        }
+        // for the writer to fail (and be unregistered) on the next message
+        let _ = reader.shutdown(Shutdown::Both);
+    });
+    let (sender, receiver) = channel::bounded(SEND_QUEUE_SIZE);
+    subscribers
+        .lock()
+        .unwrap()
+        .push(Subscriber { sender, topics });
+    for message in receiver.iter() {
+        let result = stream.write_all(&message);
+        if let Err(e) = result {
+            let _ = stream.shutdown(Shutdown::Both);
+            return Err(e).chain_err(|| "failed to send ZMQ message");
+        }
+    }
+    Ok(())
+}
+
+//
+// ZMQ publisher of the index events (to the subscribers' topics), sending
+// multi-part [topic, body, sequence] messages like bitcoind:
+// - `indexedblock`: block hash and height
+// - `mempooltx`: txid of a new mempool transaction
+// - `scripthashupdate`: script hash, txid and height (0 in the mempool)
+// of each script funded or spent by the new transactions
+//
+pub struct Publisher {
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+    sequences: Mutex<HashMap<&'static str, u32>>,
+}
+
+impl Publisher {
+    pub fn start(addr: SocketAddr) -> Result<Publisher> {
+        let listener =
+            TcpListener::bind(addr).chain_err(|| format!("failed to bind ZMQ at {}", addr))?;
+        info!("publishing ZMQ index events at tcp://{}", addr);
+        Ok(Publisher::serve(listener))
+    }
+
+    fn serve(listener: TcpListener) -> Publisher {
+        let subscribers = Arc::new(Mutex::new(Vec::<Subscriber>::new()));
+        let accepted = Arc::clone(&subscribers);
+        spawn_thread("zmq_pub", move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        warn!("failed to accept ZMQ subscriber: {}", e);
+                        continue;
+                    }
+                };
+                let subscribers = Arc::clone(&accepted);
+                spawn_thread("zmq_pub", move || {
+                    if let Err(e) = serve_subscriber(stream, &subscribers) {
+                        debug!("ZMQ subscriber failed: {}", e);
+                    }
+                });
+            }
+        });
+        Publisher {
+            subscribers,
+            sequences: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn publish(&self, topic: &'static str, body: &[u8]) {
+        let sequence = {
+            let mut sequences = self.sequences.lock().unwrap();
+            let sequence = sequences.entry(topic).or_insert(0);
+            *sequence = sequence.wrapping_add(1);
+            sequence.wrapping_sub(1)
+        };
+        let mut message = vec![];
+        encode_frame(&mut message, FLAG_MORE, topic.as_bytes());
+        encode_frame(&mut message, FLAG_MORE, body);
+        encode_frame(&mut message, 0, &sequence.to_le_bytes());
+        let message = Arc::new(message);
+        self.subscribers.lock().unwrap().retain(|subscriber| {
+            if !matches(&subscriber.topics.lock().unwrap(), topic) {
+                return true;
+            }
+            match subscriber.sender.try_send(Arc::clone(&message)) {
+                Ok(()) => true,
+                Err(channel::TrySendError::Full(_)) => {
+                    trace!("dropped ZMQ {:?} message: subscriber queue is full", topic);
+                    true
+                }
+                Err(channel::TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+
+    // The hashes are sent in the byte order they are displayed in (like bitcoind's)
+    pub fn indexed_block(&self, blockhash: &Sha256dHash, height: usize) {
+        let mut body = blockhash[..].to_vec();
+        body.reverse();
+        body.extend_from_slice(&(height as u32).to_le_bytes());
+        self.publish("indexedblock", &body);
+    }
+
+    pub fn mempool_tx(&self, txid: &Sha256dHash) {
+        let mut body = txid[..].to_vec();
+        body.reverse();
+        self.publish("mempooltx", &body);
+    }
+
+    pub fn script_hash_update(&self, script_hash: &FullHash, txid: &Sha256dHash, height: usize) {
+        let mut body = script_hash.to_vec();
+        body.reverse(); // as an Electrum script hash
+        let mut txid = txid[..].to_vec();
+        txid.reverse();
+        body.extend_from_slice(&txid);
+        body.extend_from_slice(&(height as u32).to_le_bytes());
+        self.publish("scripthashupdate", &body);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn publisher(listener: TcpListener) {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut peer = [0u8; 64];
+        stream.read_exact(&mut peer).unwrap();
+        assert_eq!(&peer[12..16], b"NULL");
+        stream.write_all(&greeting()).unwrap();
+
+        let (flags, body) = read_frame(&mut stream, MAX_COMMAND_SIZE).unwrap();
+        assert_eq!(flags, FLAG_COMMAND);
+        assert_eq!(command_name(&body), b"READY");
+        assert!(body.ends_with(b"SUB"));
+        let mut ready = vec![5];
+        ready.extend_from_slice(b"READY");
+        write_frame(&mut stream, FLAG_COMMAND, &ready).unwrap();
+
+        let (flags, body) = read_frame(&mut stream, MAX_COMMAND_SIZE).unwrap();
+        assert_eq!(flags, 0);
+        assert_eq!(body, b"\x01hashtx");
+
+        // another topic, then a multi-part message with a long body
+        write_frame(&mut stream, 0, b"rawtx").unwrap();
+        write_frame(&mut stream, FLAG_MORE, b"hashtx").unwrap();
+        write_frame(&mut stream, FLAG_MORE, &[0u8; 300]).unwrap();
+        write_frame(&mut stream, 0, &[0u8; 4]).unwrap();
+    }
+
+    #[test]
+    fn test_frame_size() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        // a long frame claiming 2^40 bytes isn't allocated
+        client.write_all(&[FLAG_LONG]).unwrap();
+        client.write_all(&(1u64 << 40).to_be_bytes()).unwrap();
+        let e = read_frame(&mut server, MAX_COMMAND_SIZE).unwrap_err();
+        assert!(e.to_string().contains("too large"));
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || publisher(listener));
+
+        let (sender, receiver) = channel::bounded(1);
+        let client = thread::spawn(move || subscribe(addr, "hashtx", &sender));
+
+        receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        server.join().unwrap();
+        assert!(client.join().unwrap().is_err()); // disconnected by the publisher
+        assert!(receiver.try_recv().is_err()); // "rawtx" was ignored
+    }
+
+    fn read_message(stream: &mut TcpStream) -> Vec<Vec<u8>> {
+        let mut parts = vec![];
+        loop {
+            let (flags, body) = read_frame(stream, MAX_NOTIFICATION_SIZE).unwrap();
+            parts.push(body);
+            if flags & FLAG_MORE == 0 {
+                return parts;
+            }
+        }
+    }
+
+    #[test]
+    fn test_publish() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let publisher = Publisher::serve(listener);
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        handshake(&mut stream, "SUB").unwrap();
+        write_frame(&mut stream, 0, b"\x01scripthash").unwrap();
+        write_frame(&mut stream, 0, b"\x01mempool").unwrap();
+        write_frame(&mut stream, 0, b"\x00scripthash").unwrap();
+        let subscribed = || {
+            let subscribers = publisher.subscribers.lock().unwrap();
+            let topics = subscribers
+                .first()
+                .map(|s| s.topics.lock().unwrap().clone());
+            topics == Some(vec![b"mempool".to_vec()])
+        };
+        while !subscribed() {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let txid = Sha256dHash::default();
+        publisher.script_hash_update(&[1u8; 32], &txid, 0); // unsubscribed
+        publisher.indexed_block(&txid, 1); // not subscribed
+        publisher.mempool_tx(&txid);
+        publisher.mempool_tx(&txid);
+        let message = read_message(&mut stream);
+        assert_eq!(
+            message,
+            vec![b"mempooltx".to_vec(), vec![0u8; 32], vec![0, 0, 0, 0]]
+        );
+        let message = read_message(&mut stream);
+        assert_eq!(message[2], vec![1, 0, 0, 0]); // the sequence is per topic
+
+        drop(stream);
+        while !publisher.subscribers.lock().unwrap().is_empty() {
+            publisher.mempool_tx(&txid);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_refresher() {
+        let notifier = Notifier::new();
+        let refresher = Refresher::new(&notifier);
+        // no update is started meanwhile
+        assert!(!refresher.refresh(Some(Duration::from_millis(10))));
+        assert!(notifier.receiver().try_recv().is_ok());
+
+        refresher.start(); // an ongoing update, which doesn't count
+        let main_loop = {
+            let (refresher, receiver) = (Arc::clone(&refresher), notifier.receiver().clone());
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                refresher.finish();
+                receiver.recv().unwrap();
+                refresher.start();
+                refresher.finish();
+            })
+        };
+        assert!(refresher.refresh(None));
+        assert_eq!(*refresher.updates.lock().unwrap(), (2, 2));
+        main_loop.join().unwrap();
+    }
+}